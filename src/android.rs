@@ -0,0 +1,73 @@
+use std::{fs::{self, File}, path::{Path, PathBuf}, process};
+
+use crate::app;
+use crate::perf;
+use crate::{print_step, resolve, resolve_status, shell_quote};
+
+/// Directory on the device the binary and the recording are placed in
+const DEVICE_DIR: &str = "/data/local/tmp";
+
+
+pub fn is_android_target(target: &str) -> bool {
+    target.contains("-android")
+}
+
+/// Push the binary to the connected device, record it with simpleperf and convert the data locally
+///
+/// Returns the path of a trace in `perf script` format.
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool, frequency: Option<u32>) -> PathBuf {
+    let name = Path::new(executable).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "app".to_string());
+    let device_binary = format!("{}/{}", DEVICE_DIR, name);
+    let device_data = format!("{}/simpleperf.data", DEVICE_DIR);
+    let data_path = dir.join("simpleperf.data");
+    let trace_path = dir.join("simpleperf.trace");
+
+    print_step("Pushing binary to device");
+    adb(&["push", executable, &device_binary]);
+    adb(&["shell", &format!("chmod +x {}", shell_quote(&device_binary))]);
+
+    print_step("Running program with simpleperf");
+    // adb joins its arguments into one command line for the shell of the device
+    let mut command: Vec<String> = app::env().iter()
+        .map(|(key, value)| shell_quote(&format!("{}={}", key, value)))
+        .collect();
+    if !command.is_empty() {
+        command.insert(0, "env".to_string());
+    }
+    let frequency = frequency.unwrap_or(perf::FREQUENCY).to_string();
+    command.extend(["simpleperf", "record", "-g", "-f", &frequency, "-o", &device_data, &device_binary].map(shell_quote));
+    command.extend(app_args.iter().map(|a| shell_quote(a)));
+    let status = app::run(process::Command::new("adb").arg("shell").arg(command.join(" ")).stdin(app::stdin()));
+    app::check_exit(status, ignore_exit);
+
+    print_step("Pulling recording from device");
+    adb(&["pull", &device_data, &data_path.to_string_lossy()]);
+
+    // simpleperf looks up binaries under their device path inside the symfs directory
+    let symfs = dir.join("android-symfs");
+    let symfs_binary = symfs.join(device_binary.trim_start_matches('/'));
+    if let Some(parent) = symfs_binary.parent() {
+        resolve(fs::create_dir_all(parent));
+    }
+    resolve(fs::copy(executable, &symfs_binary));
+
+    print_step("Symbolizing with the unstripped host binary");
+    let trace_file = resolve(File::create(&trace_path));
+    let status = resolve(process::Command::new("report_sample.py")
+        .arg("-i")
+        .arg(&data_path)
+        .arg("--symfs")
+        .arg(&symfs)
+        .stdout(process::Stdio::from(trace_file))
+        .status());
+    resolve_status(status);
+
+    trace_path
+}
+
+fn adb(args: &[&str]) {
+    let status = resolve(process::Command::new("adb").args(args).status());
+    resolve_status(status);
+}
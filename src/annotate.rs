@@ -0,0 +1,298 @@
+//! Source lines of a function annotated with the share of its samples
+//!
+//! `perf script` resolves the sampled instruction of every sample to its source line (with
+//! addr2line under the hood), so inlined code is attributed to the line it was written on. With
+//! `--asm` the disassembly of objdump is interleaved, where the offset perf reports into the
+//! symbol is matched against the start address of the symbol in `nm`'s table.
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+
+use crate::manifest;
+use crate::perf;
+use crate::report;
+use crate::serve::html_escape;
+use crate::symbols;
+use crate::{AnnotateArgs, log_command, print_step, resolve};
+
+/// Share of the samples from which a line is highlighted
+const HOT_LINE: f64 = 10.0;
+
+/// Leaf frame of a sample, resolved to its source line
+#[derive(Debug, Clone)]
+struct Hit {
+    function: String,
+    /// Offset of the sampled instruction into the function
+    offset: Option<u64>,
+    dso: Option<PathBuf>,
+    line: Option<(PathBuf, u32)>,
+}
+
+/// One line of the annotated listing
+#[derive(Debug, Clone)]
+enum Row {
+    File(PathBuf),
+    Source { count: Option<u64>, number: u32, text: String },
+    Instruction { count: Option<u64>, address: u64, text: String },
+    Note { count: u64, text: String },
+}
+
+
+pub fn run(args: &AnnotateArgs) {
+    let data = match &args.data {
+        Some(data) => data.clone(),
+        None => resolve(manifest::load()).target_directory.join("profiling").join("perf.data"),
+    };
+    if !data.is_file() {
+        resolve::<(), _>(Err(format!("{} does not exist, record first or pass --data", data.to_string_lossy())));
+    }
+
+    symbols::prepare(&data);
+    print_step("Resolving source lines");
+    let hits = resolve(source_lines(&data));
+    let mut by_function: HashMap<&str, Vec<&Hit>> = HashMap::new();
+    for hit in hits.iter().filter(|h| h.function.contains(&args.symbol)) {
+        by_function.entry(&hit.function).or_default().push(hit);
+    }
+    let mut functions: Vec<(&str, Vec<&Hit>)> = by_function.into_iter().collect();
+    functions.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+    let Some((function, function_hits)) = functions.first() else {
+        resolve(Err(format!("No samples in a function matching {:?}", args.symbol)))
+    };
+    if functions.len() > 1 {
+        eprintln!("{}", format!("Note: {} functions match {:?}, showing the hottest", functions.len(), args.symbol).yellow());
+        for (other, other_hits) in functions.iter().skip(1).take(report::SUMMARY_ROWS) {
+            eprintln!("{}", format!("  {} ({} samples)", other, other_hits.len()).yellow());
+        }
+    }
+
+    let total = function_hits.len() as u64;
+    let mut lines: HashMap<&Path, BTreeMap<u32, u64>> = HashMap::new();
+    let mut unknown = 0;
+    for hit in function_hits {
+        match &hit.line {
+            Some((file, line)) => *lines.entry(file).or_default().entry(*line).or_default() += 1,
+            None => unknown += 1,
+        }
+    }
+
+    let mut rows = if args.asm {
+        print_step("Disassembling");
+        resolve(disassembly(function, function_hits, &lines))
+    } else {
+        source_rows(&lines, args.context)
+    };
+    if unknown > 0 && !args.asm {
+        rows.push(Row::Note { count: unknown, text: "without line information".to_string() });
+    }
+
+    let title = format!("{} ({} samples, {:.2}% of all)", function, total, report::percent(total, hits.len() as u64));
+    match &args.output {
+        Some(path) if path.extension().is_some_and(|e| e == "html") => {
+            resolve(fs::write(path, html(&title, &rows, total)));
+            println!("Annotated source: {}", path.to_string_lossy().cyan());
+        },
+        Some(path) => {
+            let mut text = format!("{}\n", title);
+            for row in &rows {
+                text.push_str(&format_row(row, total));
+                text.push('\n');
+            }
+            resolve(fs::write(path, text));
+            println!("Annotated source: {}", path.to_string_lossy().cyan());
+        },
+        None => {
+            println!("{}", title.bold());
+            for row in &rows {
+                print_row(row, total);
+            }
+        },
+    }
+}
+
+/// Run `perf script` printing the source line of the sampled instruction of every sample
+fn source_lines(data: &Path) -> Result<Vec<Hit>, String> {
+    let mut command = process::Command::new(perf::binary());
+    command.arg("script")
+        .args(["-F", "ip,sym,symoff,dso,srcline", "--full-source-path", "--hide-call-graph"])
+        .args(symbols::perf_args())
+        .arg(format!("--input={}", data.to_string_lossy()))
+        .stderr(process::Stdio::inherit());
+    log_command(&command);
+    let output = command.output().map_err(|e| format!("Could not run perf ({})", e))?;
+    if !output.status.success() {
+        return Err("perf script failed".to_string());
+    }
+    Ok(parse_source_lines(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `<ip> <symbol>+<offset> (<dso>)` lines, each followed by a `<file>:<line>` line if it is known
+fn parse_source_lines(output: &str) -> Vec<Hit> {
+    let mut hits: Vec<Hit> = Vec::new();
+    for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some((ip, rest)) = line.split_once(char::is_whitespace)
+            && !ip.is_empty() && ip.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut symbol = rest.trim();
+            let mut dso = None;
+            if let Some(start) = symbol.rfind(" (") && symbol.ends_with(')') {
+                dso = Some(PathBuf::from(&symbol[start + 2..symbol.len() - 1]));
+                symbol = &symbol[..start];
+            }
+            let (function, offset) = match symbol.rsplit_once("+0x") {
+                Some((function, offset)) => (function, u64::from_str_radix(offset, 16).ok()),
+                None => (symbol, None),
+            };
+            hits.push(Hit { function: function.to_string(), offset, dso, line: None });
+        } else if let Some((file, number)) = line.rsplit_once(':')
+            && let Ok(number) = number.parse()
+            && let Some(hit) = hits.last_mut() {
+            hit.line = Some((PathBuf::from(file), number));
+        }
+    }
+    hits
+}
+
+/// The lines of each file around the sampled ones, the file with the most samples first
+fn source_rows(lines: &HashMap<&Path, BTreeMap<u32, u64>>, context: u32) -> Vec<Row> {
+    // The file with the most samples is usually the one the function is defined in
+    let mut files: Vec<_> = lines.iter().collect();
+    files.sort_by_key(|(file, lines)| (std::cmp::Reverse(lines.values().sum::<u64>()), **file));
+
+    let mut rows = Vec::new();
+    for (file, lines) in files {
+        rows.push(Row::File(file.to_path_buf()));
+        let Ok(source) = fs::read_to_string(file) else {
+            rows.extend(lines.iter().map(|(number, count)| Row::Source {
+                count: Some(*count), number: *number, text: "(source not available)".to_string() }));
+            continue;
+        };
+        let source: Vec<&str> = source.lines().collect();
+        let (Some(first), Some(last)) = (lines.keys().next(), lines.keys().next_back()) else { continue };
+        let start = first.saturating_sub(context).max(1);
+        let end = (last + context).min(source.len() as u32);
+        for number in start..=end {
+            let text = source.get(number as usize - 1).copied().unwrap_or_default().to_string();
+            rows.push(Row::Source { count: lines.get(&number).copied(), number, text });
+        }
+    }
+    rows
+}
+
+/// Disassembly of the function with the source lines objdump attributes the instructions to
+fn disassembly(function: &str, hits: &[&Hit], lines: &HashMap<&Path, BTreeMap<u32, u64>>) -> Result<Vec<Row>, String> {
+    let Some(dso) = hits.iter().find_map(|h| h.dso.as_deref()) else {
+        return Err(format!("perf did not report the binary containing {}", function));
+    };
+    let (start, size) = symbol_range(dso, function)?;
+    let mut counts: HashMap<u64, u64> = HashMap::new();
+    for offset in hits.iter().filter_map(|h| h.offset) {
+        *counts.entry(start + offset).or_default() += 1;
+    }
+
+    let mut command = process::Command::new("objdump");
+    command.args(["--disassemble", "--demangle", "--line-numbers", "--no-show-raw-insn"])
+        .arg(format!("--start-address={:#x}", start))
+        .arg(format!("--stop-address={:#x}", start + size))
+        .arg(dso);
+    log_command(&command);
+    let output = command.output().map_err(|e| format!("Could not run objdump ({}), is binutils installed?", e))?;
+    if !output.status.success() {
+        return Err(format!("objdump failed:\n{}", String::from_utf8_lossy(&output.stderr)));
+    }
+
+    let mut sources: HashMap<PathBuf, Option<String>> = HashMap::new();
+    let mut rows = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let trimmed = line.trim();
+        if let Some((address, text)) = trimmed.split_once(':')
+            && let Ok(address) = u64::from_str_radix(address, 16)
+            && line.starts_with(char::is_whitespace) {
+            rows.push(Row::Instruction { count: counts.get(&address).copied(), address, text: text.trim().replace('\t', " ") });
+        } else if let Some((file, number)) = trimmed.split(" (discriminator").next().unwrap_or_default().rsplit_once(':')
+            && let Ok(number) = number.parse::<u32>()
+            && file.starts_with('/') {
+            let file = PathBuf::from(file);
+            let source = sources.entry(file.clone()).or_insert_with(|| fs::read_to_string(&file).ok());
+            let text = source.as_deref().and_then(|s| s.lines().nth(number as usize - 1)).unwrap_or("").to_string();
+            if !matches!(rows.iter().rev().find(|r| matches!(r, Row::File(_))), Some(Row::File(f)) if *f == file) {
+                rows.push(Row::File(file.clone()));
+            }
+            let count = lines.get(file.as_path()).and_then(|l| l.get(&number)).copied();
+            rows.push(Row::Source { count, number, text });
+        }
+    }
+    Ok(rows)
+}
+
+/// Start address and size of a function in the symbol table of a binary
+fn symbol_range(dso: &Path, function: &str) -> Result<(u64, u64), String> {
+    let mut command = process::Command::new("nm");
+    command.args(["--demangle", "--print-size", "--defined-only"]).arg(dso);
+    log_command(&command);
+    let output = command.output().map_err(|e| format!("Could not run nm ({}), is binutils installed?", e))?;
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| {
+            let mut fields = line.splitn(4, ' ');
+            let start = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let size = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let _kind = fields.next()?;
+            (fields.next()? == function).then_some((start, size))
+        })
+        .ok_or_else(|| format!("Could not find {} in the symbol table of {}", function, dso.to_string_lossy()))
+}
+
+fn format_share(count: Option<u64>, total: u64) -> String {
+    match count {
+        Some(count) => format!("{:>7.2}%", report::percent(count, total)),
+        None => format!("{:>8}", ""),
+    }
+}
+
+fn format_row(row: &Row, total: u64) -> String {
+    match row {
+        Row::File(file) => format!("\n{}", file.to_string_lossy()),
+        Row::Source { count, number, text } => format!("{}  {:>5}  {}", format_share(*count, total), number, text),
+        Row::Instruction { count, address, text } => format!("{}  {:>12x}:  {}", format_share(*count, total), address, text),
+        Row::Note { count, text } => format!("\n{}  {}", format_share(Some(*count), total), text),
+    }
+}
+
+fn print_row(row: &Row, total: u64) {
+    let text = format_row(row, total);
+    let text = match row {
+        Row::File(_) => text.cyan(),
+        Row::Source { count: Some(count), .. } | Row::Instruction { count: Some(count), .. }
+            if report::percent(*count, total) >= HOT_LINE => text.red().bold(),
+        Row::Instruction { count: None, .. } | Row::Note { .. } => text.dimmed(),
+        _ => text.normal(),
+    };
+    println!("{}", text);
+}
+
+/// Standalone page with the rows tinted by their share of the samples
+fn html(title: &str, rows: &[Row], total: u64) -> String {
+    let mut table = String::new();
+    for row in rows {
+        let (class, count, label, text) = match row {
+            Row::File(file) => {
+                table.push_str(&format!("<tr class=\"file\"><td colspan=\"3\">{}</td></tr>\n", html_escape(&file.to_string_lossy())));
+                continue;
+            },
+            Row::Source { count, number, text } => ("source", *count, number.to_string(), text.as_str()),
+            Row::Instruction { count, address, text } => ("asm", *count, format!("{:x}", address), text.as_str()),
+            Row::Note { count, text } => ("note", Some(*count), String::new(), text.as_str()),
+        };
+        let share = count.map(|c| report::percent(c, total));
+        let style = share.map(|s| format!(" style=\"background: rgba(255, 0, 0, {:.2})\"", (s / 100.0).max(0.05))).unwrap_or_default();
+        table.push_str(&format!("<tr class=\"{}\"{}><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            class, style, share.map(|s| format!("{:.2}%", s)).unwrap_or_default(), label, html_escape(text)));
+    }
+
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+        <style>body {{ font-family: sans-serif; }} table {{ border-collapse: collapse; }} \
+        td {{ padding: 0 1em; font-family: monospace; white-space: pre; }} td:first-child, td:nth-child(2) {{ text-align: right; }} \
+        tr.file td {{ padding-top: 1em; font-weight: bold; }} tr.asm td {{ color: #555; }}</style>\n\
+        </head>\n<body>\n<h1>{}</h1>\n<table>\n{}</table>\n</body>\n</html>\n",
+        html_escape(title), html_escape(title), table)
+}
@@ -0,0 +1,277 @@
+//! Process settings of the profiled application, applied by every backend that launches it
+
+use std::{ffi::OsStr, fs::{self, File}, io, os::unix::process::ExitStatusExt, path::{Path, PathBuf}, process, sync::{Mutex, OnceLock}};
+
+use colored::Colorize;
+
+use crate::{RunArgs, resolve, resolve_status};
+
+/// Files in the `--log-output` directory
+const STDOUT_LOG: &str = "stdout.log";
+const STDERR_LOG: &str = "stderr.log";
+
+/// tee processes copying the output of the application into the logs
+static TEES: Mutex<Vec<process::Child>> = Mutex::new(Vec::new());
+
+/// First non-zero exit code of a launched application
+static EXIT_CODE: Mutex<Option<i32>> = Mutex::new(None);
+
+/// Settings taken from the command line by [`configure`]
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Settings {
+    /// Environment variables of the application, in the order they were given
+    env: Vec<(String, String)>,
+    /// Working directory of the application, cargo-pprof's own by default
+    cwd: Option<PathBuf>,
+    /// File the application reads its input from, the terminal is inherited otherwise
+    stdin: Option<PathBuf>,
+    /// Directory the output of the application is copied to
+    log_dir: Option<PathBuf>,
+    /// Keep going if the application fails and exit with its code in the end
+    mirror_exit: bool,
+}
+
+
+/// Remember the settings of the run for all commands created by [`command`]
+///
+/// The project's environment variables are overridden by `--env-file`, which is overridden by `--env`.
+pub fn configure(run: &RunArgs, project_env: &[(String, String)]) {
+    let mut env = project_env.to_vec();
+    if let Some(path) = &run.env_file {
+        env.extend(resolve(read_env_file(path)));
+    }
+    for assignment in &run.env {
+        env.push(resolve(parse_assignment(assignment)
+            .ok_or_else(|| format!("Invalid environment variable {:?}, expected KEY=VALUE", assignment))));
+    }
+    if let Some(cwd) = &run.cwd && !cwd.is_dir() {
+        resolve::<(), _>(Err(format!("Working directory {} does not exist", cwd.to_string_lossy())));
+    }
+    if let Some(stdin) = &run.stdin && !stdin.is_file() {
+        resolve::<(), _>(Err(format!("Input file {} does not exist", stdin.to_string_lossy())));
+    }
+    if let Some(dir) = &run.log_output {
+        resolve(fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create {} ({})", dir.to_string_lossy(), e)));
+        // Every launch appends, so start with empty logs
+        for name in [STDOUT_LOG, STDERR_LOG] {
+            resolve(File::create(dir.join(name)));
+        }
+    }
+    let _ = SETTINGS.set(Settings {
+        env,
+        cwd: run.cwd.clone(),
+        stdin: run.stdin.clone(),
+        log_dir: run.log_output.clone(),
+        mirror_exit: run.mirror_exit,
+    });
+}
+
+/// Command that launches the application, directly or through a profiler
+///
+/// Tools like perf or valgrind pass their environment on, so the settings are applied to them.
+pub fn command(program: impl AsRef<OsStr>) -> process::Command {
+    let mut command = process::Command::new(program);
+    if let Some(settings) = SETTINGS.get() {
+        command.envs(settings.env.iter().map(|(k, v)| (k, v)));
+        if let Some(cwd) = &settings.cwd {
+            command.current_dir(cwd);
+        }
+        if let Some(dir) = &settings.log_dir {
+            command.stdout(tee(&dir.join(STDOUT_LOG), io::stdout().into()));
+            command.stderr(tee(&dir.join(STDERR_LOG), io::stderr().into()));
+        }
+    }
+    command.stdin(stdin());
+    command
+}
+
+/// Standard input of the application, a fresh handle of the `--stdin` file for every call
+pub fn stdin() -> process::Stdio {
+    match SETTINGS.get().and_then(|s| s.stdin.as_ref()) {
+        Some(path) => resolve(File::open(path)
+            .map_err(|e| format!("Could not open {} ({})", path.to_string_lossy(), e))).into(),
+        None => process::Stdio::inherit(),
+    }
+}
+
+/// Run a command created by [`command`] and return its exit status
+pub fn run(command: &mut process::Command) -> process::ExitStatus {
+    crate::log_command(command);
+    resolve(command.status()
+        .map_err(|e| format!("Could not run {} ({})", command.get_program().to_string_lossy(), e)))
+}
+
+/// Start a command created by [`command`] in the background, for backends polling while it runs
+pub fn spawn(command: &mut process::Command) -> process::Child {
+    crate::log_command(command);
+    resolve(command.spawn()
+        .map_err(|e| format!("Could not run {} ({})", command.get_program().to_string_lossy(), e)))
+}
+
+/// Abort if the application failed, unless its exit code is ignored or mirrored
+pub fn check_exit(status: process::ExitStatus, ignore_exit: bool) {
+    if !status.success() {
+        // Like shells, report death by a signal as 128 + signal number
+        let code = status.code().or_else(|| status.signal().map(|s| 128 + s)).unwrap_or(1);
+        EXIT_CODE.lock().unwrap().get_or_insert(code);
+    }
+    if !ignore_exit && !SETTINGS.get().is_some_and(|s| s.mirror_exit) {
+        resolve_status(status);
+    }
+}
+
+/// Exit with the code of the application if `--mirror-exit` was given
+pub fn mirror_exit() {
+    if SETTINGS.get().is_some_and(|s| s.mirror_exit) {
+        process::exit(EXIT_CODE.lock().unwrap().unwrap_or(0));
+    }
+}
+
+/// Pipe into a `tee` process that appends to the log and passes everything on to `output`
+///
+/// tee exits on its own once the application closes the pipe, so nothing is lost if cargo-pprof
+/// exits right after the application (e.g. because of its exit code).
+fn tee(log: &Path, output: process::Stdio) -> process::Stdio {
+    let mut child = resolve(process::Command::new("tee")
+        .arg("-a")
+        .arg(log)
+        .stdin(process::Stdio::piped())
+        .stdout(output)
+        .spawn()
+        .map_err(|e| format!("Could not run tee ({})", e)));
+    let stdin = child.stdin.take().map(process::Stdio::from).unwrap_or_else(process::Stdio::inherit);
+    TEES.lock().unwrap().push(child);
+    stdin
+}
+
+/// The `--log-output` file of stdout or stderr opened for appending, for output read from a pipe
+///
+/// Commands whose output is piped into cargo-pprof bypass [`tee`], so the lines they print are
+/// copied into this file instead.
+pub fn output_log(stderr: bool) -> Option<File> {
+    let dir = SETTINGS.get()?.log_dir.as_ref()?;
+    let path = dir.join(if stderr { STDERR_LOG } else { STDOUT_LOG });
+    Some(resolve(fs::OpenOptions::new().append(true).create(true).open(&path)
+        .map_err(|e| format!("Could not open {} ({})", path.to_string_lossy(), e))))
+}
+
+/// Wait until the logs of all launched applications are written, and print where they are
+pub fn finish_logs() {
+    let tees: Vec<_> = TEES.lock().unwrap().drain(..).collect();
+    for mut tee in tees {
+        let _ = tee.wait();
+    }
+    if let Some(dir) = SETTINGS.get().and_then(|s| s.log_dir.as_ref()) {
+        println!("Application output: {}", dir.to_string_lossy().cyan());
+    }
+}
+
+/// Run a `--pre` or `--post` hook with the shell, in the environment and directory of the application
+pub fn run_hook(name: &str, hook: &str) {
+    crate::print_step(&format!("Running {} hook", name));
+    let mut command = process::Command::new("sh");
+    command.arg("-c").arg(hook).current_dir(cwd());
+    if let Some(settings) = SETTINGS.get() {
+        command.envs(settings.env.iter().map(|(k, v)| (k, v)));
+    }
+    let status = run(&mut command);
+    if !status.success() {
+        resolve::<(), _>(Err(format!("The {} hook failed ({})", name, status)));
+    }
+}
+
+/// Environment variables given with `--env` and `--env-file`
+pub fn env() -> &'static [(String, String)] {
+    SETTINGS.get().map(|s| s.env.as_slice()).unwrap_or_default()
+}
+
+/// File given with `--stdin`
+pub fn input() -> Option<&'static Path> {
+    SETTINGS.get().and_then(|s| s.stdin.as_deref())
+}
+
+/// Directory the application runs in, files it writes to relative paths end up there
+pub fn cwd() -> &'static Path {
+    SETTINGS.get().and_then(|s| s.cwd.as_deref()).unwrap_or(Path::new("."))
+}
+
+/// Read a dotenv style file (`KEY=VALUE` per line, `#` comments and an optional `export`)
+fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {} ({})", path.to_string_lossy(), e))?;
+    let mut env = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_assignment(line)
+            .ok_or_else(|| format!("Invalid line {} in {}, expected KEY=VALUE", i + 1, path.to_string_lossy()))?;
+        env.push((key, unquote(&value).to_string()));
+    }
+    Ok(env)
+}
+
+fn parse_assignment(assignment: &str) -> Option<(String, String)> {
+    let (key, value) = assignment.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((key.to_string(), value.to_string()))
+}
+
+fn unquote(value: &str) -> &str {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value.strip_prefix(quote).and_then(|v| v.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    value
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignments() {
+        assert_eq!(parse_assignment("RUST_LOG=debug"), Some(("RUST_LOG".to_string(), "debug".to_string())));
+        assert_eq!(parse_assignment(" KEY =a=b"), Some(("KEY".to_string(), "a=b".to_string())));
+        assert_eq!(parse_assignment("EMPTY="), Some(("EMPTY".to_string(), String::new())));
+        assert_eq!(parse_assignment("=value"), None);
+        assert_eq!(parse_assignment("TWO WORDS=value"), None);
+        assert_eq!(parse_assignment("no assignment"), None);
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(unquote(" \"a b\" "), "a b");
+        assert_eq!(unquote("'a \"b\"'"), "a \"b\"");
+        assert_eq!(unquote("\"unbalanced'"), "\"unbalanced'");
+        assert_eq!(unquote("plain"), "plain");
+    }
+
+    #[test]
+    fn env_files() {
+        let path = std::env::temp_dir().join(format!("cargo-pprof-env-test-{}", process::id()));
+        fs::write(&path, "# comment\n\nRUST_LOG=info\nexport DATABASE_URL=\"postgres://localhost/db\"\n  PORT = '8080'\n").unwrap();
+        let env = read_env_file(&path);
+        fs::write(&path, "A=1\ninvalid line\n").unwrap();
+        let invalid = read_env_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(env.unwrap(), [
+            ("RUST_LOG".to_string(), "info".to_string()),
+            ("DATABASE_URL".to_string(), "postgres://localhost/db".to_string()),
+            ("PORT".to_string(), "8080".to_string()),
+        ]);
+        assert_eq!(invalid.unwrap_err(), format!("Invalid line 2 in {}, expected KEY=VALUE", path.to_string_lossy()));
+    }
+}
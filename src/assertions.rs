@@ -0,0 +1,112 @@
+//! Performance budgets checked against the recorded profile, for CI
+//!
+//! An assertion like `my_crate::hot_path < 15%` compares the share of samples whose stack
+//! contains a function matching the pattern. With a `self` prefix only the innermost frame is
+//! considered, and a value without `%` compares the absolute number of samples.
+
+use colored::Colorize;
+
+use crate::profile::{self, Profile};
+use crate::report;
+use crate::{print_step, resolve};
+
+#[derive(Debug, Clone)]
+pub struct Assertion {
+    text: String,
+    /// Substring of the function names the assertion applies to
+    pattern: String,
+    self_only: bool,
+    op: Op,
+    value: f64,
+    percent: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+
+/// Parse an assertion of the form `[self] PATTERN OP VALUE[%]`
+pub fn parse(text: &str) -> Result<Assertion, String> {
+    // Function names can contain angle brackets, so the operator is the rightmost one
+    let (position, len, op) = [("<=", Op::LessEqual), (">=", Op::GreaterEqual), ("<", Op::Less), (">", Op::Greater)].iter()
+        .filter_map(|(symbol, op)| text.rfind(symbol).map(|p| (p, symbol.len(), *op)))
+        .max_by_key(|(p, len, _)| (*p, *len))
+        .ok_or_else(|| format!("expected one of <, <=, >, >= in {:?}", text))?;
+
+    let subject = text[..position].trim();
+    let (self_only, pattern) = match subject.strip_prefix("self ") {
+        Some(pattern) => (true, pattern.trim()),
+        None => (false, subject),
+    };
+    if pattern.is_empty() {
+        return Err(format!("missing function pattern in {:?}", text));
+    }
+
+    let value = text[position + len..].trim();
+    let (value, percent) = match value.strip_suffix('%') {
+        Some(value) => (value.trim(), true),
+        None => (value, false),
+    };
+    let value = value.parse().map_err(|_| format!("invalid value {:?} in {:?}", value, text))?;
+
+    Ok(Assertion { text: text.to_string(), pattern: pattern.to_string(), self_only, op, value, percent })
+}
+
+/// Evaluate the assertions against the profile of this run and fail if any is violated
+pub fn check(assertions: &[Assertion]) {
+    if assertions.is_empty() {
+        return;
+    }
+    let Some(source) = report::profile_source(&report::outputs()) else {
+        resolve(Err("--assert needs a trace or folded stacks output"))
+    };
+    let profile = resolve(profile::load(&source));
+
+    print_step("Checking assertions");
+    let mut failed = 0;
+    for assertion in assertions {
+        let actual = assertion.evaluate(&profile);
+        let holds = match assertion.op {
+            Op::Less => actual < assertion.value,
+            Op::LessEqual => actual <= assertion.value,
+            Op::Greater => actual > assertion.value,
+            Op::GreaterEqual => actual >= assertion.value,
+        };
+        let actual = if assertion.percent { format!("{:.2}%", actual) } else { format!("{}", actual) };
+        if holds {
+            println!("{} {} (is {})", "ok".green(), assertion.text, actual);
+        } else {
+            println!("{} {} (is {})", "FAILED".red(), assertion.text, actual);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        resolve::<(), _>(Err(format!("{} of {} assertions failed", failed, assertions.len())));
+    }
+}
+
+impl Assertion {
+    /// Samples (or their share in percent) the assertion applies to
+    fn evaluate(&self, profile: &Profile) -> f64 {
+        let mut matching = 0;
+        let mut total = 0;
+        for sample in &profile.samples {
+            let value = sample.values.first().copied().unwrap_or(0);
+            total += value;
+            let matches = if self.self_only {
+                sample.frames.first().is_some_and(|f| f.function.contains(&self.pattern))
+            } else {
+                sample.frames.iter().any(|f| f.function.contains(&self.pattern))
+            };
+            if matches {
+                matching += value;
+            }
+        }
+        if self.percent { report::percent(matching, total) } else { matching as f64 }
+    }
+}
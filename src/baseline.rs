@@ -0,0 +1,131 @@
+//! Named baselines of stored runs and comparing new runs against them
+//!
+//! A baseline is a copy of a stored run in `target/pprof/baselines/<name>/`, so it survives
+//! `cargo pprof clean` and retention.
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use colored::Colorize;
+
+use crate::manifest;
+use crate::profile::{self, Profile};
+use crate::report;
+use crate::store::{self, MANIFEST, Run};
+use crate::{BaselineAction, BaselineArgs, CompareArgs, print_step, resolve};
+
+const BASELINES_DIR: &str = "baselines";
+
+
+pub fn run(args: &BaselineArgs) {
+    let dir = baselines_dir();
+    match &args.action {
+        BaselineAction::Save { name, run } => {
+            let (run, run_dir) = resolve(store::find_run(run));
+            let target = dir.join(name);
+            if target.exists() {
+                resolve(fs::remove_dir_all(&target));
+            }
+            resolve(fs::create_dir_all(&target));
+            for entry in resolve(fs::read_dir(&run_dir)).flatten() {
+                resolve(fs::copy(entry.path(), target.join(entry.file_name())));
+            }
+            println!("Saved run {} as baseline {}", run.id, name.cyan());
+        },
+        BaselineAction::List => {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                println!("No baselines saved yet");
+                return;
+            };
+            let mut baselines: Vec<(String, Run)> = entries.flatten()
+                .filter_map(|e| Some((e.file_name().to_string_lossy().to_string(), load_run(&e.path()).ok()?)))
+                .collect();
+            baselines.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, run) in baselines {
+                println!("{:<20} {}  {}", name, run.id, run.command_line());
+            }
+        },
+        BaselineAction::Remove { name } => {
+            resolve(fs::remove_dir_all(dir.join(name))
+                .map_err(|e| format!("Could not remove baseline {} ({})", name, e)));
+        },
+    }
+}
+
+/// Print the change of this run against the baseline and fail if it regressed too much
+pub fn compare(args: &CompareArgs) {
+    let Some(name) = &args.compare_baseline else { return };
+    let baseline_dir = baselines_dir().join(name);
+    let baseline_run = resolve(load_run(&baseline_dir));
+    let Some(source) = report::profile_source(&report::outputs()) else {
+        resolve(Err("--compare-baseline needs a trace or folded stacks output"))
+    };
+    let Some(baseline_source) = baseline_run.outputs.iter()
+        .filter(|o| o.format == "trace" || o.format == "folded")
+        .min_by_key(|o| o.format != "trace")
+        .map(|o| baseline_dir.join(&o.file)) else {
+        resolve(Err(format!("Baseline {} has no trace or folded stacks", name)))
+    };
+
+    let profile = resolve(profile::load(&source));
+    let baseline = resolve(profile::load(&baseline_source));
+    print_step(&format!("Comparing with baseline {} (run {})", name, baseline_run.id));
+    let change = print_comparison(&profile, &baseline, args.delta_threshold);
+
+    if let Some(max) = args.max_regression
+        && change > max {
+        resolve::<(), _>(Err(format!("The total {} grew by {:.1}%, more than the allowed {}%",
+            value_name(&profile), change, max)));
+    }
+}
+
+/// Print the total change and the functions whose share changed by at least `threshold`
+/// percentage points, returns the change of the total in percent
+pub fn print_comparison(profile: &Profile, baseline: &Profile, threshold: f64) -> f64 {
+    let (rows, total) = report::function_stats(profile);
+    let (baseline_rows, baseline_total) = report::function_stats(baseline);
+    let after: HashMap<&str, f64> = rows.iter().map(|r| (r.function, report::percent(r.self_values[0], total))).collect();
+    let before: HashMap<&str, f64> = baseline_rows.iter().map(|r| (r.function, report::percent(r.self_values[0], baseline_total))).collect();
+
+    let change = if baseline_total == 0 { 0.0 } else { (total as f64 - baseline_total as f64) * 100.0 / baseline_total as f64 };
+    let change_text = format!("{:+.1}%", change);
+    println!("Total {}: {} -> {} ({})", value_name(profile), baseline_total, total,
+        if change > 0.0 { change_text.red() } else { change_text.green() });
+
+    let mut functions: Vec<&str> = after.keys().chain(before.keys()).copied().collect();
+    functions.sort();
+    functions.dedup();
+    let mut deltas: Vec<(&str, f64, f64)> = functions.into_iter()
+        .map(|f| (f, before.get(f).copied().unwrap_or(0.0), after.get(f).copied().unwrap_or(0.0)))
+        .filter(|(_, b, a)| (a - b).abs() >= threshold)
+        .collect();
+    deltas.sort_by(|x, y| (y.2 - y.1).abs().total_cmp(&(x.2 - x.1).abs()).then(x.0.cmp(y.0)));
+
+    if deltas.is_empty() {
+        println!("No function changed by {} percentage points or more", threshold);
+        return change;
+    }
+    println!("\n{}", "Functions with the largest change in self share".bold());
+    println!("{:>8} {:>8} {:>8}  Function", "Before", "After", "Change");
+    for (function, before, after) in deltas.into_iter().take(report::SUMMARY_ROWS) {
+        let delta = format!("{:>+8.2}", after - before);
+        println!("{:>7.2}% {:>7.2}% {}  {}", before, after,
+            if after > before { delta.red() } else { delta.green() }, function);
+    }
+    change
+}
+
+fn value_name(profile: &Profile) -> &str {
+    profile.value_names.first().map(String::as_str).unwrap_or("samples")
+}
+
+fn load_run(dir: &Path) -> Result<Run, String> {
+    let content = fs::read_to_string(dir.join(MANIFEST))
+        .map_err(|_| format!("No baseline {} (see `cargo pprof baseline list`)",
+            dir.file_name().unwrap_or_default().to_string_lossy()))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid baseline manifest ({})", e))
+}
+
+fn baselines_dir() -> PathBuf {
+    let metadata = resolve(manifest::load());
+    store::store_dir(&metadata.target_directory).join(BASELINES_DIR)
+}
@@ -0,0 +1,91 @@
+//! Profiling criterion benchmarks in their `--profile-time` mode
+
+use std::{fs::{self, File, OpenOptions}, io::Write, path::Path, process, time::{SystemTime, UNIX_EPOCH}};
+
+use colored::Colorize;
+
+use crate::perf;
+use crate::profile::{self, PerfEvent};
+use crate::report::{self, Format};
+use crate::spans;
+use crate::{BenchArgs, resolve, resolve_status};
+
+/// Seconds each benchmark is iterated for if `--profile-time` is not passed
+const DEFAULT_PROFILE_TIME: &str = "5";
+
+/// Benchmark that was measured between two wall-clock times (in seconds)
+struct Window {
+    id: String,
+    start: f64,
+    end: f64,
+}
+
+
+/// Run the benchmarks in profiling mode with perf only enabled while criterion iterates them
+pub fn run(args: &BenchArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Trace]);
+    let executable = crate::build(&["--bench", &args.bench]);
+    let dir = crate::output_dir(&executable);
+
+    let mut bench_args = vec!["--bench".to_string()];
+    if !args.run.app_args.iter().any(|a| a == "--profile-time") {
+        bench_args.extend(["--profile-time".to_string(), DEFAULT_PROFILE_TIME.to_string()]);
+    }
+    bench_args.extend(args.run.app_args.iter().cloned());
+
+    let control_path = dir.join("perf-control.fifo");
+    let _ = fs::remove_file(&control_path);
+    let status = resolve(process::Command::new("mkfifo").arg(&control_path).status());
+    resolve_status(status);
+
+    let mut recording = perf::Recording::new(dir, "bench", &executable, &bench_args, args.run.ignore_exit);
+    // Start disabled and let the benchmark output switch recording on and off
+    recording.record_args.extend(["-D", "-1"].map(String::from));
+    recording.record_args.push(format!("--control=fifo:{}", control_path.to_string_lossy()));
+    recording.record_args.extend(spans::PERF_CLOCK_ARGS.iter().map(|a| a.to_string()));
+
+    let mut control: Option<File> = None;
+    let mut windows: Vec<Window> = Vec::new();
+    let mut on_line = |line: &str| {
+        let Some(rest) = line.trim().strip_prefix("Benchmarking ") else { return };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        if let Some((id, _)) = rest.split_once(": Profiling for") {
+            send(&mut control, &control_path, "enable");
+            windows.push(Window { id: id.to_string(), start: now, end: f64::INFINITY });
+        } else if rest.contains(": Complete") {
+            send(&mut control, &control_path, "disable");
+            if let Some(window) = windows.last_mut() {
+                window.end = now;
+            }
+        }
+    };
+    let trace_path = perf::record_watching(&recording, Some(&mut on_line));
+    let _ = fs::remove_file(&control_path);
+
+    if windows.is_empty() {
+        eprintln!("{}", "Warning: no criterion benchmark was profiled, is this a criterion bench target?".yellow());
+    }
+    let content = resolve(fs::read_to_string(&trace_path));
+    let events = resolve(profile::parse_perf_events(&trace_path));
+    for window in &windows {
+        let in_window = |e: &PerfEvent| e.time >= window.start && e.time <= window.end;
+        let stem = format!("bench.{}", report::file_name_part(&window.id));
+        if formats.contains(&Format::Trace) {
+            let path = dir.join(format!("{}.trace", stem));
+            resolve(perf::write_filtered_trace(&content, &path, in_window));
+            report::print_output(Format::Trace, &path);
+        }
+        let window_events: Vec<PerfEvent> = events.iter().filter(|e| in_window(e)).cloned().collect();
+        report::emit(&profile::from_perf_events(&window_events), &formats, dir, &stem);
+    }
+}
+
+/// Send a command to perf's control FIFO, which perf keeps open for the whole recording
+fn send(control: &mut Option<File>, path: &Path, command: &str) {
+    if control.is_none() {
+        *control = OpenOptions::new().write(true).open(path).ok();
+    }
+    if let Some(file) = control {
+        let _ = writeln!(file, "{}", command);
+    }
+}
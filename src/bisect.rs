@@ -0,0 +1,125 @@
+//! Finding the commit that introduced a performance regression with `git bisect`
+//!
+//! The bisection runs in a separate worktree, so the checkout of the user stays untouched.
+//! Every candidate is recorded like in `compare-commits` and classified as bad if its metric
+//! exceeds the one of the good revision by more than the threshold.
+
+use std::{env, fs, path::{Path, PathBuf}, process};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::compare_commits::{self, COMPARE_DIR, git, git_output, remove_worktree};
+use crate::manifest;
+use crate::profile;
+use crate::{BisectArgs, log_command, print_step, resolve};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// Time between the first and the last sample
+    Wallclock,
+    /// Number of samples
+    Samples,
+}
+
+
+pub fn run(args: &BisectArgs) {
+    let threshold = resolve(parse_percent(&args.threshold));
+    let toplevel = PathBuf::from(git_output(Path::new("."), &["rev-parse", "--show-toplevel"]));
+    let cwd = resolve(env::current_dir());
+    let relative = cwd.strip_prefix(&toplevel).unwrap_or(Path::new("")).to_path_buf();
+    let metadata = resolve(manifest::load());
+    let dir = metadata.target_directory.join(COMPARE_DIR);
+    resolve(fs::create_dir_all(&dir));
+    let worktree = dir.join("worktree-bisect");
+    let package_dir = worktree.join(&relative);
+    // Revisions like HEAD have a different meaning inside the worktree
+    let good = git_output(&toplevel, &["rev-parse", &format!("{}^{{commit}}", args.good)]);
+    let bad = git_output(&toplevel, &["rev-parse", &format!("{}^{{commit}}", args.bad)]);
+
+    remove_worktree(&toplevel, &worktree);
+    git(&toplevel, &["worktree", "add", "--detach", &worktree.to_string_lossy(), &good]);
+
+    print_step(&format!("Measuring good revision {}", args.good));
+    let reference = resolve(measure(&package_dir, &dir, args));
+    println!("{}: {}", args.good, args.metric.format(reference));
+    git(&worktree, &["checkout", "--quiet", "--detach", &bad]);
+    print_step(&format!("Measuring bad revision {}", args.bad));
+    let value = resolve(measure(&package_dir, &dir, args));
+    println!("{}: {} ({})", args.bad, args.metric.format(value), format_change(reference, value));
+    if !is_regression(reference, value, threshold) {
+        remove_worktree(&toplevel, &worktree);
+        resolve::<(), _>(Err(format!("{} is not slower than {} by more than {}", args.bad, args.good, args.threshold)));
+    }
+
+    git(&worktree, &["bisect", "start", &bad, &good]);
+    loop {
+        let commit = git_output(&worktree, &["rev-parse", "--short", "HEAD"]);
+        print_step(&format!("Measuring {}", commit));
+        let verdict = match measure(&package_dir, &dir, args) {
+            Ok(value) => {
+                let regressed = is_regression(reference, value, threshold);
+                println!("{}: {} ({}) is {}", commit, args.metric.format(value), format_change(reference, value),
+                    if regressed { "bad".red() } else { "good".green() });
+                if regressed { "bad" } else { "good" }
+            },
+            Err(e) => {
+                eprintln!("{}", format!("Warning: skipping {} ({})", commit, e).yellow());
+                "skip"
+            },
+        };
+
+        let mut command = process::Command::new("git");
+        command.args(["bisect", verdict]).current_dir(&worktree);
+        log_command(&command);
+        let output = resolve(command.output());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if stdout.contains("is the first bad commit") || stdout.contains("only skipped commits left") {
+            print_step("Bisection finished");
+            print!("{}", stdout);
+            break;
+        } else if !output.status.success() {
+            eprintln!("{}", String::from_utf8_lossy(&output.stderr).trim());
+            break;
+        }
+    }
+
+    git(&worktree, &["bisect", "reset"]);
+    remove_worktree(&toplevel, &worktree);
+}
+
+/// Record the revision checked out in the worktree and compute the metric
+fn measure(package_dir: &Path, dir: &Path, args: &BisectArgs) -> Result<f64, String> {
+    let trace = compare_commits::record(package_dir, dir, &args.app_args)?;
+    let events = profile::parse_perf_events(&trace).map_err(|e| format!("Could not read the trace ({})", e))?;
+    Ok(match args.metric {
+        Metric::Samples => events.len() as f64,
+        Metric::Wallclock => compare_commits::wall_time(&events),
+    })
+}
+
+impl Metric {
+    fn format(self, value: f64) -> String {
+        match self {
+            Metric::Wallclock => format!("{:.3}s", value),
+            Metric::Samples => format!("{} samples", value),
+        }
+    }
+}
+
+fn is_regression(reference: f64, value: f64, threshold: f64) -> bool {
+    value > reference * (1.0 + threshold / 100.0)
+}
+
+fn format_change(reference: f64, value: f64) -> String {
+    if reference == 0.0 {
+        return "-".to_string();
+    }
+    format!("{:+.1}%", (value - reference) * 100.0 / reference)
+}
+
+/// Parse `10%` or `10` as 10 percent
+fn parse_percent(value: &str) -> Result<f64, String> {
+    value.trim().trim_end_matches('%').trim().parse()
+        .map_err(|_| format!("Invalid threshold {:?}, expected a percentage like 10%", value))
+}
@@ -0,0 +1,86 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::{print_step, resolve};
+
+/// Values reported per function and the cachegrind events they are summed from
+const DERIVED_VALUES: &[(&str, &[&str])] = &[
+    ("Ir", &["Ir"]),
+    ("I1 misses", &["I1mr"]),
+    ("D1 misses", &["D1mr", "D1mw"]),
+    ("LL misses", &["ILmr", "DLmr", "DLmw"]),
+];
+
+
+/// Run the application under cachegrind and return the simulated counts per function
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> Profile {
+    let out_path = dir.join("cachegrind.out");
+
+    print_step("Running program with cachegrind");
+    let status = app::run(app::command("valgrind")
+        .arg("--tool=cachegrind")
+        .arg("--cache-sim=yes")
+        .arg(format!("--cachegrind-out-file={}", out_path.to_string_lossy()))
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    eprintln!("Cachegrind output: {}", out_path.to_string_lossy());
+
+    let content = resolve(fs::read_to_string(&out_path));
+    resolve(parse(&content))
+}
+
+/// Parse a cachegrind output file
+pub fn parse(content: &str) -> Result<Profile, String> {
+    let mut events: Vec<&str> = Vec::new();
+    let mut file = "";
+    let mut function = "";
+    let mut counts: HashMap<(&str, &str), Vec<u64>> = HashMap::new();
+
+    for line in content.lines() {
+        if let Some(names) = line.strip_prefix("events:") {
+            events = names.split_whitespace().collect();
+        } else if let Some(name) = line.strip_prefix("fl=")
+                .or_else(|| line.strip_prefix("fi="))
+                .or_else(|| line.strip_prefix("fe=")) {
+            file = name;
+        } else if let Some(name) = line.strip_prefix("fn=") {
+            function = name;
+        } else if line.starts_with(|c: char| c.is_ascii_digit()) {
+            let entry = counts.entry((function, file))
+                .or_insert_with(|| vec![0; events.len()]);
+            for (acc, count) in entry.iter_mut().zip(line.split_whitespace().skip(1)) {
+                *acc += count.parse::<u64>().unwrap_or(0);
+            }
+        }
+    }
+
+    if events.is_empty() {
+        return Err("Cachegrind output does not declare any events".to_string());
+    }
+
+    let derived: Vec<(&str, Vec<usize>)> = DERIVED_VALUES.iter()
+        .map(|(name, sources)| {
+            let indices = sources.iter()
+                .filter_map(|s| events.iter().position(|e| e == s))
+                .collect();
+            (*name, indices)
+        })
+        .filter(|(_, indices): &(&str, Vec<usize>)| !indices.is_empty())
+        .collect();
+
+    let samples = counts.into_iter()
+        .map(|((function, file), counts)| Sample {
+            frames: vec![Frame { function: function.to_string(), module: file.to_string() }],
+            values: derived.iter()
+                .map(|(_, indices)| indices.iter().map(|i| counts[*i]).sum())
+                .collect(),
+        })
+        .collect();
+
+    Ok(Profile {
+        value_names: derived.iter().map(|(name, _)| name.to_string()).collect(),
+        samples,
+    })
+}
@@ -0,0 +1,90 @@
+//! Origin of a frame, shown as the sample category in the Firefox Profiler and the flame graph colors
+//!
+//! Functions of the binary are told apart by their crate: the crates of the workspace, the
+//! standard library and everything else, which are the dependencies. Frames of shared objects
+//! are system libraries, since Rust links its own code statically.
+
+use std::{path::Path, sync::OnceLock};
+
+use crate::demangle;
+use crate::manifest;
+use crate::profile::Frame;
+
+/// Crates linked into every binary with the standard library
+const STD_CRATES: &[&str] = &["std", "core", "alloc", "std_detect", "panic_unwind", "panic_abort", "compiler_builtins"];
+
+/// Crate names of the workspace targets, looked up on first use
+static WORKSPACE: OnceLock<Vec<String>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Other,
+    User,
+    Dependency,
+    Std,
+    System,
+    Kernel,
+}
+
+impl Category {
+    /// All categories in the order of their indices
+    pub const ALL: [Category; 6] = [Category::Other, Category::User, Category::Dependency, Category::Std, Category::System, Category::Kernel];
+
+    pub fn index(self) -> usize {
+        Self::ALL.iter().position(|c| *c == self).unwrap_or(0)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Other => "Other",
+            Category::User => "User code",
+            Category::Dependency => "Dependencies",
+            Category::Std => "Standard library",
+            Category::System => "System libraries",
+            Category::Kernel => "Kernel",
+        }
+    }
+
+    /// One of the colors the Firefox Profiler knows
+    pub fn color(self) -> &'static str {
+        match self {
+            Category::Other => "grey",
+            Category::User => "yellow",
+            Category::Dependency => "purple",
+            Category::Std => "blue",
+            Category::System => "green",
+            Category::Kernel => "orange",
+        }
+    }
+}
+
+pub fn of(frame: &Frame) -> Category {
+    let module = Path::new(&frame.module).file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    if frame.module.starts_with("[kernel") || frame.module.starts_with("[guest.kernel") {
+        return Category::Kernel;
+    }
+    if module.contains(".so") || frame.module.starts_with("[vdso") || frame.module.starts_with("[vsyscall") {
+        return Category::System;
+    }
+    if !frame.function.contains("::") {
+        return Category::Other;
+    }
+    let name = demangle::crate_name(&frame.function);
+    if STD_CRATES.contains(&name) {
+        Category::Std
+    } else if workspace().iter().any(|c| c == name) {
+        Category::User
+    } else {
+        Category::Dependency
+    }
+}
+
+fn workspace() -> &'static [String] {
+    WORKSPACE.get_or_init(|| {
+        let Ok(metadata) = manifest::load() else { return Vec::new() };
+        metadata.packages.iter()
+            .flat_map(|p| &p.targets)
+            .map(|t| t.name.replace('-', "_"))
+            .collect()
+    })
+}
@@ -0,0 +1,175 @@
+//! Causal profiling with coz, estimating how much speeding up a line would speed up the program
+
+use std::{collections::{BTreeMap, HashMap}, fs, path::Path};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::{CausalArgs, print_step, resolve};
+
+/// coz attributes samples to lines by walking frame pointers and reading the debug info
+const RUSTFLAGS: &[&str] = &["-C", "force-frame-pointers=yes"];
+
+/// Virtual speedups the effect of a line is reported for
+const SPEEDUPS: &[f64] = &[0.25, 0.5, 0.75, 1.0];
+
+/// Number of lines listed per progress point
+const TABLE_ROWS: usize = 20;
+
+/// Progress measured during one experiment
+#[derive(Debug, Default, Clone, Copy)]
+struct Measurement {
+    /// Nanoseconds the experiment ran
+    duration: f64,
+    /// Visits of a throughput point, or arrivals of a latency point
+    visits: f64,
+    /// Requests in flight at the end of each experiment at a latency point, summed up
+    in_flight: f64,
+    /// Number of experiments summed up
+    count: f64,
+}
+
+#[derive(Debug, Default)]
+struct ProgressPoint {
+    latency: bool,
+    /// Measurements per selected line and virtual speedup (in percent)
+    experiments: HashMap<(String, u32), Measurement>,
+}
+
+
+/// Build the binary with frame pointers, run it under coz and print the estimated effect per line
+pub fn run(args: &CausalArgs) {
+    let executable = crate::build_with_rustflags(&[], RUSTFLAGS);
+    let dir = crate::output_dir(&executable);
+    let output_path = dir.join("profile.coz");
+    // coz appends to existing profiles
+    let _ = fs::remove_file(&output_path);
+
+    print_step("Running program with coz");
+    let status = app::run(app::command("coz")
+        .arg("run")
+        .arg("-o").arg(&output_path)
+        .arg("---")
+        .arg(&executable)
+        .args(&args.run.app_args));
+    app::check_exit(status, args.run.ignore_exit);
+
+    let content = resolve(fs::read_to_string(&output_path)
+        .map_err(|e| format!("Could not read {} ({})", output_path.to_string_lossy(), e)));
+    let points = parse(&content);
+    if points.is_empty() {
+        eprintln!("{}", "Warning: no progress points were visited, mark them with coz::progress!() or coz::begin!/end!".yellow());
+    }
+    for (name, point) in &points {
+        print_point(name, point);
+    }
+    println!("coz profile: {}", output_path.to_string_lossy().cyan());
+    println!("Interactive plots: coz plot -f {}", output_path.to_string_lossy());
+}
+
+/// Parse the tab-separated records of a coz profile
+///
+/// Each `experiment` record is followed by the progress made at every point during it
+/// (`throughput-point name=<point> delta=<visits>` or `latency-point name=<point> arrivals=<n> ...`).
+fn parse(content: &str) -> BTreeMap<String, ProgressPoint> {
+    let mut points: BTreeMap<String, ProgressPoint> = BTreeMap::new();
+    let mut experiment: Option<(String, u32, f64)> = None;
+
+    for line in content.lines() {
+        let mut fields = line.split('\t');
+        let kind = fields.next().unwrap_or_default();
+        let fields: HashMap<&str, &str> = fields.filter_map(|f| f.split_once('=')).collect();
+        let number = |key: &str| fields.get(key).and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+
+        match kind {
+            "experiment" => {
+                let Some(selected) = fields.get("selected") else { continue };
+                let speedup = (number("speedup") * 100.0).round() as u32;
+                experiment = Some((selected.to_string(), speedup, number("duration")));
+            },
+            "throughput-point" | "latency-point" => {
+                let (Some((selected, speedup, duration)), Some(name)) = (&experiment, fields.get("name")) else { continue };
+                let point = points.entry(name.to_string()).or_default();
+                point.latency = kind == "latency-point";
+                let measurement = point.experiments.entry((selected.clone(), *speedup)).or_default();
+                measurement.duration += duration;
+                measurement.count += 1.0;
+                if point.latency {
+                    measurement.visits += number("arrivals");
+                    measurement.in_flight += number("difference");
+                } else {
+                    measurement.visits += number("delta");
+                }
+            },
+            _ => (),
+        }
+    }
+    points
+}
+
+impl Measurement {
+    /// Nanoseconds per visit of a throughput point, or the mean latency by Little's law
+    fn cost(&self, latency: bool) -> Option<f64> {
+        if self.visits == 0.0 {
+            return None;
+        }
+        if latency {
+            Some(self.in_flight / self.count * self.duration / self.visits)
+        } else {
+            Some(self.duration / self.visits)
+        }
+    }
+}
+
+/// Print the program speedup for the virtual speedups of each line, largest effect first
+fn print_point(name: &str, point: &ProgressPoint) {
+    // Experiments without a virtual speedup measure the baseline of the whole program
+    let mut baseline = Measurement::default();
+    for ((_, speedup), m) in &point.experiments {
+        if *speedup == 0 {
+            baseline.duration += m.duration;
+            baseline.visits += m.visits;
+            baseline.in_flight += m.in_flight;
+            baseline.count += m.count;
+        }
+    }
+    let Some(baseline) = baseline.cost(point.latency) else {
+        eprintln!("{}", format!("Warning: no baseline experiments for progress point {}", name).yellow());
+        return;
+    };
+
+    let mut lines: BTreeMap<&str, Vec<Option<f64>>> = BTreeMap::new();
+    for ((line, speedup), m) in &point.experiments {
+        let Some(column) = SPEEDUPS.iter().position(|s| (s * 100.0).round() as u32 == *speedup) else { continue };
+        let Some(cost) = m.cost(point.latency) else { continue };
+        lines.entry(line).or_insert_with(|| vec![None; SPEEDUPS.len()])[column] = Some((baseline - cost) * 100.0 / baseline);
+    }
+    let effect = |effects: &[Option<f64>]| effects.iter().flatten().fold(0.0f64, |a, e| a.max(*e));
+    let mut rows: Vec<_> = lines.into_iter().collect();
+    rows.sort_by(|a, b| effect(&b.1).total_cmp(&effect(&a.1)).then(a.0.cmp(b.0)));
+
+    let kind = if point.latency { "latency" } else { "throughput" };
+    println!("\n{}", format!("Program speedup at {} ({})", name, kind).bold());
+    for speedup in SPEEDUPS {
+        print!("{:>9}", format!("+{:.0}%", speedup * 100.0));
+    }
+    println!("  Line");
+    for (line, effects) in rows.into_iter().take(TABLE_ROWS) {
+        for effect in effects {
+            match effect {
+                Some(effect) => print!("{:>8.1}%", effect),
+                None => print!("{:>9}", "-"),
+            }
+        }
+        println!("  {}", shorten(line));
+    }
+}
+
+/// Source paths relative to the current directory if they are inside of it
+fn shorten(line: &str) -> String {
+    let Ok(cwd) = std::env::current_dir() else { return line.to_string() };
+    match Path::new(line).strip_prefix(&cwd) {
+        Ok(relative) => relative.to_string_lossy().to_string(),
+        Err(_) => line.to_string(),
+    }
+}
@@ -0,0 +1,70 @@
+//! Posting profile summaries to GitHub pull requests from GitHub Actions
+
+use std::{env, fs, path::Path};
+
+use serde_json::{Value, json};
+
+use crate::profile;
+use crate::push::{self, curl_quote};
+use crate::report;
+use crate::{CiCommentArgs, print_step, resolve};
+
+
+/// Render the Markdown summary of a trace and post it as comment on the current pull request
+pub fn run(args: &CiCommentArgs) {
+    let profile = resolve(profile::load(&args.trace));
+    let baseline = args.baseline.as_ref().map(|b| resolve(profile::load(b)));
+    let mut markdown = report::markdown_summary(&profile, baseline.as_ref());
+    markdown.push_str(&format!("\n<sub>Profiled with cargo-pprof from `{}`</sub>\n", file_name(&args.trace)));
+
+    if args.print {
+        println!("{}", markdown);
+        return;
+    }
+
+    let token = match env::var("GITHUB_TOKEN") {
+        Ok(token) => token,
+        Err(_) => resolve(Err("GITHUB_TOKEN is not set, pass it to the job or use --print")),
+    };
+    let repository = match env::var("GITHUB_REPOSITORY") {
+        Ok(repository) => repository,
+        Err(_) => resolve(Err("GITHUB_REPOSITORY is not set, this command has to run inside GitHub Actions")),
+    };
+    let pr = match args.pr.or_else(pull_request_number) {
+        Some(pr) => pr,
+        None => resolve(Err("Could not determine the pull request, pass --pr")),
+    };
+    let api = env::var("GITHUB_API_URL").unwrap_or_else(|_| "https://api.github.com".to_string());
+    let url = format!("{}/repos/{}/issues/{}/comments", api, repository, pr);
+
+    print_step(&format!("Posting summary to pull request #{}", pr));
+    let body = json!({ "body": markdown }).to_string();
+    resolve(push::post(&url, "application/json", body.as_bytes(), &[
+        format!("header = {}", curl_quote(&format!("Authorization: Bearer {}", token))),
+        format!("header = {}", curl_quote("Accept: application/vnd.github+json")),
+    ]));
+    eprintln!("Done");
+}
+
+/// Number of the pull request that triggered the workflow
+fn pull_request_number() -> Option<u64> {
+    if let Ok(path) = env::var("GITHUB_EVENT_PATH")
+        && let Ok(content) = fs::read_to_string(path)
+        && let Ok(event) = serde_json::from_str::<Value>(&content)
+        && let Some(number) = event["pull_request"]["number"].as_u64()
+    {
+        return Some(number);
+    }
+
+    // refs/pull/<number>/merge
+    env::var("GITHUB_REF").ok()?
+        .strip_prefix("refs/pull/")?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
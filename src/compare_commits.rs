@@ -0,0 +1,199 @@
+//! Recording two revisions with identical settings and comparing them
+//!
+//! Each revision is checked out into a temporary git worktree and recorded by a child
+//! `cargo pprof`, sharing one target directory so dependencies are only built once. With
+//! `--runs` every revision is recorded repeatedly and the differences are tested with Welch's
+//! t-test, so conclusions are not drawn from single noisy runs.
+
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+
+use crate::baseline;
+use crate::manifest;
+use crate::profile::{self, PerfEvent};
+use crate::report::{self, Format};
+use crate::stats;
+use crate::{CompareCommitsArgs, log_command, print_step, resolve, resolve_status, verbosity};
+
+/// Directory of the worktrees and the shared target directory, inside the target directory
+pub const COMPARE_DIR: &str = "pprof/compare";
+
+/// p-value below which a change counts as significant
+const SIGNIFICANCE: f64 = 0.05;
+
+
+pub fn run(args: &CompareCommitsArgs) {
+    let toplevel = PathBuf::from(git_output(Path::new("."), &["rev-parse", "--show-toplevel"]));
+    let cwd = resolve(env::current_dir());
+    // Record the same package if it is not at the root of the repository
+    let relative = cwd.strip_prefix(&toplevel).unwrap_or(Path::new("")).to_path_buf();
+    let metadata = resolve(manifest::load());
+    let dir = metadata.target_directory.join(COMPARE_DIR);
+    resolve(fs::create_dir_all(&dir));
+
+    let mut traces = Vec::new();
+    for rev in [&args.rev_a, &args.rev_b] {
+        let commit = git_output(&toplevel, &["rev-parse", "--short", &format!("{}^{{commit}}", rev)]);
+        let worktree = dir.join(format!("worktree-{}", commit));
+        remove_worktree(&toplevel, &worktree);
+        print_step(&format!("Checking out {} ({})", rev, commit));
+        git(&toplevel, &["worktree", "add", "--detach", &worktree.to_string_lossy(), &commit]);
+
+        let mut saved_traces = Vec::new();
+        for i in 1..=args.runs {
+            let trace = resolve(record(&worktree.join(&relative), &dir, &args.app_args));
+            let saved = if args.runs == 1 {
+                dir.join(format!("{}.trace", commit))
+            } else {
+                dir.join(format!("{}-{}.trace", commit, i))
+            };
+            resolve(fs::copy(&trace, &saved));
+            report::print_output(Format::Trace, &saved);
+            saved_traces.push(saved);
+        }
+        remove_worktree(&toplevel, &worktree);
+        traces.push((rev, saved_traces));
+    }
+
+    let [(rev_a, traces_a), (rev_b, traces_b)] = &traces[..] else { return };
+    print_step(&format!("Comparing {} with {}", rev_b, rev_a));
+    if args.runs == 1 {
+        let before = resolve(profile::load(&traces_a[0]));
+        let after = resolve(profile::load(&traces_b[0]));
+        baseline::print_comparison(&after, &before, args.delta_threshold);
+    } else {
+        let runs_a: Vec<Run> = traces_a.iter().map(|t| Run::load(t)).collect();
+        let runs_b: Vec<Run> = traces_b.iter().map(|t| Run::load(t)).collect();
+        print_statistics(rev_a, &runs_a, rev_b, &runs_b);
+    }
+}
+
+/// Wall time and function shares of one recording
+struct Run {
+    wall_time: f64,
+    /// Self share of each function in percent
+    shares: HashMap<String, f64>,
+}
+
+impl Run {
+    fn load(trace: &Path) -> Run {
+        let events = resolve(profile::parse_perf_events(trace));
+        let profile = profile::from_perf_events(&events);
+        let (rows, total) = report::function_stats(&profile);
+        Run {
+            wall_time: wall_time(&events),
+            shares: rows.iter().map(|r| (r.function.to_string(), report::percent(r.self_values[0], total))).collect(),
+        }
+    }
+}
+
+/// Mean, median and deviation of the wall time and the hottest functions, with Welch's t-test
+fn print_statistics(rev_a: &str, runs_a: &[Run], rev_b: &str, runs_b: &[Run]) {
+    let wall_a: Vec<f64> = runs_a.iter().map(|r| r.wall_time).collect();
+    let wall_b: Vec<f64> = runs_b.iter().map(|r| r.wall_time).collect();
+    println!("{}", format!("Wall time over {} and {} runs", runs_a.len(), runs_b.len()).bold());
+    println!("{:<12} {:>10} {:>10} {:>10}", "", "Mean", "Median", "Stddev");
+    for (rev, values) in [(rev_a, &wall_a), (rev_b, &wall_b)] {
+        let summary = stats::summarize(values);
+        println!("{:<12} {:>9.3}s {:>9.3}s {:>9.3}s", rev, summary.mean, summary.median, summary.stddev);
+    }
+    print_significance(stats::summarize(&wall_a).mean, stats::summarize(&wall_b).mean, stats::welch_p_value(&wall_a, &wall_b));
+
+    // Functions that are hot on either side, by their mean share
+    let mut functions: Vec<&str> = runs_a.iter().chain(runs_b).flat_map(|r| r.shares.keys().map(String::as_str)).collect();
+    functions.sort();
+    functions.dedup();
+    let shares = |runs: &[Run], function: &str| -> Vec<f64> {
+        runs.iter().map(|r| r.shares.get(function).copied().unwrap_or(0.0)).collect()
+    };
+    let mut rows: Vec<(&str, Vec<f64>, Vec<f64>)> = functions.into_iter()
+        .map(|f| (f, shares(runs_a, f), shares(runs_b, f)))
+        .collect();
+    let hotness = |a: &[f64], b: &[f64]| stats::summarize(a).mean.max(stats::summarize(b).mean);
+    rows.sort_by(|x, y| hotness(&y.1, &y.2).total_cmp(&hotness(&x.1, &x.2)).then(x.0.cmp(y.0)));
+
+    println!("\n{}", "Self share of the hottest functions (mean ± stddev)".bold());
+    println!("{:>16} {:>16} {:>8} {:>8}  Function", rev_a, rev_b, "Change", "p");
+    for (function, a, b) in rows.into_iter().take(report::SUMMARY_ROWS) {
+        let (sa, sb) = (stats::summarize(&a), stats::summarize(&b));
+        let p = stats::welch_p_value(&a, &b);
+        let p_text = p.map(|p| format!("{:.3}", p)).unwrap_or_else(|| "-".to_string());
+        let p_text = if p.is_some_and(|p| p < SIGNIFICANCE) { p_text.bold() } else { p_text.normal() };
+        println!("{:>8.2}% ±{:>5.2} {:>8.2}% ±{:>5.2} {:>+8.2} {:>8}  {}",
+            sa.mean, sa.stddev, sb.mean, sb.stddev, sb.mean - sa.mean, p_text, function);
+    }
+}
+
+fn print_significance(before: f64, after: f64, p: Option<f64>) {
+    let change = if before == 0.0 { 0.0 } else { (after - before) * 100.0 / before };
+    match p {
+        Some(p) if p < SIGNIFICANCE => println!("Change {:+.1}% is significant (p = {:.3})", change, p),
+        Some(p) => println!("Change {:+.1}% is not significant (p = {:.3})", change, p),
+        None => println!("Change {:+.1}%", change),
+    }
+}
+
+/// Time between the first and the last sample of a recording
+pub fn wall_time(events: &[PerfEvent]) -> f64 {
+    let start = events.iter().map(|e| e.time).fold(f64::INFINITY, f64::min);
+    let end = events.iter().map(|e| e.time).fold(f64::NEG_INFINITY, f64::max);
+    if events.is_empty() { 0.0 } else { end - start }
+}
+
+/// Record the package in `package_dir` with a child `cargo pprof`, returns the trace
+///
+/// The build and the outputs are placed in `dir`, which is shared by all revisions.
+pub fn record(package_dir: &Path, dir: &Path, app_args: &[String]) -> Result<PathBuf, String> {
+    let outputs_list = dir.join("outputs");
+    let _ = fs::remove_file(&outputs_list);
+
+    let mut command = process::Command::new(resolve(env::current_exe()));
+    command.arg("pprof");
+    match verbosity() {
+        0 => { command.arg("-q"); },
+        1 => (),
+        n => { command.args((1..n).map(|_| "-v")); },
+    }
+    command.args(["--format", "trace", "--"])
+        .args(app_args)
+        .current_dir(package_dir)
+        .env("CARGO_TARGET_DIR", dir.join("target"))
+        .env(report::OUTPUTS_ENV_VAR, &outputs_list);
+    log_command(&command);
+    let status = command.status().map_err(|e| format!("Could not run cargo pprof ({})", e))?;
+    if !status.success() {
+        return Err(format!("Recording {} failed", package_dir.to_string_lossy()));
+    }
+
+    let outputs = fs::read_to_string(&outputs_list).unwrap_or_default();
+    let trace = outputs.lines()
+        .filter_map(|l| l.split_once(' '))
+        .find(|(format, _)| *format == "trace")
+        .map(|(_, path)| PathBuf::from(path));
+    trace.ok_or_else(|| "The recording did not produce a trace".to_string())
+}
+
+pub fn remove_worktree(toplevel: &Path, worktree: &Path) {
+    if worktree.exists() {
+        git(toplevel, &["worktree", "remove", "--force", &worktree.to_string_lossy()]);
+    }
+}
+
+pub fn git(dir: &Path, args: &[&str]) {
+    let mut command = process::Command::new("git");
+    command.args(args).current_dir(dir).stdout(process::Stdio::null());
+    log_command(&command);
+    resolve_status(resolve(command.status()));
+}
+
+pub fn git_output(dir: &Path, args: &[&str]) -> String {
+    let output = resolve(process::Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output());
+    if !output.status.success() {
+        resolve::<(), _>(Err(format!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
@@ -0,0 +1,194 @@
+//! Shell completion scripts generated from the command line definition
+
+use clap::{CommandFactory, ValueEnum, builder::ValueHint};
+
+use crate::manifest;
+use crate::{Args, CompletionsArgs, resolve};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    /// Uses the bash completion through bashcompinit
+    Zsh,
+    Fish,
+}
+
+/// Options whose values are names of targets of the current package, by target kind
+const TARGET_OPTIONS: &[(&str, &str)] = &[("bench", "bench")];
+
+/// Subcommand of `cargo pprof` (or the top level with an empty name) and what it accepts
+struct Node {
+    name: String,
+    about: String,
+    options: Vec<Opt>,
+}
+
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    help: String,
+    takes_value: bool,
+    /// Possible values of value enums
+    values: Vec<String>,
+    path: bool,
+    /// Target kind listed by `--targets` for the value
+    targets: Option<&'static str>,
+}
+
+
+pub fn run(args: &CompletionsArgs) {
+    if let Some(kind) = &args.targets {
+        print_targets(kind);
+    } else if let Some(shell) = args.shell {
+        let nodes = nodes();
+        let script = match shell {
+            Shell::Bash => bash(&nodes),
+            Shell::Zsh => format!("autoload -U +X bashcompinit && bashcompinit\n\n{}", bash(&nodes)),
+            Shell::Fish => fish(&nodes),
+        };
+        print!("{}", script);
+    }
+}
+
+/// Names of the targets of the given kind, for dynamic completion
+fn print_targets(kind: &str) {
+    let Ok(metadata) = manifest::load() else { return };
+    let Some(package) = metadata.current_package() else { return };
+    for target in package.targets.iter().filter(|t| t.kind.iter().any(|k| k == kind)) {
+        println!("{}", target.name);
+    }
+}
+
+/// The `pprof` command and its subcommands
+fn nodes() -> Vec<Node> {
+    let mut command = Args::command();
+    command.build();
+    let Some(pprof) = command.find_subcommand("pprof") else {
+        resolve(Err("Missing pprof command"))
+    };
+
+    let node = |name: &str, command: &clap::Command| Node {
+        name: name.to_string(),
+        about: command.get_about().map(|a| a.to_string()).unwrap_or_default(),
+        options: command.get_arguments()
+            .filter(|a| !a.is_positional() && !a.is_hide_set())
+            .map(|a| Opt {
+                long: a.get_long().map(str::to_string),
+                short: a.get_short(),
+                help: a.get_help().map(|h| h.to_string()).unwrap_or_default(),
+                takes_value: a.get_action().takes_values(),
+                values: a.get_possible_values().iter()
+                    .filter(|v| !v.is_hide_set())
+                    .map(|v| v.get_name().to_string())
+                    .collect(),
+                path: matches!(a.get_value_hint(), ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath),
+                targets: TARGET_OPTIONS.iter()
+                    .find(|(id, _)| *id == a.get_id().as_str())
+                    .map(|(_, kind)| *kind),
+            })
+            .collect(),
+    };
+
+    let mut nodes = vec![node("", pprof)];
+    nodes.extend(pprof.get_subcommands()
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+        .map(|c| node(c.get_name(), c)));
+    nodes
+}
+
+impl Opt {
+    fn words(&self) -> Vec<String> {
+        self.long.iter().map(|l| format!("--{}", l))
+            .chain(self.short.map(|s| format!("-{}", s)))
+            .collect()
+    }
+
+    /// First line of the help, shortened for completion menus
+    fn summary(&self) -> &str {
+        self.help.lines().next().unwrap_or_default()
+    }
+}
+
+fn bash(nodes: &[Node]) -> String {
+    let subcommands: Vec<&str> = nodes.iter().skip(1).map(|n| n.name.as_str()).collect();
+    let mut script = String::from("_cargo_pprof() {\n");
+    script.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\" cmd=\"\" i\n");
+    script.push_str("    for ((i = 1; i < COMP_CWORD; i++)); do\n");
+    script.push_str("        case \"${COMP_WORDS[i]}\" in\n");
+    // Everything after `--` belongs to the application
+    script.push_str("            --) return 0 ;;\n");
+    script.push_str(&format!("            {}) cmd=\"${{COMP_WORDS[i]}}\" ;;\n", subcommands.join("|")));
+    script.push_str("        esac\n    done\n\n    case \"$cmd\" in\n");
+
+    for node in nodes {
+        let pattern = if node.name.is_empty() { "\"\"" } else { &node.name };
+        script.push_str(&format!("        {})\n            case \"$prev\" in\n", pattern));
+        for opt in node.options.iter().filter(|o| o.takes_value) {
+            let completion = if let Some(kind) = opt.targets {
+                format!("COMPREPLY=($(compgen -W \"$(cargo pprof completions --targets {} 2>/dev/null)\" -- \"$cur\"))", kind)
+            } else if !opt.values.is_empty() {
+                format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", opt.values.join(" "))
+            } else if opt.path {
+                "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string()
+            } else {
+                "COMPREPLY=()".to_string()
+            };
+            script.push_str(&format!("                {}) {}; return 0 ;;\n", opt.words().join("|"), completion));
+        }
+        script.push_str("            esac\n");
+        let mut words: Vec<String> = node.options.iter().flat_map(Opt::words).collect();
+        if node.name.is_empty() {
+            words.extend(subcommands.iter().map(|s| s.to_string()));
+        }
+        script.push_str(&format!("            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            ;;\n", words.join(" ")));
+    }
+    script.push_str("    esac\n}\n\n");
+
+    script.push_str("complete -F _cargo_pprof cargo-pprof\n\n");
+    script.push_str("# Hook into cargo's own completion for `cargo pprof`\n");
+    script.push_str("if ! declare -F _cargo >/dev/null && declare -F _completion_loader >/dev/null; then\n");
+    script.push_str("    _completion_loader cargo\nfi\n");
+    script.push_str("_cargo_pprof_cargo() {\n");
+    script.push_str("    if [[ \"${COMP_WORDS[1]}\" == pprof ]]; then\n        _cargo_pprof\n");
+    script.push_str("    elif declare -F _cargo >/dev/null; then\n        _cargo \"$@\"\n    fi\n}\n");
+    script.push_str("complete -F _cargo_pprof_cargo cargo\n");
+    script
+}
+
+fn fish(nodes: &[Node]) -> String {
+    let quote = |s: &str| format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"));
+    let subcommands: Vec<&str> = nodes.iter().skip(1).map(|n| n.name.as_str()).collect();
+    let top_level = format!("__fish_seen_subcommand_from pprof; and not __fish_seen_subcommand_from {}", subcommands.join(" "));
+
+    let mut script = String::new();
+    for node in nodes {
+        let condition = if node.name.is_empty() {
+            top_level.clone()
+        } else {
+            script.push_str(&format!("complete -c cargo -f -n {} -a {} -d {}\n",
+                quote(&top_level), node.name, quote(&node.about)));
+            format!("__fish_seen_subcommand_from pprof; and __fish_seen_subcommand_from {}", node.name)
+        };
+        for opt in &node.options {
+            let mut line = format!("complete -c cargo -n {}", quote(&condition));
+            if let Some(long) = &opt.long {
+                line.push_str(&format!(" -l {}", long));
+            }
+            if let Some(short) = opt.short {
+                line.push_str(&format!(" -s {}", short));
+            }
+            if let Some(kind) = opt.targets {
+                line.push_str(&format!(" -x -a {}", quote(&format!("(cargo pprof completions --targets {} 2>/dev/null)", kind))));
+            } else if !opt.values.is_empty() {
+                line.push_str(&format!(" -x -a {}", quote(&opt.values.join(" "))));
+            } else if opt.path {
+                line.push_str(" -r -F");
+            } else if opt.takes_value {
+                line.push_str(" -x");
+            }
+            line.push_str(&format!(" -d {}\n", quote(opt.summary())));
+            script.push_str(&line);
+        }
+    }
+    script
+}
@@ -0,0 +1,213 @@
+//! Defaults from configuration files, overridden by the command line
+//!
+//! The user configuration in `~/.config/cargo-pprof/config.toml` holds machine-specific settings,
+//! `[package.metadata.pprof]` in Cargo.toml the settings of a project. Both accept the same keys,
+//! the project configuration takes precedence over the user configuration.
+//!
+//! ```toml
+//! [package.metadata.pprof]
+//! frequency = 4999
+//! call-graph = "dwarf"
+//! events = ["cycles"]
+//! formats = ["trace", "gecko"]
+//! args = ["--input", "data/large.txt"]
+//! pre = "./scripts/seed-db.sh"
+//! collapse-generics = true
+//!
+//! [package.metadata.pprof.env]
+//! RUST_LOG = "info"
+//! ```
+//!
+//! ```toml
+//! # ~/.config/cargo-pprof/config.toml
+//! browser = "flatpak run org.mozilla.firefox"
+//! perf-path = "/opt/perf/bin/perf"
+//! debuginfod = "https://debuginfod.elfutils.org/"
+//! firefox-path = "/opt/firefox/firefox"
+//! upload-to = "s3://profiles/cargo-pprof"
+//! push-server = "http://pyroscope.internal:4040"
+//! sudo = true
+//! keep-last = 20
+//!
+//! [grafana]
+//! url = "https://profiles-prod-001.grafana.net"
+//! user = "123456"
+//! key-file = "/home/me/.config/cargo-pprof/grafana.key"
+//! ```
+//!
+//! The Grafana credentials of `--push grafana` are overridden by `GRAFANA_PROFILES_URL`,
+//! `GRAFANA_PROFILES_USER` and `GRAFANA_PROFILES_API_KEY`. `key` holds the API key itself,
+//! `key-file` the path of a file containing it, which keeps it out of shared configuration files.
+
+use std::{collections::BTreeMap, env, fs, path::PathBuf, sync::OnceLock};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::manifest;
+use crate::toml;
+use crate::report::Format;
+use crate::{PProfArgs, resolve};
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub frequency: Option<u32>,
+    pub call_graph: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub formats: Vec<String>,
+    /// Environment variables of the application, `--env-file` and `--env` take precedence
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Arguments the application is run with if none are given after `--`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Browser command used to open the viewers
+    pub browser: Option<String>,
+    /// perf binary used instead of the one on the PATH
+    pub perf_path: Option<PathBuf>,
+    /// debuginfod servers used instead of `DEBUGINFOD_URLS`
+    pub debuginfod: Option<String>,
+    /// Firefox binary the viewers fall back to instead of the one on the PATH
+    pub firefox_path: Option<PathBuf>,
+    /// Destination of `--upload` and the `upload` subcommand instead of the Firefox Profiler's storage
+    pub upload_to: Option<String>,
+    /// Server of `--push` if no `--server` is given
+    pub push_server: Option<String>,
+    /// Run `perf record` (and with it the application) with sudo
+    pub sudo: Option<bool>,
+    /// Number of stored runs kept when a new one is stored, if `--keep-last` is not given
+    pub keep_last: Option<usize>,
+    /// Shell commands run before and after the application if `--pre` and `--post` are not given
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    /// Merge the instances of generic functions if neither `--collapse-generics` nor `--keep-generics` is given
+    pub collapse_generics: Option<bool>,
+    /// Endpoint and credentials of `--push grafana`
+    #[serde(default)]
+    pub grafana: Grafana,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Grafana {
+    pub url: Option<String>,
+    pub user: Option<String>,
+    pub key: Option<String>,
+    /// File containing the API key
+    pub key_file: Option<PathBuf>,
+}
+
+
+/// The merged user and project configuration, read on first use
+pub fn load() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(|| load_user().merge(load_project()))
+}
+
+/// Location of the user configuration, following the XDG base directory specification
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .filter(|d| d.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+    Some(dir.join("cargo-pprof").join("config.toml"))
+}
+
+fn load_user() -> Config {
+    let Some(path) = user_config_path() else { return Config::default() };
+    let Ok(content) = fs::read_to_string(&path) else { return Config::default() };
+    let error = |e: String| format!("Invalid configuration {} ({})", path.to_string_lossy(), e);
+    let value = resolve(toml::parse(&content).map_err(error));
+    resolve(Config::deserialize(value).map_err(|e| error(e.to_string())))
+}
+
+/// The configuration of the current package, empty outside of a cargo project
+fn load_project() -> Config {
+    let Ok(metadata) = manifest::load() else { return Config::default() };
+    let Some(package) = metadata.current_package() else { return Config::default() };
+    match package.metadata.get("pprof") {
+        Some(table) => resolve(Config::deserialize(table)
+            .map_err(|e| format!("Invalid [package.metadata.pprof] in {} ({})", package.manifest_path.to_string_lossy(), e))),
+        None => Config::default(),
+    }
+}
+
+impl Config {
+    /// Settings of `other` take precedence, environment variables are merged
+    fn merge(mut self, other: Config) -> Config {
+        let list = |own: Vec<String>, other: Vec<String>| if other.is_empty() { own } else { other };
+        self.env.extend(other.env);
+        Config {
+            frequency: other.frequency.or(self.frequency),
+            call_graph: other.call_graph.or(self.call_graph),
+            events: list(self.events, other.events),
+            formats: list(self.formats, other.formats),
+            env: self.env,
+            args: list(self.args, other.args),
+            browser: other.browser.or(self.browser),
+            perf_path: other.perf_path.or(self.perf_path),
+            debuginfod: other.debuginfod.or(self.debuginfod),
+            firefox_path: other.firefox_path.or(self.firefox_path),
+            upload_to: other.upload_to.or(self.upload_to),
+            push_server: other.push_server.or(self.push_server),
+            sudo: other.sudo.or(self.sudo),
+            keep_last: other.keep_last.or(self.keep_last),
+            pre: other.pre.or(self.pre),
+            post: other.post.or(self.post),
+            collapse_generics: other.collapse_generics.or(self.collapse_generics),
+            grafana: Grafana {
+                url: other.grafana.url.or(self.grafana.url),
+                user: other.grafana.user.or(self.grafana.user),
+                key: other.grafana.key.or(self.grafana.key),
+                key_file: other.grafana.key_file.or(self.grafana.key_file),
+            },
+        }
+    }
+
+    /// Fill in the settings of the recording the command line leaves open
+    ///
+    /// Recording settings only affect the default recording, subcommands just take the
+    /// environment variables, the browser, the push server, the hooks and the retention of stored runs.
+    pub fn apply(&self, args: &mut PProfArgs) {
+        if let Some(run) = args.run_args_mut() {
+            if run.browser.is_none() {
+                run.browser = self.browser.clone();
+            }
+            if run.push.server.is_none() {
+                run.push.server = self.push_server.clone();
+            }
+            run.keep_last = run.keep_last.or(self.keep_last);
+            if run.pre.is_none() {
+                run.pre = self.pre.clone();
+            }
+            if run.post.is_none() {
+                run.post = self.post.clone();
+            }
+        }
+        if args.action.is_some() {
+            return;
+        }
+        args.frequency = args.frequency.or(self.frequency);
+        if args.call_graph.is_none() {
+            args.call_graph = self.call_graph.clone();
+        }
+        if args.events.is_empty() {
+            args.events = self.events.clone();
+        }
+        if args.formats.is_empty() {
+            args.formats = self.formats.iter()
+                .map(|f| resolve(Format::from_str(f, true)
+                    .map_err(|_| format!("Unknown format {:?} in [package.metadata.pprof]", f))))
+                .collect();
+        }
+        if args.run.app_args.is_empty() {
+            args.run.app_args = self.args.clone();
+        }
+    }
+
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.env.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+    }
+}
@@ -0,0 +1,114 @@
+use std::{env, fs, path::Path, process};
+
+use colored::Colorize;
+
+use crate::perf;
+use crate::report::Format;
+use crate::{find_in_path, print_step, resolve, resolve_status};
+
+/// Capability bits relevant for perf events, see capability.h
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_PERFMON: u32 = 38;
+
+
+/// Detect whether we are running inside a container, returns the name of the runtime if known
+pub fn detect() -> Option<String> {
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+    if let Ok(runtime) = env::var("container") {
+        return Some(runtime);
+    }
+    let cgroup = fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+    ["docker", "kubepods", "containerd", "libpod", "lxc"].iter()
+        .find(|name| cgroup.contains(*name))
+        .map(|name| name.to_string())
+}
+
+/// Whether the process may use perf events regardless of the paranoid setting
+pub fn has_perf_capability() -> bool {
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let Some(caps) = status.lines().find_map(|l| l.strip_prefix("CapEff:")) else { return false };
+    let caps = u64::from_str_radix(caps.trim(), 16).unwrap_or(0);
+    caps & (1 << CAP_PERFMON) != 0 || caps & (1 << CAP_SYS_ADMIN) != 0
+}
+
+/// Explain what the container needs to allow perf to record
+pub fn print_hints(runtime: &str) {
+    eprintln!("{}", format!("Running inside a container ({}), perf events are probably restricted", runtime).yellow());
+    if !has_perf_capability() {
+        eprintln!("{}", "The container lacks CAP_PERFMON (or CAP_SYS_ADMIN)".yellow());
+    }
+    eprintln!("{}", "Start the container with the following flags to allow profiling:".yellow());
+    eprintln!("{}", "    --cap-add PERFMON --security-opt seccomp=unconfined".yellow());
+    eprintln!("{}", "and lower kernel.perf_event_paranoid on the host if needed:".yellow());
+    eprintln!("{}", "    sudo sysctl kernel.perf_event_paranoid=1".yellow());
+}
+
+/// Container engine used to inspect containers, docker is preferred over podman
+fn engine() -> &'static str {
+    if find_in_path("docker").is_some() { "docker" } else { "podman" }
+}
+
+/// Record a process running inside a container from the host for the given number of seconds
+pub fn record(name: &str, duration: u64, formats: &[Format], ignore_exit: bool) {
+    let engine = engine();
+    let dir = Path::new("target").join("profiling").join(format!("container-{}", name));
+    resolve(fs::create_dir_all(&dir));
+
+    let output = resolve(process::Command::new(engine)
+        .args(["inspect", "--format", "{{.State.Pid}}", name])
+        .stderr(process::Stdio::inherit())
+        .output());
+    resolve_status(output.status);
+    let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if pid.is_empty() || pid == "0" {
+        resolve::<(), _>(Err(format!("Container {} is not running", name)));
+    }
+    eprintln!("Container process: {}", pid);
+
+    print_step("Extracting binaries from the container");
+    let symfs = dir.join("symfs");
+    for path in mapped_files(&pid) {
+        let dest = symfs.join(path.trim_start_matches('/'));
+        if let Some(parent) = dest.parent() {
+            resolve(fs::create_dir_all(parent));
+        }
+        let status = process::Command::new(engine)
+            .arg("cp")
+            .arg(format!("{}:{}", name, path))
+            .arg(&dest)
+            .stdout(process::Stdio::null())
+            .status();
+        if !status.is_ok_and(|s| s.success()) {
+            eprintln!("{}", format!("Warning: could not copy {} out of the container", path).yellow());
+        }
+    }
+
+    let mut recording = perf::Recording::new(&dir, "perf", "sleep", &[duration.to_string()], ignore_exit);
+    recording.target.splice(0..0, ["-p".to_string(), pid.clone(), "--".to_string()]);
+    recording.script_args = vec![format!("--symfs={}", symfs.to_string_lossy())];
+    let trace_path = perf::record(&recording);
+
+    perf::convert(&trace_path, formats, &dir, "perf");
+    if formats.contains(&Format::Trace) {
+        perf::print_trace_hint(&trace_path);
+    }
+}
+
+/// Files mapped into the process, as seen from inside its mount namespace
+fn mapped_files(pid: &str) -> Vec<String> {
+    let maps = resolve(fs::read_to_string(format!("/proc/{}/maps", pid))
+        .map_err(|e| format!("Could not read memory maps of {} ({}), try running as root", pid, e)));
+    let mut files: Vec<String> = maps.lines()
+        .filter_map(|l| l.split_whitespace().nth(5))
+        .filter(|p| p.starts_with('/'))
+        .map(str::to_string)
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+}
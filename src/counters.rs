@@ -0,0 +1,159 @@
+//! Counter tracks polled from `/proc` while the application runs
+//!
+//! The processes of the application are found by their executable, so forked workers are
+//! included as well. Readings are timestamped with the wall clock, which perf has to use too
+//! (see [`spans::PERF_CLOCK_ARGS`](crate::spans::PERF_CLOCK_ARGS)).
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::gecko::Counter;
+use crate::timing;
+
+/// Time between two readings, the CPU time is only updated every clock tick anyway
+const INTERVAL: Duration = Duration::from_millis(50);
+
+/// Usage of all processes of the application at one point in time
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    /// Seconds since the epoch
+    time: f64,
+    /// User and system time in clock ticks, of each process summed up
+    cpu_ticks: u64,
+    /// Resident set size in bytes of the processes still running
+    rss: u64,
+}
+
+pub struct Poller {
+    cpu_usage: bool,
+    rss: bool,
+    done: Arc<AtomicBool>,
+    readings: Arc<Mutex<Vec<Reading>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+
+impl Poller {
+    /// Start polling the processes running `executable` in the background, for the selected tracks
+    pub fn start(executable: &str, cpu_usage: bool, rss: bool) -> Poller {
+        let executable = fs::canonicalize(executable).unwrap_or_else(|_| PathBuf::from(executable));
+        let done = Arc::new(AtomicBool::new(false));
+        let readings = Arc::new(Mutex::new(Vec::new()));
+        let reader = {
+            let (done, readings) = (done.clone(), readings.clone());
+            thread::spawn(move || poll(&executable, &done, &readings))
+        };
+        Poller { cpu_usage, rss, done, readings, reader }
+    }
+
+    /// Stop polling and return the counter tracks with times in seconds since the epoch
+    pub fn finish(self) -> Vec<Counter> {
+        self.done.store(true, Ordering::SeqCst);
+        let _ = self.reader.join();
+        let readings = Arc::try_unwrap(self.readings)
+            .map(|r| r.into_inner().unwrap())
+            .unwrap_or_default();
+        let mut counters = Vec::new();
+        if readings.is_empty() {
+            return counters;
+        }
+        if self.cpu_usage {
+            counters.push(cpu_counter(&readings));
+        }
+        if self.rss {
+            counters.push(rss_counter(&readings));
+        }
+        counters
+    }
+}
+
+fn poll(executable: &Path, done: &AtomicBool, readings: &Mutex<Vec<Reading>>) {
+    // Processes that exited keep the ticks they used, so the sum never goes down
+    let mut ticks: HashMap<u32, u64> = HashMap::new();
+    while !done.load(Ordering::SeqCst) {
+        let pids = processes(executable);
+        let mut rss = 0;
+        for pid in &pids {
+            if let Some(cpu_ticks) = cpu_ticks(*pid) {
+                ticks.insert(*pid, cpu_ticks);
+            }
+            rss += resident_bytes(*pid).unwrap_or(0);
+        }
+        if !pids.is_empty() {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+            readings.lock().unwrap().push(Reading { time, cpu_ticks: ticks.values().sum(), rss });
+        }
+        thread::sleep(INTERVAL);
+    }
+}
+
+/// Ids of the processes whose executable is `executable`
+pub fn processes(executable: &Path) -> Vec<u32> {
+    fs::read_dir("/proc").into_iter().flatten().flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse().ok())
+        .filter(|pid: &u32| fs::read_link(format!("/proc/{}/exe", pid)).is_ok_and(|exe| exe == executable))
+        .collect()
+}
+
+/// User and system time of all threads of a process in clock ticks
+fn cpu_ticks(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    // utime and stime are fields 14 and 15, counting from pid as field 1
+    let user: u64 = fields.get(11)?.parse().ok()?;
+    let system: u64 = fields.get(12)?.parse().ok()?;
+    Some(user + system)
+}
+
+/// Resident set size of a process in bytes
+fn resident_bytes(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kilobytes = status.lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// CPU time used since the previous reading in microseconds
+fn cpu_counter(readings: &[Reading]) -> Counter {
+    let micros_per_tick = 1_000_000.0 / timing::clock_ticks();
+    let mut previous = 0;
+    let samples = readings.iter()
+        .map(|reading| {
+            let delta = reading.cpu_ticks.saturating_sub(previous);
+            previous = previous.max(reading.cpu_ticks);
+            (reading.time, (delta as f64 * micros_per_tick) as i64)
+        })
+        .collect();
+    Counter {
+        name: "CPU usage".to_string(),
+        category: "CPU".to_string(),
+        description: format!("CPU time in microseconds used by the application in the last {} ms", INTERVAL.as_millis()),
+        samples,
+    }
+}
+
+/// Change of the resident set size since the previous reading, shown as a memory track
+fn rss_counter(readings: &[Reading]) -> Counter {
+    let mut previous = 0;
+    let mut samples: Vec<(f64, i64)> = readings.iter()
+        .map(|reading| {
+            let delta = reading.rss as i64 - previous;
+            previous = reading.rss as i64;
+            (reading.time, delta)
+        })
+        .collect();
+    // The processes are gone after the last reading
+    if let Some((time, _)) = samples.last().copied() {
+        samples.push((time + INTERVAL.as_secs_f64(), -previous));
+    }
+    Counter {
+        name: "RSS".to_string(),
+        category: "Memory".to_string(),
+        description: "Resident set size of the application, polled from /proc".to_string(),
+        samples,
+    }
+}
@@ -0,0 +1,695 @@
+//! Demangling of Rust symbols that perf leaves mangled
+//!
+//! perf only knows the legacy mangling and is easily confused by generic-heavy names, so every
+//! frame of a trace is passed through here. Both the legacy (`_ZN...E`) and the v0 (`_R...`)
+//! schemes are understood, anything else is returned unchanged. With `--short-names` the hashes
+//! and crate disambiguators are dropped and the paths in generic arguments are shortened to
+//! their last segment. `--collapse-generics` replaces the generic arguments with `_`, so that the
+//! monomorphized instances of a function end up in the same frame.
+
+use std::sync::{OnceLock, atomic::{AtomicBool, Ordering}};
+
+use crate::config;
+
+/// Whether `--short-names` was given
+static SHORT: AtomicBool = AtomicBool::new(false);
+
+/// `--collapse-generics` or `--keep-generics`, the configuration decides if neither was given
+static COLLAPSE: OnceLock<bool> = OnceLock::new();
+
+/// Nesting of backreferences and types after which a symbol is considered malformed
+const MAX_DEPTH: usize = 64;
+
+
+pub fn set_short_names(short: bool) {
+    SHORT.store(short, Ordering::Relaxed);
+}
+
+pub fn set_collapse_generics(collapse: bool) {
+    let _ = COLLAPSE.set(collapse);
+}
+
+fn collapse_generics() -> bool {
+    *COLLAPSE.get_or_init(|| config::load().collapse_generics == Some(true))
+}
+
+/// Readable name of a possibly mangled symbol
+pub fn demangle(symbol: &str) -> String {
+    let short = SHORT.load(Ordering::Relaxed);
+    let demangled = demangle_scheme(symbol, short);
+    let demangled = if short { shorten(&demangled) } else { demangled };
+    if collapse_generics() { collapse(&demangled) } else { demangled }
+}
+
+/// Name of a symbol regardless of the options and without hash, to look it up in the output of other tools
+pub fn lookup_name(symbol: &str) -> String {
+    let demangled = demangle_scheme(symbol, false);
+    match demangled.rsplit_once("::") {
+        Some((path, last)) if is_legacy_hash(last) => path.to_string(),
+        _ => demangled,
+    }
+}
+
+/// Crate a demangled function belongs to, the first segment of its first path
+///
+/// Paths of primitive types are skipped (`<str as core::fmt::Display>::fmt` is in core), as is the
+/// disambiguator v0 symbols may carry (`std[1c3fe0a4b5a3e088]::rt`).
+pub fn crate_name(function: &str) -> &str {
+    let starts = function.char_indices()
+        .filter(|(i, _)| *i == 0 || function[..*i].ends_with(['<', '&', '*', ' ', '(', ',', '[']));
+    for (start, _) in starts {
+        let rest = &function[start..];
+        let len = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+        let after = &rest[len..];
+        let after = match after.strip_prefix('[').and_then(|a| a.split_once(']')) {
+            Some((hash, after)) if hash.bytes().all(|b| b.is_ascii_hexdigit()) => after,
+            _ => after,
+        };
+        if len > 0 && after.starts_with("::") {
+            return &rest[..len];
+        }
+    }
+    let path = function.trim_start_matches(['<', '&', '*']).trim_start_matches("dyn ");
+    path.split("::").next().unwrap_or(path)
+}
+
+fn demangle_scheme(symbol: &str, short: bool) -> String {
+    // macOS adds another underscore to all symbols
+    let mangled = symbol.strip_prefix("__").map(|s| format!("_{}", s));
+    let mangled = mangled.as_deref().unwrap_or(symbol);
+    let demangled = if let Some(v0) = mangled.strip_prefix("_R") {
+        V0 { input: v0.as_bytes(), pos: 0, out: String::new(), short, depth: 0, bound_lifetimes: 0 }.symbol()
+    } else if let Some(legacy) = mangled.strip_prefix("_ZN") {
+        legacy_symbol(legacy, short)
+    } else {
+        None
+    };
+    demangled.unwrap_or_else(|| symbol.to_string())
+}
+
+/// Demangle `<len><ident>...E`, where the last identifier may be the `h<hash>` of the crate
+fn legacy_symbol(mut rest: &str, short: bool) -> Option<String> {
+    let mut segments = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().ok()?;
+        let ident = rest.get(digits..digits + len)?;
+        rest = &rest[digits + len..];
+        segments.push(ident);
+    }
+    if short && let Some(last) = segments.last() && is_legacy_hash(last) {
+        segments.pop();
+    }
+    Some(segments.iter().map(|s| unescape(s)).collect::<Vec<_>>().join("::"))
+}
+
+fn is_legacy_hash(segment: &str) -> bool {
+    segment.len() == 17 && segment.starts_with('h') && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Replace the `$..$` escapes and `..` of legacy identifiers
+fn unescape(ident: &str) -> String {
+    // Identifiers may not start with `$`, so an underscore is put in front
+    let ident = if ident.starts_with("_$") { &ident[1..] } else { ident };
+    let mut out = String::new();
+    let mut rest = ident;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = after;
+        } else if rest.starts_with('$') && let Some(end) = rest[1..].find('$') {
+            let escape = &rest[1..end + 1];
+            let replacement = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape.strip_prefix('u').and_then(|hex| u32::from_str_radix(hex, 16).ok()).and_then(char::from_u32),
+            };
+            match replacement {
+                Some(c) => {
+                    out.push(c);
+                    rest = &rest[end + 2..];
+                },
+                None => {
+                    out.push('$');
+                    rest = &rest[1..];
+                },
+            }
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// Replace generic arguments with `<_>` and drop hash suffixes, which differ between instances,
+/// e.g. `<alloc::vec::Vec<u8>>::push::<u8>` to `<alloc::vec::Vec<_>>::push::<_>`
+///
+/// Angle brackets at the start of a path are a qualified self type and kept, as are the trait
+/// paths they contain.
+fn collapse(name: &str) -> String {
+    let name = match name.rsplit_once("::") {
+        Some((path, last)) if is_legacy_hash(last) => path,
+        _ => name,
+    };
+    let mut out = String::new();
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        let arguments = c == '<' && out.chars().next_back().is_some_and(|p| p.is_alphanumeric() || p == '_' || p == ':');
+        if !arguments {
+            out.push(c);
+            continue;
+        }
+        // Skip to the matching bracket, `->` of function types does not close one
+        let mut depth = 1;
+        let mut previous = c;
+        for c in chars.by_ref() {
+            match c {
+                '<' => depth += 1,
+                '>' if previous != '-' => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                break;
+            }
+            previous = c;
+        }
+        out.push_str("<_>");
+    }
+    out
+}
+
+/// Shorten the paths inside angle brackets to their last segment and drop hash suffixes,
+/// e.g. `<alloc::vec::Vec<alloc::string::String>>::push::h0123456789abcdef` to `<Vec<String>>::push`
+fn shorten(name: &str) -> String {
+    let name = match name.rsplit_once("::") {
+        Some((path, last)) if is_legacy_hash(last) => path,
+        _ => name,
+    };
+    let mut out = String::new();
+    let mut depth = 0usize;
+    // Start of the current path, and whether it started fresh instead of following a `<T>::`
+    let mut path_start = 0;
+    let mut fresh = true;
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            // Closures and other unnamed items are meaningless without the item they belong to
+            if depth > 0 && fresh && out.len() > path_start && chars.peek() != Some(&'{') {
+                out.truncate(path_start);
+            } else {
+                out.push_str("::");
+            }
+            continue;
+        }
+        out.push(c);
+        match c {
+            '<' => depth += 1,
+            '>' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+        match c {
+            '<' | ',' | ' ' | '(' | '[' | '&' | '*' | ';' => {
+                path_start = out.len();
+                fresh = true;
+            },
+            '>' | '}' | ')' | ']' => fresh = false,
+            _ => (),
+        }
+    }
+    out
+}
+
+/// Cursor over a v0 symbol, following <https://doc.rust-lang.org/rustc/symbol-mangling/v0.html>
+struct V0<'a> {
+    input: &'a [u8],
+    pos: usize,
+    out: String,
+    short: bool,
+    depth: usize,
+    /// Number of lifetimes bound by the enclosing `for<...>` binders
+    bound_lifetimes: u64,
+}
+
+impl V0<'_> {
+    fn symbol(mut self) -> Option<String> {
+        // Encoding version
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.path(true)?;
+        Some(self.out)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        if self.peek() == Some(b) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number terminated by `_`, where a lone `_` is 0 and all other numbers are one higher
+    fn base62(&mut self) -> Option<u64> {
+        if self.eat(b'_') {
+            return Some(0);
+        }
+        let mut value: u64 = 0;
+        loop {
+            let b = self.next()?;
+            let digit = match b {
+                b'0'..=b'9' => b - b'0',
+                b'a'..=b'z' => b - b'a' + 10,
+                b'A'..=b'Z' => b - b'A' + 36,
+                b'_' => return value.checked_add(1),
+                _ => return None,
+            };
+            value = value.checked_mul(62)?.checked_add(digit as u64)?;
+        }
+    }
+
+    fn decimal(&mut self) -> Option<usize> {
+        let start = self.pos;
+        // Numbers other than 0 never start with a zero
+        if self.eat(b'0') {
+            return Some(0);
+        }
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.pos]).ok()?.parse().ok()
+    }
+
+    fn disambiguator(&mut self) -> Option<u64> {
+        if self.eat(b's') { self.base62().map(|n| n + 1) } else { Some(0) }
+    }
+
+    /// Identifier without disambiguator, punycode is left encoded
+    fn ident(&mut self) -> Option<&str> {
+        let _punycode = self.eat(b'u');
+        let len = self.decimal()?;
+        self.eat(b'_');
+        let start = self.pos;
+        self.pos += len;
+        std::str::from_utf8(self.input.get(start..self.pos)?).ok()
+    }
+
+    /// Run `f` at the position of a backreference and continue after it
+    fn backref(&mut self, f: impl FnOnce(&mut Self) -> Option<()>) -> Option<()> {
+        let target = self.base62()? as usize;
+        if target >= self.pos || self.depth > MAX_DEPTH {
+            return None;
+        }
+        let resume = self.pos;
+        self.pos = target;
+        self.depth += 1;
+        f(self)?;
+        self.depth -= 1;
+        self.pos = resume;
+        Some(())
+    }
+
+    fn path(&mut self, value: bool) -> Option<()> {
+        match self.next()? {
+            b'C' => {
+                let disambiguator = self.disambiguator()?;
+                let name = self.ident()?.to_string();
+                self.out.push_str(&name);
+                if !self.short && disambiguator != 0 {
+                    self.out.push_str(&format!("[{:x}]", disambiguator));
+                }
+            },
+            b'N' => {
+                let namespace = self.next()?;
+                self.path(value)?;
+                let disambiguator = self.disambiguator()?;
+                let name = self.ident()?.to_string();
+                self.out.push_str("::");
+                match namespace {
+                    b'C' => self.out.push_str(&format!("{{closure#{}}}", disambiguator)),
+                    b'S' => self.out.push_str(&format!("{{shim:{}#{}}}", name, disambiguator)),
+                    b'A'..=b'Z' if name.is_empty() => self.out.push_str(&format!("{{{}#{}}}", namespace as char, disambiguator)),
+                    b'A'..=b'Z' => self.out.push_str(&format!("{{{}:{}#{}}}", namespace as char, name, disambiguator)),
+                    _ => self.out.push_str(&name),
+                }
+            },
+            b'M' => {
+                self.disambiguator()?;
+                self.skip_path()?;
+                self.out.push('<');
+                self.ty()?;
+                self.out.push('>');
+            },
+            b'X' => {
+                self.disambiguator()?;
+                self.skip_path()?;
+                self.out.push('<');
+                self.ty()?;
+                self.out.push_str(" as ");
+                self.path(false)?;
+                self.out.push('>');
+            },
+            b'Y' => {
+                self.out.push('<');
+                self.ty()?;
+                self.out.push_str(" as ");
+                self.path(false)?;
+                self.out.push('>');
+            },
+            b'I' => {
+                self.path(value)?;
+                if value {
+                    self.out.push_str("::");
+                }
+                self.out.push('<');
+                self.list(b'E', |s| s.generic_arg())?;
+                self.out.push('>');
+            },
+            b'B' => self.backref(|s| s.path(value))?,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Parse a path without printing it, for the paths of impl blocks
+    fn skip_path(&mut self) -> Option<()> {
+        let len = self.out.len();
+        self.path(false)?;
+        self.out.truncate(len);
+        Some(())
+    }
+
+    /// Items up to `end`, separated by commas
+    fn list(&mut self, end: u8, mut item: impl FnMut(&mut Self) -> Option<()>) -> Option<usize> {
+        let mut count = 0;
+        while !self.eat(end) {
+            if count > 0 {
+                self.out.push_str(", ");
+            }
+            item(self)?;
+            count += 1;
+        }
+        Some(count)
+    }
+
+    fn generic_arg(&mut self) -> Option<()> {
+        if self.eat(b'L') {
+            self.lifetime()
+        } else if self.eat(b'K') {
+            self.constant()
+        } else {
+            self.ty()
+        }
+    }
+
+    /// Lifetimes are de Bruijn indices into the bound lifetimes, named `'a`, `'b` and so on
+    fn lifetime(&mut self) -> Option<()> {
+        let index = self.base62()?;
+        if index == 0 {
+            self.out.push_str("'_");
+            return Some(());
+        }
+        let depth = self.bound_lifetimes.checked_sub(index)?;
+        self.out.push_str(&lifetime_name(depth));
+        Some(())
+    }
+
+    /// Print the `for<...>` of a binder, returns the number of lifetimes to unbind afterwards
+    fn binder(&mut self) -> Option<u64> {
+        if !self.eat(b'G') {
+            return Some(0);
+        }
+        let count = self.base62()? + 1;
+        let names: Vec<String> = (self.bound_lifetimes..self.bound_lifetimes + count).map(lifetime_name).collect();
+        self.out.push_str(&format!("for<{}> ", names.join(", ")));
+        self.bound_lifetimes += count;
+        Some(count)
+    }
+
+    fn ty(&mut self) -> Option<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return None;
+        }
+        let basic = match self.peek()? {
+            b'a' => Some("i8"), b'b' => Some("bool"), b'c' => Some("char"), b'd' => Some("f64"),
+            b'e' => Some("str"), b'f' => Some("f32"), b'h' => Some("u8"), b'i' => Some("isize"),
+            b'j' => Some("usize"), b'l' => Some("i32"), b'm' => Some("u32"), b'n' => Some("i128"),
+            b'o' => Some("u128"), b's' => Some("i16"), b't' => Some("u16"), b'u' => Some("()"),
+            b'v' => Some("..."), b'x' => Some("i64"), b'y' => Some("u64"), b'z' => Some("!"),
+            b'p' => Some("_"),
+            _ => None,
+        };
+        if let Some(basic) = basic {
+            self.pos += 1;
+            self.out.push_str(basic);
+            self.depth -= 1;
+            return Some(());
+        }
+        match self.next()? {
+            b'A' | b'S' => {
+                let array = self.input[self.pos - 1] == b'A';
+                self.out.push('[');
+                self.ty()?;
+                if array {
+                    self.out.push_str("; ");
+                    self.constant()?;
+                }
+                self.out.push(']');
+            },
+            b'R' | b'Q' => {
+                let mutable = self.input[self.pos - 1] == b'Q';
+                self.out.push('&');
+                if self.eat(b'L') {
+                    let len = self.out.len();
+                    self.lifetime()?;
+                    // Erased lifetimes are not worth printing
+                    if self.out[len..] == *"'_" {
+                        self.out.truncate(len);
+                    } else {
+                        self.out.push(' ');
+                    }
+                }
+                if mutable {
+                    self.out.push_str("mut ");
+                }
+                self.ty()?;
+            },
+            b'P' => {
+                self.out.push_str("*const ");
+                self.ty()?;
+            },
+            b'O' => {
+                self.out.push_str("*mut ");
+                self.ty()?;
+            },
+            b'F' => {
+                let bound = self.binder()?;
+                if self.eat(b'U') {
+                    self.out.push_str("unsafe ");
+                }
+                if self.eat(b'K') {
+                    if self.eat(b'C') {
+                        self.out.push_str("extern \"C\" ");
+                    } else {
+                        let abi = self.ident()?.replace('_', "-");
+                        self.out.push_str(&format!("extern \"{}\" ", abi));
+                    }
+                }
+                self.out.push_str("fn(");
+                self.list(b'E', |s| s.ty())?;
+                self.out.push(')');
+                let len = self.out.len();
+                self.out.push_str(" -> ");
+                self.ty()?;
+                if self.out[len..] == *" -> ()" {
+                    self.out.truncate(len);
+                }
+                self.bound_lifetimes -= bound;
+            },
+            b'D' => {
+                self.out.push_str("dyn ");
+                let bound = self.binder()?;
+                let mut first = true;
+                while !self.eat(b'E') {
+                    if !first {
+                        self.out.push_str(" + ");
+                    }
+                    first = false;
+                    self.dyn_trait()?;
+                }
+                self.bound_lifetimes -= bound;
+                // Lifetime of the trait object
+                if self.eat(b'L') {
+                    self.base62()?;
+                }
+            },
+            b'T' => {
+                self.out.push('(');
+                let count = self.list(b'E', |s| s.ty())?;
+                if count == 1 {
+                    self.out.push(',');
+                }
+                self.out.push(')');
+            },
+            b'B' => self.backref(|s| s.ty())?,
+            _ => {
+                self.pos -= 1;
+                self.path(false)?;
+            },
+        }
+        self.depth -= 1;
+        Some(())
+    }
+
+    fn dyn_trait(&mut self) -> Option<()> {
+        // Associated type bindings go into the generic arguments of the trait
+        self.path(false)?;
+        let mut bindings = Vec::new();
+        while self.eat(b'p') {
+            let name = self.ident()?.to_string();
+            let start = self.out.len();
+            self.ty()?;
+            bindings.push(format!("{} = {}", name, &self.out[start..]));
+            self.out.truncate(start);
+        }
+        if !bindings.is_empty() {
+            if self.out.ends_with('>') {
+                self.out.pop();
+                self.out.push_str(", ");
+            } else {
+                self.out.push('<');
+            }
+            self.out.push_str(&bindings.join(", "));
+            self.out.push('>');
+        }
+        Some(())
+    }
+
+    fn constant(&mut self) -> Option<()> {
+        if self.eat(b'p') {
+            self.out.push('_');
+            return Some(());
+        }
+        if self.eat(b'B') {
+            return self.backref(|s| s.constant());
+        }
+        let ty = self.next()?;
+        let negative = self.eat(b'n');
+        let start = self.pos;
+        while self.peek()? != b'_' {
+            self.pos += 1;
+        }
+        let hex = std::str::from_utf8(&self.input[start..self.pos]).ok()?;
+        self.pos += 1;
+        let value = u128::from_str_radix(if hex.is_empty() { "0" } else { hex }, 16).ok()?;
+        match ty {
+            b'b' => self.out.push_str(if value == 0 { "false" } else { "true" }),
+            b'c' => self.out.push(char::from_u32(value as u32)?),
+            _ => {
+                if negative {
+                    self.out.push('-');
+                }
+                self.out.push_str(&value.to_string());
+            },
+        }
+        Some(())
+    }
+}
+
+fn lifetime_name(depth: u64) -> String {
+    match depth {
+        0..26 => format!("'{}", (b'a' + depth as u8) as char),
+        _ => format!("'_{}", depth),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy() {
+        assert_eq!(demangle_scheme("_ZN4core3fmt5write17h0123456789abcdefE", false), "core::fmt::write::h0123456789abcdef");
+        assert_eq!(demangle_scheme("_ZN4core3fmt5write17h0123456789abcdefE", true), "core::fmt::write");
+        assert_eq!(
+            demangle_scheme("_ZN4core3ptr85drop_in_place$LT$std..rt..lang_start$LT$$LP$$RP$$GT$..$u7b$$u7b$closure$u7d$$u7d$$GT$17h0b04b6d8a2a3e4d3E", true),
+            "core::ptr::drop_in_place<std::rt::lang_start<()>::{{closure}}>");
+        assert_eq!(
+            demangle_scheme("_ZN70_$LT$alloc..vec..Vec$LT$T$C$A$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop17h0123456789abcdefE", true),
+            "<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop");
+        // macOS prefixes another underscore
+        assert_eq!(demangle_scheme("__ZN3foo3barE", false), "foo::bar");
+    }
+
+    #[test]
+    fn v0() {
+        assert_eq!(demangle_scheme("_RINvNtC3std3mem8align_ofdE", false), "std::mem::align_of::<f64>");
+        assert_eq!(demangle_scheme("_RNCNvC3foo4main0", false), "foo::main::{closure#0}");
+        assert_eq!(demangle_scheme("_RNvNtCs1_3foo3bar3baz", false), "foo[3]::bar::baz");
+        assert_eq!(demangle_scheme("_RNvNtCs1_3foo3bar3baz", true), "foo::bar::baz");
+        // Inherent impl of a generic type, whose path is a backreference
+        assert_eq!(demangle_scheme("_RNvMNtCs1_3foo3barINtB2_3BazmE3qux", false), "<foo[3]::bar::Baz<u32>>::qux");
+        assert_eq!(demangle_scheme("_RNvXC3fooRShNtC4core5Debug3fmt", false), "<&[u8] as core::Debug>::fmt");
+    }
+
+    #[test]
+    fn malformed_symbols_are_kept() {
+        // A backreference to itself and a truncated identifier
+        assert_eq!(demangle_scheme("_RB_", false), "_RB_");
+        assert_eq!(demangle_scheme("_RNvC3foo9bar", false), "_RNvC3foo9bar");
+        assert_eq!(demangle_scheme("_ZN3foo", false), "_ZN3foo");
+        assert_eq!(demangle_scheme("memcpy", false), "memcpy");
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(shorten("<alloc::vec::Vec<alloc::string::String>>::push::h0123456789abcdef"), "<Vec<String>>::push");
+        assert_eq!(shorten("core::ptr::drop_in_place<std::collections::HashMap<u32, alloc::string::String>>"),
+            "core::ptr::drop_in_place<HashMap<u32, String>>");
+        // Closures keep the function they are defined in
+        assert_eq!(shorten("std::rt::lang_start<()>::{{closure}}"), "std::rt::lang_start<()>::{{closure}}");
+    }
+
+    #[test]
+    fn collapsed_generics() {
+        assert_eq!(collapse("<alloc::vec::Vec<u8>>::push::<u8>"), "<alloc::vec::Vec<_>>::push::<_>");
+        assert_eq!(collapse("<foo::Bar<fn() -> u8> as core::ops::Drop>::drop"), "<foo::Bar<_> as core::ops::Drop>::drop");
+        assert_eq!(collapse("core::ptr::drop_in_place::<u8>::h0123456789abcdef"), "core::ptr::drop_in_place::<_>");
+    }
+
+    #[test]
+    fn crate_names() {
+        assert_eq!(crate_name("std::rt::lang_start"), "std");
+        assert_eq!(crate_name("<str as core::fmt::Display>::fmt"), "core");
+        assert_eq!(crate_name("<&alloc::vec::Vec<u8> as core::fmt::Debug>::fmt"), "alloc");
+        assert_eq!(crate_name("std[1c3fe0a4b5a3e088]::rt::lang_start"), "std");
+        assert_eq!(crate_name("main"), "main");
+    }
+
+    #[test]
+    fn lookup_names_drop_the_hash() {
+        assert_eq!(lookup_name("_ZN4core3fmt5write17h0123456789abcdefE"), "core::fmt::write");
+        assert_eq!(lookup_name("_RNvNtCs1_3foo3bar3baz"), "foo[3]::bar::baz");
+    }
+}
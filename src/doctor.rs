@@ -0,0 +1,218 @@
+//! Diagnosing the environment for common reasons of failing or unhelpful recordings
+
+use std::{env, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+
+use crate::config;
+use crate::perf;
+use crate::symbols;
+use crate::viewer;
+use crate::wsl::{self, WslVersion};
+use crate::{DoctorArgs, find_in_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Ok,
+    /// Recording works, but the results will be incomplete
+    Warning,
+    /// Recording will not work
+    Error,
+}
+
+/// Outcome of a single check, with the steps to fix it
+struct Check {
+    level: Level,
+    message: String,
+    fixes: Vec<String>,
+}
+
+
+pub fn run(args: &DoctorArgs) {
+    let checks = [
+        check_perf(),
+        check_paranoid(),
+        check_kptr_restrict(),
+        check_manifest(),
+        check_frame_pointers(),
+        check_debuginfod(),
+        check_browser(args.browser.as_deref().or(config::load().browser.as_deref())),
+    ];
+
+    for check in &checks {
+        let label = match check.level {
+            Level::Ok => "ok".green(),
+            Level::Warning => "warn".yellow(),
+            Level::Error => "error".red(),
+        };
+        println!("{:>5}  {}", label, check.message);
+        for fix in &check.fixes {
+            println!("       {} {}", "fix:".bold(), fix);
+        }
+    }
+
+    let errors = checks.iter().filter(|c| c.level == Level::Error).count();
+    let warnings = checks.iter().filter(|c| c.level == Level::Warning).count();
+    println!("\n{} error(s), {} warning(s)", errors, warnings);
+    if errors > 0 {
+        process::exit(1);
+    }
+}
+
+impl Check {
+    fn ok(message: impl Into<String>) -> Self {
+        Check { level: Level::Ok, message: message.into(), fixes: Vec::new() }
+    }
+
+    fn problem(level: Level, message: impl Into<String>, fixes: &[&str]) -> Self {
+        Check { level, message: message.into(), fixes: fixes.iter().map(|f| f.to_string()).collect() }
+    }
+}
+
+fn check_perf() -> Check {
+    if wsl::detect() == Some(WslVersion::Wsl1) {
+        return Check::problem(Level::Error, "WSL1 does not support perf events",
+            &["switch to WSL2: wsl --set-version <distro> 2"]);
+    }
+    if wsl::detect() == Some(WslVersion::Wsl2) && wsl::perf_binary().is_none() {
+        return Check::problem(Level::Error, "no perf build works on the WSL kernel",
+            &["install linux-tools-generic (builds are looked up in /usr/lib/linux-tools)"]);
+    }
+
+    match process::Command::new(perf::binary()).arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            Check::ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        },
+        Ok(output) => Check::problem(Level::Error,
+            format!("perf does not work: {}", String::from_utf8_lossy(&output.stderr).trim()),
+            &["install the perf build for your kernel (e.g. linux-tools-$(uname -r) or linux-perf)"]),
+        Err(_) => Check::problem(Level::Error, "perf is not installed",
+            &["install perf (linux-tools-$(uname -r) on Ubuntu, linux-perf on Debian, perf on Fedora and Arch)"]),
+    }
+}
+
+fn check_paranoid() -> Check {
+    let Some(value) = read_sysctl("perf_event_paranoid") else {
+        return Check::problem(Level::Warning, "could not read kernel.perf_event_paranoid", &[]);
+    };
+    let fixes = |setting: &str| {
+        let mut fixes = vec![format!("sudo sysctl {}", setting)];
+        if wsl::detect() == Some(WslVersion::Wsl2) {
+            fixes.push(wsl::sysctl_hint(setting));
+        }
+        fixes
+    };
+    match value {
+        v if v > 2 => Check {
+            level: Level::Error,
+            message: format!("kernel.perf_event_paranoid is {}, unprivileged processes cannot be recorded", v),
+            fixes: fixes("kernel.perf_event_paranoid=2"),
+        },
+        v if v > 1 => Check {
+            level: Level::Warning,
+            message: format!("kernel.perf_event_paranoid is {}, kernel frames are not recorded", v),
+            fixes: fixes("kernel.perf_event_paranoid=1"),
+        },
+        v => Check::ok(format!("kernel.perf_event_paranoid is {}", v)),
+    }
+}
+
+fn check_kptr_restrict() -> Check {
+    match read_sysctl("kptr_restrict") {
+        Some(0) => Check::ok("kernel.kptr_restrict is 0"),
+        Some(v) => Check::problem(Level::Warning,
+            format!("kernel.kptr_restrict is {}, kernel symbols cannot be resolved", v),
+            &["sudo sysctl kernel.kptr_restrict=0"]),
+        None => Check::problem(Level::Warning, "could not read kernel.kptr_restrict", &[]),
+    }
+}
+
+fn check_manifest() -> Check {
+    let Some(manifest) = workspace_manifest() else {
+        return Check::problem(Level::Warning, "not inside a cargo project", &[]);
+    };
+    let content = fs::read_to_string(&manifest).unwrap_or_default();
+    let Some(profile) = toml_section(&content, "profile.profiling") else {
+        return Check::problem(Level::Error,
+            format!("{} has no [profile.profiling]", manifest.to_string_lossy()),
+            &["cargo pprof --add"]);
+    };
+
+    let debug = profile.iter()
+        .filter_map(|l| l.split_once('='))
+        .find(|(key, _)| key.trim() == "debug")
+        .map(|(_, value)| value.trim().trim_matches('"').to_string());
+    match debug.as_deref() {
+        Some("false" | "0" | "none") | None => Check::problem(Level::Error,
+            "the profiling profile has no debug info, functions and lines cannot be resolved",
+            &["add `debug = true` to [profile.profiling] in Cargo.toml"]),
+        Some("line-tables-only" | "limited" | "1") => Check::problem(Level::Warning,
+            "the profiling profile only has limited debug info, inlined functions will be missing",
+            &["set `debug = true` in [profile.profiling] in Cargo.toml"]),
+        Some(_) => Check::ok("the profiling profile has debug info"),
+    }
+}
+
+fn check_frame_pointers() -> Check {
+    let mut flags = env::var("RUSTFLAGS").unwrap_or_default();
+    flags.push_str(&env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default());
+    if let Some(root) = workspace_manifest().as_deref().and_then(Path::parent) {
+        for config in [".cargo/config.toml", ".cargo/config"] {
+            flags.push_str(&fs::read_to_string(root.join(config)).unwrap_or_default());
+        }
+    }
+
+    if flags.contains("force-frame-pointers=yes") || flags.contains("force-frame-pointers=on") {
+        Check::ok("frame pointers are enabled")
+    } else {
+        Check::problem(Level::Warning,
+            "frame pointers are not forced, perf's stack walking may cut off call stacks",
+            &["export RUSTFLAGS=\"-C force-frame-pointers=yes\" or add it to .cargo/config.toml"])
+    }
+}
+
+fn check_debuginfod() -> Check {
+    match symbols::debuginfod_urls() {
+        Some(urls) => Check::ok(format!("debuginfod is enabled ({})", urls.trim())),
+        None => Check::problem(Level::Warning,
+            "DEBUGINFOD_URLS is not set, system libraries may lack symbols",
+            &["export DEBUGINFOD_URLS=https://debuginfod.elfutils.org/ (or your distribution's server, or pass --debuginfod)",
+              "or install the debug symbol packages of the libraries"]),
+    }
+}
+
+fn check_browser(browser: Option<&str>) -> Check {
+    let command = viewer::browser_command(browser);
+    let program = PathBuf::from(command.get_program());
+    if program.is_file() || find_in_path(&program.to_string_lossy()).is_some() {
+        Check::ok(format!("profiles are opened with {}", program.to_string_lossy()))
+    } else {
+        Check::problem(Level::Warning,
+            format!("{} is not installed, --open will not work", program.to_string_lossy()),
+            &["pass --browser <command> or set $BROWSER"])
+    }
+}
+
+fn read_sysctl(name: &str) -> Option<i32> {
+    fs::read_to_string(Path::new("/proc/sys/kernel").join(name)).ok()?.trim().parse().ok()
+}
+
+/// Cargo.toml of the workspace the current directory belongs to
+fn workspace_manifest() -> Option<PathBuf> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = process::Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format", "plain"])
+        .stderr(process::Stdio::null())
+        .output()
+        .ok()?;
+    output.status.success()
+        .then(|| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+/// Lines of a `[name]` table of a TOML file
+fn toml_section<'a>(content: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    let header = format!("[{}]", name);
+    let mut lines = content.lines().skip_while(|l| l.trim() != header);
+    lines.next()?;
+    Some(lines.take_while(|l| !l.trim_start().starts_with('[')).collect())
+}
@@ -0,0 +1,39 @@
+//! Recording a server while a driver command generates load
+//!
+//! The server is started under perf like any other application, but the recording only ends
+//! once the driver exits: perf is interrupted then, which also stops the server.
+
+use std::{path::PathBuf, process, time::Duration};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::perf::{self, Recording};
+use crate::ready;
+use crate::{PProfArgs, print_step, resolve};
+
+
+/// Record the server until the driver command exits, returns the path of the trace file
+pub fn record(recording: &Recording, args: &PProfArgs) -> PathBuf {
+    let Some(driver) = &args.driver else {
+        resolve(Err("No driver command given"))
+    };
+
+    print_step("Starting server with perf");
+    let mut child = ready::start(recording, args.wait_for.as_ref(), Duration::from_secs(args.wait_timeout));
+
+    print_step("Running driver");
+    let mut driver_command = process::Command::new("sh");
+    driver_command.arg("-c").arg(driver).current_dir(app::cwd());
+    driver_command.envs(app::env().iter().map(|(k, v)| (k, v)));
+    let driver_status = app::run(&mut driver_command);
+
+    print_step("Stopping recording");
+    ready::stop(&mut child);
+    perf::check_data(recording);
+    if !driver_status.success() {
+        eprintln!("{}", format!("Warning: the driver failed ({}), the recording may be incomplete", driver_status).yellow());
+    }
+
+    perf::script(recording)
+}
@@ -0,0 +1,67 @@
+//! Printing the commands of a recording instead of running them
+
+use crate::app;
+use crate::manifest;
+use crate::markers;
+use crate::perf;
+use crate::{Backend, PProfArgs, command_line, print_step, resolve, shell_word};
+
+
+/// Print the cargo, perf record and perf script command lines of a perf recording
+pub fn run(args: &PProfArgs) {
+    if args.backend != Backend::Perf || args.container.is_some() || args.io || args.net {
+        resolve::<(), _>(Err("--dry-run only supports CPU sampling with the perf backend"));
+    }
+
+    let mut cargo_args = Vec::new();
+    if let Some(target) = &args.target {
+        cargo_args.extend(["--target", target.as_str()]);
+    }
+    // The binary is not built, so predict where cargo would place it
+    let executable = predict_executable(args.target.as_deref());
+    let dir = crate::output_dir(&executable);
+    let mut recording = crate::perf_recording(args, &executable, dir);
+    let trace = dir.join(format!("{}.trace", recording.stem));
+
+    print_step("Dry run");
+    println!("{}", command_line(&crate::build_command(&cargo_args, &[])));
+    if !args.sdt_probes.is_empty() {
+        for command in markers::sdt_commands(&executable, &args.sdt_probes) {
+            println!("{}", command_line(&command));
+        }
+        markers::add_sdt_args(&args.sdt_probes, &mut recording.record_args);
+    }
+    if args.markers {
+        let fifo = dir.join("markers.fifo");
+        println!("mkfifo {}", shell_word(&fifo.to_string_lossy()));
+        recording.env.push((markers::ENV_VAR.to_string(), fifo.to_string_lossy().to_string()));
+    }
+    if let Some(pre) = &args.run.pre {
+        println!("sh -c {}", shell_word(pre));
+    }
+    let mut record = command_line(&perf::record_command(&recording));
+    if let Some(input) = app::input() {
+        record.push_str(&format!(" < {}", shell_word(&input.to_string_lossy())));
+    }
+    println!("{}", record);
+    println!("{} > {}", command_line(&perf::script_command(&recording)), shell_word(&trace.to_string_lossy()));
+    if let Some(post) = &args.run.post {
+        println!("sh -c {}", shell_word(post));
+    }
+}
+
+/// Path of the binary `cargo build --profile=profiling` would produce for the current package
+fn predict_executable(target: Option<&str>) -> String {
+    let metadata = resolve(manifest::load());
+    let Some(bin) = metadata.current_package().and_then(|p| p.targets.iter().find(|t| t.kind.iter().any(|k| k == "bin"))) else {
+        resolve(Err("Could not find a binary target"))
+    };
+
+    let mut path = metadata.target_directory.clone();
+    if let Some(target) = target {
+        path.push(target);
+    }
+    path.push("profiling");
+    path.push(&bin.name);
+    path.to_string_lossy().to_string()
+}
@@ -0,0 +1,59 @@
+use std::{fs, path::Path};
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::{print_step, resolve, shell_quote};
+
+/// Sampling frequency of the profile probe in Hz
+pub const FREQUENCY: u32 = 997;
+
+
+/// Sample user stacks of the application with DTrace (FreeBSD, illumos, macOS)
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> Profile {
+    let out_path = dir.join("dtrace.stacks");
+    let script = format!("profile-{} /pid == $target/ {{ @[ustack()] = count(); }}", FREQUENCY);
+    // DTrace runs the target command through a shell
+    let mut target = vec![shell_quote(executable)];
+    target.extend(app_args.iter().map(|a| shell_quote(a)));
+
+    print_step("Running program with dtrace");
+    let status = app::run(app::command("dtrace")
+        .args(["-q", "-x", "ustackframes=100"])
+        .arg("-n")
+        .arg(script)
+        .arg("-c")
+        .arg(target.join(" "))
+        .arg("-o")
+        .arg(&out_path));
+    app::check_exit(status, ignore_exit);
+    eprintln!("DTrace output: {}", out_path.to_string_lossy());
+
+    let content = resolve(fs::read_to_string(&out_path));
+    parse(&content)
+}
+
+/// Parse aggregated `ustack()` output: frames like ``module`function+0x1f`` followed by a count
+fn parse(content: &str) -> Profile {
+    let mut samples = Vec::new();
+    let mut frames = Vec::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Ok(count) = line.parse::<u64>() {
+            samples.push(Sample { frames: std::mem::take(&mut frames), values: vec![count] });
+            continue;
+        }
+
+        let (module, function) = line.split_once('`').unwrap_or(("[unknown]", line));
+        let function = function.rsplit_once("+0x").map(|(f, _)| f).unwrap_or(function);
+        frames.push(Frame { function: function.to_string(), module: module.to_string() });
+    }
+
+    Profile {
+        value_names: vec!["samples".to_string()],
+        samples,
+    }
+}
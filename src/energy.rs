@@ -0,0 +1,200 @@
+use std::{fs, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::app;
+use crate::perf;
+use crate::{EnergyArgs, print_step, resolve};
+
+/// RAPL events read through perf
+const PERF_EVENTS: &[&str] = &["power/energy-pkg/", "power/energy-cores/", "power/energy-gpu/", "power/energy-ram/"];
+
+/// Location of the powercap RAPL zones
+const POWERCAP_DIR: &str = "/sys/class/powercap";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnergySource {
+    /// RAPL events via `perf stat`
+    Perf,
+    /// Powercap counters in sysfs (may require root)
+    Sysfs,
+}
+
+/// Energy consumed by each domain during one interval
+#[derive(Debug, Clone)]
+struct Phase {
+    /// End of the interval in seconds since start
+    end: f64,
+    /// Joules per domain, in the order of the domain names
+    joules: Vec<f64>,
+}
+
+/// RAPL zone exposed by the powercap interface
+struct Zone {
+    name: String,
+    counter: PathBuf,
+    /// Value at which the counter wraps around, in microjoules
+    max: u64,
+}
+
+
+/// Build the binary and measure the energy consumed while it runs
+pub fn run(args: &EnergyArgs) {
+    let executable = crate::build(&[]);
+
+    let (domains, phases) = match args.source {
+        EnergySource::Perf => record_perf(&executable, args),
+        EnergySource::Sysfs => record_sysfs(&executable, args),
+    };
+    print_phases(&domains, &phases);
+}
+
+fn record_perf(executable: &str, args: &EnergyArgs) -> (Vec<String>, Vec<Phase>) {
+    let dir = crate::output_dir(executable);
+    let out_path = dir.join("energy.csv");
+
+    print_step("Running program with perf stat");
+    let status = app::run(app::command(perf::binary())
+        .arg("stat")
+        .args(["-a", "-x", ","])
+        .arg(format!("--interval-print={}", args.interval))
+        .arg(format!("--output={}", out_path.to_string_lossy()))
+        .arg(format!("--event={}", PERF_EVENTS.join(",")))
+        .arg(executable)
+        .args(&args.run.app_args));
+    app::check_exit(status, args.run.ignore_exit);
+
+    let content = resolve(fs::read_to_string(&out_path));
+    parse_perf_stat(&content)
+}
+
+/// Parse interval output of `perf stat -x,` (`<time>,<value>,<unit>,<event>,...`)
+fn parse_perf_stat(content: &str) -> (Vec<String>, Vec<Phase>) {
+    let mut domains: Vec<String> = Vec::new();
+    let mut phases: Vec<Phase> = Vec::new();
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split(',').collect();
+        if line.starts_with('#') || fields.len() < 4 {
+            continue;
+        }
+        let (Ok(time), Ok(value)) = (fields[0].trim().parse::<f64>(), fields[1].trim().parse::<f64>()) else {
+            continue;
+        };
+        let domain = fields[3].trim_start_matches("power/energy-").trim_end_matches('/').to_string();
+
+        let index = match domains.iter().position(|d| *d == domain) {
+            Some(i) => i,
+            None => {
+                domains.push(domain);
+                domains.len() - 1
+            },
+        };
+        if phases.last().is_none_or(|p| p.end != time) {
+            phases.push(Phase { end: time, joules: Vec::new() });
+        }
+        let phase = phases.last_mut().unwrap();
+        phase.joules.resize(domains.len(), 0.0);
+        phase.joules[index] = value;
+    }
+
+    for phase in &mut phases {
+        phase.joules.resize(domains.len(), 0.0);
+    }
+    (domains, phases)
+}
+
+fn record_sysfs(executable: &str, args: &EnergyArgs) -> (Vec<String>, Vec<Phase>) {
+    let zones = resolve(powercap_zones());
+    let read = |zone: &Zone| -> u64 {
+        resolve(fs::read_to_string(&zone.counter)
+            .map_err(|e| format!("Could not read {} ({})", zone.counter.to_string_lossy(), e)))
+            .trim()
+            .parse()
+            .unwrap_or(0)
+    };
+
+    print_step("Running program while sampling RAPL counters");
+    let start = Instant::now();
+    let mut last: Vec<u64> = zones.iter().map(read).collect();
+    let mut child = app::spawn(app::command(executable).args(&args.run.app_args));
+
+    let mut phases = Vec::new();
+    let status = loop {
+        thread::sleep(Duration::from_millis(args.interval));
+        let exited = resolve(child.try_wait());
+
+        let current: Vec<u64> = zones.iter().map(read).collect();
+        let joules = zones.iter().zip(last.iter().zip(&current))
+            .map(|(zone, (before, after))| {
+                let delta = if after >= before { after - before } else { zone.max - before + after };
+                delta as f64 / 1_000_000.0
+            })
+            .collect();
+        phases.push(Phase { end: start.elapsed().as_secs_f64(), joules });
+        last = current;
+
+        if let Some(status) = exited {
+            break status;
+        }
+    };
+    app::check_exit(status, args.run.ignore_exit);
+
+    (zones.into_iter().map(|z| z.name).collect(), phases)
+}
+
+fn powercap_zones() -> Result<Vec<Zone>, String> {
+    let entries = fs::read_dir(POWERCAP_DIR)
+        .map_err(|e| format!("Could not read {} ({})", POWERCAP_DIR, e))?;
+    let mut zones: Vec<Zone> = entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with("intel-rapl:")))
+        .filter_map(|p| {
+            let name = fs::read_to_string(p.join("name")).ok()?;
+            let max = fs::read_to_string(p.join("max_energy_range_uj")).ok()?;
+            Some(Zone {
+                name: format!("{} ({})", name.trim(), file_name(&p)),
+                counter: p.join("energy_uj"),
+                max: max.trim().parse().unwrap_or(u64::MAX),
+            })
+        })
+        .collect();
+    zones.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if zones.is_empty() {
+        Err("No RAPL zones found in /sys/class/powercap".to_string())
+    } else {
+        Ok(zones)
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+fn print_phases(domains: &[String], phases: &[Phase]) {
+    let widths: Vec<usize> = domains.iter().map(|d| d.len().max(10)).collect();
+
+    println!("\n{}", "Energy per phase (J)".bold());
+    print!("{:>10}", "Time (s)");
+    for (domain, width) in domains.iter().zip(&widths) {
+        print!(" {:>width$}", domain, width = width);
+    }
+    println!();
+    for phase in phases {
+        print!("{:>10.2}", phase.end);
+        for (joules, width) in phase.joules.iter().zip(&widths) {
+            print!(" {:>width$.3}", joules, width = width);
+        }
+        println!();
+    }
+
+    let duration = phases.last().map(|p| p.end).unwrap_or(0.0);
+    println!("\n{}", "Total".bold());
+    for (i, domain) in domains.iter().enumerate() {
+        let total: f64 = phases.iter().map(|p| p.joules[i]).sum();
+        let power = if duration > 0.0 { total / duration } else { 0.0 };
+        println!("{:>12}: {:.3} J ({:.2} W average)", domain, total, power);
+    }
+}
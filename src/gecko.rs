@@ -0,0 +1,214 @@
+//! Writer for the Gecko profile format, which the Firefox Profiler imports natively
+//!
+//! Unlike `perf script` output this format can carry markers, counter tracks and the category of
+//! each frame, see [`category`].
+
+use std::{collections::HashMap, fs::File, io::{self, BufWriter, Write}, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::category::{self, Category};
+use crate::profile::{Frame, Profile};
+
+/// Version of the Gecko format that is written, newer versions are upgraded by the profiler
+const GECKO_VERSION: u32 = 24;
+
+#[derive(Debug, Clone, Default)]
+pub struct GeckoProfile {
+    pub threads: Vec<Thread>,
+    pub counters: Vec<Counter>,
+    /// Sampling interval in milliseconds
+    pub interval: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Thread {
+    pub name: String,
+    pub process_name: String,
+    pub pid: u32,
+    pub tid: u32,
+    /// Time in milliseconds and call stack (innermost frame first) of each sample
+    pub samples: Vec<(f64, Vec<Frame>)>,
+    pub markers: Vec<Marker>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Marker {
+    pub name: String,
+    /// Start time in milliseconds
+    pub start: f64,
+    /// End time in milliseconds, instant markers have none
+    pub end: Option<f64>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Counter {
+    pub name: String,
+    /// Category as understood by the profiler, "Memory" counters are displayed as memory tracks
+    pub category: String,
+    pub description: String,
+    /// Time in milliseconds and change of the value since the previous sample
+    pub samples: Vec<(f64, i64)>,
+}
+
+/// String, frame and stack tables of a single thread
+#[derive(Default)]
+struct Tables {
+    strings: Vec<String>,
+    string_indices: HashMap<String, usize>,
+    frames: Vec<Value>,
+    frame_indices: HashMap<Frame, usize>,
+    stacks: Vec<Value>,
+    stack_indices: HashMap<(Option<usize>, usize), usize>,
+}
+
+
+impl Tables {
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(i) = self.string_indices.get(s) {
+            return *i;
+        }
+        self.strings.push(s.to_string());
+        self.string_indices.insert(s.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    fn frame(&mut self, frame: &Frame) -> usize {
+        if let Some(i) = self.frame_indices.get(frame) {
+            return *i;
+        }
+        let location = if frame.module.is_empty() {
+            frame.function.clone()
+        } else {
+            format!("{} ({})", frame.function, frame.module)
+        };
+        let location = self.string(&location);
+        self.frames.push(json!([location, false, 0, null, null, null, category::of(frame).index(), 0]));
+        self.frame_indices.insert(frame.clone(), self.frames.len() - 1);
+        self.frames.len() - 1
+    }
+
+    /// Intern a call stack (innermost frame first) and return the index of its leaf
+    fn stack(&mut self, frames: &[Frame]) -> Option<usize> {
+        let mut prefix = None;
+        for frame in frames.iter().rev() {
+            let frame = self.frame(frame);
+            prefix = Some(match self.stack_indices.get(&(prefix, frame)) {
+                Some(i) => *i,
+                None => {
+                    self.stacks.push(json!([prefix, frame]));
+                    self.stack_indices.insert((prefix, frame), self.stacks.len() - 1);
+                    self.stacks.len() - 1
+                },
+            });
+        }
+        prefix
+    }
+}
+
+/// Build a single-thread profile from aggregated stacks
+///
+/// Each stack is repeated according to its first value and laid out on a synthetic timeline,
+/// so only the call tree and flame graph views are meaningful.
+pub fn from_profile(profile: &Profile, name: &str, interval: f64) -> GeckoProfile {
+    let mut thread = Thread {
+        name: name.to_string(),
+        process_name: name.to_string(),
+        ..Thread::default()
+    };
+    for sample in &profile.samples {
+        for _ in 0..sample.values.first().copied().unwrap_or(0) {
+            let time = thread.samples.len() as f64 * interval;
+            thread.samples.push((time, sample.frames.clone()));
+        }
+    }
+
+    GeckoProfile { threads: vec![thread], counters: Vec::new(), interval }
+}
+
+fn thread_json(thread: &Thread) -> Value {
+    let mut tables = Tables::default();
+
+    let samples: Vec<Value> = thread.samples.iter()
+        .map(|(time, frames)| json!([tables.stack(frames), time, 0]))
+        .collect();
+    let markers: Vec<Value> = thread.markers.iter()
+        .map(|m| {
+            let phase = if m.end.is_some() { 1 } else { 0 };
+            json!([tables.string(&m.name), m.start, m.end, phase, 0, { "type": "Text", "name": m.text }])
+        })
+        .collect();
+
+    json!({
+        "name": thread.name,
+        "processType": "default",
+        "processName": thread.process_name,
+        "registerTime": 0,
+        "unregisterTime": null,
+        "pid": thread.pid,
+        "tid": thread.tid,
+        "samples": { "schema": { "stack": 0, "time": 1, "eventDelay": 2 }, "data": samples },
+        "markers": {
+            "schema": { "name": 0, "startTime": 1, "endTime": 2, "phase": 3, "category": 4, "data": 5 },
+            "data": markers,
+        },
+        "stackTable": { "schema": { "prefix": 0, "frame": 1 }, "data": tables.stacks },
+        "frameTable": {
+            "schema": {
+                "location": 0, "relevantForJS": 1, "innerWindowID": 2, "implementation": 3,
+                "line": 4, "column": 5, "category": 6, "subcategory": 7,
+            },
+            "data": tables.frames,
+        },
+        "stringTable": tables.strings,
+    })
+}
+
+pub fn write(profile: &GeckoProfile, path: &Path) -> io::Result<()> {
+    let counters: Vec<Value> = profile.counters.iter()
+        .map(|c| json!({
+            "name": c.name,
+            "category": c.category,
+            "description": c.description,
+            "sample_groups": [{
+                "id": 0,
+                "samples": {
+                    "schema": { "time": 0, "count": 1, "number": 2 },
+                    "data": c.samples.iter().map(|(t, v)| json!([t, v, 1])).collect::<Vec<_>>(),
+                },
+            }],
+        }))
+        .collect();
+
+    let gecko = json!({
+        "meta": {
+            "version": GECKO_VERSION,
+            "interval": if profile.interval > 0.0 { profile.interval } else { 1.0 },
+            "startTime": 0,
+            "shutdownTime": null,
+            "processType": 0,
+            "product": "cargo-pprof",
+            "stackwalk": 1,
+            "debug": false,
+            "presymbolicated": true,
+            "categories": Category::ALL.iter()
+                .map(|c| json!({ "name": c.name(), "color": c.color(), "subcategories": ["Other"] }))
+                .collect::<Vec<_>>(),
+            "markerSchema": [{
+                "name": "Text",
+                "display": ["marker-chart", "marker-table", "timeline-overview"],
+                "data": [{ "key": "name", "label": "Details", "format": "string" }],
+            }],
+        },
+        "libs": [],
+        "pausedRanges": [],
+        "processes": [],
+        "threads": profile.threads.iter().map(thread_json).collect::<Vec<_>>(),
+        "counters": counters,
+    });
+
+    let mut file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut file, &gecko)?;
+    file.flush()
+}
@@ -0,0 +1,78 @@
+use std::{fs, path::Path, process};
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::{print_step, resolve, resolve_status};
+
+
+/// Profile the application with binutils' gprofng and return its call tree
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> Profile {
+    let experiment = dir.join("gprofng.er");
+    // gprofng refuses to overwrite an existing experiment
+    let _ = fs::remove_dir_all(&experiment);
+
+    print_step("Running program with gprofng");
+    let status = app::run(app::command("gprofng")
+        .args(["collect", "app", "-p", "on", "-O"])
+        .arg(&experiment)
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    eprintln!("gprofng experiment: {}", experiment.to_string_lossy());
+
+    print_step("Exporting call tree");
+    let output = resolve(process::Command::new("gprofng")
+        .args(["display", "text", "-calltree"])
+        .arg(&experiment)
+        .stderr(process::Stdio::inherit())
+        .output());
+    resolve_status(output.status);
+    parse_calltree(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the output of `gprofng display text -calltree`
+///
+/// Each node line carries the inclusive time in seconds, followed by the name indented by its
+/// depth (`1.430    |  +-main`). The self time of a node is what its children do not account for.
+fn parse_calltree(content: &str) -> Profile {
+    // Open nodes from the root: depth, name, inclusive time and the time of their children
+    let mut path: Vec<(usize, String, f64, f64)> = Vec::new();
+    let mut samples = Vec::new();
+
+    let mut close = |node: (usize, String, f64, f64), path: &[(usize, String, f64, f64)]| {
+        let own = ((node.2 - node.3).max(0.0) * 1_000_000.0).round() as u64;
+        if own == 0 {
+            return;
+        }
+        let mut frames = vec![Frame { function: node.1, module: String::new() }];
+        frames.extend(path.iter().rev()
+            .filter(|n| n.1 != "<Total>")
+            .map(|n| Frame { function: n.1.clone(), module: String::new() }));
+        samples.push(Sample { frames, values: vec![own] });
+    };
+
+    for line in content.lines() {
+        let Some((value, tree)) = line.trim_start().split_once(char::is_whitespace) else { continue };
+        let Ok(inclusive) = value.parse::<f64>() else { continue };
+        let Some(depth) = tree.find("+-") else { continue };
+        let name = tree[depth + 2..].trim().to_string();
+
+        while path.last().is_some_and(|n| n.0 >= depth) {
+            let node = path.pop().unwrap();
+            close(node, &path);
+        }
+        if let Some(parent) = path.last_mut() {
+            parent.3 += inclusive;
+        }
+        path.push((depth, name, inclusive, 0.0));
+    }
+    while let Some(node) = path.pop() {
+        close(node, &path);
+    }
+    samples.retain(|s| s.frames[0].function != "<Total>");
+
+    Profile {
+        value_names: vec!["CPU time (us)".to_string()],
+        samples,
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use crate::gecko::{Marker, Thread};
+use crate::profile::PerfEvent;
+
+/// Fence tracepoints emitted by all DRM drivers when GPU work is submitted and completed
+const TRACEPOINTS: &[&str] = &["dma_fence:dma_fence_emit", "dma_fence:dma_fence_signaled"];
+
+
+/// Additional `perf record` arguments to capture GPU activity next to the CPU samples
+pub fn record_args() -> Vec<String> {
+    let mut args = vec!["-e".to_string(), "cpu-clock".to_string()];
+    for tracepoint in TRACEPOINTS {
+        args.push("-e".to_string());
+        // Record every fence event instead of sampling them with the CPU frequency
+        args.push(format!("{}/period=1/", tracepoint));
+    }
+    args
+}
+
+pub fn is_gpu_event(event: &PerfEvent) -> bool {
+    event.event.starts_with("dma_fence:")
+}
+
+/// Turn fence events into one track per GPU timeline, with a marker from submission to completion
+pub fn tracks(events: &[PerfEvent], start: f64, pid: u32) -> Vec<Thread> {
+    let mut tracks: Vec<Thread> = Vec::new();
+    let mut pending: HashMap<(String, String), (f64, String)> = HashMap::new();
+
+    for event in events {
+        let fields: HashMap<&str, &str> = event.details.split_whitespace()
+            .filter_map(|f| f.split_once('='))
+            .collect();
+        let context = fields.get("context").unwrap_or(&"").to_string();
+        let seqno = fields.get("seqno").unwrap_or(&"").to_string();
+        let time = (event.time - start) * 1000.0;
+
+        if event.event.ends_with("dma_fence_emit") {
+            let timeline = format!("GPU {} {}",
+                fields.get("driver").unwrap_or(&""),
+                fields.get("timeline").unwrap_or(&""));
+            pending.insert((context, seqno), (time, timeline));
+        } else if let Some((emitted, timeline)) = pending.remove(&(context.clone(), seqno.clone())) {
+            let track = match tracks.iter().position(|t| t.name == timeline) {
+                Some(i) => &mut tracks[i],
+                None => {
+                    tracks.push(Thread {
+                        name: timeline.clone(),
+                        process_name: timeline,
+                        pid,
+                        tid: pid,
+                        ..Thread::default()
+                    });
+                    tracks.last_mut().unwrap()
+                },
+            };
+            track.markers.push(Marker {
+                name: "GPU job".to_string(),
+                start: emitted,
+                end: Some(time),
+                text: format!("context {} seqno {}", context, seqno),
+            });
+        }
+    }
+
+    tracks
+}
@@ -0,0 +1,471 @@
+// Allocation sampler preloaded by `cargo pprof heap --backend builtin`
+//
+// Every allocation adds its size to a counter of its thread, and whenever the counter passes the
+// sample rate the allocation is logged with its call stack and the number of bytes it stands for.
+// Frees are only logged for sampled pointers, which are kept in a fixed-size hash table that is
+// rehashed once the slots of freed pointers pile up. Samples that do not fit into the table are
+// not logged, as their free could not be seen, and only counted. Each process writes `<CARGO_PPROF_ALLOC_LOG>.<pid>` with its executable mappings at the
+// start and at exit, so the return addresses can be symbolized afterwards. The log is flushed
+// every second, at exit and before an `exec`, whose new image continues the log after an `x`.
+//
+// Log lines:
+//   a <pointer> <size> <bytes sampled> <time in ns> <return addresses, innermost first...>
+//   f <pointer> <time in ns>
+//   u <pointer>                      the last free of the pointer failed (a failed realloc)
+//   d <samples not logged so far>
+//   m <line of /proc/self/maps>
+//   x                                the process executed a new image
+
+#define _GNU_SOURCE
+#include <errno.h>
+#include <execinfo.h>
+#include <fcntl.h>
+#include <pthread.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <dlfcn.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/stat.h>
+#include <time.h>
+#include <unistd.h>
+
+extern void *__libc_malloc(size_t size);
+extern void *__libc_calloc(size_t count, size_t size);
+extern void *__libc_realloc(void *ptr, size_t size);
+extern void *__libc_memalign(size_t alignment, size_t size);
+extern void *__libc_valloc(size_t size);
+extern void *__libc_pvalloc(size_t size);
+extern void __libc_free(void *ptr);
+
+#define MAX_FRAMES 64
+// `sample` and the hooked allocation function
+#define SKIP_FRAMES 2
+#define TABLE_SIZE (1 << 18)
+#define TOMBSTONE ((uintptr_t)1)
+#define BUFFER_SIZE (1 << 16)
+#define FLUSH_INTERVAL_NS 1000000000ull
+
+static int enabled;
+static int fd = -1;
+static size_t rate = 1;
+static char prefix[4096];
+
+static pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+static char buffer[BUFFER_SIZE];
+static size_t buffered;
+static uint64_t flushed_at;
+// Samples that were not logged because the table was full
+static uint64_t dropped;
+// Sampled pointers that were not freed yet, with linear probing
+static uintptr_t table[TABLE_SIZE];
+static size_t tracked;
+// Slots of freed pointers, which do not end a probe
+static size_t tombstones;
+// Copy of the table while it is rehashed
+static uintptr_t rehashed[TABLE_SIZE];
+
+// Set while the sampler itself runs, the allocations of the unwinder are not sampled
+static __thread int in_hook __attribute__((tls_model("initial-exec")));
+static __thread size_t counter __attribute__((tls_model("initial-exec")));
+
+
+static uint64_t now(void) {
+    struct timespec ts;
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return (uint64_t)ts.tv_sec * 1000000000 + (uint64_t)ts.tv_nsec;
+}
+
+static void flush(uint64_t time) {
+    flushed_at = time;
+    size_t done = 0;
+    while (fd >= 0 && done < buffered) {
+        ssize_t written = write(fd, buffer + done, buffered - done);
+        if (written <= 0) {
+            break;
+        }
+        done += (size_t)written;
+    }
+    buffered = 0;
+}
+
+static void append(const char *line, size_t len) {
+    if (buffered + len > BUFFER_SIZE) {
+        flush(flushed_at);
+    }
+    memcpy(buffer + buffered, line, len);
+    buffered += len;
+}
+
+static size_t slot(uintptr_t ptr) {
+    return (size_t)((ptr >> 4) * 0x9e3779b97f4a7c15ull >> 46) % TABLE_SIZE;
+}
+
+// Put every tracked pointer back without the tombstones
+static void rehash(void) {
+    memcpy(rehashed, table, sizeof(table));
+    memset(table, 0, sizeof(table));
+    tombstones = 0;
+    for (size_t i = 0; i < TABLE_SIZE; i++) {
+        if (rehashed[i] > TOMBSTONE) {
+            size_t j = slot(rehashed[i]);
+            while (table[j] != 0) {
+                j = (j + 1) % TABLE_SIZE;
+            }
+            table[j] = rehashed[i];
+        }
+    }
+}
+
+static int insert(uintptr_t ptr) {
+    // Keep a quarter of the slots empty, so that lookups of untracked pointers end
+    if (tracked >= TABLE_SIZE / 4 * 3) {
+        return 0;
+    }
+    if (tracked + tombstones >= TABLE_SIZE / 4 * 3) {
+        rehash();
+    }
+    size_t i = slot(ptr);
+    while (table[i] != 0 && table[i] != TOMBSTONE) {
+        i = (i + 1) % TABLE_SIZE;
+    }
+    if (table[i] == TOMBSTONE) {
+        tombstones--;
+    }
+    table[i] = ptr;
+    __atomic_store_n(&tracked, tracked + 1, __ATOMIC_RELAXED);
+    return 1;
+}
+
+// Flush if the last flush is a while ago, so that a process that is killed leaves a log
+static void flush_if_due(uint64_t time) {
+    if (time - flushed_at >= FLUSH_INTERVAL_NS) {
+        flush(time);
+    }
+}
+
+static int forget(uintptr_t ptr) {
+    for (size_t i = slot(ptr); table[i] != 0; i = (i + 1) % TABLE_SIZE) {
+        if (table[i] == ptr) {
+            table[i] = TOMBSTONE;
+            tombstones++;
+            __atomic_store_n(&tracked, tracked - 1, __ATOMIC_RELAXED);
+            return 1;
+        }
+    }
+    return 0;
+}
+
+__attribute__((noinline)) static void sample(void *ptr, size_t size) {
+    counter += size;
+    if (counter < rate) {
+        return;
+    }
+    size_t bytes = counter / rate * rate;
+    counter %= rate;
+
+    void *frames[MAX_FRAMES];
+    int count = backtrace(frames, MAX_FRAMES);
+    uint64_t time = now();
+    char line[32 * MAX_FRAMES];
+    int len = snprintf(line, sizeof(line), "a %lx %zu %zu %llu", (unsigned long)ptr, size, bytes, (unsigned long long)time);
+    for (int i = SKIP_FRAMES; i < count; i++) {
+        len += snprintf(line + len, sizeof(line) - (size_t)len, " %lx", (unsigned long)frames[i]);
+    }
+    line[len++] = '\n';
+
+    pthread_mutex_lock(&lock);
+    if (insert((uintptr_t)ptr)) {
+        append(line, (size_t)len);
+    } else {
+        // Without its free the allocation would look leaked
+        dropped++;
+    }
+    flush_if_due(time);
+    pthread_mutex_unlock(&lock);
+}
+
+// Returns whether the pointer was sampled
+__attribute__((noinline)) static int release(void *ptr) {
+    pthread_mutex_lock(&lock);
+    int found = forget((uintptr_t)ptr);
+    if (found) {
+        uint64_t time = now();
+        char line[64];
+        int len = snprintf(line, sizeof(line), "f %lx %llu\n", (unsigned long)ptr, (unsigned long long)time);
+        append(line, (size_t)len);
+        flush_if_due(time);
+    }
+    pthread_mutex_unlock(&lock);
+    return found;
+}
+
+// Track a pointer again whose free was logged, but did not happen
+static void unrelease(void *ptr) {
+    pthread_mutex_lock(&lock);
+    char line[32];
+    int len = snprintf(line, sizeof(line), "u %lx\n", (unsigned long)ptr);
+    if (insert((uintptr_t)ptr)) {
+        append(line, (size_t)len);
+    } else {
+        dropped++;
+    }
+    pthread_mutex_unlock(&lock);
+}
+
+static int hooked(void) {
+    return enabled && !in_hook;
+}
+
+// Returns whether the pointer was sampled
+static int before_free(void *ptr) {
+    int found = 0;
+    if (ptr != NULL && hooked() && __atomic_load_n(&tracked, __ATOMIC_RELAXED) > 0) {
+        in_hook = 1;
+        found = release(ptr);
+        in_hook = 0;
+    }
+    return found;
+}
+
+
+void *malloc(size_t size) {
+    void *ptr = __libc_malloc(size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *calloc(size_t count, size_t size) {
+    void *ptr = __libc_calloc(count, size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, count * size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *realloc(void *old, size_t size) {
+    // Forget the old pointer first, another thread may get it as soon as it is released
+    int sampled = before_free(old);
+    void *ptr = __libc_realloc(old, size);
+    // A failed realloc leaves the old allocation alone, `realloc(old, 0)` frees it
+    if (ptr == NULL && size != 0 && sampled && hooked()) {
+        in_hook = 1;
+        unrelease(old);
+        in_hook = 0;
+    }
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *reallocarray(void *old, size_t count, size_t size) {
+    size_t total;
+    if (__builtin_mul_overflow(count, size, &total)) {
+        errno = ENOMEM;
+        return NULL;
+    }
+    return realloc(old, total);
+}
+
+void *valloc(size_t size) {
+    void *ptr = __libc_valloc(size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *pvalloc(size_t size) {
+    void *ptr = __libc_pvalloc(size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *memalign(size_t alignment, size_t size) {
+    void *ptr = __libc_memalign(alignment, size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+void *aligned_alloc(size_t alignment, size_t size) {
+    void *ptr = __libc_memalign(alignment, size);
+    if (ptr != NULL && hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    return ptr;
+}
+
+int posix_memalign(void **out, size_t alignment, size_t size) {
+    if (alignment % sizeof(void *) != 0 || (alignment & (alignment - 1)) != 0) {
+        return EINVAL;
+    }
+    void *ptr = __libc_memalign(alignment, size);
+    if (ptr == NULL) {
+        return ENOMEM;
+    }
+    if (hooked()) {
+        in_hook = 1;
+        sample(ptr, size);
+        in_hook = 0;
+    }
+    *out = ptr;
+    return 0;
+}
+
+void free(void *ptr) {
+    before_free(ptr);
+    __libc_free(ptr);
+}
+
+
+static void write_maps(void);
+
+static void open_log(void) {
+    char path[sizeof(prefix) + 16];
+    snprintf(path, sizeof(path), "%s.%d", prefix, (int)getpid());
+    fd = open(path, O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC, 0644);
+    // The process had a log before it executed this image
+    struct stat st;
+    if (fd >= 0 && fstat(fd, &st) == 0 && st.st_size > 0) {
+        append("x\n", 2);
+    }
+    write_maps();
+}
+
+static void before_fork(void) {
+    pthread_mutex_lock(&lock);
+}
+
+static void after_fork_parent(void) {
+    pthread_mutex_unlock(&lock);
+}
+
+static void after_fork_child(void) {
+    pthread_mutex_init(&lock, NULL);
+    // The samples of the parent are written by the parent
+    buffered = 0;
+    memset(table, 0, sizeof(table));
+    tracked = 0;
+    tombstones = 0;
+    dropped = 0;
+    close(fd);
+    open_log();
+}
+
+// Append the mappings of the process, every line prefixed with `m `
+static void write_maps(void) {
+    int maps = open("/proc/self/maps", O_RDONLY | O_CLOEXEC);
+    if (maps < 0) {
+        return;
+    }
+    char chunk[4096];
+    int line_start = 1;
+    ssize_t len;
+    while ((len = read(maps, chunk, sizeof(chunk))) > 0) {
+        for (ssize_t i = 0; i < len; i++) {
+            if (line_start) {
+                append("m ", 2);
+            }
+            append(&chunk[i], 1);
+            line_start = chunk[i] == '\n';
+        }
+    }
+    close(maps);
+}
+
+// Write the maps and everything buffered, the log has to be complete before the image is gone
+static void finish_log(void) {
+    pthread_mutex_lock(&lock);
+    if (dropped > 0) {
+        char line[32];
+        int len = snprintf(line, sizeof(line), "d %llu\n", (unsigned long long)dropped);
+        append(line, (size_t)len);
+    }
+    write_maps();
+    flush(now());
+    pthread_mutex_unlock(&lock);
+}
+
+__attribute__((constructor)) static void start(void) {
+    const char *path = getenv("CARGO_PPROF_ALLOC_LOG");
+    if (path == NULL) {
+        return;
+    }
+    strncpy(prefix, path, sizeof(prefix) - 1);
+    const char *sample_rate = getenv("CARGO_PPROF_ALLOC_RATE");
+    if (sample_rate != NULL && strtoull(sample_rate, NULL, 10) > 0) {
+        rate = (size_t)strtoull(sample_rate, NULL, 10);
+    }
+
+    // The unwinder is loaded on first use, which allocates
+    in_hook = 1;
+    void *frames[1];
+    backtrace(frames, 1);
+    in_hook = 0;
+
+    flushed_at = now();
+    open_log();
+    pthread_atfork(before_fork, after_fork_parent, after_fork_child);
+    enabled = fd >= 0;
+}
+
+__attribute__((destructor)) static void finish(void) {
+    if (!enabled) {
+        return;
+    }
+    in_hook = 1;
+    finish_log();
+    enabled = 0;
+}
+
+
+// Exits that skip the destructors and execs, which replace the image. The variadic `execl`
+// functions are not wrapped, their log ends at the last flush.
+
+void _exit(int status) {
+    finish();
+    ((void (*)(int))dlsym(RTLD_NEXT, "_exit"))(status);
+    __builtin_unreachable();
+}
+
+void _Exit(int status) {
+    finish();
+    ((void (*)(int))dlsym(RTLD_NEXT, "_Exit"))(status);
+    __builtin_unreachable();
+}
+
+#define WRAP_EXEC(name, params, args) \
+    int name params { \
+        if (enabled && !in_hook) { \
+            in_hook = 1; \
+            finish_log(); \
+            in_hook = 0; \
+        } \
+        return ((int (*) params)dlsym(RTLD_NEXT, #name)) args; \
+    }
+
+WRAP_EXEC(execve, (const char *path, char *const argv[], char *const envp[]), (path, argv, envp))
+WRAP_EXEC(execv, (const char *path, char *const argv[]), (path, argv))
+WRAP_EXEC(execvp, (const char *file, char *const argv[]), (file, argv))
+WRAP_EXEC(execvpe, (const char *file, char *const argv[], char *const envp[]), (file, argv, envp))
@@ -0,0 +1,354 @@
+//! Allocation sampling with a preload library that is compiled on first use
+//!
+//! Unlike the other backends this needs nothing but a C compiler. The library (`alloc-shim.c`)
+//! logs one allocation per `--sample-rate` bytes with its return addresses, and the frees of the
+//! sampled pointers. The addresses are symbolized afterwards with the executable mappings each
+//! process logs at the start and at exit, or before it executes another image.
+
+use std::{collections::{BTreeSet, HashMap}, env, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::inline;
+use crate::maps::{self, Mapping};
+use crate::profile::{Frame, Profile, Sample};
+use super::HeapRecording;
+use crate::{print_step, resolve, resolve_status};
+
+const SOURCE: &str = include_str!("alloc-shim.c");
+const SOURCE_NAME: &str = "alloc-shim.c";
+const LIBRARY_NAME: &str = "libcargo-pprof-alloc.so";
+/// Every process writes `<prefix>.<pid>`
+const LOG_PREFIX: &str = "alloc.log";
+/// Functions of the allocator API on the way to `malloc`, dropped from the inner end of the stacks
+const ALLOCATOR_PREFIXES: &[&str] = &[
+    "__rust_", "__rdl_", "__rg_", "alloc::alloc::", "<alloc::alloc::Global",
+    "std::alloc::", "<std::alloc::System as ", "std::sys::alloc::", "std::sys::pal::unix::alloc::",
+];
+
+/// Sampled allocation
+struct Allocation {
+    size: u64,
+    /// Bytes allocated by the thread since the previous sample, which this sample stands for
+    bytes: u64,
+    /// Return addresses, innermost first
+    stack: Vec<u64>,
+    /// Monotonic clock in nanoseconds at the allocation and at its free
+    time: u64,
+    freed: Option<u64>,
+}
+
+/// Log of one process image, a process that executes another one writes a log for each
+struct Log {
+    allocations: Vec<Allocation>,
+    mappings: Vec<Mapping>,
+    /// Samples the library could not track and left out
+    dropped: u64,
+}
+
+
+/// Run the application with the sampler preloaded and symbolize the sampled stacks
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool, sample_rate: u64) -> HeapRecording {
+    let library = build_library(dir);
+    for old in log_paths(dir) {
+        let _ = fs::remove_file(old);
+    }
+
+    print_step("Running program with the allocation sampler");
+    let status = app::run(app::command(executable)
+        .args(app_args)
+        .env("LD_PRELOAD", &library)
+        .env("CARGO_PPROF_ALLOC_LOG", dir.join(LOG_PREFIX))
+        .env("CARGO_PPROF_ALLOC_RATE", sample_rate.to_string()));
+    app::check_exit(status, ignore_exit);
+    let logs: Vec<Log> = log_paths(dir).iter()
+        .flat_map(|path| parse(&resolve(fs::read_to_string(path))))
+        .collect();
+    if logs.is_empty() {
+        resolve::<(), _>(Err("The allocation sampler wrote no log (is the binary dynamically linked against glibc?)"));
+    }
+    eprintln!("Allocation logs: {}", dir.join(format!("{}.*", LOG_PREFIX)).to_string_lossy());
+
+    print_step("Symbolizing allocation stacks");
+    let frames = symbolize(&logs);
+    let peak = peak(&logs);
+    let mut stacks: HashMap<Vec<Frame>, [u64; 4]> = HashMap::new();
+    for (i, log) in logs.iter().enumerate() {
+        for allocation in &log.allocations {
+            let stack: Vec<Frame> = allocation.stack.iter()
+                .flat_map(|address| &frames[&(i, *address)])
+                .skip_while(|f| is_allocator(&f.function))
+                .cloned()
+                .collect();
+            let values = stacks.entry(stack).or_default();
+            values[0] += allocation.bytes;
+            values[1] += (allocation.bytes / allocation.size.max(1)).max(1);
+            if allocation.freed.is_none() {
+                values[2] += allocation.bytes;
+            }
+            if peak.is_some_and(|(time, _)| allocation.time <= time && allocation.freed.is_none_or(|freed| freed > time)) {
+                values[3] += allocation.bytes;
+            }
+        }
+    }
+    let profile = Profile {
+        value_names: ["bytes allocated", "allocations", "bytes leaked", "bytes at peak"].map(str::to_string).to_vec(),
+        samples: stacks.into_iter().map(|(frames, values)| Sample { frames, values: values.to_vec() }).collect(),
+    };
+
+    let allocations = logs.iter().flat_map(|l| &l.allocations);
+    let sampled = allocations.clone().count();
+    let freed = allocations.clone().filter(|a| a.freed.is_some()).count();
+    let bytes: u64 = allocations.map(|a| a.bytes).sum();
+    if sampled == 0 {
+        eprintln!("{}", format!("Warning: No allocation was sampled, lower --sample-rate (currently {} bytes)", sample_rate).yellow());
+    }
+    let dropped: u64 = logs.iter().map(|l| l.dropped).sum();
+    if dropped > 0 {
+        eprintln!("{}", format!("Warning: {} sampled allocations were left out as too many were live at once, raise --sample-rate (currently {} bytes)",
+            dropped, sample_rate).yellow());
+    }
+    let mut summary = vec![
+        format!("sampled allocations: {} (one per {} bytes allocated)", sampled, sample_rate),
+        format!("sampled allocations freed: {}", freed),
+        format!("estimated bytes allocated: {}", bytes),
+    ];
+    if let Some((time, bytes)) = peak {
+        let start = logs.iter().flat_map(|l| &l.allocations).map(|a| a.time).min().unwrap_or(time);
+        summary.push(format!("estimated peak heap memory consumption: {} bytes at {:.1}ms", bytes, (time - start) as f64 / 1e6));
+    }
+    HeapRecording { profile, summary, timeline: None }
+}
+
+/// Compile the library into the output directory, unless it was built from this source before
+fn build_library(dir: &Path) -> PathBuf {
+    let source_path = dir.join(SOURCE_NAME);
+    let library = dir.join(LIBRARY_NAME);
+    if library.exists() && fs::read_to_string(&source_path).is_ok_and(|s| s == SOURCE) {
+        return library;
+    }
+
+    print_step("Building allocation sampler");
+    resolve(fs::write(&source_path, SOURCE));
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let mut command = process::Command::new(&compiler);
+    command.args(["-shared", "-fPIC", "-O2", "-o"]).arg(&library).arg(&source_path).args(["-lpthread", "-ldl"]);
+    crate::log_command(&command);
+    let status = match command.status() {
+        Ok(status) => status,
+        Err(e) => resolve(Err(format!("Could not run {} ({}), the allocation sampler is built with a C compiler (set CC to use another one)", compiler, e))),
+    };
+    if !status.success() {
+        // Build again next time
+        let _ = fs::remove_file(&source_path);
+    }
+    resolve_status(status);
+    library
+}
+
+fn log_paths(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let mut paths: Vec<PathBuf> = entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| p.file_name().unwrap_or_default().to_string_lossy().strip_prefix(LOG_PREFIX)
+            .and_then(|pid| pid.strip_prefix('.'))
+            .is_some_and(|pid| pid.parse::<u32>().is_ok()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Logs of the images of one process, in the order they ran
+fn parse(content: &str) -> Vec<Log> {
+    let mut logs = Vec::new();
+    let mut log = Log { allocations: Vec::new(), mappings: Vec::new(), dropped: 0 };
+    // Sampled allocation at each pointer that was not freed yet, and the last one freed
+    let mut live: HashMap<u64, usize> = HashMap::new();
+    let mut freed: HashMap<u64, usize> = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split(' ');
+        match fields.next() {
+            Some("a") => {
+                let fields: Vec<&str> = fields.collect();
+                let [pointer, size, bytes, time, stack @ ..] = fields.as_slice() else { continue };
+                let (Ok(pointer), Ok(size), Ok(bytes), Ok(time)) = (u64::from_str_radix(pointer, 16), size.parse(), bytes.parse(), time.parse()) else { continue };
+                live.insert(pointer, log.allocations.len());
+                log.allocations.push(Allocation {
+                    size,
+                    bytes,
+                    stack: stack.iter().filter_map(|a| u64::from_str_radix(a, 16).ok()).collect(),
+                    time,
+                    freed: None,
+                });
+            },
+            Some("f") => {
+                let (Some(Ok(pointer)), Some(Ok(time))) = (fields.next().map(|f| u64::from_str_radix(f, 16)), fields.next().map(str::parse)) else { continue };
+                if let Some(i) = live.remove(&pointer) {
+                    log.allocations[i].freed = Some(time);
+                    freed.insert(pointer, i);
+                }
+            },
+            Some("u") => {
+                let Some(Ok(pointer)) = fields.next().map(|f| u64::from_str_radix(f, 16)) else { continue };
+                if let Some(i) = freed.remove(&pointer) {
+                    log.allocations[i].freed = None;
+                    live.insert(pointer, i);
+                }
+            },
+            Some("d") => log.dropped = fields.next().and_then(|f| f.parse().ok()).unwrap_or(log.dropped),
+            Some("m") => log.mappings.extend(maps::parse_mapping(&line[2..])),
+            Some("x") => {
+                logs.push(log);
+                log = Log { allocations: Vec::new(), mappings: Vec::new(), dropped: 0 };
+                live.clear();
+                freed.clear();
+            },
+            _ => {},
+        }
+    }
+    logs.push(log);
+    logs
+}
+
+/// Time and sampled bytes of the highest heap usage, replaying the allocations and frees of all processes
+fn peak(logs: &[Log]) -> Option<(u64, u64)> {
+    let mut changes: Vec<(u64, i64)> = Vec::new();
+    for allocation in logs.iter().flat_map(|l| &l.allocations) {
+        changes.push((allocation.time, allocation.bytes as i64));
+        if let Some(freed) = allocation.freed {
+            changes.push((freed, -(allocation.bytes as i64)));
+        }
+    }
+    // Frees first, a pointer may be freed and allocated again within a clock tick
+    changes.sort_by_key(|(time, change)| (*time, *change));
+    let mut live = 0;
+    let mut peak = None;
+    for (time, change) in changes {
+        live += change;
+        if peak.is_none_or(|(_, bytes)| live as u64 > bytes) {
+            peak = Some((time, live as u64));
+        }
+    }
+    peak
+}
+
+fn is_allocator(function: &str) -> bool {
+    // Without the crate disambiguators, like the `[1c3fe0a4b5a3e088]` of `alloc[1c3fe0a4b5a3e088]::alloc::alloc`
+    let mut path = String::with_capacity(function.len());
+    let mut depth = 0;
+    for c in function.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth > 0 => depth -= 1,
+            _ if depth == 0 => path.push(c),
+            _ => {},
+        }
+    }
+    ALLOCATOR_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+/// Frames of every return address by log, the inlined functions included
+fn symbolize(logs: &[Log]) -> HashMap<(usize, u64), Vec<Frame>> {
+    // Module and address in its file of every return address
+    let mut lookups: HashMap<(usize, u64), Option<(&str, u64)>> = HashMap::new();
+    let mut file_addresses: HashMap<(&str, u64), Option<u64>> = HashMap::new();
+    for (i, log) in logs.iter().enumerate() {
+        for address in log.allocations.iter().flat_map(|a| &a.stack) {
+            lookups.entry((i, *address)).or_insert_with(|| {
+                let mapping = log.mappings.iter().find(|m| (m.start..m.end).contains(address))?;
+                let offset = address - mapping.start + mapping.offset;
+                let file_address = (*file_addresses.entry((&mapping.path, offset))
+                    .or_insert_with(|| maps::file_address(Path::new(&mapping.path), offset)))?;
+                Some((&mapping.path, inline::lookup_offset(file_address, false)))
+            });
+        }
+    }
+
+    let modules: BTreeSet<&str> = lookups.values().flatten().map(|(module, _)| *module).collect();
+    let mut chains: HashMap<(&str, u64), Vec<String>> = HashMap::new();
+    for module in modules {
+        let addresses: BTreeSet<u64> = lookups.values().flatten().filter(|(m, _)| *m == module).map(|(_, a)| *a).collect();
+        match inline::inline_chains(module, addresses.into_iter()) {
+            Ok(found) => chains.extend(found.into_iter().map(|(address, chain)| ((module, address), chain))),
+            Err(e) => eprintln!("{}", format!("Warning: {}", e).yellow()),
+        }
+    }
+
+    lookups.into_iter()
+        .map(|((i, address), lookup)| {
+            let frames = match lookup.and_then(|key| Some((key.0, chains.get(&key).filter(|c| !c.is_empty())?))) {
+                Some((module, chain)) => chain.iter()
+                    .map(|function| Frame { function: function.clone(), module: module.to_string() })
+                    .collect(),
+                None => vec![Frame {
+                    function: format!("{:#x}", address),
+                    module: lookup.map(|(module, _)| module.to_string()).unwrap_or_default(),
+                }],
+            };
+            ((i, address), frames)
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+m 55d0c0000000-55d0c0010000 r-xp 00001000 08:01 1234 /tmp/app
+a 1000 64 4096 10 55d0c0001234 55d0c0002345
+a 2000 32 8192 20 55d0c0001234
+f 1000 30
+f 2000 40
+u 2000
+d 3
+x
+a 3000 16 1024 50 55d0c0001234
+";
+
+    #[test]
+    fn logs_of_each_image() {
+        let logs = parse(LOG);
+        assert_eq!(logs.len(), 2);
+        let allocations = &logs[0].allocations;
+        assert_eq!(allocations.len(), 2);
+        assert_eq!((allocations[0].size, allocations[0].bytes, allocations[0].time), (64, 4096, 10));
+        assert_eq!(allocations[0].stack, [0x55d0c0001234, 0x55d0c0002345]);
+        assert_eq!(allocations[0].freed, Some(30));
+        // The free of the failed realloc was undone
+        assert_eq!(allocations[1].freed, None);
+        assert_eq!(logs[0].dropped, 3);
+        assert_eq!(logs[0].mappings.len(), 1);
+        assert_eq!(logs[0].mappings[0].start, 0x55d0c0000000);
+
+        assert_eq!(logs[1].allocations.len(), 1);
+        assert!(logs[1].mappings.is_empty());
+        assert_eq!(logs[1].dropped, 0);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let logs = parse("a zz 1 2 3\na 1000 1\nf 1000\nq\n\na 1000 8 8 1\n");
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].allocations.len(), 1);
+        assert_eq!(logs[0].allocations[0].freed, None);
+    }
+
+    #[test]
+    fn peak_of_all_processes() {
+        let logs = parse(LOG);
+        assert_eq!(peak(&logs), Some((20, 12288)));
+        assert_eq!(peak(&parse("")), None);
+    }
+
+    #[test]
+    fn allocator_frames() {
+        assert!(is_allocator("alloc[1c3fe0a4b5a3e088]::alloc::alloc"));
+        assert!(is_allocator("__rust_alloc"));
+        assert!(is_allocator("<std::alloc::System as core::alloc::global::GlobalAlloc>::alloc"));
+        assert!(!is_allocator("<alloc::vec::Vec<u8>>::push"));
+        assert!(!is_allocator("app::build"));
+    }
+}
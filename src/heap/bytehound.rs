@@ -0,0 +1,39 @@
+use std::{env, path::Path, process};
+
+use crate::app;
+use super::{HeapRecording, heaptrack};
+use crate::{print_step, resolve, resolve_status};
+
+/// Preload library used when `BYTEHOUND_LIB` is not set, resolved through the library search path
+const DEFAULT_LIB: &str = "libbytehound.so";
+
+
+/// Run the application with bytehound preloaded and convert the recording via its heaptrack export
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let lib = env::var("BYTEHOUND_LIB").unwrap_or_else(|_| DEFAULT_LIB.to_string());
+    let data_path = dir.join("bytehound.dat");
+    let export_path = dir.join("bytehound.heaptrack");
+
+    print_step("Running program with bytehound");
+    let status = app::run(app::command(executable)
+        .args(app_args)
+        .env("LD_PRELOAD", &lib)
+        .env("MEMORY_PROFILER_OUTPUT", &data_path)
+        .env("MEMORY_PROFILER_LOG", "warn"));
+    app::check_exit(status, ignore_exit);
+    if !data_path.exists() {
+        resolve::<(), _>(Err(format!("Could not find bytehound output (is {} installed? set BYTEHOUND_LIB to its path)", lib)));
+    }
+    eprintln!("Bytehound output: {}", data_path.to_string_lossy());
+
+    print_step("Exporting bytehound data");
+    let status = resolve(process::Command::new("bytehound")
+        .arg("export-heaptrack")
+        .arg("--output")
+        .arg(&export_path)
+        .arg(&data_path)
+        .status());
+    resolve_status(status);
+
+    heaptrack::convert(&export_path, dir)
+}
@@ -0,0 +1,166 @@
+use std::{fs, path::Path};
+
+use colored::Colorize;
+use serde::Deserialize;
+
+use crate::app;
+use crate::profile::{Profile, Sample};
+use super::HeapRecording;
+use crate::{print_step, resolve};
+
+/// Output file written by the `dhat` crate into the working directory
+const DHAT_RS_OUTPUT: &str = "dhat-heap.json";
+
+#[derive(Deserialize, Debug)]
+struct DhatFile {
+    /// Whether block lifetimes were tracked (false in ad-hoc mode)
+    #[serde(default)]
+    bklt: bool,
+    pps: Vec<ProgramPoint>,
+    ftbl: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProgramPoint {
+    /// Total bytes
+    tb: u64,
+    /// Total blocks
+    tbk: u64,
+    /// Bytes live at the global heap peak
+    #[serde(default)]
+    gb: u64,
+    /// Bytes live at exit
+    #[serde(default)]
+    eb: u64,
+    /// Frame indices into the frame table, innermost first
+    fs: Vec<usize>,
+}
+
+
+/// Run the application under `valgrind --tool=dhat`
+pub fn record_valgrind(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let out_path = dir.join("dhat.out.json");
+
+    print_step("Running program with dhat");
+    let status = app::run(app::command("valgrind")
+        .arg("--tool=dhat")
+        .arg(format!("--dhat-out-file={}", out_path.to_string_lossy()))
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+
+    load(&out_path)
+}
+
+/// Run an application that was built with the `dhat` crate's heap profiler enabled
+pub fn record_crate(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let out_path = dir.join(DHAT_RS_OUTPUT);
+
+    print_step("Running program with the dhat heap profiler");
+    let status = app::run(app::command(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    if fs::rename(app::cwd().join(DHAT_RS_OUTPUT), &out_path).is_err() {
+        resolve::<(), _>(Err(format!("Could not find {} (is the dhat profiler enabled by the \"dhat-heap\" feature?)", DHAT_RS_OUTPUT)));
+    }
+
+    load(&out_path)
+}
+
+fn load(path: &Path) -> HeapRecording {
+    eprintln!("DHAT output: {}", path.to_string_lossy());
+    eprintln!("This file can also be viewed using the Firefox Profiler ({})", "https://profiler.firefox.com".bright_blue());
+
+    let content = resolve(fs::read_to_string(path));
+    let file: DhatFile = resolve(serde_json::from_str(&content));
+    let summary = summarize(&file);
+    HeapRecording { profile: convert(file), summary, timeline: None }
+}
+
+fn summarize(file: &DhatFile) -> Vec<String> {
+    let total_bytes: u64 = file.pps.iter().map(|p| p.tb).sum();
+    let total_blocks: u64 = file.pps.iter().map(|p| p.tbk).sum();
+    let mut summary = vec![
+        format!("total allocated: {} bytes in {} blocks", total_bytes, total_blocks),
+    ];
+    if file.bklt {
+        summary.push(format!("bytes live at peak: {}", file.pps.iter().map(|p| p.gb).sum::<u64>()));
+        summary.push(format!("bytes live at exit: {}", file.pps.iter().map(|p| p.eb).sum::<u64>()));
+    }
+    summary
+}
+
+fn convert(file: DhatFile) -> Profile {
+    let mut value_names = vec!["bytes allocated".to_string(), "allocations".to_string()];
+    if file.bklt {
+        value_names.push("bytes at peak".to_string());
+        value_names.push("bytes at exit".to_string());
+    }
+
+    let samples = file.pps.iter()
+        .map(|pp| {
+            let mut values = vec![pp.tb, pp.tbk];
+            if file.bklt {
+                values.extend([pp.gb, pp.eb]);
+            }
+            Sample {
+                frames: pp.fs.iter()
+                    .filter_map(|i| file.ftbl.get(*i))
+                    .map(|f| super::parse_valgrind_frame(f))
+                    .collect(),
+                values,
+            }
+        })
+        .collect();
+
+    Profile { value_names, samples }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = r#"{
+        "dhatFileVersion": 2,
+        "mode": "rust-heap",
+        "bklt": true,
+        "pps": [
+            { "tb": 4096, "tbk": 2, "gb": 4096, "eb": 0, "fs": [1, 2] },
+            { "tb": 100, "tbk": 10, "gb": 0, "eb": 100, "fs": [3, 9] }
+        ],
+        "ftbl": [
+            "[root]",
+            "0x4005E4: app::build (src/main.rs:10:5)",
+            "0x4005F0: main (in /tmp/app)",
+            "0x400600: app::leak (src/main.rs:20:1)"
+        ]
+    }"#;
+
+    #[test]
+    fn program_points() {
+        let file: DhatFile = serde_json::from_str(OUTPUT).unwrap();
+        assert_eq!(summarize(&file), [
+            "total allocated: 4196 bytes in 12 blocks",
+            "bytes live at peak: 4096",
+            "bytes live at exit: 100",
+        ]);
+        let profile = convert(file);
+        assert_eq!(profile.value_names, ["bytes allocated", "allocations", "bytes at peak", "bytes at exit"]);
+        assert_eq!(profile.samples[0].values, [4096, 2, 4096, 0]);
+        let functions: Vec<&str> = profile.samples[0].frames.iter().map(|f| f.function.as_str()).collect();
+        assert_eq!(functions, ["app::build", "main"]);
+        assert_eq!(profile.samples[0].frames[1].module, "/tmp/app");
+        // Frame indices outside the table are left out
+        assert_eq!(profile.samples[1].frames.len(), 1);
+    }
+
+    #[test]
+    fn ad_hoc_mode_without_lifetimes() {
+        let file: DhatFile = serde_json::from_str(r#"{ "pps": [{ "tb": 1, "tbk": 1, "fs": [] }], "ftbl": [] }"#).unwrap();
+        assert_eq!(summarize(&file).len(), 1);
+        let profile = convert(file);
+        assert_eq!(profile.value_names, ["bytes allocated", "allocations"]);
+        assert_eq!(profile.samples[0].values, [1, 1]);
+    }
+}
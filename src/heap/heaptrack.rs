@@ -0,0 +1,122 @@
+use std::{fs, path::{Path, PathBuf}, process};
+
+use crate::app;
+use super::HeapRecording;
+use crate::profile;
+use crate::{print_step, resolve, resolve_status};
+
+/// Cost types exported by `heaptrack_print` and the value names they are stored under
+const COST_TYPES: &[(&str, &str)] = &[
+    ("allocated", "bytes allocated"),
+    ("allocations", "allocations"),
+    ("leaked", "bytes leaked"),
+    ("peak", "bytes at peak"),
+];
+
+/// Lines of the `heaptrack_print` summary that are shown to the user
+const SUMMARY_PREFIXES: &[&str] = &[
+    "calls to allocation functions",
+    "temporary memory allocations",
+    "peak heap memory consumption",
+    "peak RSS",
+    "total memory leaked",
+];
+
+
+/// Run the application under heaptrack and collect its allocation stacks
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let out_prefix = dir.join("heaptrack");
+
+    print_step("Running program with heaptrack");
+    let status = app::run(app::command("heaptrack")
+        .arg("--output")
+        .arg(&out_prefix)
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    let data_path = resolve(find_output(dir));
+    eprintln!("Heaptrack output: {}", data_path.to_string_lossy());
+
+    convert(&data_path, dir)
+}
+
+/// Extract allocation stacks and totals from a heaptrack data file
+pub fn convert(data_path: &Path, dir: &Path) -> HeapRecording {
+    print_step("Converting allocation data");
+    let mut summary = Vec::new();
+    let mut folded = Vec::new();
+    for (cost_type, name) in COST_TYPES {
+        let folded_path = dir.join(format!("heaptrack.{}.tmp", cost_type));
+        let output = resolve(process::Command::new("heaptrack_print")
+            .arg("--print-flamegraph")
+            .arg(&folded_path)
+            .arg("--flamegraph-cost-type")
+            .arg(cost_type)
+            .arg(data_path)
+            .stderr(process::Stdio::inherit())
+            .output());
+        resolve_status(output.status);
+
+        if summary.is_empty() {
+            summary = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|l| SUMMARY_PREFIXES.iter().any(|p| l.starts_with(p)))
+                .map(str::to_string)
+                .collect();
+        }
+        folded.push((*name, resolve(fs::read_to_string(&folded_path))));
+        let _ = fs::remove_file(&folded_path);
+    }
+
+    let inputs: Vec<(&str, &str)> = folded.iter()
+        .map(|(name, content)| (*name, content.as_str()))
+        .collect();
+    HeapRecording { profile: profile::parse_folded(&inputs), summary, timeline: None }
+}
+
+/// Heaptrack appends a compression suffix to the output name, so look for the newest match
+fn find_output(dir: &Path) -> Result<PathBuf, String> {
+    let entries = fs::read_dir(dir).map_err(|e| e.to_string())?;
+    entries.flatten()
+        .filter(|e| {
+            let name = e.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("heaptrack.") && !name.ends_with(".tmp")
+        })
+        .max_by_key(|e| e.metadata().and_then(|m| m.modified()).ok())
+        .map(|e| e.path())
+        .ok_or_else(|| "Could not find heaptrack output".to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_with_compression_suffix() {
+        let dir = std::env::temp_dir().join(format!("cargo-pprof-heaptrack-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert!(find_output(&dir).is_err());
+
+        fs::write(dir.join("heaptrack.allocated.tmp"), "").unwrap();
+        fs::write(dir.join("other.zst"), "").unwrap();
+        assert!(find_output(&dir).is_err());
+        fs::write(dir.join("heaptrack.app.1234.zst"), "").unwrap();
+        assert_eq!(find_output(&dir).unwrap(), dir.join("heaptrack.app.1234.zst"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cost_types_as_values() {
+        let inputs: Vec<(&str, &str)> = COST_TYPES.iter().zip(["main;build 300\nmain;leak 20\n", "main;build 3\nmain;leak 1\n", "main;leak 20\n", "main;build 300\n"])
+            .map(|((_, name), content)| (*name, content))
+            .collect();
+        let profile = profile::parse_folded(&inputs);
+        assert_eq!(profile.value_names, ["bytes allocated", "allocations", "bytes leaked", "bytes at peak"]);
+        let leak = profile.samples.iter().find(|s| s.frames[0].function == "leak").unwrap();
+        assert_eq!(leak.values, [20, 1, 20, 0]);
+        assert_eq!(leak.frames[1].function, "main");
+    }
+}
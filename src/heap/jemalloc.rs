@@ -0,0 +1,77 @@
+use std::{fs, path::{Path, PathBuf}, process};
+
+use crate::app;
+use super::HeapRecording;
+use crate::profile;
+use crate::{print_step, resolve, resolve_status};
+
+/// Dump a profile every 2^30 bytes of allocation and once more at exit
+const MALLOC_CONF: &str = "prof:true,prof_accum:true,prof_final:true,lg_prof_interval:30";
+
+/// jeprof reports and the value names they are stored under
+const REPORTS: &[(&str, &str)] = &[
+    ("--inuse_space", "bytes in use"),
+    ("--alloc_space", "bytes allocated"),
+];
+
+
+/// Run a jemalloc-based application with heap profiling enabled and symbolize the last dump
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let prefix = dir.join("jeprof");
+    for old in dumps(dir) {
+        let _ = fs::remove_file(old);
+    }
+
+    print_step("Running program with jemalloc heap profiling");
+    let conf = format!("{},prof_prefix:{}", MALLOC_CONF, prefix.to_string_lossy());
+    let status = app::run(app::command(executable)
+        .args(app_args)
+        .env("MALLOC_CONF", &conf)
+        // tikv-jemallocator prefixes its symbols and configuration by default
+        .env("_RJEM_MALLOC_CONF", &conf));
+    app::check_exit(status, ignore_exit);
+
+    let mut dumps = dumps(dir);
+    dumps.sort_by_key(|p| p.metadata().and_then(|m| m.modified()).ok());
+    let last = match dumps.last() {
+        Some(last) => last,
+        None => resolve(Err("No heap dumps found (does the binary use jemalloc with the \"profiling\" feature?)")),
+    };
+    eprintln!("Heap dumps collected: {}", dumps.len());
+    eprintln!("Final heap dump: {}", last.to_string_lossy());
+
+    print_step("Symbolizing heap dump with jeprof");
+    let mut folded = Vec::new();
+    for (report, name) in REPORTS {
+        let output = resolve(process::Command::new("jeprof")
+            .arg("--collapsed")
+            .arg("--show_bytes")
+            .arg(report)
+            .arg(executable)
+            .arg(last)
+            .stderr(process::Stdio::inherit())
+            .output());
+        resolve_status(output.status);
+        folded.push((*name, String::from_utf8_lossy(&output.stdout).to_string()));
+    }
+
+    let inputs: Vec<(&str, &str)> = folded.iter()
+        .map(|(name, content)| (*name, content.as_str()))
+        .collect();
+    HeapRecording {
+        profile: profile::parse_folded(&inputs),
+        summary: vec![format!("heap dumps collected: {}", dumps.len())],
+        timeline: None,
+    }
+}
+
+fn dumps(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            let name = p.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("jeprof.") && name.ends_with(".heap")
+        })
+        .collect()
+}
@@ -0,0 +1,234 @@
+use std::{fs, path::Path};
+
+use crate::app;
+use super::HeapRecording;
+use crate::gecko::{Counter, GeckoProfile, Marker, Thread};
+use crate::profile::{Frame, Profile, Sample};
+use crate::{print_step, resolve};
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// Time in milliseconds
+    time: f64,
+    heap: u64,
+    heap_extra: u64,
+    /// Lines of the allocation tree, if this is a detailed snapshot
+    tree: Vec<String>,
+    peak: bool,
+}
+
+
+/// Run the application under massif and collect the memory timeline and the peak allocation stacks
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> HeapRecording {
+    let out_path = dir.join("massif.out");
+
+    print_step("Running program with massif");
+    let status = app::run(app::command("valgrind")
+        .arg("--tool=massif")
+        .arg("--time-unit=ms")
+        .arg(format!("--massif-out-file={}", out_path.to_string_lossy()))
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    eprintln!("Massif output: {}", out_path.to_string_lossy());
+
+    let content = resolve(fs::read_to_string(&out_path));
+    let snapshots = parse(&content);
+    let peak = snapshots.iter()
+        .filter(|s| !s.tree.is_empty())
+        .max_by_key(|s| (s.peak, s.heap));
+
+    // The peak is always a detailed snapshot, unless the application did not allocate at all
+    let peak = match peak {
+        Some(peak) => peak,
+        None => resolve(Err("massif recorded no detailed snapshot, the application did not allocate on the heap")),
+    };
+    let summary = vec![
+        format!("snapshots taken: {}", snapshots.len()),
+        format!("peak heap memory consumption: {} bytes (+{} extra) at {:.1}ms", peak.heap, peak.heap_extra, peak.time),
+    ];
+
+    HeapRecording {
+        profile: peak_profile(peak),
+        summary,
+        timeline: Some(timeline(executable, &snapshots, peak)),
+    }
+}
+
+fn parse(content: &str) -> Vec<Snapshot> {
+    let mut snapshots = Vec::new();
+    let mut current: Option<Snapshot> = None;
+
+    for line in content.lines() {
+        if line.starts_with("snapshot=") {
+            snapshots.extend(current.take());
+            current = Some(Snapshot::default());
+        }
+        let Some(snapshot) = current.as_mut() else { continue };
+
+        if let Some(v) = line.strip_prefix("time=") {
+            snapshot.time = v.parse().unwrap_or(0.0);
+        } else if let Some(v) = line.strip_prefix("mem_heap_B=") {
+            snapshot.heap = v.parse().unwrap_or(0);
+        } else if let Some(v) = line.strip_prefix("mem_heap_extra_B=") {
+            snapshot.heap_extra = v.parse().unwrap_or(0);
+        } else if let Some(v) = line.strip_prefix("heap_tree=") {
+            snapshot.peak = v == "peak";
+        } else if line.trim_start().starts_with('n') && line.contains(':') {
+            snapshot.tree.push(line.to_string());
+        }
+    }
+    snapshots.extend(current);
+
+    snapshots
+}
+
+/// Convert the allocation tree of a snapshot into stacks weighted by their live bytes
+fn peak_profile(snapshot: &Snapshot) -> Profile {
+    let mut samples = Vec::new();
+    // Frames from the allocation function down to the current node, with their byte counts
+    let mut path: Vec<(usize, Frame, u64, u64)> = Vec::new();
+
+    let mut flush = |path: &mut Vec<(usize, Frame, u64, u64)>, depth: usize| {
+        while path.last().is_some_and(|(d, ..)| *d >= depth) {
+            let (_, _, bytes, children) = path.last().unwrap();
+            let own = bytes.saturating_sub(*children);
+            if own > 0 && path.len() > 1 {
+                samples.push(Sample {
+                    // Skip the artificial root node ("heap allocation functions")
+                    frames: path[1..].iter().map(|(_, f, ..)| f.clone()).collect(),
+                    values: vec![own],
+                });
+            }
+            path.pop();
+        }
+    };
+
+    for line in &snapshot.tree {
+        let depth = line.len() - line.trim_start().len();
+        let Some((_, rest)) = line.trim_start().split_once(": ") else { continue };
+        let (bytes, label) = rest.split_once(' ').unwrap_or((rest, ""));
+        let bytes: u64 = bytes.parse().unwrap_or(0);
+
+        flush(&mut path, depth);
+        if let Some(parent) = path.last_mut() {
+            parent.3 += bytes;
+        }
+        path.push((depth, super::parse_valgrind_frame(label), bytes, 0));
+    }
+    flush(&mut path, 0);
+
+    Profile {
+        value_names: vec!["bytes at peak".to_string()],
+        samples,
+    }
+}
+
+fn timeline(executable: &str, snapshots: &[Snapshot], peak: &Snapshot) -> GeckoProfile {
+    let mut samples = Vec::new();
+    let mut last = 0;
+    for snapshot in snapshots {
+        let heap = (snapshot.heap + snapshot.heap_extra) as i64;
+        samples.push((snapshot.time, heap - last));
+        last = heap;
+    }
+
+    let name = Path::new(executable).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    GeckoProfile {
+        threads: vec![Thread {
+            name: name.clone(),
+            process_name: name,
+            markers: vec![Marker {
+                name: "Heap peak".to_string(),
+                start: peak.time,
+                end: None,
+                text: format!("{} bytes", peak.heap),
+            }],
+            ..Thread::default()
+        }],
+        counters: vec![Counter {
+            name: "Heap".to_string(),
+            category: "Memory".to_string(),
+            description: "Heap memory including allocator overhead, as sampled by massif".to_string(),
+            samples,
+        }],
+        interval: 0.0,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "\
+desc: (none)
+cmd: /tmp/app
+time_unit: ms
+#-----------
+snapshot=0
+#-----------
+time=0
+mem_heap_B=0
+mem_heap_extra_B=0
+mem_stacks_B=0
+heap_tree=empty
+#-----------
+snapshot=1
+#-----------
+time=12.5
+mem_heap_B=3000
+mem_heap_extra_B=24
+mem_stacks_B=0
+heap_tree=peak
+n2: 3000 (heap allocation functions) malloc/new/new[], --alloc-fns, etc.
+ n1: 2000 0x4005E4: app::build (main.rs:10)
+  n0: 2000 0x4005F0: main (in /tmp/app)
+ n0: 1000 0x400600: app::other (in /tmp/app)
+#-----------
+snapshot=2
+#-----------
+time=20
+mem_heap_B=1000
+mem_heap_extra_B=8
+mem_stacks_B=0
+heap_tree=detailed
+n1: 1000 (heap allocation functions) malloc/new/new[], --alloc-fns, etc.
+ n0: 1000 0x400600: app::other (in /tmp/app)
+";
+
+    #[test]
+    fn snapshots() {
+        let snapshots = parse(OUTPUT);
+        assert_eq!(snapshots.len(), 3);
+        assert!(snapshots[0].tree.is_empty());
+        assert_eq!((snapshots[1].time, snapshots[1].heap, snapshots[1].heap_extra), (12.5, 3000, 24));
+        assert!(snapshots[1].peak);
+        assert_eq!(snapshots[1].tree.len(), 4);
+        assert!(!snapshots[2].peak);
+    }
+
+    #[test]
+    fn own_bytes_of_the_tree() {
+        let snapshots = parse(OUTPUT);
+        let profile = peak_profile(&snapshots[1]);
+        assert_eq!(profile.value_names, ["bytes at peak"]);
+        let stacks: Vec<(Vec<&str>, u64)> = profile.samples.iter()
+            .map(|s| (s.frames.iter().map(|f| f.function.as_str()).collect(), s.values[0]))
+            .collect();
+        assert_eq!(stacks, [(vec!["app::build", "main"], 2000), (vec!["app::other"], 1000)]);
+        assert_eq!(profile.samples[0].frames[1].module, "/tmp/app");
+    }
+
+    #[test]
+    fn peak_marker_in_the_timeline() {
+        let snapshots = parse(OUTPUT);
+        let timeline = timeline("/tmp/app", &snapshots, &snapshots[1]);
+        assert_eq!(timeline.threads[0].name, "app");
+        assert_eq!(timeline.threads[0].markers[0].start, 12.5);
+        assert_eq!(timeline.counters[0].samples, [(0.0, 0), (12.5, 3024), (20.0, -2016)]);
+    }
+}
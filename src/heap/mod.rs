@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use colored::Colorize;
+
+use crate::gecko::{self, GeckoProfile};
+use crate::profile::{Frame, Profile, Sample};
+use crate::report::{self, Format};
+use crate::{HeapArgs, HeapBackend, resolve};
+
+mod builtin;
+mod bytehound;
+mod dhat;
+mod heaptrack;
+mod jemalloc;
+mod massif;
+
+/// Report of the allocations still live at one point, `--leaks` or `--peak`
+struct View {
+    /// Values of the backends that count the live bytes
+    values: &'static [&'static str],
+    name: &'static str,
+    /// Added to the output names
+    suffix: &'static str,
+    description: &'static str,
+    /// Backends that can not tell which bytes were live
+    unsupported: &'static [HeapBackend],
+    missing: &'static str,
+}
+
+const LEAKS: View = View {
+    values: &["bytes leaked", "bytes at exit", "bytes in use"],
+    name: "bytes leaked",
+    suffix: "leaks",
+    description: "bytes not freed at exit",
+    unsupported: &[HeapBackend::Massif],
+    missing: "The recording does not tell which allocations were freed (is the dhat crate in ad-hoc mode?)",
+};
+
+const PEAK: View = View {
+    values: &["bytes at peak"],
+    name: "bytes at peak",
+    suffix: "peak",
+    description: "bytes live at the heap peak",
+    unsupported: &[HeapBackend::Jemalloc],
+    missing: "The recording does not tell which allocations were live at the peak (is the dhat crate in ad-hoc mode?)",
+};
+
+/// Result of a heap recording
+struct HeapRecording {
+    /// Allocation stacks
+    profile: Profile,
+    /// Backend-specific totals that are printed after the report
+    summary: Vec<String>,
+    /// Memory usage over time, if the backend records it
+    timeline: Option<GeckoProfile>,
+}
+
+
+/// Build the binary and profile its heap usage with the selected backend
+pub fn run(args: &HeapArgs) {
+    let formats = report::formats_or(&args.formats, &args.backend.default_formats());
+    if formats.contains(&Format::Trace) {
+        eprintln!("{}", "Warning: heap profiling does not produce traces".yellow());
+    }
+    let view = match (args.leaks, args.peak) {
+        (true, _) => Some(&LEAKS),
+        (_, true) => Some(&PEAK),
+        _ => None,
+    };
+    if let Some(view) = view && view.unsupported.contains(&args.backend) {
+        resolve::<(), _>(Err(format!("This backend does not record the {}, use another one for --{}", view.description, view.suffix)));
+    }
+
+    let cargo_args: &[&str] = match args.backend {
+        HeapBackend::DhatRs => &["--features", "dhat-heap"],
+        _ => &[],
+    };
+    let executable = crate::build(cargo_args);
+    let dir = crate::output_dir(&executable);
+
+    let recording = match args.backend {
+        HeapBackend::Heaptrack => heaptrack::record(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::Dhat => dhat::record_valgrind(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::DhatRs => dhat::record_crate(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::Massif => massif::record(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::Bytehound => bytehound::record(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::Jemalloc => jemalloc::record(&executable, &args.run.app_args, dir, args.run.ignore_exit),
+        HeapBackend::Builtin => builtin::record(&executable, &args.run.app_args, dir, args.run.ignore_exit, args.sample_rate),
+    };
+
+    let mut stem = stem(args.backend).to_string();
+    let mut summary = recording.summary;
+    match view {
+        Some(view) => {
+            let live = match live(&recording.profile, view) {
+                Some(live) => live,
+                None => resolve(Err(view.missing)),
+            };
+            let bytes: u64 = live.samples.iter().map(|s| s.values[0]).sum();
+            summary.push(format!("{}: {} from {} stacks", view.description, bytes, live.samples.len()));
+            stem = format!("{}.{}", stem, view.suffix);
+            report::emit(&live, &formats, dir, &stem);
+        },
+        None => report::emit(&recording.profile, &formats, dir, &stem),
+    }
+    if formats.contains(&Format::Gecko) {
+        match &recording.timeline {
+            Some(timeline) => {
+                let path = dir.join(format!("{}.json", stem));
+                resolve(gecko::write(timeline, &path));
+                report::print_output(Format::Gecko, &path);
+            },
+            None => eprintln!("{}", "Warning: this backend does not record a memory timeline".yellow()),
+        }
+    }
+    if !summary.is_empty() {
+        println!();
+        for line in &summary {
+            println!("{}", line);
+        }
+    }
+}
+
+fn stem(backend: HeapBackend) -> &'static str {
+    match backend {
+        HeapBackend::Heaptrack => "heaptrack",
+        HeapBackend::Dhat | HeapBackend::DhatRs => "dhat",
+        HeapBackend::Massif => "massif",
+        HeapBackend::Bytehound => "bytehound",
+        HeapBackend::Jemalloc => "jemalloc",
+        HeapBackend::Builtin => "alloc",
+    }
+}
+
+/// The live bytes of a view by stack, the stacks retaining the most first
+fn live(profile: &Profile, view: &View) -> Option<Profile> {
+    let index = profile.value_names.iter().position(|n| view.values.contains(&n.as_str()))?;
+    let mut stacks: HashMap<&[Frame], u64> = HashMap::new();
+    for sample in profile.samples.iter().filter(|s| s.values[index] > 0) {
+        *stacks.entry(&sample.frames).or_default() += sample.values[index];
+    }
+    let mut samples: Vec<Sample> = stacks.into_iter()
+        .map(|(frames, bytes)| Sample { frames: frames.to_vec(), values: vec![bytes] })
+        .collect();
+    samples.sort_by(|a, b| b.values[0].cmp(&a.values[0]).then_with(|| a.frames.iter().map(|f| &f.function).cmp(b.frames.iter().map(|f| &f.function))));
+    Some(Profile { value_names: vec![view.name.to_string()], samples })
+}
+
+/// Parse a frame as printed by valgrind tools, like `0x1234: foo::bar (src/foo.rs:10:5)`
+fn parse_valgrind_frame(entry: &str) -> Frame {
+    let entry = match entry.split_once(": ") {
+        Some((addr, rest)) if addr.starts_with("0x") => rest,
+        _ => entry,
+    };
+    match entry.rfind(" (") {
+        Some(i) if entry.ends_with(')') => {
+            let location = &entry[i + 2..entry.len() - 1];
+            Frame {
+                function: entry[..i].to_string(),
+                module: location.strip_prefix("in ").unwrap_or(location).to_string(),
+            }
+        },
+        _ => Frame { function: entry.to_string(), module: String::new() },
+    }
+}
@@ -0,0 +1,25 @@
+use std::path::Path;
+
+use crate::perf;
+use crate::report::{self, Format};
+use crate::{ImportArgs, resolve};
+
+
+/// Convert a `perf.data` recorded elsewhere, symbolizing it with the binaries available locally
+pub fn run(args: &ImportArgs) {
+    if !args.data.is_file() {
+        resolve::<(), _>(Err(format!("{} does not exist", args.data.to_string_lossy())));
+    }
+    let formats = report::formats_or(&args.formats, &[Format::Trace]);
+    let dir = args.data.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let stem = args.data.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+    let mut recording = perf::Recording::new(dir, &stem, "", &[], false);
+    recording.data = args.data.clone();
+    let trace_path = perf::script(&recording);
+
+    perf::convert(&trace_path, &formats, dir, &stem);
+    if formats.contains(&Format::Trace) {
+        perf::print_trace_hint(&trace_path);
+    }
+}
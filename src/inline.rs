@@ -0,0 +1,178 @@
+//! Expansion of inlined functions with `addr2line`, for traces perf converted without `--inline`
+//!
+//! perf only prints the function a sampled address belongs to, while most of the code in an
+//! optimized Rust binary was inlined into it. The address in the binary is the start of the
+//! symbol in `nm`'s table plus the offset perf prints, `addr2line` then lists the whole inline
+//! chain from the debug info. Only the binaries of the cargo target directory are expanded.
+//! Binaries with split debug info are read with `llvm-addr2line`, see [`split_debuginfo`].
+
+use std::{collections::{BTreeSet, HashMap}, fs, io::{self, Write}, path::Path, process, sync::atomic::{AtomicBool, Ordering}, thread};
+
+use colored::Colorize;
+
+use crate::demangle;
+use crate::split_debuginfo;
+use crate::{log_command, print_step};
+
+/// Whether `--inline-frames` was given
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Stack line of a trace, split into its parts
+struct StackLine<'a> {
+    ip: &'a str,
+    symbol: &'a str,
+    offset: u64,
+    module: &'a str,
+}
+
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Additional `perf script` arguments, perf's own (slow) inline lookup is replaced
+pub fn script_args() -> Vec<String> {
+    if !enabled() {
+        return Vec::new();
+    }
+    ["--no-inline", "-F", "+symoff"].iter().map(|a| a.to_string()).collect()
+}
+
+/// Insert a stack line for every inlined function in front of the function it was inlined into
+pub fn expand(trace_path: &Path) -> io::Result<()> {
+    let trace = fs::read_to_string(trace_path)?;
+
+    let mut offsets: HashMap<&str, BTreeSet<(&str, u64)>> = HashMap::new();
+    let mut leaf = false;
+    for line in trace.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            leaf = true;
+            continue;
+        }
+        if let Some(frame) = parse_line(line) && frame.module.contains("/target/") {
+            offsets.entry(frame.module).or_default().insert((frame.symbol, lookup_offset(frame.offset, leaf)));
+        }
+        leaf = false;
+    }
+    if offsets.is_empty() {
+        return Ok(());
+    }
+
+    print_step("Expanding inlined functions with addr2line");
+    let mut inlined: HashMap<(&str, &str, u64), Vec<String>> = HashMap::new();
+    for (module, frames) in &offsets {
+        let starts = symbol_starts(module);
+        let frames: Vec<(&str, u64, u64)> = frames.iter()
+            .filter_map(|(symbol, offset)| {
+                let start = (*starts.get(&demangle::lookup_name(symbol))?)?;
+                Some((*symbol, *offset, start + offset))
+            })
+            .collect();
+        let mut chains = match inline_chains(module, frames.iter().map(|(_, _, address)| *address)) {
+            Ok(chains) => chains,
+            Err(e) => {
+                // The other binaries are still expanded
+                eprintln!("{}", format!("Warning: {}", e).yellow());
+                continue;
+            },
+        };
+        for (symbol, offset, address) in frames {
+            // The last function of a chain is the one perf found
+            if let Some(mut chain) = chains.remove(&address) && chain.pop().is_some() && !chain.is_empty() {
+                inlined.insert((module, symbol, offset), chain);
+            }
+        }
+    }
+
+    let mut expanded = String::with_capacity(trace.len());
+    let mut leaf = false;
+    for line in trace.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            leaf = true;
+        } else {
+            if let Some(frame) = parse_line(line)
+                    && let Some(chain) = inlined.get(&(frame.module, frame.symbol, lookup_offset(frame.offset, leaf))) {
+                // Same indentation and address as the line of the containing function
+                let ip = &line[..line.len() - line.trim_start().len() + frame.ip.len()];
+                for function in chain {
+                    expanded.push_str(&format!("{} {} ({})\n", ip, function, frame.module));
+                }
+            }
+            leaf = false;
+        }
+        expanded.push_str(line);
+        expanded.push('\n');
+    }
+    fs::write(trace_path, expanded)
+}
+
+/// Offset a frame is looked up with, return addresses of callers point behind the call instruction
+pub fn lookup_offset(offset: u64, leaf: bool) -> u64 {
+    if leaf { offset } else { offset.saturating_sub(1) }
+}
+
+/// Parse `<ip> <symbol>+0x<offset> (<module>)`, frames without offset can not be expanded
+fn parse_line(line: &str) -> Option<StackLine<'_>> {
+    let (ip, rest) = line.trim().split_once(char::is_whitespace)?;
+    let (symbol, module) = rest.trim().strip_suffix(')')?.rsplit_once(" (")?;
+    let (symbol, offset) = symbol.rsplit_once("+0x")?;
+    Some(StackLine { ip, symbol, offset: u64::from_str_radix(offset, 16).ok()?, module })
+}
+
+/// Start address of every symbol by its lookup name, `None` if several symbols share the name
+pub fn symbol_starts(module: &str) -> HashMap<String, Option<u64>> {
+    let mut command = process::Command::new("nm");
+    command.arg("--defined-only").arg(module);
+    log_command(&command);
+    let mut starts = HashMap::new();
+    let Ok(output) = command.stderr(process::Stdio::null()).output() else { return starts };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (Some(start), Some(_kind), Some(symbol)) = (fields.next(), fields.next(), fields.next()) else { continue };
+        let Ok(start) = u64::from_str_radix(start, 16) else { continue };
+        starts.entry(demangle::lookup_name(symbol))
+            .and_modify(|s: &mut Option<u64>| if *s != Some(start) { *s = None })
+            .or_insert(Some(start));
+    }
+    starts
+}
+
+/// The functions inlined at each address, innermost first and ending with the function containing them
+pub fn inline_chains(module: &str, addresses: impl Iterator<Item = u64>) -> Result<HashMap<u64, Vec<String>>, String> {
+    let mut command = split_debuginfo::addr2line(Path::new(module));
+    command.args(["--addresses", "--inlines", "--functions", "--exe"]).arg(module);
+    log_command(&command);
+    let mut child = command.stdin(process::Stdio::piped()).stdout(process::Stdio::piped()).stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run addr2line ({}), is binutils installed?", e))?;
+    // There may be too many addresses for the command line, and addr2line answers while they are written
+    let input: String = addresses.map(|a| format!("{:#x}\n", a)).collect();
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().map_err(|e| format!("Could not run addr2line ({})", e))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        return Err(format!("addr2line failed:\n{}", String::from_utf8_lossy(&output.stderr)));
+    }
+
+    // Every address is followed by a function and a location line per entry of its chain
+    let mut chains: HashMap<u64, Vec<String>> = HashMap::new();
+    let mut current = None;
+    let mut function_line = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(address) = line.strip_prefix("0x") {
+            current = u64::from_str_radix(address, 16).ok();
+            function_line = true;
+            continue;
+        }
+        if function_line && line != "??" && let Some(address) = current {
+            chains.entry(address).or_default().push(demangle::demangle(line));
+        }
+        function_line = !function_line;
+    }
+    Ok(chains)
+}
@@ -0,0 +1,60 @@
+//! Names for code compiled at runtime, from the jitdump files of the application
+//!
+//! JIT compilers like wasmtime (`--profile=jitdump`) write the code they generate to a
+//! `jit-<pid>.dump` file and map it, so the file shows up in the recording. `perf inject --jit`
+//! turns the dump into ELF files in the build-id cache and points the samples to them. The
+//! timestamps in the dump come from the monotonic clock, which perf has to record with as well.
+
+use std::{fs, path::Path, process};
+
+use colored::Colorize;
+
+use crate::config;
+use crate::perf::{self, Recording};
+use crate::{log_command, print_step, resolve, resolve_status};
+
+/// perf clock that matches the timestamps of jitdump files
+pub const PERF_CLOCK_ARGS: &[&str] = &["-k", "mono"];
+
+
+/// Replace the recorded data with the output of `perf inject --jit` if the application wrote jitdump files
+pub fn inject(recording: &Recording) {
+    if !maps_jitdump(&recording.data) {
+        return;
+    }
+    let monotonic = recording.record_args.windows(2)
+        .any(|w| w[0] == "-k" && ["mono", "monotonic", "CLOCK_MONOTONIC"].contains(&w[1].as_str()));
+    if !monotonic {
+        eprintln!("{}", "Warning: The application wrote jitdump files, but perf did not record with the monotonic clock, \
+            record with --jit to name the JIT-compiled frames".yellow());
+        return;
+    }
+
+    print_step("Injecting JIT-compiled code");
+    let injected = recording.data.with_extension("jit.data");
+    let mut command = process::Command::new(perf::binary());
+    command.args(["inject", "--jit"])
+        .arg(format!("--input={}", recording.data.to_string_lossy()))
+        .arg(format!("--output={}", injected.to_string_lossy()));
+    log_command(&command);
+    resolve_status(resolve(command.status()));
+    resolve(fs::rename(&injected, &recording.data));
+}
+
+/// Whether a jitdump file was mapped during the recording
+fn maps_jitdump(data: &Path) -> bool {
+    let mut command = process::Command::new(perf::binary());
+    command.arg("script");
+    if config::load().sudo == Some(true) {
+        command.arg("--force");
+    }
+    command.args(["--show-mmap-events", "-F", "comm"])
+        .arg(format!("--input={}", data.to_string_lossy()));
+    log_command(&command);
+    let Ok(output) = command.stderr(process::Stdio::null()).output() else { return false };
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter(|l| l.contains("PERF_RECORD_MMAP"))
+        .filter_map(|l| l.split_whitespace().last())
+        .filter_map(|path| Path::new(path).file_name()?.to_str())
+        .any(|name| name.starts_with("jit-") && name.ends_with(".dump"))
+}
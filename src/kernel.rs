@@ -0,0 +1,101 @@
+//! Symbols of the kernel frames in the recorded call stacks
+//!
+//! perf looks up kernel addresses in `/proc/kallsyms`, which only lists them to unprivileged
+//! users if `kernel.kptr_restrict` is 0. Otherwise every kernel frame of a syscall-heavy profile
+//! is an anonymous address. A `vmlinux` with debug info additionally gives perf the static
+//! functions and inlined code of the kernel.
+
+use std::{fs::{self, File}, io::{BufRead, BufReader}, path::PathBuf, sync::OnceLock};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::config;
+use crate::resolve;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KernelSymbols {
+    /// Record kernel frames and warn if their symbols are hidden
+    #[default]
+    Auto,
+    /// Only record the application's own code
+    Off,
+    /// Fail if kernel frames can not be recorded with symbols
+    Require,
+}
+
+static MODE: OnceLock<KernelSymbols> = OnceLock::new();
+
+/// Kernel image given with `--vmlinux`
+static VMLINUX: OnceLock<PathBuf> = OnceLock::new();
+
+
+pub fn set_options(mode: KernelSymbols, vmlinux: Option<PathBuf>) {
+    let _ = MODE.set(mode);
+    if let Some(vmlinux) = vmlinux {
+        let _ = VMLINUX.set(vmlinux);
+    }
+}
+
+fn mode() -> KernelSymbols {
+    MODE.get().copied().unwrap_or_default()
+}
+
+/// Additional `perf record` arguments
+pub fn record_args() -> Vec<String> {
+    match mode() {
+        KernelSymbols::Off => vec!["--all-user".to_string()],
+        KernelSymbols::Auto | KernelSymbols::Require => Vec::new(),
+    }
+}
+
+/// Arguments for `perf script` and `perf report` with the `--vmlinux` image
+pub fn perf_args() -> Vec<String> {
+    VMLINUX.get().map(|path| format!("--vmlinux={}", path.to_string_lossy())).into_iter().collect()
+}
+
+/// Explain what is missing if kernel frames are recorded without symbols, and fail with `require`
+pub fn check() {
+    let mode = mode();
+    // Both restrictions do not apply to root
+    if mode == KernelSymbols::Off || config::load().sudo == Some(true) {
+        return;
+    }
+    let mut problems = Vec::new();
+    // Most distributions do not record the kernel by default, which is only worth a warning if it was asked for
+    let kernel_recorded = read_sysctl("perf_event_paranoid").is_none_or(|v| v <= 1);
+    if !kernel_recorded && mode == KernelSymbols::Require {
+        problems.push(("kernel.perf_event_paranoid is above 1, kernel frames are not recorded at all",
+            "sudo sysctl kernel.perf_event_paranoid=1"));
+    }
+    if kernel_recorded && !kallsyms_readable() {
+        problems.push(("kernel addresses in /proc/kallsyms are hidden (kernel.kptr_restrict), kernel frames show up as bare addresses",
+            "sudo sysctl kernel.kptr_restrict=0"));
+    }
+    if let Some(vmlinux) = VMLINUX.get() && !vmlinux.is_file() {
+        resolve::<(), _>(Err(format!("The kernel image {} does not exist", vmlinux.to_string_lossy())));
+    }
+
+    match mode {
+        KernelSymbols::Require if !problems.is_empty() => {
+            let reasons: Vec<String> = problems.iter().map(|(problem, fix)| format!("{} ({})", problem, fix)).collect();
+            resolve::<(), _>(Err(format!("Kernel symbols are required, but {}", reasons.join(", and "))));
+        },
+        _ => for (problem, fix) in problems {
+            eprintln!("{}", format!("Warning: The time spent in syscalls can not be attributed: {}", problem).yellow());
+            eprintln!("{}", format!("Hint: Allow it with `{}` or pass --kernel-symbols off to only record user space", fix).yellow());
+        },
+    }
+}
+
+/// Whether `/proc/kallsyms` shows the actual addresses, it lists zeros if they are restricted
+fn kallsyms_readable() -> bool {
+    let Ok(symbols) = File::open("/proc/kallsyms") else { return false };
+    BufReader::new(symbols).lines().map_while(Result::ok).take(100)
+        .filter_map(|l| l.split_whitespace().next().map(str::to_string))
+        .any(|address| address.bytes().any(|b| b != b'0'))
+}
+
+fn read_sysctl(name: &str) -> Option<i32> {
+    fs::read_to_string(format!("/proc/sys/kernel/{}", name)).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,183 @@
+//! Profiling `cargo test`, split by test case with the JSON events of libtest
+//!
+//! The tests of each binary run one after another, and the `started` and finished events libtest
+//! prints for each test mark the window of its samples. perf records with the wall clock, so that
+//! the sample times can be compared to the times the events arrived at.
+
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, process, time::{SystemTime, UNIX_EPOCH}};
+
+use colored::Colorize;
+use serde::Deserialize;
+
+use crate::perf;
+use crate::profile::{self, PerfEvent};
+use crate::report::{self, Format};
+use crate::spans;
+use crate::{TestArgs, print_step, resolve, resolve_status};
+
+/// libtest arguments for one JSON event per line, only accepted with `RUSTC_BOOTSTRAP` on stable
+const JSON_ARGS: &[&str] = &["-Z", "unstable-options", "--format", "json", "--report-time", "--test-threads=1"];
+
+/// Test binary built by `cargo test --no-run`
+struct TestBinary {
+    name: String,
+    executable: PathBuf,
+    /// Directory of the package, which `cargo test` runs the tests in
+    cwd: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Artifact {
+    reason: String,
+    target: Option<ArtifactTarget>,
+    profile: Option<ArtifactProfile>,
+    executable: Option<PathBuf>,
+    manifest_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtifactTarget {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArtifactProfile {
+    test: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Event {
+    #[serde(rename = "type")]
+    kind: String,
+    event: String,
+    name: Option<String>,
+    /// Seconds the test took, with `--report-time`
+    exec_time: Option<f64>,
+}
+
+/// Wall-clock window (in seconds since the epoch) in which a test ran
+struct Window {
+    name: String,
+    start: f64,
+    end: f64,
+    outcome: String,
+    exec_time: Option<f64>,
+}
+
+
+/// Build the test binaries, record each one and report the samples of every test
+pub fn run(args: &TestArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Summary]);
+    for binary in build(args) {
+        record_binary(&binary, args, &formats);
+    }
+}
+
+fn build(args: &TestArgs) -> Vec<TestBinary> {
+    print_step("Building tests");
+    let cargo_path = resolve(env::var("CARGO"));
+    let mut command = process::Command::new(cargo_path);
+    command.args(["test", "--no-run", "--profile=profiling", "--message-format=json-render-diagnostics"]);
+    if args.lib {
+        command.arg("--lib");
+    }
+    for test in &args.tests {
+        command.args(["--test", test]);
+    }
+    for package in &args.packages {
+        command.args(["--package", package]);
+    }
+    crate::log_command(&command);
+    let output = resolve(command.stderr(process::Stdio::inherit()).output());
+    resolve_status(output.status);
+
+    let binaries: Vec<TestBinary> = String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|l| serde_json::from_str::<Artifact>(l).ok())
+        .filter(|a| a.reason == "compiler-artifact" && a.profile.as_ref().is_some_and(|p| p.test))
+        .filter_map(|a| Some(TestBinary {
+            name: a.target?.name,
+            executable: a.executable?,
+            cwd: a.manifest_path.and_then(|p| p.parent().map(Path::to_path_buf)),
+        }))
+        .collect();
+    if binaries.is_empty() {
+        resolve::<(), _>(Err("cargo test built no test binaries"));
+    }
+    binaries
+}
+
+fn record_binary(binary: &TestBinary, args: &TestArgs, formats: &[Format]) {
+    let dir = binary.executable.parent().and_then(Path::parent).unwrap_or(Path::new(".")).join("tests");
+    resolve(fs::create_dir_all(&dir));
+    let stem = format!("test.{}", report::file_name_part(&binary.name));
+
+    eprintln!("\n{}", binary.name.bold());
+    let mut test_args: Vec<String> = JSON_ARGS.iter().map(|a| a.to_string()).collect();
+    test_args.extend(args.run.app_args.iter().cloned());
+    let mut recording = perf::Recording::new(&dir, &stem, &binary.executable.to_string_lossy(), &test_args, args.run.ignore_exit);
+    recording.record_args.extend(spans::PERF_CLOCK_ARGS.iter().map(|a| a.to_string()));
+    recording.env.push(("RUSTC_BOOTSTRAP".to_string(), "1".to_string()));
+    recording.cwd = binary.cwd.clone();
+
+    let mut windows: Vec<Window> = Vec::new();
+    let mut on_line = |line: &str| {
+        let Ok(event) = serde_json::from_str::<Event>(line) else { return };
+        let (true, Some(name)) = (event.kind == "test", event.name) else { return };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        if event.event == "started" {
+            windows.push(Window { name, start: now, end: f64::INFINITY, outcome: String::new(), exec_time: None });
+        } else if let Some(window) = windows.iter_mut().rev().find(|w| w.name == name) {
+            window.end = now;
+            window.outcome = event.event;
+            window.exec_time = event.exec_time;
+        }
+    };
+    let trace_path = perf::record_watching(&recording, Some(&mut on_line));
+    if windows.is_empty() {
+        eprintln!("{}", "Warning: libtest printed no test events, the binary may not use the default test harness".yellow());
+        return;
+    }
+
+    let content = resolve(fs::read_to_string(&trace_path));
+    let events = resolve(profile::parse_perf_events(&trace_path));
+    let mut tests: Vec<(&Window, Vec<PerfEvent>)> = windows.iter()
+        .map(|w| (w, events.iter().filter(|e| e.time >= w.start && e.time <= w.end).cloned().collect()))
+        .collect();
+    tests.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.name.cmp(&b.0.name)));
+
+    if formats.contains(&Format::Summary) {
+        print_tests(&tests, events.len());
+        if let Some((window, events)) = tests.first().filter(|(_, events)| !events.is_empty()) {
+            println!("\n{}", format!("Hottest test: {}", window.name).bold());
+            report::print_summary(&profile::from_perf_events(events));
+        }
+    }
+    // The table above is the summary of the single tests
+    let formats: Vec<Format> = formats.iter().copied().filter(|f| *f != Format::Summary).collect();
+    for (window, test_events) in &tests {
+        let in_window = |e: &PerfEvent| e.time >= window.start && e.time <= window.end;
+        let test_stem = format!("{}.{}", stem, report::file_name_part(&window.name));
+        if formats.contains(&Format::Trace) {
+            let path = dir.join(format!("{}.trace", test_stem));
+            resolve(perf::write_filtered_trace(&content, &path, in_window));
+            report::print_output(Format::Trace, &path);
+        }
+        report::emit(&profile::from_perf_events(test_events), &formats, &dir, &test_stem);
+    }
+}
+
+/// Print the samples and the hottest function of every test, the test with the most samples first
+fn print_tests(tests: &[(&Window, Vec<PerfEvent>)], total: usize) {
+    println!("\n{:>8} {:>8} {:>9}  {:<8}  {:<50}  {}", "Share %".bold(), "samples".bold(), "time".bold(), "outcome".bold(), "test".bold(), "hottest function".bold());
+    for (window, events) in tests {
+        let mut functions: HashMap<&str, usize> = HashMap::new();
+        for event in events {
+            if let Some(leaf) = event.frames.first() {
+                *functions.entry(&leaf.function).or_default() += 1;
+            }
+        }
+        let hottest = functions.into_iter().max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(a.0))).map(|(f, _)| f).unwrap_or("-");
+        let time = window.exec_time.map(|t| format!("{:.3}s", t)).unwrap_or_else(|| "-".to_string());
+        println!("{:>7.2}% {:>8} {:>9}  {:<8}  {:<50}  {}", report::percent(events.len() as u64, total as u64), events.len(), time, window.outcome, window.name, hottest);
+    }
+}
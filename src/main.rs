@@ -1,12 +1,98 @@
-use std::{env, fmt::Display, fs::{self, File}, io::BufRead, path::Path, process};
+use std::{env, fmt::Display, fs, io::BufRead, path::{Path, PathBuf}, process, sync::{Mutex, atomic::{AtomicU8, Ordering}}, time::{Duration, Instant, SystemTime}};
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, ColorChoice, Parser, Subcommand, ValueEnum};
 use colored::Colorize;
 use serde::Deserialize;
 use std::io::Write;
 
+use energy::EnergySource;
+use kernel::KernelSymbols;
+use push::PushTarget;
+use messages::Message;
+use report::Format;
+
+mod android;
+mod annotate;
+mod app;
+mod assertions;
+mod baseline;
+mod bench;
+mod bisect;
+mod cachegrind;
+mod category;
+mod causal;
+mod ci;
+mod compare_commits;
+mod completions;
+mod config;
+mod container;
+mod counters;
+mod demangle;
+mod doctor;
+mod driver;
+mod dry_run;
+mod dtrace;
+mod energy;
+mod gecko;
+mod gprofng;
+mod gpu;
+mod heap;
+mod import;
+mod inline;
+mod jit;
+mod kernel;
+mod libtest;
+mod man;
+mod manifest;
+mod maps;
+mod markers;
+mod messages;
+mod nextest;
+mod perf;
+mod pools;
+mod pprof;
+mod profile;
+mod push;
+mod query;
+mod ready;
+mod sched;
+mod remote;
+mod report;
+mod resymbolize;
+mod serve;
+mod server;
+mod shutdown;
+mod size;
+mod spans;
+mod split_debuginfo;
+mod startup;
+mod stats;
+mod store;
+mod strace;
+mod symbols;
+mod syscalls;
+mod tasks;
+mod timing;
+mod toml;
+mod top;
+mod trend;
+mod tui;
+mod upload;
+mod viewer;
+mod vscode;
+mod vtune;
+mod wasm;
+mod watch;
+mod wsl;
+
 pub const CARGO_TOML_SNIPPET: &str = include_str!("cargo-toml-snippet.toml");
 
+/// Level set by `-q` and `-v`, see [`verbosity`]
+static VERBOSITY: AtomicU8 = AtomicU8::new(1);
+
+/// Step announced by the last [`print_step`] and when it started
+static STEP: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
@@ -22,24 +108,889 @@ enum Command {
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 struct PProfArgs {
+    #[clap(subcommand)]
+    action: Option<Action>,
+
+    /// Print subprocess command lines and the duration of each step (-vv also makes cargo and perf verbose)
+    #[clap(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Do not print the step banners
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// When to use colors in the output
+    #[clap(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// Also print progress events and output paths as JSON lines, for editor integrations
+    #[clap(long, value_enum, default_value_t = messages::MessageFormat::Human, global = true)]
+    message_format: messages::MessageFormat,
+
+    /// perf binary to use instead of the one on the PATH (e.g. a build matching a custom kernel)
+    #[clap(long, global = true)]
+    perf_path: Option<PathBuf>,
+
+    /// debuginfod servers (space separated) to fetch the symbols of system libraries from, instead of $DEBUGINFOD_URLS
+    #[clap(long, value_name = "URL", global = true)]
+    debuginfod: Option<String>,
+
+    /// Look up the recorded binaries below this directory, e.g. a copy of the root file system of the target machine
+    #[clap(long, value_name = "DIR", global = true)]
+    symfs: Option<PathBuf>,
+
+    /// Directory with separate debug files (`*.debug` or a `.build-id` tree) to add to perf's build-id cache
+    #[clap(long = "debug-dir", value_name = "DIR", global = true)]
+    debug_dirs: Vec<PathBuf>,
+
+    /// Drop hashes and crate disambiguators from function names and only keep the last segment of paths in generics
+    #[clap(long, global = true)]
+    short_names: bool,
+
+    /// Whether kernel frames are recorded: warn if their symbols are hidden, leave them out, or fail without them
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = KernelSymbols::Auto, global = true)]
+    kernel_symbols: KernelSymbols,
+
+    /// Kernel image with debug info to resolve the kernel frames with, instead of /proc/kallsyms
+    #[clap(long, value_name = "PATH", global = true)]
+    vmlinux: Option<PathBuf>,
+
+    /// Expand the functions inlined into each sampled function with addr2line, which is faster than perf's own lookup
+    #[clap(long, global = true)]
+    inline_frames: bool,
+
+    /// Merge the monomorphized instances of generic functions into one frame by replacing their generic arguments with `_`
+    #[clap(long, global = true, conflicts_with = "keep_generics")]
+    collapse_generics: bool,
+
+    /// Keep the instances of generic functions apart even if the configuration collapses them
+    #[clap(long, global = true)]
+    keep_generics: bool,
+
+    /// Rank functions by their own samples only and leave the callers out of the folded stacks and pprof output,
+    /// for recordings whose call stacks could not be unwound
+    #[clap(long, global = true)]
+    flat: bool,
+
+    /// Keep every thread of a thread pool (like rayon or tokio workers) as its own track in the Firefox Profiler output
+    #[clap(long, global = true)]
+    per_thread: bool,
+
+    /// Drop the samples of call stacks with less than this share in percent from the trace,
+    /// so that traces of long runs stay small enough for the Firefox Profiler
+    #[clap(long, value_name = "PCT", value_parser = perf::parse_min_weight, global = true)]
+    min_weight: Option<f64>,
+
+    /// Firefox binary to open profiles with if no other browser is configured
+    #[clap(long, global = true)]
+    firefox_path: Option<PathBuf>,
+
     /// Add "profiling" profile to Cargo.toml (simple append)
     #[clap(long)]
     add: bool,
 
+    /// Add tasks running cargo-pprof to .vscode/tasks.json
+    #[clap(long)]
+    emit_tasks: bool,
+
     /// Open the firefox profiler and exit
     #[clap(short, long)]
     open_firefox_profiler: bool,
 
+    /// Backend used to record the application
+    #[clap(long, value_enum, default_value_t = Backend::Perf)]
+    backend: Backend,
+
+    /// Sampling frequency of perf in Hz
+    #[clap(short = 'F', long)]
+    frequency: Option<u32>,
+
+    /// How perf records call stacks (fp, dwarf or lbr)
+    #[clap(long, value_name = "MODE")]
+    call_graph: Option<String>,
+
+    /// Event to sample instead of the CPU clock, e.g. cycles or cache-misses
+    #[clap(short, long = "event")]
+    events: Vec<String>,
+
+    /// Record block and file I/O tracepoints instead of CPU samples (block requests system-wide, needs kernel.perf_event_paranoid <= 0)
+    #[clap(long)]
+    io: bool,
+
+    /// Record socket syscall tracepoints instead of CPU samples
+    #[clap(long, conflicts_with = "io")]
+    net: bool,
+
+    /// Build for the given target triple (Android targets are recorded on the device via adb and simpleperf,
+    /// WASI targets under wasmtime's guest profiler)
+    #[clap(long)]
+    target: Option<String>,
+
+    /// Record a process running in the given Docker/Podman container instead of building the crate
+    #[clap(long)]
+    container: Option<String>,
+
+    /// Recording length in seconds when attaching to a running process or with --startup,
+    /// with `--shutdown signal:SIG` the time until the signal is sent
+    #[clap(long, default_value_t = 10)]
+    duration: u64,
+
+    /// Additionally record GPU jobs (DRM fence tracepoints) as tracks of the Firefox Profiler output
+    #[clap(long)]
+    gpu: bool,
+
+    /// Merge span events of the `tracing` crate into the Firefox Profiler output as markers
+    /// (the application has to install a JSON subscriber, see the hint printed if none is found)
+    #[clap(long)]
+    tracing: bool,
+
+    /// Attribute the samples of async runtime worker threads to the task that was polled, from the
+    /// enter and exit events of the `tracing` spans (tokio tasks need `--cfg tokio_unstable`)
+    #[clap(long, requires = "tracing")]
+    async_tasks: bool,
+
+    /// Record the USDT probe `PROVIDER:NAME` of the binary as markers (`NAME_start`/`NAME_end` are paired)
+    #[clap(long = "sdt", value_name = "PROVIDER:NAME")]
+    sdt_probes: Vec<String>,
+
+    /// Record with the monotonic clock of jitdump files, so that code compiled at runtime
+    /// (e.g. by wasmtime with `--profile=jitdump`) gets names with `perf inject --jit`
+    #[clap(long, conflicts_with_all = ["tracing", "markers", "cpu_usage", "rss"])]
+    jit: bool,
+
+    /// Add a counter track with the CPU time used by the application, polled from /proc, to the Firefox Profiler output
+    #[clap(long)]
+    cpu_usage: bool,
+
+    /// Add a memory track with the resident set size of the application, polled from /proc, to the Firefox Profiler output
+    #[clap(long)]
+    rss: bool,
+
+    /// Collect markers the application writes to the FIFO in $CARGO_PPROF_MARKERS
+    /// (one `begin <name>`, `end <name>` or `<name>` per line)
+    #[clap(long)]
+    markers: bool,
+
+    /// Also run the application once without perf and print the wall and CPU time overhead of the recording
+    #[clap(long, conflicts_with_all = ["driver", "wait_for"])]
+    overhead: bool,
+
+    /// Shell command generating load for a server: the recording stops when it exits
+    #[clap(long, value_name = "CMD")]
+    driver: Option<String>,
+
+    /// Only start sampling once the application is ready: `port:PORT` accepts connections,
+    /// `log:TEXT` was printed or `http:URL` responds (also starts a --driver, with --startup sampling stops then)
+    #[clap(long, value_name = "PROBE", value_parser = ready::parse)]
+    wait_for: Option<ready::Probe>,
+
+    /// Seconds to wait for the application to become ready
+    #[clap(long, value_name = "SECONDS", default_value_t = 30, requires = "wait_for")]
+    wait_timeout: u64,
+
+    /// Record the startup from exec until --wait-for succeeds (or for --duration seconds) at a high
+    /// frequency with DWARF stacks, and print the time spent in each phase of the initialization
+    #[clap(long, conflicts_with_all = ["driver", "overhead"])]
+    startup: bool,
+
+    /// Record only the shutdown: `signal:SIG` enables sampling after --duration seconds and sends SIG
+    /// to the application, `marker:NAME` once the application writes NAME to the $CARGO_PPROF_MARKERS FIFO
+    #[clap(long, value_name = "TRIGGER", value_parser = shutdown::parse, conflicts_with_all = ["driver", "wait_for", "startup", "overhead"])]
+    shutdown: Option<shutdown::Trigger>,
+
+    /// Output formats to generate (defaults depend on the backend)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    /// Additionally write the outputs of every process the application spawned to separate files
+    #[clap(long)]
+    split_processes: bool,
+
+    /// Print the cargo and perf command lines instead of running them
+    #[clap(long)]
+    dry_run: bool,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Profile heap allocations instead of CPU time
+    #[clap(alias = "alloc")]
+    Heap(HeapArgs),
+
+    /// Trace syscalls with strace and aggregate their counts and latencies
+    Strace(StraceArgs),
+
+    /// Measure energy consumption via RAPL counters
+    Energy(EnergyArgs),
+
+    /// Record on a remote machine over SSH and symbolize locally
+    Remote(RemoteArgs),
+
+    /// Upload a profile and print a shareable Firefox Profiler link
+    Upload(UploadArgs),
+
+    /// Post a Markdown summary of a trace to the current GitHub pull request
+    CiComment(CiCommentArgs),
+
+    /// Convert and symbolize a perf.data recorded elsewhere
+    Import(ImportArgs),
+
+    /// Record the tests selected by cargo-nextest filters, with one trace per test
+    Nextest(NextestArgs),
+
+    /// Record `cargo test` and split the recording by test case with the JSON events of libtest
+    Test(TestArgs),
+
+    /// Record a criterion benchmark in its --profile-time mode, with one trace per benchmark
+    Bench(BenchArgs),
+
+    /// Estimate the effect of optimizing each line with coz (mark progress points with the `coz` crate)
+    Causal(CausalArgs),
+
+    /// Check the environment for problems with recording and print how to fix them
+    Doctor(DoctorArgs),
+
+    /// Print a shell completion script (e.g. `cargo pprof completions bash > ~/.local/share/bash-completion/completions/cargo-pprof`)
+    Completions(CompletionsArgs),
+
+    /// Record again whenever a file of the package changes, serving the latest recording with --open
+    Watch(WatchArgs),
+
+    /// Keep serving all recordings of the session on localhost, with Firefox Profiler links for each
+    Serve(ServeArgs),
+
+    /// List the runs stored in target/pprof
+    List,
+
+    /// Print the outputs and the summary of a stored run
+    Show(ShowArgs),
+
+    /// Plot the share of a function in each stored run, to spot slowly creeping regressions
+    Trend(TrendArgs),
+
+    /// Count the samples matching an expression of selectors (e.g. `function=^alloc:: and thread=worker`)
+    Query(QueryArgs),
+
+    /// Remove stored runs and leftover perf.data files
+    Clean(CleanArgs),
+
+    /// Record two git revisions with identical settings and compare their profiles
+    CompareCommits(CompareCommitsArgs),
+
+    /// Find the commit that introduced a regression by recording the candidates of `git bisect`
+    Bisect(BisectArgs),
+
+    /// Manage named baselines that runs can be compared against with --compare-baseline
+    Baseline(BaselineArgs),
+
+    /// Time repeated runs of the binary without a profiler and report the wall-clock statistics
+    Time(TimeArgs),
+
+    /// Show the hottest functions while the application runs, refreshed continuously
+    Top(TopArgs),
+
+    /// Browse a trace, folded stacks or perf.data interactively in the terminal
+    Tui(TuiArgs),
+
+    /// Print the source of a function with the share of its samples on each line
+    Annotate(AnnotateArgs),
+
+    /// Symbolize a perf.data or trace again with a binary that has debug info, without recording again
+    Resymbolize(ResymbolizeArgs),
+
+    /// Attribute the code size of the binary to its crates and functions, with a flame graph of the sizes
+    Size(SizeArgs),
+
+    /// Print the man page, documenting each stage, the profile, the environment variables and the exit codes (e.g. `cargo pprof man | man -l -`)
+    Man,
+}
+
+#[derive(Parser, Debug)]
+struct HeapArgs {
+    /// Backend used to record allocations
+    #[clap(long, value_enum, default_value_t = HeapBackend::Heaptrack)]
+    backend: HeapBackend,
+
+    /// Average number of bytes allocated between two samples of the builtin backend (1 samples every allocation)
+    #[clap(long, value_name = "BYTES", default_value_t = 65536, value_parser = clap::value_parser!(u64).range(1..))]
+    sample_rate: u64,
+
+    /// Report the allocations not freed by the time the program exited, by the bytes they retain
+    #[clap(long)]
+    leaks: bool,
+
+    /// Report the allocations that were live when the heap usage peaked, by the bytes they held
+    #[clap(long, conflicts_with = "leaks")]
+    peak: bool,
+
+    /// Output formats to generate (defaults depend on the backend)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct StraceArgs {
+    /// Do not record the user stack of each syscall (required if strace lacks stack trace support)
+    #[clap(long)]
+    no_stacks: bool,
+
+    /// Output formats to generate (defaults depend on the backend)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct EnergyArgs {
+    /// Where to read the energy counters from
+    #[clap(long, value_enum, default_value_t = EnergySource::Perf)]
+    source: EnergySource,
+
+    /// Length of each reported phase in milliseconds
+    #[clap(long, default_value_t = 1000)]
+    interval: u64,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct RemoteArgs {
+    /// Machine to record on, as passed to ssh (e.g. user@server)
+    #[clap(long)]
+    host: String,
+
+    /// Build for the given target triple if the remote machine has a different architecture
+    #[clap(long)]
+    target: Option<String>,
+
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct UploadArgs {
+    /// Firefox Profiler file or trace to upload
+    path: PathBuf,
+
+    /// Upload to this object store URL (via HTTP PUT) instead of the Firefox Profiler's storage,
+    /// `s3://bucket/prefix` and `gs://bucket/prefix` also store a metadata manifest
+    #[clap(long)]
+    upload_to: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct CiCommentArgs {
+    /// Trace or folded stacks to summarize
+    trace: PathBuf,
+
+    /// Trace or folded stacks of the base branch to compare against
+    #[clap(long)]
+    baseline: Option<PathBuf>,
+
+    /// Pull request to comment on (detected from the GitHub Actions environment by default)
+    #[clap(long)]
+    pr: Option<u64>,
+
+    /// Only print the Markdown instead of posting it
+    #[clap(long)]
+    print: bool,
+}
+
+#[derive(Parser, Debug)]
+struct ImportArgs {
+    /// perf.data file to convert
+    data: PathBuf,
+
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+}
+
+#[derive(Parser, Debug)]
+struct NextestArgs {
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    // Filters are passed to `cargo nextest list` as the application arguments
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct TestArgs {
+    /// Only test the library of the package
+    #[clap(long)]
+    lib: bool,
+
+    /// Only test the given integration test target
+    #[clap(long = "test", value_name = "NAME")]
+    tests: Vec<String>,
+
+    /// Package to test
+    #[clap(short, long = "package", value_name = "SPEC")]
+    packages: Vec<String>,
+
+    /// Output formats to generate per test (defaults to a summary, trace adds one trace per test)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    // Arguments after `--` are passed to the test binaries, like a filter or `--skip`
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct BenchArgs {
+    /// Name of the criterion bench target
+    #[clap(long)]
+    bench: String,
+
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    // Arguments after `--` are passed to criterion, like `--profile-time 10` or a filter
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct DoctorArgs {
+    /// Browser command to check instead of the default one
+    #[clap(long)]
+    browser: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct WatchArgs {
+    /// Milliseconds between checks for changed files
+    #[clap(long, value_name = "MS", default_value_t = 500)]
+    poll_interval: u64,
+
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct ServeArgs {
+    /// Port to listen on (0 picks a free one)
+    #[clap(long, default_value_t = 8720)]
+    port: u16,
+
+    /// Open the index page in the browser
+    #[clap(long)]
+    open: bool,
+
+    /// Browser command to open the index page with
+    #[clap(long)]
+    browser: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct ShowArgs {
+    /// ID of the run, a unique prefix of it or `latest`
+    id: String,
+
+    /// Open the run in its viewer again
+    #[clap(long)]
+    open: bool,
+
+    /// Browser command to open the run with
+    #[clap(long)]
+    browser: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct TrendArgs {
+    /// Function to follow, all functions containing this string are counted
+    function: String,
+
+    /// Only include runs whose command line contains this string
+    #[clap(long, value_name = "TEXT")]
+    command: Option<String>,
+
+    /// Print the shares as CSV instead of a chart
+    #[clap(long)]
+    csv: bool,
+}
+
+#[derive(Parser, Debug)]
+struct QueryArgs {
+    /// Selectors `function=<regex>` (any frame), `leaf=<regex>`, `crate=<name>`, `thread=<regex>`, `pid=<n>`, `tid=<n>`
+    /// and `time=<from>..<to>` (seconds since the first sample), combined with `and`, `or`, `not` and parentheses
+    expr: String,
+
+    /// Trace or folded stacks to query instead of a stored run
+    #[clap(long, conflicts_with = "run")]
+    input: Option<PathBuf>,
+
+    /// ID of the stored run to query, a unique prefix of it or `latest`
+    #[clap(long, default_value = "latest")]
+    run: String,
+
+    /// Also break the matching samples down by sampled function, crate or thread
+    #[clap(long, value_enum)]
+    by: Option<query::GroupBy>,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CleanArgs {
+    /// Keep the N newest stored runs
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    keep_last: usize,
+}
+
+#[derive(Parser, Debug)]
+struct CompareCommitsArgs {
+    /// Revision the comparison starts from
+    rev_a: String,
+
+    /// Revision compared against the first one
+    rev_b: String,
+
+    /// Record each revision this many times and test the differences for significance
+    #[clap(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    runs: u32,
+
+    /// Only list functions whose self share changed by at least this many percentage points
+    #[clap(long, value_name = "POINTS", default_value_t = 0.5)]
+    delta_threshold: f64,
+
+    /// Arguments passed to the application of both revisions
+    #[clap(last(true))]
+    app_args: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct BisectArgs {
+    /// Metric the revisions are compared by
+    #[clap(long, value_enum, default_value_t = bisect::Metric::Wallclock)]
+    metric: bisect::Metric,
+
+    /// Increase of the metric over the good revision from which a revision counts as bad
+    #[clap(long, default_value = "10%")]
+    threshold: String,
+
+    /// Revision without the regression
+    #[clap(long)]
+    good: String,
+
+    /// Revision with the regression
+    #[clap(long)]
+    bad: String,
+
+    /// Arguments passed to the application of every revision
+    #[clap(last(true))]
+    app_args: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct BaselineArgs {
+    #[clap(subcommand)]
+    action: BaselineAction,
+}
+
+#[derive(Subcommand, Debug)]
+enum BaselineAction {
+    /// Save a stored run as baseline, replacing an existing one of the same name
+    Save {
+        name: String,
+
+        /// ID of the stored run, a unique prefix of it or `latest`
+        #[clap(long, default_value = "latest")]
+        run: String,
+    },
+
+    /// List the saved baselines
+    List,
+
+    /// Remove a baseline
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+struct TimeArgs {
+    /// Number of timed runs
+    #[clap(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    runs: u32,
+
+    /// Number of untimed runs before, to warm up caches
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    warmup: u32,
+
+    /// Show the output of the application instead of discarding it
+    #[clap(long)]
+    show_output: bool,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct TopArgs {
+    /// Milliseconds between two refreshes of the display
+    #[clap(long, value_name = "MS", default_value_t = 1000)]
+    interval: u64,
+
+    /// Sampling frequency of perf in Hz
+    #[clap(short = 'F', long)]
+    frequency: Option<u32>,
+
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Parser, Debug)]
+struct TuiArgs {
+    /// Trace, folded stacks or perf.data to view
+    path: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct AnnotateArgs {
+    /// Function to annotate, the hottest one containing this string is chosen
+    symbol: String,
+
+    /// perf.data to read the samples from [default: target/profiling/perf.data]
+    #[clap(long)]
+    data: Option<PathBuf>,
+
+    /// Number of lines shown around the sampled ones
+    #[clap(long, default_value_t = 3)]
+    context: u32,
+
+    /// Interleave the disassembly with the samples of each instruction
+    #[clap(long)]
+    asm: bool,
+
+    /// Write the annotation to this file instead, as HTML if it ends in .html and plain text otherwise
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+struct ResymbolizeArgs {
+    /// perf.data file or `perf script` trace to symbolize
+    input: PathBuf,
+
+    /// Binary with the debug info to take the names of its frames from, matched by file name (repeatable)
+    #[clap(long = "binary", value_name = "PATH", required = true)]
+    binaries: Vec<PathBuf>,
+
+    /// Output formats to generate (defaults to trace)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+}
+
+#[derive(Parser, Debug)]
+struct SizeArgs {
+    /// Binary to analyze instead of building the package
+    binary: Option<PathBuf>,
+
+    /// Output formats to generate (defaults to summary and folded)
+    #[clap(long = "format", value_enum)]
+    formats: Vec<Format>,
+
+    /// Render the folded stacks to a flame graph (or open the pprof profile) afterwards
+    #[clap(long)]
+    open: bool,
+}
+
+#[derive(Parser, Debug)]
+struct CompletionsArgs {
+    /// Shell to generate the script for
+    #[clap(value_enum, required_unless_present = "targets")]
+    shell: Option<completions::Shell>,
+
+    /// Print the names of the targets of this kind, used by the scripts for dynamic completion
+    #[clap(long, hide = true)]
+    targets: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct CausalArgs {
+    #[clap(flatten)]
+    run: RunArgs,
+}
+
+/// Options shared by all modes that run the application
+#[derive(Parser, Debug)]
+struct RunArgs {
     /// Ignore exit code of the profiled application
     #[clap(short, long)]
     ignore_exit: bool,
 
+    /// Finish the recording even if the profiled application fails and exit with its exit code
+    #[clap(long, conflicts_with = "ignore_exit")]
+    mirror_exit: bool,
+
+    /// Set an environment variable of the profiled application
+    #[clap(long = "env", value_name = "KEY=VALUE")]
+    env: Vec<String>,
+
+    /// Read environment variables of the profiled application from a dotenv file (applied before --env)
+    #[clap(long)]
+    env_file: Option<PathBuf>,
+
+    /// Working directory of the profiled application
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+
+    /// Feed the profiled application's standard input from a file instead of the terminal
+    #[clap(long)]
+    stdin: Option<PathBuf>,
+
+    /// Copy the profiled application's stdout and stderr to `stdout.log` and `stderr.log` in this
+    /// directory while still printing them
+    #[clap(long, value_name = "DIR")]
+    log_output: Option<PathBuf>,
+
+    /// Open the recording afterwards in the viewer matching its format (Firefox Profiler for traces)
+    #[clap(long)]
+    open: bool,
+
+    /// Open the perf data in the Hotspot GUI afterwards
+    #[clap(long)]
+    open_hotspot: bool,
+
+    /// Bundle the perf data with all referenced binaries into a tarball afterwards
+    #[clap(long)]
+    archive: bool,
+
+    /// Shell command run before the build and the recording, e.g. to seed a database
+    #[clap(long, value_name = "CMD")]
+    pre: Option<String>,
+
+    /// Shell command run after the recording stopped, e.g. to collect server-side metrics
+    #[clap(long, value_name = "CMD")]
+    post: Option<String>,
+
+    /// After storing the run in target/pprof, remove all but the N newest stored runs
+    #[clap(long, value_name = "N")]
+    keep_last: Option<usize>,
+
+    #[clap(flatten)]
+    compare: CompareArgs,
+
+    /// Fail unless the profile satisfies a budget like `my_crate::hot_path < 15%` (repeatable, see the man page)
+    #[clap(long = "assert", value_name = "EXPR", value_parser = assertions::parse)]
+    assertions: Vec<assertions::Assertion>,
+
+    /// Browser command used to open the viewers (defaults to $BROWSER or xdg-open)
+    #[clap(long)]
+    browser: Option<String>,
+
+    /// Upload the recording afterwards and print a shareable link
+    #[clap(long)]
+    upload: bool,
+
+    /// Upload to this object store URL (via HTTP PUT) instead of the Firefox Profiler's storage,
+    /// `s3://bucket/prefix` and `gs://bucket/prefix` archive all outputs with a metadata manifest
+    #[clap(long)]
+    upload_to: Option<String>,
+
+    #[clap(flatten)]
+    push: PushArgs,
+
     /// Arguments that are passed to the profiled application
     #[clap(last(true))]
     app_args: Vec<String>,
 }
 
+/// Options for comparing recordings against a baseline
+#[derive(Parser, Debug)]
+struct CompareArgs {
+    /// Compare the recording against a baseline saved with `cargo pprof baseline save`
+    #[clap(long, value_name = "NAME")]
+    compare_baseline: Option<String>,
+
+    /// Only list functions whose self share changed by at least this many percentage points
+    #[clap(long, value_name = "POINTS", default_value_t = 0.5, requires = "compare_baseline")]
+    delta_threshold: f64,
+
+    /// Fail if the total sample count grew by more than this many percent
+    #[clap(long, value_name = "PERCENT", requires = "compare_baseline")]
+    max_regression: Option<f64>,
+}
+
+/// Options for pushing recordings to continuous profiling services
+#[derive(Parser, Debug)]
+struct PushArgs {
+    /// Push the recording to a continuous profiling service afterwards
+    #[clap(long, value_enum)]
+    push: Option<PushTarget>,
+
+    /// URL of the profiling service
+    #[clap(long, requires = "push")]
+    server: Option<String>,
+
+    /// Application name the profile is filed under (defaults to the name of the current directory)
+    #[clap(long, requires = "push")]
+    app: Option<String>,
+
+    /// Additional label attached to pushed profiles (the git commit is added automatically)
+    #[clap(long = "label", value_name = "KEY=VALUE", requires = "push")]
+    labels: Vec<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// Sample with perf (requires access to performance counters)
+    Perf,
+    /// Simulate caches with valgrind's cachegrind (deterministic, works without hardware counters)
+    Cachegrind,
+    /// Sample user stacks with DTrace (FreeBSD and other systems without perf)
+    Dtrace,
+    /// Collect hotspots with Intel VTune (result directory can be opened in vtune-gui)
+    Vtune,
+    /// Profile with binutils' gprofng (works where perf is restricted)
+    Gprofng,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum HeapBackend {
+    /// Trace every allocation with heaptrack
+    Heaptrack,
+    /// Run under valgrind's dhat tool
+    Dhat,
+    /// Use the `dhat` crate compiled into the application (enabled by its "dhat-heap" feature)
+    DhatRs,
+    /// Take heap snapshots over time with valgrind's massif tool
+    Massif,
+    /// Preload bytehound's low-overhead allocator hooks (library path can be set via BYTEHOUND_LIB)
+    Bytehound,
+    /// Collect heap dumps of a jemalloc-based binary built with profiling support
+    Jemalloc,
+    /// Sample allocations with a preload library compiled on first use (needs only a C compiler)
+    Builtin,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 struct CompilerMessage {
     executable: String,
@@ -50,7 +1001,10 @@ fn resolve<T, E: Display>(result: Result<T, E>) -> T {
     match result {
         Ok(t) => t,
         Err(e) => {
-            eprintln!("{}", format!("Error: {}", e).red());
+            let message = e.to_string();
+            eprintln!("{}", format!("Error: {}", message).red());
+            messages::emit(Message::Error { message: &message });
+            messages::emit(Message::Finished { success: false });
             process::exit(1)
         },
     }
@@ -66,16 +1020,132 @@ fn resolve_status(status: process::ExitStatus) {
     }
 }
 
+impl PProfArgs {
+    /// Options of the application run by the selected action, if it runs one
+    fn run_args_mut(&mut self) -> Option<&mut RunArgs> {
+        match &mut self.action {
+            Some(Action::Heap(args)) => Some(&mut args.run),
+            Some(Action::Strace(args)) => Some(&mut args.run),
+            Some(Action::Energy(args)) => Some(&mut args.run),
+            Some(Action::Nextest(args)) => Some(&mut args.run),
+            Some(Action::Test(args)) => Some(&mut args.run),
+            Some(Action::Bench(args)) => Some(&mut args.run),
+            Some(Action::Causal(args)) => Some(&mut args.run),
+            Some(Action::Remote(args)) => Some(&mut args.run),
+            Some(Action::Watch(args)) => Some(&mut args.run),
+            Some(Action::Time(args)) => Some(&mut args.run),
+            Some(Action::Top(args)) => Some(&mut args.run),
+            Some(Action::Upload(_) | Action::CiComment(_) | Action::Import(_) | Action::Doctor(_)
+                | Action::Serve(_) | Action::List | Action::Show(_) | Action::Trend(_) | Action::Query(_) | Action::Clean(_) | Action::Baseline(_)
+                | Action::CompareCommits(_) | Action::Bisect(_) | Action::Tui(_) | Action::Annotate(_) | Action::Resymbolize(_) | Action::Size(_) | Action::Completions(_) | Action::Man) => None,
+            None => Some(&mut self.run),
+        }
+    }
+}
+
+impl Backend {
+    fn default_formats(self) -> Vec<Format> {
+        match self {
+            Backend::Perf => vec![Format::Trace],
+            Backend::Cachegrind => vec![Format::Summary],
+            Backend::Dtrace => vec![Format::Folded, Format::Gecko],
+            Backend::Vtune => vec![Format::Summary],
+            Backend::Gprofng => vec![Format::Summary, Format::Folded],
+        }
+    }
+}
+
+impl HeapBackend {
+    fn default_formats(self) -> Vec<Format> {
+        match self {
+            HeapBackend::Massif => vec![Format::Summary, Format::Folded, Format::Gecko],
+            HeapBackend::Jemalloc => vec![Format::Summary, Format::Folded, Format::Pprof],
+            _ => vec![Format::Summary, Format::Folded],
+        }
+    }
+}
+
 fn print_step(desc: &str) {
+    finish_step();
+    messages::emit(Message::StepStarted { step: desc });
+    *STEP.lock().unwrap() = Some((desc.to_string(), Instant::now()));
+    if verbosity() == 0 {
+        return;
+    }
     let msg = format!("=> {}", desc);
     eprintln!("\n{}", msg.green().bold());
 }
 
-fn open_firefox_profiler() {
-    let status = resolve(process::Command::new("firefox")
-        .arg("https://profiler.firefox.com")
-        .status());
-    resolve_status(status);
+/// Print how long the current step took in verbose mode
+fn finish_step() {
+    let Some((desc, started)) = STEP.lock().unwrap().take() else { return };
+    let seconds = started.elapsed().as_secs_f64();
+    messages::emit(Message::StepFinished { step: &desc, seconds });
+    if verbosity() >= 2 {
+        eprintln!("{}", format!("{} took {:.2}s", desc, seconds).dimmed());
+    }
+}
+
+/// 0 with `-q`, 1 by default and one more per `-v`
+fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::Relaxed)
+}
+
+/// Print the command line of a subprocess in verbose mode
+fn log_command(command: &process::Command) {
+    if verbosity() >= 2 {
+        eprintln!("{}", format!("$ {}", command_line(command)).dimmed());
+    }
+}
+
+/// Search the directories of `PATH` for an executable
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Abbreviated hash of the checked out commit, if inside a git repository
+fn git_commit() -> Option<String> {
+    let output = process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .stderr(process::Stdio::null())
+        .output()
+        .ok()?;
+    let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !commit.is_empty()).then_some(commit)
+}
+
+/// Quote an argument for a POSIX shell
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Quote an argument for a POSIX shell only if it contains special characters
+fn shell_word(arg: &str) -> String {
+    if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:+,@%".contains(c)) {
+        arg.to_string()
+    } else {
+        shell_quote(arg)
+    }
+}
+
+/// Shell command line equivalent to a command, including its working directory and environment
+fn command_line(command: &process::Command) -> String {
+    let quote = |arg: &std::ffi::OsStr| shell_word(&arg.to_string_lossy());
+    let mut parts = Vec::new();
+    if let Some(dir) = command.get_current_dir() {
+        parts.push(format!("cd {} &&", quote(dir.as_os_str())));
+    }
+    for (key, value) in command.get_envs() {
+        if let Some(value) = value {
+            parts.push(format!("{}={}", key.to_string_lossy(), quote(value)));
+        }
+    }
+    parts.push(quote(command.get_program()));
+    parts.extend(command.get_args().map(quote));
+    parts.join(" ")
 }
 
 fn add_to_cargo_toml() {
@@ -88,25 +1158,17 @@ fn add_to_cargo_toml() {
 }
 
 
-fn main() {
-    let Command::PProf(args) = Args::parse().command;
-
-    if args.open_firefox_profiler {
-        open_firefox_profiler();
-        process::exit(0);
-    } else if args.add {
-        add_to_cargo_toml();
-        process::exit(0);
-    }
-
-
-    let cargo_path = resolve(env::var("CARGO"));
+/// Build the binary with the profiling profile and return the path of the executable
+fn build(cargo_args: &[&str]) -> String {
+    build_with_rustflags(cargo_args, &[])
+}
 
+/// Like [`build`], appending flags to the `RUSTFLAGS` of the environment
+fn build_with_rustflags(cargo_args: &[&str], rustflags: &[&str]) -> String {
     print_step("Building binary");
-    let cargo_out = resolve(process::Command::new(cargo_path)
-        .arg("build")
-        .arg("--message-format=json-render-diagnostics")
-        .arg("--profile=profiling")
+    let mut command = build_command(cargo_args, rustflags);
+    log_command(&command);
+    let cargo_out = resolve(command
         .stderr(process::Stdio::inherit())
         .output());
     resolve_status(cargo_out.status);
@@ -117,35 +1179,442 @@ fn main() {
         Some(msg) => msg.executable.clone(),
         None => resolve(Err("Could not find executable".to_string())),
     };
-    let dir = match Path::new(&executable).parent() {
+    eprintln!("Binary found: {}", executable);
+    messages::emit(Message::BinaryBuilt { executable: &executable });
+    executable
+}
+
+/// The `cargo build` invocation of [`build_with_rustflags`]
+fn build_command(cargo_args: &[&str], rustflags: &[&str]) -> process::Command {
+    let cargo_path = resolve(env::var("CARGO"));
+    let mut command = process::Command::new(cargo_path);
+    if !rustflags.is_empty() {
+        let mut flags = env::var("RUSTFLAGS").unwrap_or_default();
+        for flag in rustflags {
+            flags.push(' ');
+            flags.push_str(flag);
+        }
+        command.env("RUSTFLAGS", flags.trim());
+    }
+    command.arg("build")
+        .arg("--message-format=json-render-diagnostics")
+        .arg("--profile=profiling")
+        .args(cargo_args);
+    if colored::control::SHOULD_COLORIZE.should_colorize() {
+        command.arg("--color=always");
+    } else {
+        command.arg("--color=never");
+    }
+    if verbosity() >= 3 {
+        command.arg("--verbose");
+    } else if verbosity() == 0 {
+        command.arg("--quiet");
+    }
+    command
+}
+
+/// Directory the build artifacts and recordings are stored in
+fn output_dir(executable: &str) -> &Path {
+    match Path::new(executable).parent() {
         Some(dir) => dir,
         None => resolve(Err("Could not determine output directory")),
-    };
-    let perf_out_path = dir.join("perf.data");
-    let trace_path = dir.join("perf.trace");
-    eprintln!("Binary found: {}", executable);
+    }
+}
+
+/// The recording of the perf backend, without the probes and the marker FIFO that need setup
+fn perf_recording<'a>(args: &PProfArgs, executable: &str, dir: &'a Path) -> perf::Recording<'a> {
+    let run = &args.run;
+    let mut recording = perf::Recording::new(dir, "perf", executable, &run.app_args, run.ignore_exit);
+    recording.record_args = perf::sampling_args(args.frequency, args.call_graph.as_deref(), &args.events);
+    recording.frequency = args.frequency.unwrap_or(perf::FREQUENCY);
+    if args.gpu {
+        recording.record_args.extend(gpu::record_args());
+    }
+    if args.formats.contains(&Format::Timechart) {
+        if args.events.is_empty() && !args.gpu {
+            recording.record_args.extend(["-e".to_string(), "cpu-clock".to_string()]);
+        }
+        recording.record_args.extend(sched::record_args());
+    }
+    if args.tracing {
+        let spans_path = dir.join("spans.json");
+        recording.env.push((spans::ENV_VAR.to_string(), spans_path.to_string_lossy().to_string()));
+    }
+    // Markers written by the application and polled counters are timestamped with the wall clock
+    if args.tracing || args.markers || args.cpu_usage || args.rss {
+        recording.record_args.extend(spans::PERF_CLOCK_ARGS.iter().map(|a| a.to_string()));
+    }
+    if args.jit {
+        recording.record_args.extend(jit::PERF_CLOCK_ARGS.iter().map(|a| a.to_string()));
+    }
+    recording
+}
+
+/// Build the binary and record CPU samples with the selected backend
+fn record(args: &PProfArgs) {
+    let run = &args.run;
+    let formats = report::formats_or(&args.formats, &args.backend.default_formats());
+    if (args.driver.is_some() || args.wait_for.is_some() || args.shutdown.is_some())
+        && (args.backend != Backend::Perf || args.container.is_some() || args.io || args.net || args.target.is_some()) {
+        resolve::<(), _>(Err("--driver, --wait-for and --shutdown are only supported for local CPU sampling with perf"));
+    }
+    if args.overhead && (args.backend != Backend::Perf || args.container.is_some() || args.io || args.net || args.target.is_some()) {
+        eprintln!("{}", "Warning: --overhead is only measured for local CPU sampling with perf".yellow());
+    }
+    if args.formats.contains(&Format::Timechart) && (args.backend != Backend::Perf || args.container.is_some() || args.io || args.net || args.target.is_some()) {
+        eprintln!("{}", "Warning: The timechart is only written for local CPU sampling with perf".yellow());
+    }
+    if args.jit && (args.backend != Backend::Perf || args.container.is_some() || args.io || args.net || args.target.is_some()) {
+        eprintln!("{}", "Warning: JIT-compiled frames are only named for local CPU sampling with perf".yellow());
+    }
+    if args.split_processes && (args.backend != Backend::Perf || args.container.is_some() || args.io || args.net || args.target.is_some()) {
+        eprintln!("{}", "Warning: Processes are only split for local CPU sampling with perf".yellow());
+    }
+
+    if let Some(name) = &args.container {
+        container::record(name, args.duration, &formats, run.ignore_exit);
+        return;
+    }
+
+    let mut cargo_args = Vec::new();
+    if let Some(target) = &args.target {
+        cargo_args.extend(["--target", target.as_str()]);
+    }
+    let executable = build(&cargo_args);
+    split_debuginfo::check(Path::new(&executable));
+    let dir = output_dir(&executable);
+
+    if args.target.as_deref().is_some_and(android::is_android_target) {
+        let trace_path = android::record(&executable, &run.app_args, dir, run.ignore_exit, args.frequency);
+        perf::convert(&trace_path, &formats, dir, "simpleperf");
+        if formats.contains(&Format::Trace) {
+            perf::print_trace_hint(&trace_path);
+        }
+        return;
+    }
+
+    if args.target.as_deref().is_some_and(wasm::is_wasm_target) {
+        let formats = report::formats_or(&args.formats, &[Format::Summary, Format::Gecko]);
+        wasm::record(&executable, &run.app_args, &formats, dir, run.ignore_exit);
+        return;
+    }
+
+    if args.io {
+        syscalls::record(syscalls::Mode::Io, &executable, run, &args.formats, dir);
+        return;
+    } else if args.net {
+        syscalls::record(syscalls::Mode::Net, &executable, run, &args.formats, dir);
+        return;
+    }
+
+    match args.backend {
+        Backend::Perf => {
+            let mut formats = formats;
+            let mut recording = perf_recording(args, &executable, dir);
+            let spans_path = dir.join("spans.json");
+            if args.tracing {
+                let _ = fs::remove_file(&spans_path);
+            }
+            if !args.sdt_probes.is_empty() {
+                markers::add_sdt_events(&executable, &args.sdt_probes, &mut recording.record_args);
+            }
+            let fifo = (args.markers || matches!(args.shutdown, Some(shutdown::Trigger::Marker(_)))).then(|| markers::Fifo::start(dir));
+            if let Some(fifo) = &fifo {
+                recording.env.push((markers::ENV_VAR.to_string(), fifo.path().to_string_lossy().to_string()));
+            }
+            if (args.gpu || args.tracing || args.markers || args.cpu_usage || args.rss || !args.sdt_probes.is_empty()) && !formats.contains(&Format::Gecko) {
+                formats.push(Format::Gecko);
+            }
+            let unprofiled = args.overhead.then(|| timing::run_unprofiled(&executable, run));
+            let poller = (args.cpu_usage || args.rss).then(|| counters::Poller::start(&executable, args.cpu_usage, args.rss));
+            let snapshotter = maps::Snapshotter::start(&executable, &maps::path_for(&recording.data));
+            let mut ready_after = None;
+            let (trace_path, profiled) = match (&args.driver, &args.wait_for) {
+                (None, None) if let Some(trigger) = &args.shutdown => {
+                    (shutdown::record(&recording, &executable, trigger, Duration::from_secs(args.duration), fifo.as_ref()), None)
+                },
+                (None, probe) if args.startup => {
+                    let limit = Duration::from_secs(if probe.is_some() { args.wait_timeout } else { args.duration });
+                    let (trace_path, startup_ready) = ready::record_startup(&recording, probe.as_ref(), limit);
+                    ready_after = startup_ready;
+                    (trace_path, None)
+                },
+                (Some(_), _) => (driver::record(&recording, args), None),
+                (None, Some(probe)) => (ready::record(&recording, probe, Duration::from_secs(args.wait_timeout)), None),
+                (None, None) => {
+                    let (trace_path, profiled) = perf::record_measured(&recording, None);
+                    (trace_path, Some(profiled))
+                },
+            };
+            let mut markers = if args.tracing { spans::read(&spans_path) } else { Vec::new() };
+            markers.extend(fifo.map(markers::Fifo::finish).unwrap_or_default());
+            let counters = poller.map(counters::Poller::finish).unwrap_or_default();
+            snapshotter.finish();
+            if args.async_tasks {
+                resolve(tasks::attribute(&trace_path, &spans_path));
+            }
+            perf::convert_with_markers(&trace_path, &formats, dir, "perf", &markers, &counters);
+            if args.split_processes {
+                perf::split_processes(&trace_path, &formats, dir, "perf");
+            }
+            if args.startup && formats.contains(&Format::Summary) {
+                startup::print_phases(&trace_path, ready_after);
+            }
+            if formats.contains(&Format::Trace) {
+                perf::print_trace_hint(&trace_path);
+            }
+            if let (Some(unprofiled), Some(profiled)) = (unprofiled, profiled) {
+                timing::print_overhead(&unprofiled, &profiled, args);
+            }
+        },
+        Backend::Cachegrind => {
+            if formats.contains(&Format::Trace) {
+                eprintln!("{}", "Warning: the cachegrind backend does not produce traces".yellow());
+            }
+            let profile = cachegrind::record(&executable, &run.app_args, dir, run.ignore_exit);
+            report::emit(&profile, &formats, dir, "cachegrind");
+        },
+        Backend::Dtrace => {
+            if formats.contains(&Format::Trace) {
+                eprintln!("{}", "Warning: the dtrace backend does not produce perf traces, use --format gecko".yellow());
+            }
+            let profile = dtrace::record(&executable, &run.app_args, dir, run.ignore_exit);
+            report::emit(&profile, &formats, dir, "dtrace");
+            if formats.contains(&Format::Gecko) {
+                let path = dir.join("dtrace.json");
+                let gecko = gecko::from_profile(&profile, &executable, 1000.0 / dtrace::FREQUENCY as f64);
+                resolve(gecko::write(&gecko, &path));
+                report::print_output(Format::Gecko, &path);
+            }
+        },
+        Backend::Vtune => {
+            if formats.iter().any(|f| matches!(f, Format::Trace | Format::Gecko)) {
+                eprintln!("{}", "Warning: the vtune backend only reports time per function, without traces".yellow());
+            }
+            let profile = vtune::record(&executable, &run.app_args, dir, run.ignore_exit);
+            report::emit(&profile, &formats, dir, "vtune");
+        },
+        Backend::Gprofng => {
+            if formats.contains(&Format::Trace) {
+                eprintln!("{}", "Warning: the gprofng backend does not produce traces".yellow());
+            }
+            let profile = gprofng::record(&executable, &run.app_args, dir, run.ignore_exit);
+            report::emit(&profile, &formats, dir, "gprofng");
+        },
+    }
+}
+
+fn main() {
+    let Command::PProf(mut args) = Args::parse().command;
+    VERBOSITY.store(if args.quiet { 0 } else { 1 + args.verbose }, Ordering::Relaxed);
+    messages::set_format(args.message_format);
+    match args.color {
+        ColorChoice::Always => colored::control::set_override(true),
+        ColorChoice::Never => colored::control::set_override(false),
+        ColorChoice::Auto => (),
+    }
+    if let Some(path) = &args.perf_path {
+        perf::set_binary(path.clone());
+    }
+    if let Some(urls) = &args.debuginfod {
+        symbols::set_debuginfod(urls.clone());
+    }
+    symbols::set_search_paths(args.symfs.clone(), args.debug_dirs.clone());
+    kernel::set_options(args.kernel_symbols, args.vmlinux.clone());
+    demangle::set_short_names(args.short_names);
+    inline::set_enabled(args.inline_frames);
+    report::set_flat(args.flat);
+    pools::set_per_thread(args.per_thread);
+    if let Some(min_weight) = args.min_weight {
+        perf::set_min_weight(min_weight);
+    }
+    if args.collapse_generics || args.keep_generics {
+        demangle::set_collapse_generics(args.collapse_generics);
+    }
+    if let Some(path) = &args.firefox_path {
+        viewer::set_firefox_path(path.clone());
+    }
+
+    if args.open_firefox_profiler {
+        viewer::open_url(server::PROFILER_URL, args.run.browser.as_deref());
+        process::exit(0);
+    } else if args.add {
+        add_to_cargo_toml();
+        process::exit(0);
+    } else if args.emit_tasks {
+        vscode::emit_tasks();
+        process::exit(0);
+    }
 
-    print_step("Running program with perf");
-    let status = resolve(process::Command::new("perf")
-        .arg("record")
-        .arg(format!("--output={}", perf_out_path.to_string_lossy()))
-        .args(["-g", "-F", "999"])
-        .arg(executable)
-        .args(args.app_args)
-        .status());
-    if !args.ignore_exit {
-        resolve_status(status);
-    }
-
-    print_step("Converting data to trace format");
-    let trace_file = resolve(File::create(&trace_path));
-    let status = resolve(process::Command::new("perf")
-        .arg("script")
-        .args(["-F", "+pid"])
-        .arg(format!("--input={}", perf_out_path.to_string_lossy()))
-        .stdout(process::Stdio::from(trace_file))
-        .status());
-    resolve_status(status);
-    println!("Trace file: {}", trace_path.to_string_lossy().cyan());
-    println!("This file can be viewed using the Firefox Profiler ({})", "https://profiler.firefox.com".bright_blue());
+    // The settings of --startup take precedence over the project defaults, only the command line overrides them
+    if args.startup {
+        args.frequency = args.frequency.or(Some(startup::FREQUENCY));
+        args.call_graph = args.call_graph.or_else(|| Some(startup::CALL_GRAPH.to_string()));
+    }
+    let config = config::load();
+    config.apply(&mut args);
+    if let Some(run) = args.run_args_mut() {
+        app::configure(run, &config.env());
+    }
+    if args.dry_run {
+        dry_run::run(&args);
+        process::exit(0);
+    }
+
+    // Watch passes the hooks on to the recording of every change
+    if !matches!(args.action, Some(Action::Watch(_)))
+        && let Some(hook) = args.run_args_mut().and_then(|r| r.pre.clone()) {
+        app::run_hook("pre", &hook);
+    }
+
+    let started = SystemTime::now();
+    let run = match &args.action {
+        Some(Action::Heap(heap_args)) => {
+            heap::run(heap_args);
+            &heap_args.run
+        },
+        Some(Action::Strace(strace_args)) => {
+            strace::run(strace_args);
+            &strace_args.run
+        },
+        Some(Action::Energy(energy_args)) => {
+            energy::run(energy_args);
+            &energy_args.run
+        },
+        Some(Action::Nextest(nextest_args)) => {
+            nextest::run(nextest_args);
+            &nextest_args.run
+        },
+        Some(Action::Test(test_args)) => {
+            libtest::run(test_args);
+            &test_args.run
+        },
+        Some(Action::Bench(bench_args)) => {
+            bench::run(bench_args);
+            &bench_args.run
+        },
+        Some(Action::Causal(causal_args)) => {
+            causal::run(causal_args);
+            &causal_args.run
+        },
+        Some(Action::Remote(remote_args)) => {
+            remote::run(remote_args);
+            &remote_args.run
+        },
+        Some(Action::Time(time_args)) => {
+            timing::run(time_args);
+            &time_args.run
+        },
+        Some(Action::Top(top_args)) => {
+            top::run(top_args);
+            &top_args.run
+        },
+        Some(Action::Upload(upload_args)) => {
+            upload::run(upload_args);
+            process::exit(0);
+        },
+        Some(Action::CiComment(ci_args)) => {
+            ci::run(ci_args);
+            process::exit(0);
+        },
+        Some(Action::Import(import_args)) => {
+            import::run(import_args);
+            process::exit(0);
+        },
+        Some(Action::Doctor(doctor_args)) => {
+            doctor::run(doctor_args);
+            process::exit(0);
+        },
+        Some(Action::Watch(watch_args)) => {
+            watch::run(watch_args);
+            process::exit(0);
+        },
+        Some(Action::Serve(serve_args)) => {
+            serve::run(serve_args);
+            process::exit(0);
+        },
+        Some(Action::List) => {
+            store::list();
+            process::exit(0);
+        },
+        Some(Action::Show(show_args)) => {
+            store::show(show_args);
+            process::exit(0);
+        },
+        Some(Action::Trend(trend_args)) => {
+            trend::run(trend_args);
+            process::exit(0);
+        },
+        Some(Action::Query(query_args)) => {
+            query::run(query_args);
+            process::exit(0);
+        },
+        Some(Action::Clean(clean_args)) => {
+            store::clean(clean_args);
+            process::exit(0);
+        },
+        Some(Action::CompareCommits(compare_args)) => {
+            compare_commits::run(compare_args);
+            process::exit(0);
+        },
+        Some(Action::Bisect(bisect_args)) => {
+            bisect::run(bisect_args);
+            process::exit(0);
+        },
+        Some(Action::Baseline(baseline_args)) => {
+            baseline::run(baseline_args);
+            process::exit(0);
+        },
+        Some(Action::Tui(tui_args)) => {
+            tui::run(tui_args);
+            process::exit(0);
+        },
+        Some(Action::Annotate(annotate_args)) => {
+            annotate::run(annotate_args);
+            process::exit(0);
+        },
+        Some(Action::Resymbolize(resymbolize_args)) => {
+            resymbolize::run(resymbolize_args);
+            process::exit(0);
+        },
+        Some(Action::Size(size_args)) => {
+            size::run(size_args);
+            process::exit(0);
+        },
+        Some(Action::Completions(completions_args)) => {
+            completions::run(completions_args);
+            process::exit(0);
+        },
+        Some(Action::Man) => {
+            man::run();
+            process::exit(0);
+        },
+        None => {
+            record(&args);
+            &args.run
+        },
+    };
+    app::finish_logs();
+    if let Some(hook) = &run.post {
+        app::run_hook("post", hook);
+    }
+    store::save(started, run.keep_last);
+    baseline::compare(&run.compare);
+    assertions::check(&run.assertions);
+    if run.upload || run.upload_to.is_some() {
+        upload::upload_outputs(run.upload_to.as_deref().or(config.upload_to.as_deref()));
+    }
+    push::push_outputs(&run.push, started);
+    if run.open {
+        viewer::open_outputs(run.browser.as_deref());
+    }
+    if run.archive {
+        perf::archive();
+    }
+    if run.open_hotspot {
+        viewer::open_hotspot();
+    }
+    finish_step();
+    messages::emit(Message::Finished { success: true });
+    app::mirror_exit();
 }
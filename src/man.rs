@@ -0,0 +1,172 @@
+//! Man page generated from the command line definition
+
+use clap::CommandFactory;
+
+use crate::config;
+use crate::markers;
+use crate::spans;
+use crate::{Args, CARGO_TOML_SNIPPET, resolve};
+
+/// Stages of a recording, in the order they run
+const PIPELINE: &[(&str, &str)] = &[
+    ("Build", "The current package is built with `cargo build --profile=profiling`, adding `-C force-frame-pointers=yes` to RUSTFLAGS so stacks can be walked without DWARF unwinding."),
+    ("Record", "The binary is run under the selected backend (perf record by default), with the arguments after `--`. Markers, tracing spans and SDT probes are set up before the application starts."),
+    ("Convert", "The recording is symbolized with `perf script` into a .trace file next to the binary, and converted into the requested formats (folded stacks, summary, Firefox Profiler JSON, pprof)."),
+    ("Share", "The results are optionally opened in the Firefox Profiler (--open), uploaded (--upload), pushed to a continuous profiling server (--push) or archived with their build IDs (--archive)."),
+];
+
+/// Environment variables read by cargo-pprof or set for the application
+const ENVIRONMENT: &[(&str, &str)] = &[
+    ("CARGO", "Cargo binary used for building, set by cargo when running `cargo pprof`."),
+    ("RUSTFLAGS", "Extended with the flags needed for profiling when building."),
+    ("BROWSER", "Browser opening profiles if --browser and the configuration do not set one."),
+    ("DEBUGINFOD_URLS", "Servers perf fetches debug info of system libraries from."),
+    ("XDG_CONFIG_HOME", "Directory of the user configuration (defaults to ~/.config)."),
+    (markers::ENV_VAR, "Set for the application with --markers: FIFO to write markers to."),
+    (spans::ENV_VAR, "Set for the application with --tracing: file to write tracing spans to."),
+    ("BYTEHOUND_LIB", "Path of libbytehound.so for `heap --backend bytehound`."),
+    ("CC", "C compiler building the allocation sampler of `heap --backend builtin` (defaults to cc)."),
+    ("CARGO_PPROF_ALLOC_LOG, CARGO_PPROF_ALLOC_RATE", "Set for the application with `heap --backend builtin`: log prefix and sample rate of the allocation sampler."),
+    ("GITHUB_TOKEN, GITHUB_REPOSITORY, GITHUB_REF, GITHUB_EVENT_PATH, GITHUB_API_URL", "Pull request and credentials of `ci-comment`."),
+    ("GRAFANA_PROFILES_URL, GRAFANA_PROFILES_USER, GRAFANA_PROFILES_API_KEY", "Server and credentials of `--push grafana`, take precedence over `[grafana]` in the configuration."),
+    ("PARCA_BEARER_TOKEN", "Credentials of `--push parca`."),
+];
+
+/// Exit codes and when they are returned
+const EXIT_STATUS: &[(&str, &str)] = &[
+    ("0", "The recording and all conversions succeeded."),
+    ("1", "A step failed, the application exited with an error (without --ignore-exit), or `doctor` found errors."),
+    ("N", "With --mirror-exit, the exit code of the application (128 + signal number if it was killed by a signal)."),
+];
+
+
+pub fn run() {
+    let mut command = Args::command();
+    command.build();
+    let Some(pprof) = command.find_subcommand("pprof") else {
+        resolve(Err("Missing pprof command"))
+    };
+
+    let mut page = String::new();
+    page.push_str(&format!(".TH CARGO-PPROF 1 \"\" \"cargo-pprof {}\"\n", env!("CARGO_PKG_VERSION")));
+    section(&mut page, "NAME");
+    page.push_str(&format!("cargo\\-pprof \\- {}\n", escape(&pprof.get_about().map(|a| a.to_string()).unwrap_or_default())));
+
+    section(&mut page, "SYNOPSIS");
+    page.push_str(&format!("{}\n", escape("cargo pprof [OPTIONS] [-- APP_ARGS...]")));
+    page.push_str(".br\n");
+    page.push_str(&format!("{}\n", escape("cargo pprof <SUBCOMMAND> [OPTIONS] [-- APP_ARGS...]")));
+
+    section(&mut page, "DESCRIPTION");
+    page.push_str("A recording runs through the following stages:\n");
+    for (stage, description) in PIPELINE {
+        item(&mut page, stage, description);
+    }
+
+    section(&mut page, "OPTIONS");
+    options(&mut page, pprof, true);
+
+    section(&mut page, "SUBCOMMANDS");
+    for subcommand in pprof.get_subcommands().filter(|c| !c.is_hide_set() && c.get_name() != "help") {
+        page.push_str(&format!(".SS {}\n", escape(subcommand.get_name())));
+        page.push_str(&format!("{}\n", text(&subcommand.get_about().map(|a| a.to_string()).unwrap_or_default())));
+        options(&mut page, subcommand, false);
+    }
+
+    section(&mut page, "PROFILE");
+    page.push_str(&format!("{}\n", text("The build uses the following profile, which `cargo pprof --add` appends to Cargo.toml:")));
+    literal(&mut page, CARGO_TOML_SNIPPET);
+
+    section(&mut page, "CONFIGURATION");
+    let user_config = config::user_config_path()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|| "~/.config/cargo-pprof/config.toml".to_string());
+    page.push_str(&format!("{}\n", text(&format!("Defaults are read from the [package.metadata.pprof] table of the package and from {}, \
+        with the package taking precedence and command line options overriding both.", user_config))));
+
+    section(&mut page, "ASSERTIONS");
+    page.push_str(&format!("{}\n", text("Each --assert has the form `[self] PATTERN OP VALUE[%]` with OP one of <, <=, > and >=. \
+        PATTERN matches all functions whose name contains it. The value is the share of samples with a matching function \
+        on the stack, or with `self` as the innermost frame. Without `%` the number of samples is compared instead.")));
+
+    section(&mut page, "ENVIRONMENT");
+    for (name, description) in ENVIRONMENT {
+        item(&mut page, name, description);
+    }
+
+    section(&mut page, "EXIT STATUS");
+    for (code, description) in EXIT_STATUS {
+        item(&mut page, code, description);
+    }
+
+    print!("{}", page);
+}
+
+fn section(page: &mut String, name: &str) {
+    page.push_str(&format!(".SH {}\n", name));
+}
+
+/// Tagged paragraph
+fn item(page: &mut String, tag: &str, description: &str) {
+    page.push_str(&format!(".TP\n\\fB{}\\fR\n{}\n", escape(tag), text(description)));
+}
+
+/// Unfilled block, for snippets
+fn literal(page: &mut String, content: &str) {
+    page.push_str(".PP\n.RS\n.nf\n");
+    for line in content.lines() {
+        page.push_str(&format!("{}\n", text(line)));
+    }
+    page.push_str(".fi\n.RE\n");
+}
+
+/// Options of a command, without the global ones except on the command they are defined on
+fn options(page: &mut String, command: &clap::Command, globals: bool) {
+    for arg in command.get_arguments().filter(|a| !a.is_hide_set() && (globals || !a.is_global_set())) {
+        let mut tag = match (arg.get_short(), arg.get_long()) {
+            (Some(short), Some(long)) => format!("-{}, --{}", short, long),
+            (Some(short), None) => format!("-{}", short),
+            (None, Some(long)) => format!("--{}", long),
+            (None, None) => arg.get_value_names().and_then(|n| n.first()).map(|n| n.to_string())
+                .unwrap_or_else(|| arg.get_id().to_string().to_uppercase()),
+        };
+        if !arg.is_positional() && arg.get_action().takes_values() {
+            let name = arg.get_value_names().and_then(|n| n.first()).map(|n| n.to_string())
+                .unwrap_or_else(|| arg.get_id().to_string().to_uppercase());
+            tag.push_str(&format!(" <{}>", name));
+        }
+
+        let mut description = arg.get_long_help().or(arg.get_help()).map(|h| h.to_string()).unwrap_or_default();
+        let values: Vec<String> = arg.get_possible_values().iter()
+            .filter(|v| !v.is_hide_set())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        if !values.is_empty() && arg.get_action().takes_values() {
+            description.push_str(&format!(" [possible values: {}]", values.join(", ")));
+        }
+        let defaults: Vec<String> = arg.get_default_values().iter().map(|v| v.to_string_lossy().to_string()).collect();
+        if !defaults.is_empty() && arg.get_action().takes_values() {
+            description.push_str(&format!(" [default: {}]", defaults.join(", ")));
+        }
+        item(page, &tag, &description);
+    }
+}
+
+/// Text escaped for roff, protecting lines that would start with a control character
+fn text(s: &str) -> String {
+    s.lines()
+        .map(|line| {
+            let line = escape(line);
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('-', "\\-")
+}
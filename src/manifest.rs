@@ -0,0 +1,51 @@
+//! Package information from `cargo metadata`
+
+use std::{env, path::PathBuf, process};
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug)]
+pub struct Metadata {
+    pub target_directory: PathBuf,
+    pub packages: Vec<Package>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Package {
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+    /// Contents of the `[package.metadata]` table
+    #[serde(default)]
+    pub metadata: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+
+/// Metadata of the workspace the current directory belongs to, without dependencies
+pub fn load() -> Result<Metadata, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = process::Command::new(cargo)
+        .args(["metadata", "--no-deps", "--format-version=1"])
+        .output()
+        .map_err(|e| format!("Could not run cargo metadata ({})", e))?;
+    if !output.status.success() {
+        return Err(format!("cargo metadata failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| format!("Unexpected output of cargo metadata ({})", e))
+}
+
+impl Metadata {
+    /// The package of the current directory, or the first one of the workspace
+    pub fn current_package(&self) -> Option<&Package> {
+        let cwd = env::current_dir().unwrap_or_default();
+        self.packages.iter()
+            .filter(|p| p.manifest_path.parent().is_some_and(|d| cwd.starts_with(d)))
+            .max_by_key(|p| p.manifest_path.components().count())
+            .or(self.packages.first())
+    }
+}
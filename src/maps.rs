@@ -0,0 +1,274 @@
+//! Snapshot of the files mapped into the application, stored next to the recording
+//!
+//! The memory maps of the application's processes are polled while it runs, which also catches
+//! libraries that are opened later on. Every mapped file is stored with its GNU build-id, so a
+//! later symbolization on another machine or after a rebuild can tell whether the files it finds
+//! are still the ones that were recorded, and look up the right ones by build-id otherwise.
+
+use std::{collections::{BTreeMap, BTreeSet}, env, fs::{self, File}, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::Duration};
+
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::counters;
+
+/// Time between two reads of the memory maps
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// ELF segment types
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// ELF note type of the GNU build-id
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Snapshot written by the last [`Snapshotter::finish`]
+static LAST: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Snapshot {
+    pub processes: Vec<Process>,
+    /// Every mapped file with its build-id, if it has one
+    pub modules: Vec<Module>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Process {
+    pub pid: u32,
+    pub mappings: Vec<Mapping>,
+}
+
+/// Executable mapping of a file, as listed in `/proc/<pid>/maps`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    /// Offset of the mapping in the file
+    pub offset: u64,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Module {
+    pub path: String,
+    pub build_id: Option<String>,
+}
+
+/// Entry of the program header table of an ELF file
+struct Segment {
+    kind: u32,
+    /// Offset and size in the file
+    offset: u64,
+    size: u64,
+    /// Virtual address the segment is loaded at
+    address: u64,
+}
+
+pub struct Snapshotter {
+    path: PathBuf,
+    done: Arc<AtomicBool>,
+    mappings: Arc<Mutex<BTreeMap<u32, BTreeSet<Mapping>>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+
+impl Snapshotter {
+    /// Start reading the memory maps of the processes running `executable` in the background,
+    /// replacing the snapshot at `path` of an earlier recording
+    pub fn start(executable: &str, path: &Path) -> Snapshotter {
+        let _ = fs::remove_file(path);
+        let executable = fs::canonicalize(executable).unwrap_or_else(|_| PathBuf::from(executable));
+        let done = Arc::new(AtomicBool::new(false));
+        let mappings = Arc::new(Mutex::new(BTreeMap::new()));
+        let reader = {
+            let (done, mappings) = (done.clone(), mappings.clone());
+            thread::spawn(move || poll(&executable, &done, &mappings))
+        };
+        Snapshotter { path: path.to_path_buf(), done, mappings, reader }
+    }
+
+    /// Stop reading and write the snapshot, with the build-ids of the mapped files
+    pub fn finish(self) {
+        self.done.store(true, Ordering::SeqCst);
+        let _ = self.reader.join();
+        let mappings = Arc::try_unwrap(self.mappings)
+            .map(|m| m.into_inner().unwrap())
+            .unwrap_or_default();
+        if mappings.is_empty() {
+            return;
+        }
+
+        let paths: BTreeSet<&str> = mappings.values().flatten().map(|m| m.path.as_str()).collect();
+        let modules = paths.into_iter()
+            .map(|path| Module { path: path.to_string(), build_id: build_id(Path::new(path)) })
+            .collect();
+        let processes = mappings.iter()
+            .map(|(pid, mappings)| Process { pid: *pid, mappings: mappings.iter().cloned().collect() })
+            .collect();
+        let snapshot = Snapshot { processes, modules };
+        match serde_json::to_string_pretty(&snapshot).map(|json| fs::write(&self.path, json)) {
+            Ok(Ok(())) => *LAST.lock().unwrap() = Some(self.path),
+            _ => eprintln!("{}", format!("Warning: Could not write the memory maps to {}", self.path.to_string_lossy()).yellow()),
+        }
+    }
+}
+
+fn poll(executable: &Path, done: &AtomicBool, mappings: &Mutex<BTreeMap<u32, BTreeSet<Mapping>>>) {
+    while !done.load(Ordering::SeqCst) {
+        for pid in counters::processes(executable) {
+            let Ok(maps) = fs::read_to_string(format!("/proc/{}/maps", pid)) else { continue };
+            mappings.lock().unwrap().entry(pid).or_default().extend(maps.lines().filter_map(parse_mapping));
+        }
+        thread::sleep(INTERVAL);
+    }
+}
+
+/// Parse an executable file mapping (`<start>-<end> r-xp <offset> <dev> <inode> <path>`)
+pub fn parse_mapping(line: &str) -> Option<Mapping> {
+    let mut fields = line.split_whitespace();
+    let (start, end) = fields.next()?.split_once('-')?;
+    let permissions = fields.next()?;
+    let offset = fields.next()?;
+    let path = fields.nth(2)?;
+    if !permissions.contains('x') || !path.starts_with('/') {
+        return None;
+    }
+    Some(Mapping {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        offset: u64::from_str_radix(offset, 16).ok()?,
+        path: path.to_string(),
+    })
+}
+
+/// The snapshot written during this run, if any
+pub fn last() -> Option<PathBuf> {
+    LAST.lock().unwrap().clone()
+}
+
+/// Snapshot stored next to a recording, `<stem>.maps.json` for `<stem>.data`
+pub fn path_for(data: &Path) -> PathBuf {
+    data.with_extension("maps.json")
+}
+
+pub fn load(path: &Path) -> Result<Snapshot, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Could not read {} ({})", path.to_string_lossy(), e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid memory maps {} ({})", path.to_string_lossy(), e))
+}
+
+/// Warn about every recorded file that is missing or was replaced, looking for it below `symfs`
+///
+/// Returns the files with a different build-id, which can still be found in perf's build-id cache.
+pub fn verify(snapshot: &Snapshot, symfs: Option<&Path>) -> Vec<Module> {
+    let mut replaced = Vec::new();
+    for module in &snapshot.modules {
+        let Some(recorded) = &module.build_id else { continue };
+        let path = match symfs {
+            Some(root) => root.join(module.path.trim_start_matches('/')),
+            None => PathBuf::from(&module.path),
+        };
+        match build_id(&path) {
+            Some(current) if current == *recorded => (),
+            Some(_) => {
+                let cached = cache_path(recorded).filter(|p| p.exists());
+                match &cached {
+                    Some(cached) => eprintln!("{}", format!("Warning: {} was rebuilt since the recording, using {}",
+                        path.to_string_lossy(), cached.to_string_lossy()).yellow()),
+                    None => eprintln!("{}", format!("Warning: {} was rebuilt since the recording (build-id {}), its symbols may be wrong",
+                        path.to_string_lossy(), recorded).yellow()),
+                }
+                replaced.push(module.clone());
+            },
+            None if !path.exists() => {
+                eprintln!("{}", format!("Warning: {} (build-id {}) is missing", path.to_string_lossy(), recorded).yellow());
+            },
+            None => (),
+        }
+    }
+    replaced
+}
+
+/// Location of a file in perf's build-id cache
+pub fn cache_path(build_id: &str) -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    let (dir, rest) = build_id.split_at_checked(2)?;
+    Some(PathBuf::from(home).join(".debug").join(".build-id").join(dir).join(rest).join("elf"))
+}
+
+/// GNU build-id of an ELF file in hex, read from its note segments
+pub fn build_id(path: &Path) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let (segments, little) = segments(&mut file)?;
+    for segment in segments.iter().filter(|s| s.kind == PT_NOTE) {
+        let mut notes = vec![0u8; segment.size.min(1 << 16) as usize];
+        file.seek(SeekFrom::Start(segment.offset)).ok()?;
+        file.read_exact(&mut notes).ok()?;
+        if let Some(id) = find_build_id(&notes, little) {
+            return Some(id);
+        }
+    }
+    None
+}
+
+/// Virtual address of an offset in an ELF file, from the loadable segment containing it
+pub fn file_address(path: &Path, offset: u64) -> Option<u64> {
+    let (segments, _) = segments(&mut File::open(path).ok()?)?;
+    segments.iter()
+        .find(|s| s.kind == PT_LOAD && (s.offset..s.offset + s.size).contains(&offset))
+        .map(|s| s.address + offset - s.offset)
+}
+
+/// The program headers of an ELF file, and whether it is little endian
+fn segments(file: &mut File) -> Option<(Vec<Segment>, bool)> {
+    let mut header = [0u8; 64];
+    file.read_exact(&mut header).ok()?;
+    if &header[..4] != b"\x7fELF" {
+        return None;
+    }
+    let is64 = header[4] == 2;
+    let little = header[5] == 1;
+    let read = |bytes: &[u8]| read_int(bytes, little);
+    let (phoff, phentsize, phnum) = if is64 {
+        (read(&header[32..40]), read(&header[54..56]), read(&header[56..58]))
+    } else {
+        (read(&header[28..32]), read(&header[42..44]), read(&header[44..46]))
+    };
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let mut entry = vec![0u8; phentsize as usize];
+        file.seek(SeekFrom::Start(phoff + i * phentsize)).ok()?;
+        file.read_exact(&mut entry).ok()?;
+        segments.push(if is64 {
+            Segment { kind: read(&entry[0..4]) as u32, offset: read(&entry[8..16]), address: read(&entry[16..24]), size: read(&entry[32..40]) }
+        } else {
+            Segment { kind: read(&entry[0..4]) as u32, offset: read(&entry[4..8]), address: read(&entry[8..12]), size: read(&entry[16..20]) }
+        });
+    }
+    Some((segments, little))
+}
+
+fn read_int(bytes: &[u8], little: bool) -> u64 {
+    bytes.iter().enumerate()
+        .fold(0u64, |value, (i, b)| value | u64::from(*b) << (8 * if little { i } else { bytes.len() - 1 - i }))
+}
+
+/// Walk the notes of a segment (`namesz`, `descsz`, `type`, name and descriptor padded to 4 bytes)
+fn find_build_id(notes: &[u8], little: bool) -> Option<String> {
+    let read = |bytes: &[u8]| read_int(bytes, little);
+    let align = |n: usize| n.div_ceil(4) * 4;
+    let mut pos = 0;
+    while pos + 12 <= notes.len() {
+        let name_size = read(&notes[pos..pos + 4]) as usize;
+        let desc_size = read(&notes[pos + 4..pos + 8]) as usize;
+        let kind = read(&notes[pos + 8..pos + 12]) as u32;
+        let name = notes.get(pos + 12..pos + 12 + name_size)?;
+        let desc_start = pos + 12 + align(name_size);
+        let desc = notes.get(desc_start..desc_start + desc_size)?;
+        if kind == NT_GNU_BUILD_ID && name == b"GNU\0" {
+            return Some(desc.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+        pos = desc_start + align(desc_size);
+    }
+    None
+}
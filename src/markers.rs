@@ -0,0 +1,177 @@
+//! User-defined markers, from USDT (sdt) probes in the binary or lines written to a FIFO
+
+use std::{collections::{HashMap, HashSet}, fs::{self, File, OpenOptions}, io::{BufRead, BufReader}, path::{Path, PathBuf}, process, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::gecko::Marker;
+use crate::perf;
+use crate::profile::PerfEvent;
+use crate::{print_step, resolve, resolve_status};
+
+/// Environment variable that tells the application where the marker FIFO is
+pub const ENV_VAR: &str = "CARGO_PPROF_MARKERS";
+
+/// Prefix perf gives events of probes defined in the binary's `.note.stapsdt` section
+const SDT_PREFIX: &str = "sdt_";
+
+/// Reader of the marker FIFO, which the application writes `begin <name>`, `end <name>` or
+/// `<name>` (an instant marker) lines to
+pub struct Fifo {
+    path: PathBuf,
+    done: Arc<AtomicBool>,
+    markers: Arc<Mutex<Vec<Marker>>>,
+    /// Names of the markers read so far, begun or instant
+    seen: Arc<Mutex<HashSet<String>>>,
+    reader: thread::JoinHandle<()>,
+}
+
+
+/// Add the `-e` arguments for the given `provider:name` probes after registering them with perf
+pub fn add_sdt_events(executable: &str, probes: &[String], record_args: &mut Vec<String>) {
+    print_step("Registering sdt probes");
+    for mut command in sdt_commands(executable, probes) {
+        let status = resolve(command.status());
+        resolve_status(status);
+    }
+    add_sdt_args(probes, record_args);
+}
+
+/// The perf invocations registering the probes of the binary
+pub fn sdt_commands(executable: &str, probes: &[String]) -> Vec<process::Command> {
+    let mut buildid_cache = process::Command::new(perf::binary());
+    buildid_cache.arg("buildid-cache").arg(format!("--add={}", executable));
+    let mut commands = vec![buildid_cache];
+    for probe in probes {
+        let mut command = process::Command::new(perf::binary());
+        command.args(["probe", "--quiet", "-x", executable, "--add", &format!("{}{}", SDT_PREFIX, probe)]);
+        commands.push(command);
+    }
+    commands
+}
+
+/// Add the `-e` arguments recording the registered probes
+pub fn add_sdt_args(probes: &[String], record_args: &mut Vec<String>) {
+    if !record_args.iter().any(|a| a == "-e") {
+        record_args.extend(["-e".to_string(), "cpu-clock".to_string()]);
+    }
+    for probe in probes {
+        record_args.push("-e".to_string());
+        // Record every probe hit instead of sampling them with the CPU frequency
+        record_args.push(format!("{}{}/period=1/", SDT_PREFIX, probe));
+    }
+}
+
+pub fn is_sdt_event(event: &PerfEvent) -> bool {
+    event.event.starts_with(SDT_PREFIX)
+}
+
+/// Turn probe hits into markers, `<name>_start`/`<name>_end` (or `_begin`/`_end`) pairs become
+/// interval markers and all other probes instant markers
+pub fn from_sdt_events(events: &[PerfEvent]) -> Vec<Marker> {
+    let mut builder = Builder::default();
+    for event in events {
+        let name = event.event.trim_start_matches(SDT_PREFIX);
+        let name = name.split_once(':').map(|(_, n)| n).unwrap_or(name);
+        if let Some(base) = name.strip_suffix("_start").or_else(|| name.strip_suffix("_begin")) {
+            builder.begin(base, event.time, &event.details);
+        } else if let Some(base) = name.strip_suffix("_end") {
+            builder.end(base, event.time);
+        } else {
+            builder.instant(name, event.time, &event.details);
+        }
+    }
+    builder.finish()
+}
+
+impl Fifo {
+    /// Create the FIFO in `dir` and start collecting markers in the background
+    pub fn start(dir: &Path) -> Fifo {
+        let path = dir.join("markers.fifo");
+        let _ = fs::remove_file(&path);
+        let status = resolve(process::Command::new("mkfifo").arg(&path).status());
+        resolve_status(status);
+
+        let done = Arc::new(AtomicBool::new(false));
+        let markers = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let reader = {
+            let (path, done, markers, seen) = (path.clone(), done.clone(), markers.clone(), seen.clone());
+            thread::spawn(move || read_fifo(&path, &done, &markers, &seen))
+        };
+        Fifo { path, done, markers, seen, reader }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the application wrote a marker with this name yet
+    pub fn has_seen(&self, name: &str) -> bool {
+        self.seen.lock().unwrap().contains(name)
+    }
+
+    /// Stop reading and return the markers with times in seconds since the epoch
+    pub fn finish(self) -> Vec<Marker> {
+        self.done.store(true, Ordering::SeqCst);
+        // Opening the write end wakes up the reader if the application never opened the FIFO
+        let _ = OpenOptions::new().write(true).open(&self.path);
+        let _ = self.reader.join();
+        let _ = fs::remove_file(&self.path);
+        Arc::try_unwrap(self.markers)
+            .map(|m| m.into_inner().unwrap())
+            .unwrap_or_default()
+    }
+}
+
+/// Read lines until [`Fifo::finish`] is called, reopening the FIFO whenever all writers closed it
+fn read_fifo(path: &Path, done: &AtomicBool, markers: &Mutex<Vec<Marker>>, seen: &Mutex<HashSet<String>>) {
+    let mut builder = Builder::default();
+    // Always open at least once, as finishing opens the write end and waits for a reader
+    loop {
+        let Ok(file) = File::open(path) else { break };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+            let line = line.trim();
+            if let Some(name) = line.strip_prefix("begin ") {
+                builder.begin(name, time, "");
+                seen.lock().unwrap().insert(name.to_string());
+            } else if let Some(name) = line.strip_prefix("end ") {
+                builder.end(name, time);
+            } else if !line.is_empty() {
+                builder.instant(line, time, "");
+                seen.lock().unwrap().insert(line.to_string());
+            }
+        }
+        if done.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+    *markers.lock().unwrap() = builder.finish();
+}
+
+/// Pairs begin and end events by name
+#[derive(Default)]
+struct Builder {
+    open: HashMap<String, Vec<(f64, String)>>,
+    markers: Vec<Marker>,
+}
+
+impl Builder {
+    fn begin(&mut self, name: &str, time: f64, text: &str) {
+        self.open.entry(name.to_string()).or_default().push((time, text.to_string()));
+    }
+
+    fn end(&mut self, name: &str, time: f64) {
+        match self.open.get_mut(name).and_then(Vec::pop) {
+            Some((start, text)) => self.markers.push(Marker { name: name.to_string(), start, end: Some(time), text }),
+            None => self.instant(&format!("{} (end)", name), time, ""),
+        }
+    }
+
+    fn instant(&mut self, name: &str, time: f64, text: &str) {
+        self.markers.push(Marker { name: name.to_string(), start: time, end: None, text: text.to_string() });
+    }
+
+    fn finish(self) -> Vec<Marker> {
+        self.markers
+    }
+}
@@ -0,0 +1,49 @@
+//! Machine-readable progress events for editor integrations and task runners
+//!
+//! With `--message-format json` every stage, the built binary, each output and the end of the
+//! run are printed to stdout as one JSON object per line, next to the usual output. Consumers
+//! skip the lines that are not JSON, like with cargo's own JSON messages.
+
+use std::{io::Write, path::Path, sync::atomic::{AtomicBool, Ordering}};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// Whether events are printed, set by [`set_format`]
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Only the human-readable output
+    Human,
+    /// Additionally print progress events as newline-delimited JSON
+    Json,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    StepStarted { step: &'a str },
+    StepFinished { step: &'a str, seconds: f64 },
+    BinaryBuilt { executable: &'a str },
+    Artifact { format: &'a str, path: &'a Path },
+    Error { message: &'a str },
+    Finished { success: bool },
+}
+
+
+pub fn set_format(format: MessageFormat) {
+    JSON.store(format == MessageFormat::Json, Ordering::Relaxed);
+}
+
+/// Print the event if JSON messages are enabled
+pub fn emit(message: Message) {
+    if !JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(&message) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
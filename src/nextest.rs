@@ -0,0 +1,143 @@
+//! Profiling tests selected through cargo-nextest
+
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+use serde::Deserialize;
+
+use crate::gecko::{self, GeckoProfile};
+use crate::perf;
+use crate::profile::{self, PerfEvent};
+use crate::report::{self, Format};
+use crate::{NextestArgs, print_step, resolve, resolve_status};
+
+/// Length of thread names as stored by the kernel (without the terminating zero)
+const COMM_LENGTH: usize = 15;
+
+#[derive(Deserialize, Debug)]
+struct TestList {
+    #[serde(rename = "rust-suites")]
+    suites: HashMap<String, Suite>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Suite {
+    #[serde(rename = "binary-id")]
+    binary_id: String,
+    #[serde(rename = "binary-path")]
+    binary_path: PathBuf,
+    cwd: Option<PathBuf>,
+    testcases: HashMap<String, TestCase>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TestCase {
+    #[serde(rename = "filter-match")]
+    filter_match: FilterMatch,
+}
+
+#[derive(Deserialize, Debug)]
+struct FilterMatch {
+    status: String,
+}
+
+
+/// Record every test binary with matching tests and split the recording per test
+pub fn run(args: &NextestArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Trace]);
+    let cargo_path = resolve(env::var("CARGO"));
+
+    print_step("Building and listing tests");
+    let output = resolve(process::Command::new(cargo_path)
+        .args(["nextest", "list", "--cargo-profile", "profiling", "--message-format", "json"])
+        .args(&args.run.app_args)
+        .stderr(process::Stdio::inherit())
+        .output());
+    resolve_status(output.status);
+    let list: TestList = resolve(serde_json::from_slice(&output.stdout));
+
+    let mut suites: Vec<Suite> = list.suites.into_values().collect();
+    suites.sort_by(|a, b| a.binary_id.cmp(&b.binary_id));
+    for suite in suites {
+        let mut tests: Vec<String> = suite.testcases.into_iter()
+            .filter(|(_, t)| t.filter_match.status == "matches")
+            .map(|(name, _)| name)
+            .collect();
+        if tests.is_empty() {
+            continue;
+        }
+        tests.sort();
+        record_suite(&suite.binary_id, &suite.binary_path, suite.cwd.as_deref(), &tests, &formats, args.run.ignore_exit);
+    }
+}
+
+fn record_suite(binary_id: &str, binary: &Path, cwd: Option<&Path>, tests: &[String], formats: &[Format], ignore_exit: bool) {
+    let dir = binary.parent().and_then(Path::parent).unwrap_or(Path::new(".")).join("nextest");
+    resolve(fs::create_dir_all(&dir));
+    let stem = report::file_name_part(binary_id);
+
+    eprintln!("\n{} ({} tests)", binary_id.bold(), tests.len());
+    let mut test_args = tests.to_vec();
+    // Run the tests one after another, each in its own thread named after the test
+    test_args.extend(["--exact", "--test-threads=1", "--nocapture"].map(String::from));
+    let mut recording = perf::Recording::new(&dir, &stem, &binary.to_string_lossy(), &test_args, ignore_exit);
+    // Tests expect to run in their package directory, as nextest does
+    recording.cwd = cwd.map(Path::to_path_buf);
+    let trace_path = perf::record(&recording);
+
+    let content = resolve(fs::read_to_string(&trace_path));
+    let events = resolve(profile::parse_perf_events(&trace_path));
+    let names = test_threads(&events, tests);
+
+    let mut attributed: Vec<&String> = names.values().collect();
+    attributed.sort();
+    attributed.dedup();
+    for test in &attributed {
+        let is_test = |e: &PerfEvent| names.get(&e.tid).is_some_and(|n| n == *test);
+        let test_stem = format!("{}.{}", stem, report::file_name_part(test));
+        if formats.contains(&Format::Trace) {
+            let path = dir.join(format!("{}.trace", test_stem));
+            resolve(perf::write_filtered_trace(&content, &path, is_test));
+            report::print_output(Format::Trace, &path);
+        }
+        let test_events: Vec<PerfEvent> = events.iter().filter(|e| is_test(e)).cloned().collect();
+        report::emit(&profile::from_perf_events(&test_events), formats, &dir, &test_stem);
+    }
+
+    if formats.contains(&Format::Gecko) {
+        let start = events.iter().map(|e| e.time).fold(f64::INFINITY, f64::min);
+        let mut threads = perf::gecko_threads(&events, start);
+        for thread in &mut threads {
+            if let Some(test) = names.get(&thread.tid) {
+                thread.name = test.clone();
+            }
+        }
+        let path = dir.join(format!("{}.json", stem));
+        let gecko = GeckoProfile { threads, counters: Vec::new(), interval: perf::sampling_interval() };
+        resolve(gecko::write(&gecko, &path));
+        report::print_output(Format::Gecko, &path);
+    }
+    if attributed.is_empty() {
+        eprintln!("{}", "Warning: no samples could be attributed to a test".yellow());
+    }
+}
+
+/// Map thread ids to the tests that ran on them
+///
+/// The thread names are truncated by the kernel, so the tests (which run in sorted order) are
+/// matched to the threads in the order they first appear.
+fn test_threads(events: &[PerfEvent], tests: &[String]) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    let mut remaining = tests.iter();
+    for event in events {
+        if event.pid == event.tid || names.contains_key(&event.tid) {
+            continue;
+        }
+        let truncated = |t: &&String| t.chars().take(COMM_LENGTH).collect::<String>() == event.comm;
+        if let Some(test) = remaining.clone().find(truncated) {
+            while remaining.next().is_some_and(|t| t != test) {}
+            names.insert(event.tid, test.clone());
+        }
+    }
+    names
+}
@@ -0,0 +1,520 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, process, sync::{Mutex, OnceLock, atomic::{AtomicU32, Ordering}}};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::config;
+use crate::container;
+use crate::gecko::{self, Counter, GeckoProfile, Marker, Thread};
+use crate::gpu;
+use crate::inline;
+use crate::jit;
+use crate::kernel;
+use crate::maps;
+use crate::markers;
+use crate::pools;
+use crate::profile::{self, PerfEvent};
+use crate::report::{self, Format};
+use crate::sched;
+use crate::symbols;
+use crate::timing::{self, Measurement};
+use crate::wsl::{self, WslVersion};
+use crate::{print_step, resolve, resolve_status};
+
+
+/// Arguments for `perf record` when sampling CPU time
+pub const SAMPLING_ARGS: &[&str] = &["-g", "-F", "999"];
+
+/// Sampling frequency in Hz of [`SAMPLING_ARGS`]
+pub const FREQUENCY: u32 = 999;
+
+/// Arguments for `perf record` with the given settings, [`SAMPLING_ARGS`] for the defaults
+pub fn sampling_args(frequency: Option<u32>, call_graph: Option<&str>, events: &[String]) -> Vec<String> {
+    let mut args = match call_graph {
+        Some(mode) => vec!["--call-graph".to_string(), mode.to_string()],
+        None => vec!["-g".to_string()],
+    };
+    args.extend(["-F".to_string(), frequency.unwrap_or(FREQUENCY).to_string()]);
+    for event in events {
+        args.extend(["-e".to_string(), event.clone()]);
+    }
+    args
+}
+
+/// Data file converted by the last call to [`script`]
+static LAST_DATA: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Sampling frequency of the recording converted by the last call to [`script`]
+static LAST_FREQUENCY: AtomicU32 = AtomicU32::new(FREQUENCY);
+
+/// perf binary given with `--perf-path`, or else detected on first use
+static BINARY: OnceLock<PathBuf> = OnceLock::new();
+
+/// Share in percent below which call stacks are dropped from the trace, given with `--min-weight`
+static MIN_WEIGHT: OnceLock<f64> = OnceLock::new();
+
+
+/// Use the given perf binary instead of looking it up
+pub fn set_binary(path: PathBuf) {
+    let _ = BINARY.set(path);
+}
+
+pub fn set_min_weight(percent: f64) {
+    let _ = MIN_WEIGHT.set(percent);
+}
+
+/// Parse the `--min-weight` percentage
+pub fn parse_min_weight(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("expected a percentage between 0 and 100 instead of {:?}", s)),
+    }
+}
+
+/// Path of the perf binary to use
+pub fn binary() -> PathBuf {
+    BINARY.get_or_init(|| {
+        if let Some(path) = &config::load().perf_path {
+            return path.clone();
+        }
+        match wsl::detect() {
+            Some(WslVersion::Wsl1) => resolve(Err("WSL1 does not support perf events, please switch to WSL2 (wsl --set-version <distro> 2)")),
+            Some(WslVersion::Wsl2) => match wsl::perf_binary() {
+                Some(perf) => perf,
+                None => resolve(Err("Could not find a working perf binary, install linux-tools-generic (perf builds are looked up in /usr/lib/linux-tools)")),
+            },
+            None => PathBuf::from("perf"),
+        }
+    }).clone()
+}
+
+/// Warn if the kernel does not allow recording unprivileged processes
+pub fn check_paranoid() {
+    let Ok(value) = fs::read_to_string("/proc/sys/kernel/perf_event_paranoid") else { return };
+    if value.trim().parse::<i32>().is_ok_and(|v| v > 2) {
+        eprintln!("{}", format!("Warning: kernel.perf_event_paranoid is {}, recording will probably fail", value.trim()).yellow());
+        eprintln!("{}", "Lower it with: sudo sysctl kernel.perf_event_paranoid=2".yellow());
+        if wsl::detect() == Some(WslVersion::Wsl2) {
+            eprintln!("{}", format!("Under WSL2 {}", wsl::sysctl_hint("kernel.perf_event_paranoid=2")).yellow());
+        }
+    }
+}
+
+/// Settings of a single `perf record` and `perf script` run
+#[derive(Debug, Clone)]
+pub struct Recording<'a> {
+    /// The trace is stored as `<stem>.trace` in this directory
+    pub dir: &'a Path,
+    pub stem: &'a str,
+    /// Recorded data, `<stem>.data` in the directory by default
+    pub data: PathBuf,
+    pub record_args: Vec<String>,
+    pub script_args: Vec<String>,
+    /// What to record, either the command to run or e.g. `-p <pid> -- sleep 10`
+    pub target: Vec<String>,
+    /// Additional environment variables of the recorded command
+    pub env: Vec<(String, String)>,
+    /// Working directory of the recorded command, the current one by default
+    pub cwd: Option<PathBuf>,
+    pub ignore_exit: bool,
+    /// Sampling frequency in Hz given in `record_args`
+    pub frequency: u32,
+}
+
+impl<'a> Recording<'a> {
+    /// Sample a command with the default settings
+    pub fn new(dir: &'a Path, stem: &'a str, executable: &str, app_args: &[String], ignore_exit: bool) -> Self {
+        let mut target = vec![executable.to_string()];
+        target.extend(app_args.iter().cloned());
+        Recording {
+            dir,
+            stem,
+            data: dir.join(format!("{}.data", stem)),
+            record_args: SAMPLING_ARGS.iter().map(|a| a.to_string()).collect(),
+            script_args: Vec::new(),
+            target,
+            env: Vec::new(),
+            cwd: None,
+            ignore_exit,
+            frequency: FREQUENCY,
+        }
+    }
+}
+
+
+/// Record with `perf record` and convert the data with `perf script`, returns the path of the trace file
+pub fn record(recording: &Recording) -> PathBuf {
+    record_watching(recording, None)
+}
+
+/// Like [`record`], additionally passing every line the program prints to stdout to `on_line`
+pub fn record_watching(recording: &Recording, on_line: Option<&mut dyn FnMut(&str)>) -> PathBuf {
+    record_measured(recording, on_line).0
+}
+
+/// Like [`record_watching`], additionally returning how long `perf record` took
+pub fn record_measured(recording: &Recording, on_line: Option<&mut dyn FnMut(&str)>) -> (PathBuf, Measurement) {
+    let perf_out_path = &recording.data;
+
+    check_paranoid();
+    kernel::check();
+
+    print_step("Running program with perf");
+    let _ = fs::remove_file(perf_out_path);
+    let mut command = record_command(recording);
+    let (status, measurement) = timing::measure(|| match on_line {
+        None => app::run(&mut command),
+        Some(on_line) => {
+            crate::log_command(&command);
+            let mut child = resolve(command.stdout(process::Stdio::piped()).spawn());
+            let stdout = BufReader::new(child.stdout.take().unwrap());
+            let mut log = app::output_log(false);
+            for line in stdout.lines().map_while(Result::ok) {
+                println!("{}", line);
+                if let Some(log) = &mut log {
+                    let _ = writeln!(log, "{}", line);
+                }
+                on_line(&line);
+            }
+            resolve(child.wait())
+        },
+    });
+    check_data(recording);
+    app::check_exit(status, recording.ignore_exit);
+
+    (script(recording), measurement)
+}
+
+/// Fail if `perf record` did not write any data, with hints if running in a container
+pub fn check_data(recording: &Recording) {
+    if fs::metadata(&recording.data).map(|m| m.len()).unwrap_or(0) == 0 {
+        if let Some(runtime) = &container::detect() {
+            container::print_hints(runtime);
+        }
+        resolve::<(), _>(Err("perf did not record any data"));
+    }
+}
+
+/// The `perf record` invocation of a recording
+pub fn record_command(recording: &Recording) -> process::Command {
+    let mut event_args = Vec::new();
+    // Hardware counters are rarely passed through to containers, the software clock always works
+    if container::detect().is_some() && !recording.record_args.iter().any(|a| a == "-e") {
+        event_args.extend(["-e", "cpu-clock"]);
+    }
+
+    let mut command = if config::load().sudo == Some(true) {
+        // Keep the environment, it carries the settings of the application
+        let mut command = app::command("sudo");
+        command.arg("--preserve-env").arg(binary());
+        command
+    } else {
+        app::command(binary())
+    };
+    if let Some(cwd) = &recording.cwd {
+        command.current_dir(cwd);
+    }
+    command.arg("record");
+    if crate::verbosity() >= 3 {
+        command.arg("--verbose");
+    } else if crate::verbosity() == 0 {
+        command.arg("--quiet");
+    }
+    command.arg(format!("--output={}", recording.data.to_string_lossy()))
+        .args(event_args)
+        .args(kernel::record_args())
+        .args(&recording.record_args)
+        .args(&recording.target)
+        .envs(recording.env.iter().cloned());
+    command
+}
+
+/// The `perf script` invocation converting a recording, writing the trace to stdout
+pub fn script_command(recording: &Recording) -> process::Command {
+    let mut command = process::Command::new(binary());
+    command.arg("script");
+    // Data recorded with sudo is owned by root
+    if config::load().sudo == Some(true) {
+        command.arg("--force");
+    }
+    command.args(["-F", "+pid"])
+        .args(inline::script_args())
+        .args(symbols::perf_args())
+        .args(&recording.script_args)
+        .arg(format!("--input={}", recording.data.to_string_lossy()));
+    if let Some(urls) = symbols::debuginfod_urls() {
+        command.env("DEBUGINFOD_URLS", urls);
+    }
+    command
+}
+
+/// Convert `<stem>.data` with `perf script`, returns the path of the trace file
+pub fn script(recording: &Recording) -> PathBuf {
+    let perf_out_path = &recording.data;
+    let trace_path = recording.dir.join(format!("{}.trace", recording.stem));
+
+    *LAST_DATA.lock().unwrap() = Some(perf_out_path.clone());
+    LAST_FREQUENCY.store(recording.frequency, Ordering::Relaxed);
+    jit::inject(recording);
+    symbols::prepare(perf_out_path);
+
+    print_step("Converting data to trace format");
+    let trace_file = resolve(File::create(&trace_path));
+    let mut command = script_command(recording);
+    crate::log_command(&command);
+    let status = resolve(command
+        .stdout(process::Stdio::from(trace_file))
+        .status());
+    resolve_status(status);
+    if inline::enabled() {
+        resolve(inline::expand(&trace_path));
+    }
+    if let Some(min_weight) = MIN_WEIGHT.get() {
+        resolve(prune(&trace_path, *min_weight));
+    }
+    let mut events = resolve(profile::parse_perf_events(&trace_path));
+    events.retain(|e| !gpu::is_gpu_event(e) && !markers::is_sdt_event(e) && !sched::is_sched_event(e));
+    symbols::print_quality(&events);
+
+    trace_path
+}
+
+/// The `perf.data` file recorded during this run, if any
+pub fn last_data() -> Option<PathBuf> {
+    LAST_DATA.lock().unwrap().clone()
+}
+
+/// Sampling frequency in Hz of the last recording, the default for data recorded elsewhere
+pub fn last_frequency() -> u32 {
+    LAST_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Time between two samples of the last recording in milliseconds
+pub fn sampling_interval() -> f64 {
+    1000.0 / last_frequency() as f64
+}
+
+/// Bundle the recorded data with all binaries it references, so it can be symbolized elsewhere
+///
+/// Uses `perf archive` for the build-id cache and falls back to packing the binaries listed by
+/// `perf buildid-list` under their original paths, to be used with `--symfs`.
+pub fn archive() {
+    let Some(data) = last_data() else {
+        eprintln!("{}", "Warning: only perf recordings can be archived".yellow());
+        return;
+    };
+    let dir = data.parent().unwrap_or(Path::new("."));
+    let data_name = data.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let bundle = data.with_extension("portable.tar.gz");
+
+    print_step("Archiving recording with its binaries");
+    let perf_archive = process::Command::new(binary())
+        .arg("archive")
+        .arg(&data)
+        .stdout(process::Stdio::null())
+        .status();
+    let mut tar = process::Command::new("tar");
+    tar.arg("-czf").arg(&bundle).arg("-C").arg(dir).arg(&data_name);
+    let snapshot = maps::path_for(&data);
+    if let Some(name) = snapshot.file_name().filter(|_| snapshot.exists()) {
+        tar.arg(name);
+    }
+    if perf_archive.is_ok_and(|s| s.success()) {
+        tar.arg(format!("{}.tar.bz2", data_name));
+    } else {
+        eprintln!("{}", "Warning: perf archive failed, collecting the referenced binaries directly".yellow());
+        let output = resolve(process::Command::new(binary())
+            .arg("buildid-list")
+            .arg(format!("--input={}", data.to_string_lossy()))
+            .stderr(process::Stdio::inherit())
+            .output());
+        resolve_status(output.status);
+        let binaries: Vec<String> = String::from_utf8_lossy(&output.stdout).lines()
+            .filter_map(|l| l.split_once(' ').map(|(_, path)| path.to_string()))
+            .filter(|path| path.starts_with('/') && Path::new(path).is_file())
+            .collect();
+        tar.arg("-C").arg("/").args(binaries.iter().map(|b| b.trim_start_matches('/')));
+    }
+    let status = resolve(tar.status());
+    resolve_status(status);
+    println!("Portable recording: {}", bundle.to_string_lossy().cyan());
+}
+
+/// Drop the samples whose call stack makes up less than `min_weight` percent of all samples
+///
+/// Tracepoint events (with a `:` in the event name) are kept, markers and tracks are made of them.
+fn prune(trace_path: &Path, min_weight: f64) -> io::Result<()> {
+    let trace = fs::read_to_string(trace_path)?;
+    let blocks: Vec<&str> = trace.split("\n\n").filter(|b| !b.trim().is_empty()).collect();
+    // Functions of the stack of every sample, the addresses differ between samples of the same stack
+    let stacks: Vec<Option<Vec<profile::Frame>>> = blocks.iter()
+        .map(|block| {
+            let mut lines = block.trim_matches('\n').lines();
+            let header = profile::parse_perf_header(lines.next()?);
+            (!header.event.contains(':')).then(|| lines.map(profile::parse_perf_frame).collect())
+        })
+        .collect();
+    let mut counts: HashMap<&[profile::Frame], u64> = HashMap::new();
+    for stack in stacks.iter().flatten() {
+        *counts.entry(stack).or_default() += 1;
+    }
+    let total: u64 = counts.values().sum();
+    let threshold = total as f64 * min_weight / 100.0;
+
+    let mut pruned = String::with_capacity(trace.len());
+    let mut dropped = 0;
+    for (block, stack) in blocks.iter().zip(&stacks) {
+        if stack.as_ref().is_some_and(|s| (counts[s.as_slice()] as f64) < threshold) {
+            dropped += 1;
+            continue;
+        }
+        pruned.push_str(block.trim_matches('\n'));
+        pruned.push_str("\n\n");
+    }
+    println!("Dropped {} of {} samples with call stacks below {}% (--min-weight)", dropped, total, min_weight);
+    fs::write(trace_path, pruned)
+}
+
+/// Write the events of a `perf script` trace for which `keep` returns true to `path`
+pub fn write_filtered_trace(trace: &str, path: &Path, keep: impl Fn(&PerfEvent) -> bool) -> io::Result<()> {
+    let mut filtered = String::new();
+    for block in trace.split("\n\n") {
+        let Some(header) = block.lines().next().filter(|l| !l.trim().is_empty()) else { continue };
+        if keep(&profile::parse_perf_header(header)) {
+            filtered.push_str(block.trim_matches('\n'));
+            filtered.push_str("\n\n");
+        }
+    }
+    fs::write(path, filtered)
+}
+
+/// Generate the requested report formats from a trace recorded by [`record`]
+pub fn convert(trace_path: &Path, formats: &[Format], dir: &Path, stem: &str) {
+    convert_with_markers(trace_path, formats, dir, stem, &[], &[]);
+}
+
+/// Like [`convert`], adding markers to the main thread and counter tracks to the Firefox Profiler output
+///
+/// The marker and counter times are given in seconds of the trace clock.
+pub fn convert_with_markers(trace_path: &Path, formats: &[Format], dir: &Path, stem: &str, markers: &[Marker], counters: &[Counter]) {
+    if formats.iter().all(|f| *f == Format::Trace) {
+        return;
+    }
+
+    let events = resolve(profile::parse_perf_events(trace_path));
+    let (gpu_events, events): (Vec<_>, Vec<_>) = events.into_iter().partition(gpu::is_gpu_event);
+    let (sdt_events, events): (Vec<_>, Vec<_>) = events.into_iter().partition(markers::is_sdt_event);
+    let (sched_events, events): (Vec<_>, Vec<_>) = events.into_iter()
+        .partition(|e| formats.contains(&Format::Timechart) && sched::is_sched_event(e));
+    let mut user_markers = markers.to_vec();
+    user_markers.extend(markers::from_sdt_events(&sdt_events));
+    report::emit(&profile::from_perf_events(&events), formats, dir, stem);
+    if formats.contains(&Format::Summary) {
+        pools::print_summary(&events);
+    }
+    let timelines = formats.contains(&Format::Timechart).then(|| sched::timelines(&events, &sched_events));
+    if let Some(timelines) = &timelines {
+        let path = dir.join(format!("{}.timechart.svg", stem));
+        resolve(sched::write_svg(timelines, &path));
+        report::print_output(Format::Timechart, &path);
+    }
+
+    if formats.contains(&Format::Gecko) {
+        let start = events.iter().chain(&gpu_events).chain(&sdt_events).chain(&sched_events)
+            .map(|e| e.time)
+            .fold(f64::INFINITY, f64::min);
+        let pid = events.first().map(|e| e.pid).unwrap_or(0);
+        let mut threads = gecko_threads(&events, start);
+        pools::merge(&mut threads);
+        let main = threads.iter().position(|t| t.pid == t.tid).unwrap_or(0);
+        if let Some(main) = threads.get_mut(main) {
+            main.markers.extend(user_markers.iter().map(|m| Marker {
+                start: (m.start - start) * 1000.0,
+                end: m.end.map(|end| (end - start) * 1000.0),
+                ..m.clone()
+            }));
+        }
+        if let Some(timelines) = &timelines {
+            sched::add_markers(&mut threads, timelines, start);
+        }
+        threads.extend(gpu::tracks(&gpu_events, start, pid));
+
+        let path = dir.join(format!("{}.json", stem));
+        let counters = counters.iter()
+            .map(|c| Counter {
+                samples: c.samples.iter().map(|(time, value)| ((time - start) * 1000.0, *value)).collect(),
+                ..c.clone()
+            })
+            .collect();
+        let gecko = GeckoProfile { threads, counters, interval: sampling_interval() };
+        resolve(gecko::write(&gecko, &path));
+        report::print_output(Format::Gecko, &path);
+    }
+}
+
+pub fn print_trace_hint(trace_path: &Path) {
+    report::print_output(Format::Trace, trace_path);
+    println!("This file can be viewed using the Firefox Profiler ({})", "https://profiler.firefox.com".bright_blue());
+}
+
+/// Group sample events into one Gecko thread per thread id, with times relative to `start`
+pub fn gecko_threads(events: &[PerfEvent], start: f64) -> Vec<Thread> {
+    let mut threads: Vec<Thread> = Vec::new();
+    let mut indices: HashMap<u32, usize> = HashMap::new();
+    let process_names = process_names(events);
+
+    for event in events {
+        let index = *indices.entry(event.tid).or_insert_with(|| {
+            threads.push(Thread {
+                name: event.comm.clone(),
+                process_name: process_names[&event.pid].clone(),
+                pid: event.pid,
+                tid: event.tid,
+                ..Thread::default()
+            });
+            threads.len() - 1
+        });
+        // exec and prctl rename threads, keep the name they ended up with
+        threads[index].name.clone_from(&event.comm);
+        threads[index].samples.push(((event.time - start) * 1000.0, event.frames.clone()));
+    }
+
+    // Threads of the same process next to each other, the profiler shows them as one track group
+    threads.sort_by_key(|t| (t.pid, t.pid != t.tid, t.tid));
+    threads
+}
+
+/// Name of every process, the command of its main thread after the last exec or else of its first event
+pub fn process_names(events: &[PerfEvent]) -> HashMap<u32, String> {
+    let mut names: HashMap<u32, String> = HashMap::new();
+    for event in events {
+        if event.pid == event.tid {
+            names.insert(event.pid, event.comm.clone());
+        } else {
+            names.entry(event.pid).or_insert_with(|| event.comm.clone());
+        }
+    }
+    names
+}
+
+/// Write the trace and the other formats of every recorded process to `<stem>.<name>-<pid>.*`
+pub fn split_processes(trace_path: &Path, formats: &[Format], dir: &Path, stem: &str) {
+    let events = resolve(profile::parse_perf_events(trace_path));
+    let names = process_names(&events);
+    if names.len() < 2 {
+        eprintln!("{}", "Warning: Only a single process was recorded, --split-processes has nothing to split".yellow());
+        return;
+    }
+    let content = resolve(fs::read_to_string(trace_path));
+    let mut pids: Vec<&u32> = names.keys().collect();
+    pids.sort();
+    for pid in pids {
+        let process_stem = format!("{}.{}-{}", stem, report::file_name_part(&names[pid]), pid);
+        let path = dir.join(format!("{}.trace", process_stem));
+        resolve(write_filtered_trace(&content, &path, |e| e.pid == *pid));
+        convert(&path, formats, dir, &process_stem);
+        if formats.contains(&Format::Trace) {
+            report::print_output(Format::Trace, &path);
+        }
+    }
+}
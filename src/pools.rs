@@ -0,0 +1,114 @@
+//! Thread pools, told apart by the names their threads share
+//!
+//! Data-parallel programs spread the same work over many threads, which are easier to read as a
+//! single track per pool. A pool is a group of at least two threads of a process whose names
+//! only differ in a trailing index (`rayon-0`, `rayon-1`), or that carry the name of a runtime
+//! that does not number its threads (`tokio-runtime-worker`).
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, sync::atomic::{AtomicBool, Ordering}};
+
+use colored::Colorize;
+
+use crate::gecko::Thread;
+use crate::profile::PerfEvent;
+use crate::report;
+
+/// Names of pool threads without an index, as truncated to 15 characters by the kernel
+const UNNUMBERED: &[(&str, &str)] = &[("tokio-runtime-w", "tokio-runtime-worker")];
+
+/// Whether `--per-thread` was given
+static PER_THREAD: AtomicBool = AtomicBool::new(false);
+
+
+pub fn set_per_thread(per_thread: bool) {
+    PER_THREAD.store(per_thread, Ordering::Relaxed);
+}
+
+/// Name of the pool a thread with this name may belong to
+fn pool_name(comm: &str) -> Option<String> {
+    if let Some((_, name)) = UNNUMBERED.iter().find(|(prefix, _)| comm.starts_with(prefix)) {
+        return Some(name.to_string());
+    }
+    let base = comm.trim_end_matches(|c: char| c.is_ascii_digit());
+    if base.len() == comm.len() {
+        return None;
+    }
+    let base = base.trim_end_matches(['-', '_', '#', ' ', ':', '.']);
+    (!base.is_empty()).then(|| base.to_string())
+}
+
+/// Pool of each thread that belongs to one, by process and thread id
+fn pools<'a>(threads: impl Iterator<Item = (u32, u32, &'a str)>) -> HashMap<(u32, u32), String> {
+    let mut members: BTreeMap<(u32, String), BTreeSet<u32>> = BTreeMap::new();
+    for (pid, tid, comm) in threads {
+        // The main thread is not part of a pool even if it is named like one
+        if pid == tid {
+            continue;
+        }
+        if let Some(pool) = pool_name(comm) {
+            members.entry((pid, pool)).or_default().insert(tid);
+        }
+    }
+    members.into_iter()
+        .filter(|(_, tids)| tids.len() > 1)
+        .flat_map(|((pid, pool), tids)| tids.into_iter().map(move |tid| ((pid, tid), pool.clone())))
+        .collect()
+}
+
+/// Merge the threads of each pool into one, unless `--per-thread` was given
+pub fn merge(threads: &mut Vec<Thread>) {
+    if PER_THREAD.load(Ordering::Relaxed) {
+        return;
+    }
+    let pools = pools(threads.iter().map(|t| (t.pid, t.tid, t.name.as_str())));
+    let mut merged: Vec<Thread> = Vec::new();
+    let mut indices: HashMap<(u32, &str), usize> = HashMap::new();
+    let mut sizes: Vec<usize> = Vec::new();
+    for thread in threads.drain(..) {
+        let Some(pool) = pools.get(&(thread.pid, thread.tid)) else {
+            merged.push(thread);
+            sizes.push(1);
+            continue;
+        };
+        match indices.get(&(thread.pid, pool.as_str())) {
+            Some(&i) => {
+                merged[i].samples.extend(thread.samples);
+                merged[i].markers.extend(thread.markers);
+                sizes[i] += 1;
+            },
+            None => {
+                indices.insert((thread.pid, pool.as_str()), merged.len());
+                merged.push(Thread { name: pool.clone(), ..thread });
+                sizes.push(1);
+            },
+        }
+    }
+    for i in indices.into_values() {
+        merged[i].name = format!("{} ({} threads)", merged[i].name, sizes[i]);
+        merged[i].samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+    *threads = merged;
+}
+
+/// Print the share of the samples each pool got and how evenly they were spread over its threads
+pub fn print_summary(events: &[PerfEvent]) {
+    let pools = pools(events.iter().map(|e| (e.pid, e.tid, e.comm.as_str())));
+    if pools.is_empty() {
+        return;
+    }
+    let mut per_thread: BTreeMap<(u32, &str), HashMap<u32, u64>> = BTreeMap::new();
+    for event in events {
+        if let Some(pool) = pools.get(&(event.pid, event.tid)) {
+            *per_thread.entry((event.pid, pool.as_str())).or_default().entry(event.tid).or_default() += 1;
+        }
+    }
+
+    println!("\n{}", "Thread pools".bold());
+    println!("{:>8} {:>10} {:>8} {:>10}  Pool", "Share %", "samples", "threads", "busiest %");
+    for ((pid, pool), threads) in per_thread {
+        let samples: u64 = threads.values().sum();
+        let busiest = threads.values().max().copied().unwrap_or(0);
+        println!("{:>7.2}% {:>10} {:>8} {:>9.2}%  {} ({})", report::percent(samples, events.len() as u64), samples,
+            threads.len(), report::percent(busiest, samples), pool, pid);
+    }
+}
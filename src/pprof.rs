@@ -0,0 +1,255 @@
+//! Encoder for the protobuf based pprof format (`profile.proto`)
+//!
+//! The output is left uncompressed, which `go tool pprof` and most ingest APIs accept as well.
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+use crate::profile::{Frame, Profile};
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+
+    fn packed(&mut self, field: u32, values: &[u64]) {
+        let mut inner = Encoder::default();
+        for value in values {
+            inner.varint(*value);
+        }
+        self.bytes(field, &inner.buf);
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Encoder)) {
+        let mut inner = Encoder::default();
+        build(&mut inner);
+        self.bytes(field, &inner.buf);
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable::default();
+        table.get("");
+        table
+    }
+
+    fn get(&mut self, s: &str) -> u64 {
+        if let Some(i) = self.indices.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.indices.insert(s.to_string(), i);
+        i
+    }
+}
+
+
+/// Encode a profile as pprof protobuf
+pub fn encode(profile: &Profile) -> Vec<u8> {
+    let mut strings = StringTable::new();
+    let mut locations: HashMap<&Frame, u64> = HashMap::new();
+    let mut out = Encoder::default();
+
+    for name in &profile.value_names {
+        let ty = strings.get(&name.replace(' ', "_"));
+        let unit = strings.get(if name.contains("bytes") { "bytes" } else { "count" });
+        out.message(1, |m| {
+            m.uint(1, ty);
+            m.uint(2, unit);
+        });
+    }
+
+    for sample in &profile.samples {
+        let ids: Vec<u64> = sample.frames.iter()
+            .map(|f| {
+                let next = locations.len() as u64 + 1;
+                *locations.entry(f).or_insert(next)
+            })
+            .collect();
+        out.message(2, |m| {
+            m.packed(1, &ids);
+            m.packed(2, &sample.values);
+        });
+    }
+
+    let mut locations: Vec<_> = locations.into_iter().collect();
+    locations.sort_by_key(|(_, id)| *id);
+    for (frame, id) in &locations {
+        let name = strings.get(&frame.function);
+        let filename = strings.get(&frame.module);
+        // Functions and locations share their ids as every location is a distinct frame
+        out.message(4, |m| {
+            m.uint(1, *id);
+            m.message(4, |l| l.uint(1, *id));
+        });
+        out.message(5, |m| {
+            m.uint(1, *id);
+            m.uint(2, name);
+            m.uint(3, name);
+            m.uint(4, filename);
+        });
+    }
+
+    for s in &strings.strings {
+        out.bytes(6, s.as_bytes());
+    }
+
+    out.buf
+}
+
+pub fn write(profile: &Profile, path: &Path) -> io::Result<()> {
+    fs::write(path, encode(profile))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::Sample;
+
+    fn read_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let (byte, rest) = buf.split_first().unwrap();
+            *buf = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Fields of a message as field number, varint value or length, and payload
+    fn decode(mut buf: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        let mut fields = Vec::new();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf);
+            let value = read_varint(&mut buf);
+            let mut payload = Vec::new();
+            if key & 7 == 2 {
+                let (bytes, rest) = buf.split_at(value as usize);
+                payload = bytes.to_vec();
+                buf = rest;
+            }
+            fields.push((key >> 3, value, payload));
+        }
+        fields
+    }
+
+    fn packed(mut buf: &[u8]) -> Vec<u64> {
+        let mut values = Vec::new();
+        while !buf.is_empty() {
+            values.push(read_varint(&mut buf));
+        }
+        values
+    }
+
+    #[test]
+    fn varints() {
+        let encoded = |value| {
+            let mut encoder = Encoder::default();
+            encoder.varint(value);
+            encoder.buf
+        };
+        assert_eq!(encoded(0), [0]);
+        assert_eq!(encoded(127), [0x7f]);
+        assert_eq!(encoded(128), [0x80, 0x01]);
+        assert_eq!(encoded(300), [0xac, 0x02]);
+        assert_eq!(encoded(u64::MAX).len(), 10);
+        assert_eq!(encoded(u64::MAX)[9], 0x01);
+    }
+
+    #[test]
+    fn fields() {
+        let mut encoder = Encoder::default();
+        // Zero is the default and left out
+        encoder.uint(1, 0);
+        encoder.uint(2, 150);
+        encoder.bytes(3, b"ab");
+        encoder.packed(4, &[1, 300]);
+        encoder.message(5, |m| m.uint(1, 1));
+        assert_eq!(encoder.buf, [0x10, 0x96, 0x01, 0x1a, 2, b'a', b'b', 0x22, 3, 1, 0xac, 0x02, 0x2a, 2, 0x08, 1]);
+    }
+
+    #[test]
+    fn profile() {
+        let frame = |function: &str| Frame { function: function.to_string(), module: "/bin/app".to_string() };
+        let profile = Profile {
+            value_names: vec!["samples".to_string(), "bytes allocated".to_string()],
+            samples: vec![
+                Sample { frames: vec![frame("leaf"), frame("main")], values: vec![3, 0] },
+                Sample { frames: vec![frame("main")], values: vec![1, 64] },
+            ],
+        };
+        let fields = decode(&encode(&profile));
+        let strings: Vec<String> = fields.iter()
+            .filter(|f| f.0 == 6)
+            .map(|f| String::from_utf8(f.2.clone()).unwrap())
+            .collect();
+        assert_eq!(strings[0], "");
+        let string = |index: u64| strings[index as usize].as_str();
+
+        let sample_types: Vec<(&str, &str)> = fields.iter()
+            .filter(|f| f.0 == 1)
+            .map(|f| {
+                let inner = decode(&f.2);
+                (string(inner[0].1), string(inner[1].1))
+            })
+            .collect();
+        assert_eq!(sample_types, [("samples", "count"), ("bytes_allocated", "bytes")]);
+
+        let samples: Vec<(Vec<u64>, Vec<u64>)> = fields.iter()
+            .filter(|f| f.0 == 2)
+            .map(|f| {
+                let inner = decode(&f.2);
+                (packed(&inner[0].2), packed(&inner[1].2))
+            })
+            .collect();
+        assert_eq!(samples, [(vec![1, 2], vec![3, 0]), (vec![2], vec![1, 64])]);
+
+        // Every location has the function of the same id, named after the frame
+        let functions: Vec<(u64, &str, &str)> = fields.iter()
+            .filter(|f| f.0 == 5)
+            .map(|f| {
+                let inner = decode(&f.2);
+                (inner[0].1, string(inner[1].1), string(inner[3].1))
+            })
+            .collect();
+        assert_eq!(functions, [(1, "leaf", "/bin/app"), (2, "main", "/bin/app")]);
+        assert_eq!(fields.iter().filter(|f| f.0 == 4).count(), 2);
+    }
+}
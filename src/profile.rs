@@ -0,0 +1,182 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader}, path::Path};
+
+use crate::demangle;
+
+
+/// Backend-independent representation of a recording
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Names of the values every sample carries (e.g. "samples" or "D1 misses")
+    pub value_names: Vec<String>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Sample {
+    /// Call stack, innermost frame first
+    pub frames: Vec<Frame>,
+    /// One value per entry in [`Profile::value_names`]
+    pub values: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub function: String,
+    /// Binary, shared object or source file the function belongs to
+    pub module: String,
+}
+
+
+/// Single event of a `perf script` trace
+#[derive(Debug, Clone, Default)]
+pub struct PerfEvent {
+    /// Command name of the thread
+    pub comm: String,
+    pub pid: u32,
+    pub tid: u32,
+    /// Timestamp in seconds
+    pub time: f64,
+    /// Event name, e.g. `cpu-clock` or `syscalls:sys_enter_read`
+    pub event: String,
+    /// Everything behind the event name, like the fields of a tracepoint
+    pub details: String,
+    /// Call stack, innermost frame first
+    pub frames: Vec<Frame>,
+}
+
+
+/// Load a folded stack file or a `perf script` trace as written by the backends
+pub fn load(path: &Path) -> Result<Profile, String> {
+    let error = |e: io::Error| format!("Could not read {} ({})", path.to_string_lossy(), e);
+    if path.extension().is_some_and(|e| e == "folded") {
+        let content = fs::read_to_string(path).map_err(error)?;
+        Ok(parse_folded(&[("samples", &content)]))
+    } else {
+        let events = parse_perf_events(path).map_err(error)?;
+        Ok(from_perf_events(&events))
+    }
+}
+
+/// Turn `perf script` events into a profile with one value per sample
+pub fn from_perf_events(events: &[PerfEvent]) -> Profile {
+    let samples = events.iter()
+        .map(|e| Sample { frames: e.frames.clone(), values: vec![1] })
+        .collect();
+
+    Profile {
+        value_names: vec!["samples".to_string()],
+        samples,
+    }
+}
+
+/// Parse the output of `perf script` (with `-F +pid`) into single events
+pub fn parse_perf_events(path: &Path) -> io::Result<Vec<PerfEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    let mut current: Option<PerfEvent> = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            events.extend(current.take());
+        } else if line.starts_with(char::is_whitespace) {
+            if let Some(event) = current.as_mut() {
+                event.frames.push(parse_perf_frame(&line));
+            }
+        } else {
+            events.extend(current.take());
+            current = Some(parse_perf_header(&line));
+        }
+    }
+    events.extend(current);
+
+    Ok(events)
+}
+
+/// Parse an event header (`<comm> <pid>/<tid> [<cpu>] <time>: [<period>] <event>: <details>`)
+pub fn parse_perf_header(line: &str) -> PerfEvent {
+    let mut event = PerfEvent::default();
+    let mut tokens = line.split_whitespace().peekable();
+
+    // The command name may contain spaces, so skip forward to the pid/tid pair
+    let mut comm = Vec::new();
+    for token in tokens.by_ref() {
+        if let Some((pid, tid)) = token.split_once('/')
+                && let (Ok(pid), Ok(tid)) = (pid.parse::<u32>(), tid.parse::<u32>()) {
+            event.pid = pid;
+            event.tid = tid;
+            break;
+        }
+        comm.push(token);
+    }
+    event.comm = comm.join(" ");
+    if tokens.peek().is_some_and(|t| t.starts_with('[')) {
+        tokens.next();
+    }
+    if let Some(time) = tokens.next() {
+        event.time = time.trim_end_matches(':').parse().unwrap_or(0.0);
+    }
+    if tokens.peek().is_some_and(|t| t.parse::<u64>().is_ok()) {
+        tokens.next();
+    }
+    if let Some(name) = tokens.next() {
+        event.event = name.trim_end_matches(':').to_string();
+        if let Some(i) = line.find(name) {
+            event.details = line[i + name.len()..].trim().to_string();
+        }
+    }
+
+    event
+}
+
+/// Parse a single stack line (`<addr> <symbol>+<offset> (<dso>)`)
+pub fn parse_perf_frame(line: &str) -> Frame {
+    let line = line.trim();
+    let rest = match line.split_once(char::is_whitespace) {
+        Some((_addr, rest)) => rest.trim(),
+        None => line,
+    };
+    let (symbol, module) = match rest.rfind(" (") {
+        Some(i) if rest.ends_with(')') => (&rest[..i], &rest[i + 2..rest.len() - 1]),
+        _ => (rest, "[unknown]"),
+    };
+    let function = match symbol.rfind("+0x") {
+        Some(i) => &symbol[..i],
+        None => symbol,
+    };
+
+    Frame {
+        function: demangle::demangle(function),
+        module: module.to_string(),
+    }
+}
+
+/// Parse collapsed stacks (`root;...;leaf <value>`) into a profile
+///
+/// Each input is read as one value type, stacks missing from an input get a value of zero.
+pub fn parse_folded(inputs: &[(&str, &str)]) -> Profile {
+    let mut stacks: HashMap<&str, Vec<u64>> = HashMap::new();
+
+    for (i, (_, content)) in inputs.iter().enumerate() {
+        for line in content.lines() {
+            let Some((stack, value)) = line.trim().rsplit_once(' ') else { continue };
+            let Some(value) = value.parse::<u64>().ok()
+                .or_else(|| value.parse::<f64>().ok().map(|v| v.round() as u64)) else { continue };
+            stacks.entry(stack).or_insert_with(|| vec![0; inputs.len()])[i] += value;
+        }
+    }
+
+    let samples = stacks.into_iter()
+        .map(|(stack, values)| Sample {
+            frames: stack.rsplit(';')
+                .map(|f| Frame { function: f.to_string(), module: String::new() })
+                .collect(),
+            values,
+        })
+        .collect();
+
+    Profile {
+        value_names: inputs.iter().map(|(name, _)| name.to_string()).collect(),
+        samples,
+    }
+}
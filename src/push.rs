@@ -0,0 +1,287 @@
+//! Pushing recordings to continuous profiling services
+
+use std::{env, fs, io::Write, os::unix::fs::OpenOptionsExt, process, time::{SystemTime, UNIX_EPOCH}};
+
+use clap::ValueEnum;
+use colored::Colorize;
+use serde_json::json;
+
+use crate::config;
+use crate::perf;
+use crate::pprof;
+use crate::profile::{self, Profile};
+use crate::report;
+use crate::server::percent_encode;
+use crate::{PushArgs, git_commit, print_step, resolve};
+
+/// Server used if none is configured
+const DEFAULT_PYROSCOPE_SERVER: &str = "http://localhost:4040";
+
+/// gRPC endpoint of a local Parca server
+const DEFAULT_PARCA_SERVER: &str = "http://localhost:7070";
+
+/// gRPC method that ingests pprof profiles
+const PARCA_WRITE_RAW: &str = "parca.profilestore.v1alpha1.ProfileStoreService/WriteRaw";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushTarget {
+    /// Pyroscope's HTTP ingest API
+    Pyroscope,
+    /// Grafana Cloud Profiles (credentials in GRAFANA_PROFILES_USER and GRAFANA_PROFILES_API_KEY or the configuration)
+    Grafana,
+    /// Parca or Polar Signals Cloud via gRPC (requires grpcurl, token in PARCA_BEARER_TOKEN)
+    Parca,
+}
+
+
+/// Push the profile written during this run to the configured service
+pub fn push_outputs(args: &PushArgs, started: SystemTime) {
+    let Some(target) = args.push else { return };
+    let Some(profile) = load_outputs() else {
+        eprintln!("{}", "Warning: nothing to push, no profile was written during this run".yellow());
+        return;
+    };
+
+    let app = args.app.clone().unwrap_or_else(default_app_name);
+    let mut labels: Vec<(String, String)> = Vec::new();
+    if let Some(commit) = git_commit() {
+        labels.push(("git_sha".to_string(), commit));
+    }
+    for label in &args.labels {
+        match label.split_once('=') {
+            Some((key, value)) => labels.push((key.to_string(), value.to_string())),
+            None => resolve(Err(format!("Invalid label {:?}, expected KEY=VALUE", label))),
+        }
+    }
+
+    match target {
+        PushTarget::Pyroscope => {
+            let server = args.server.as_deref().unwrap_or(DEFAULT_PYROSCOPE_SERVER);
+            print_step(&format!("Pushing profile to {}", server));
+            resolve(push_pyroscope(&profile, server, &app, &labels, started, &[]));
+        },
+        PushTarget::Grafana => {
+            let grafana = &config::load().grafana;
+            let server = match args.server.clone().or_else(|| env::var("GRAFANA_PROFILES_URL").ok()).or_else(|| grafana.url.clone()) {
+                Some(server) => server,
+                None => resolve(Err("No Grafana endpoint configured, pass --server, set GRAFANA_PROFILES_URL or grafana.url in the configuration")),
+            };
+            let user = env::var("GRAFANA_PROFILES_USER").ok().or_else(|| grafana.user.clone());
+            let credentials = match (user, grafana_key(grafana)) {
+                (Some(user), Some(key)) => format!("user = {}", curl_quote(&format!("{}:{}", user, key))),
+                _ => resolve(Err("Set GRAFANA_PROFILES_USER and GRAFANA_PROFILES_API_KEY, or grafana.user and grafana.key or grafana.key-file in the configuration, to push to Grafana Cloud")),
+            };
+            print_step(&format!("Pushing profile to {}", server));
+            resolve(push_pyroscope(&profile, &server, &app, &labels, started, &[credentials]));
+        },
+        PushTarget::Parca => {
+            let server = args.server.as_deref().unwrap_or(DEFAULT_PARCA_SERVER);
+            print_step(&format!("Pushing profile to {}", server));
+            resolve(push_parca(&profile, server, &app, &labels));
+        },
+    }
+    eprintln!("Pushed profile as {}", app);
+}
+
+/// Profile written during this run, or else the one of its trace
+///
+/// Folded stacks are not loaded again, as they lost the value names telling heap from CPU profiles.
+fn load_outputs() -> Option<Profile> {
+    if let Some(profile) = report::emitted() {
+        return Some(profile);
+    }
+    let (_, path) = report::outputs().into_iter().find(|(f, _)| *f == report::Format::Trace)?;
+    Some(resolve(profile::load(&path)))
+}
+
+/// API key of Grafana Cloud from the environment, or else from the configuration
+fn grafana_key(grafana: &config::Grafana) -> Option<String> {
+    if let Ok(key) = env::var("GRAFANA_PROFILES_API_KEY") {
+        return Some(key);
+    }
+    if let Some(key) = &grafana.key {
+        return Some(key.clone());
+    }
+    let path = grafana.key_file.as_ref()?;
+    match fs::read_to_string(path) {
+        Ok(key) => Some(key.trim().to_string()),
+        Err(e) => resolve(Err(format!("Could not read the Grafana API key from {} ({})", path.to_string_lossy(), e))),
+    }
+}
+
+fn default_app_name() -> String {
+    env::current_dir().ok()
+        .and_then(|d| d.file_name().map(|n| n.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "cargo-pprof".to_string())
+}
+
+/// Unit of the first value of the profile: CPU samples, or bytes or objects of heap profiles
+fn unit(profile: &Profile) -> &'static str {
+    match profile.value_names.first().map(String::as_str) {
+        Some("samples") => "samples",
+        Some(name) if name.contains("byte") || name.contains("allocated") || name.contains("leaked") => "bytes",
+        _ => "objects",
+    }
+}
+
+/// Post the first value of the profile as folded stacks to `/ingest`, Grafana Cloud uses the same API
+fn push_pyroscope(profile: &Profile, server: &str, app: &str, labels: &[(String, String)], started: SystemTime, secret_options: &[String]) -> Result<(), String> {
+    let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    let name = format!("{}{{{}}}", app, labels.join(","));
+    let (units, rate) = match unit(profile) {
+        "samples" => ("samples", perf::last_frequency()),
+        units => (units, 100),
+    };
+    let url = format!(
+        "{}/ingest?name={}&from={}&until={}&format=folded&sampleRate={}&units={}&aggregationType=sum&spyName=cargo-pprof",
+        server.trim_end_matches('/'), percent_encode(&name), seconds(started), seconds(SystemTime::now()), rate, units);
+
+    let mut body = String::new();
+    for (stack, value) in report::folded_stacks(profile, 0) {
+        body.push_str(&format!("{} {}\n", stack, value));
+    }
+    post(&url, "text/plain", body.as_bytes(), secret_options)
+}
+
+/// Send the profile in pprof format to Parca's `WriteRaw` method
+fn push_parca(profile: &Profile, server: &str, app: &str, labels: &[(String, String)]) -> Result<(), String> {
+    let name = if unit(profile) == "samples" { "process_cpu" } else { "memory" };
+    let mut label_set = vec![
+        json!({ "name": "__name__", "value": name }),
+        json!({ "name": "app", "value": app }),
+    ];
+    label_set.extend(labels.iter().map(|(k, v)| json!({ "name": k, "value": v })));
+    let request = json!({
+        "series": [{
+            "labels": { "labels": label_set },
+            "samples": [{ "rawProfile": base64_encode(&pprof::encode(profile)) }],
+        }],
+        "normalized": true,
+    });
+
+    let (address, plaintext) = match server.strip_prefix("http://") {
+        Some(address) => (address, true),
+        None => (server.strip_prefix("https://").unwrap_or(server), false),
+    };
+    let mut command = process::Command::new("grpcurl");
+    if plaintext {
+        command.arg("-plaintext");
+    }
+    // grpcurl reads the token from its environment, so it does not show up in the process list
+    if env::var_os("PARCA_BEARER_TOKEN").is_some() {
+        command.args(["-expand-headers", "-H", "authorization: Bearer ${PARCA_BEARER_TOKEN}"]);
+    }
+    let mut child = command
+        .args(["-d", "@"])
+        .arg(address)
+        .arg(PARCA_WRITE_RAW)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not run grpcurl ({})", e))?;
+    child.stdin.take()
+        .ok_or("Could not write to grpcurl")?
+        .write_all(request.to_string().as_bytes())
+        .map_err(|e| format!("Could not write to grpcurl ({})", e))?;
+    let status = child.wait().map_err(|e| format!("Could not run grpcurl ({})", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Pushing the profile to {} failed", server))
+    }
+}
+
+/// POST a body with curl
+///
+/// `secret_options` are lines of a curl config file (like `user = "name:key"`), which is used
+/// to keep credentials out of the process list.
+pub fn post(url: &str, content_type: &str, body: &[u8], secret_options: &[String]) -> Result<(), String> {
+    let config_path = env::temp_dir().join(format!("cargo-pprof-curl-{}", process::id()));
+    let mut command = process::Command::new("curl");
+    if !secret_options.is_empty() {
+        let mut config = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&config_path)
+            .map_err(|e| format!("Could not write curl config ({})", e))?;
+        writeln!(config, "{}", secret_options.join("\n"))
+            .map_err(|e| format!("Could not write curl config ({})", e))?;
+        command.arg("--config").arg(&config_path);
+    }
+
+    let result = send(command, url, content_type, body);
+    let _ = fs::remove_file(&config_path);
+    result
+}
+
+/// Quote a value for a curl config file
+pub fn curl_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn send(mut command: process::Command, url: &str, content_type: &str, body: &[u8]) -> Result<(), String> {
+    let mut child = command
+        .args(["--silent", "--show-error", "--fail", "--data-binary", "@-"])
+        .arg("--header").arg(format!("Content-Type: {}", content_type))
+        .arg(url)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not run curl ({})", e))?;
+    child.stdin.take()
+        .ok_or("Could not write to curl")?
+        .write_all(body)
+        .map_err(|e| format!("Could not write to curl ({})", e))?;
+    let status = child.wait().map_err(|e| format!("Could not run curl ({})", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Pushing the profile to {} failed", url))
+    }
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |b, (i, byte)| b | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(buffer >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(names: &[&str]) -> Profile {
+        Profile { value_names: names.iter().map(|n| n.to_string()).collect(), samples: Vec::new() }
+    }
+
+    #[test]
+    fn units_of_cpu_and_heap_profiles() {
+        assert_eq!(unit(&named(&["samples"])), "samples");
+        assert_eq!(unit(&named(&["bytes allocated", "allocations", "bytes leaked", "bytes at peak"])), "bytes");
+        assert_eq!(unit(&named(&["bytes at peak"])), "bytes");
+        assert_eq!(unit(&named(&["allocations"])), "objects");
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+    }
+}
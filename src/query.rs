@@ -0,0 +1,550 @@
+//! Count the samples matching an expression, for analyses the canned reports do not cover
+//!
+//! An expression combines selectors with `and` (or just a space), `or`, `not` and parentheses:
+//!
+//! ```text
+//! function=<regex>    any frame of the stack matches
+//! leaf=<regex>        the sampled function matches
+//! crate=<name>        any frame belongs to the crate
+//! thread=<regex>      the thread name matches
+//! tid=<n>, pid=<n>    thread or process id
+//! time=<from>..<to>   seconds since the first sample, either end may be left out (also `ms`)
+//! ```
+//!
+//! Values with spaces or parentheses are quoted (`thread="tokio worker"`). The regular
+//! expressions support literals, `.`, classes like `[a-z]`, `\d`, `\w`, `\s`, the repetitions
+//! `*`, `+`, `?`, the anchors `^` and `$`, and `|` between whole alternatives.
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::demangle::crate_name;
+use crate::profile::{self, Frame};
+use crate::report::{self, Format, SUMMARY_ROWS};
+use crate::store;
+use crate::{QueryArgs, resolve};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// The sampled function
+    Function,
+    /// Crate of the sampled function
+    Crate,
+    /// Thread name
+    Thread,
+}
+
+/// Sample or folded stack with everything the selectors look at
+struct Entry {
+    frames: Vec<Frame>,
+    comm: String,
+    pid: u32,
+    tid: u32,
+    /// Seconds since the first sample, unknown for folded stacks
+    time: Option<f64>,
+    weight: u64,
+}
+
+enum Expr {
+    Function(Pattern),
+    Leaf(Pattern),
+    Crate(String),
+    Thread(Pattern),
+    Pid(u32),
+    Tid(u32),
+    Time(Option<f64>, Option<f64>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+/// Regular expression of the subset described in the module documentation
+struct Pattern {
+    alternatives: Vec<Alternative>,
+}
+
+struct Alternative {
+    start: bool,
+    end: bool,
+    items: Vec<(Atom, Repeat)>,
+}
+
+enum Atom {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+#[derive(Clone, Copy)]
+enum Repeat {
+    One,
+    Optional,
+    Many,
+    AtLeastOne,
+}
+
+#[derive(Serialize)]
+struct Output {
+    matched: u64,
+    total: u64,
+    percent: f64,
+    groups: Vec<Group>,
+}
+
+#[derive(Serialize)]
+struct Group {
+    name: String,
+    samples: u64,
+    percent: f64,
+}
+
+
+pub fn run(args: &QueryArgs) {
+    let expr = resolve(parse(&args.expr));
+    let path = match &args.input {
+        Some(path) => path.clone(),
+        None => {
+            let (run, dir) = resolve(store::find_run(&args.run));
+            let outputs: Vec<(Format, PathBuf)> = run.outputs.iter()
+                .filter_map(|o| Some((Format::from_str(&o.format, true).ok()?, dir.join(&o.file))))
+                .collect();
+            resolve(report::profile_source(&outputs)
+                .ok_or_else(|| format!("Run {} has no trace or folded stacks", run.id)))
+        },
+    };
+    let entries = resolve(load(&path));
+    if entries.iter().all(|e| e.time.is_none()) && expr.needs_trace() {
+        resolve::<(), _>(Err(format!("{} has no threads and timestamps, query a trace instead", path.to_string_lossy())));
+    }
+
+    let total: u64 = entries.iter().map(|e| e.weight).sum();
+    let matching: Vec<&Entry> = entries.iter().filter(|e| expr.matches(e)).collect();
+    let matched: u64 = matching.iter().map(|e| e.weight).sum();
+
+    let mut groups: Vec<Group> = Vec::new();
+    if let Some(by) = args.by {
+        let mut weights: HashMap<String, u64> = HashMap::new();
+        for entry in &matching {
+            let leaf = entry.frames.first().map(|f| f.function.as_str()).unwrap_or("[unknown]");
+            let name = match by {
+                GroupBy::Function => leaf.to_string(),
+                GroupBy::Crate => crate_name(leaf).to_string(),
+                GroupBy::Thread => entry.comm.clone(),
+            };
+            *weights.entry(name).or_default() += entry.weight;
+        }
+        groups = weights.into_iter()
+            .map(|(name, samples)| Group { percent: report::percent(samples, total), name, samples })
+            .collect();
+        groups.sort_by(|a, b| b.samples.cmp(&a.samples).then_with(|| a.name.cmp(&b.name)));
+    }
+
+    if args.json {
+        let output = Output { matched, total, percent: report::percent(matched, total), groups };
+        println!("{}", resolve(serde_json::to_string_pretty(&output)));
+        return;
+    }
+    println!("{} of {} samples match ({:.2}%)", matched, total, report::percent(matched, total));
+    if !groups.is_empty() {
+        println!("\n{:>8} {:>10}  Name", "Share %", "samples");
+        for group in groups.iter().take(SUMMARY_ROWS) {
+            println!("{:>7.2}% {:>10}  {}", group.percent, group.samples, group.name);
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Entry>, String> {
+    if path.extension().is_some_and(|e| e == "folded") {
+        let profile = profile::load(path)?;
+        return Ok(profile.samples.into_iter()
+            .map(|s| Entry { frames: s.frames, comm: String::new(), pid: 0, tid: 0, time: None, weight: s.values[0] })
+            .collect());
+    }
+    let events = profile::parse_perf_events(path).map_err(|e| format!("Could not read {} ({})", path.to_string_lossy(), e))?;
+    let first = events.iter().map(|e| e.time).fold(f64::INFINITY, f64::min);
+    Ok(events.into_iter()
+        .map(|e| Entry { time: Some(e.time - first), frames: e.frames, comm: e.comm, pid: e.pid, tid: e.tid, weight: 1 })
+        .collect())
+}
+
+impl Expr {
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Expr::Function(pattern) => entry.frames.iter().any(|f| pattern.matches(&f.function)),
+            Expr::Leaf(pattern) => entry.frames.first().is_some_and(|f| pattern.matches(&f.function)),
+            Expr::Crate(name) => entry.frames.iter().any(|f| crate_name(&f.function) == name),
+            Expr::Thread(pattern) => pattern.matches(&entry.comm),
+            Expr::Pid(pid) => entry.pid == *pid,
+            Expr::Tid(tid) => entry.tid == *tid,
+            Expr::Time(from, to) => entry.time.is_some_and(|t| from.is_none_or(|f| t >= f) && to.is_none_or(|e| t < e)),
+            Expr::Not(expr) => !expr.matches(entry),
+            Expr::And(a, b) => a.matches(entry) && b.matches(entry),
+            Expr::Or(a, b) => a.matches(entry) || b.matches(entry),
+        }
+    }
+
+    /// Whether the expression selects by something only traces record
+    fn needs_trace(&self) -> bool {
+        match self {
+            Expr::Thread(_) | Expr::Pid(_) | Expr::Tid(_) | Expr::Time(..) => true,
+            Expr::Function(_) | Expr::Leaf(_) | Expr::Crate(_) => false,
+            Expr::Not(expr) => expr.needs_trace(),
+            Expr::And(a, b) | Expr::Or(a, b) => a.needs_trace() || b.needs_trace(),
+        }
+    }
+}
+
+fn parse(input: &str) -> Result<Expr, String> {
+    let tokens = tokenize(input)?;
+    let mut pos = 0;
+    let expr = parse_or(&tokens, &mut pos)?;
+    match tokens.get(pos) {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?} in the query", token)),
+    }
+}
+
+/// Split into words, parentheses and quoted parts, which are joined with the word they are part of
+fn tokenize(input: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            tokens.push(c.to_string());
+            chars.next();
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '(' || c == ')' {
+                    break;
+                }
+                chars.next();
+                if c == '"' {
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => word.push(c),
+                            None => return Err("Unterminated quote in the query".to_string()),
+                        }
+                    }
+                } else {
+                    word.push(c);
+                }
+            }
+            tokens.push(word);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_or(tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens, pos)?;
+    while tokens.get(*pos).is_some_and(|t| t == "or") {
+        *pos += 1;
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(expr)
+}
+
+fn parse_and(tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+    let mut expr = parse_unary(tokens, pos)?;
+    loop {
+        match tokens.get(*pos).map(String::as_str) {
+            Some("and") => *pos += 1,
+            // Selectors next to each other are combined with `and` as well
+            Some(token) if token != "or" && token != ")" => (),
+            _ => return Ok(expr),
+        }
+        expr = Expr::And(Box::new(expr), Box::new(parse_unary(tokens, pos)?));
+    }
+}
+
+fn parse_unary(tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+    let Some(token) = tokens.get(*pos) else { return Err("The query ends unexpectedly".to_string()) };
+    *pos += 1;
+    match token.as_str() {
+        "not" => Ok(Expr::Not(Box::new(parse_unary(tokens, pos)?))),
+        "(" => {
+            let expr = parse_or(tokens, pos)?;
+            if tokens.get(*pos).is_none_or(|t| t != ")") {
+                return Err("Missing `)` in the query".to_string());
+            }
+            *pos += 1;
+            Ok(expr)
+        },
+        _ => parse_selector(token),
+    }
+}
+
+fn parse_selector(token: &str) -> Result<Expr, String> {
+    let Some((key, value)) = token.split_once('=') else {
+        return Err(format!("Expected a selector like `function=<regex>` instead of {:?}", token));
+    };
+    let id = || value.parse::<u32>().map_err(|_| format!("Invalid {} {:?}", key, value));
+    Ok(match key {
+        "function" => Expr::Function(Pattern::parse(value)?),
+        "leaf" => Expr::Leaf(Pattern::parse(value)?),
+        "crate" => Expr::Crate(value.to_string()),
+        "thread" => Expr::Thread(Pattern::parse(value)?),
+        "pid" => Expr::Pid(id()?),
+        "tid" => Expr::Tid(id()?),
+        "time" => {
+            let (from, to) = value.split_once("..").ok_or_else(|| format!("Expected `time=<from>..<to>` instead of {:?}", token))?;
+            Expr::Time(parse_seconds(from)?, parse_seconds(to)?)
+        },
+        _ => return Err(format!("Unknown selector {:?} (function, leaf, crate, thread, pid, tid or time)", key)),
+    })
+}
+
+/// Seconds, or milliseconds with an `ms` suffix, nothing for an open end
+fn parse_seconds(s: &str) -> Result<Option<f64>, String> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let (number, factor) = match s.strip_suffix("ms") {
+        Some(number) => (number, 1e-3),
+        None => (s.strip_suffix('s').unwrap_or(s), 1.0),
+    };
+    number.parse::<f64>().map(|n| Some(n * factor)).map_err(|_| format!("Invalid time {:?}", s))
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Pattern, String> {
+        let alternatives = split_alternatives(pattern).into_iter()
+            .map(|alternative| Alternative::parse(&alternative).map_err(|e| format!("{} in the pattern {:?}", e, pattern)))
+            .collect::<Result<_, _>>()?;
+        Ok(Pattern { alternatives })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        self.alternatives.iter().any(|a| {
+            if a.start {
+                match_here(&a.items, &text, a.end)
+            } else {
+                (0..=text.len()).any(|i| match_here(&a.items, &text[i..], a.end))
+            }
+        })
+    }
+}
+
+/// Split at every `|` that is not escaped or inside a class
+fn split_alternatives(pattern: &str) -> Vec<String> {
+    let mut alternatives = vec![String::new()];
+    let mut chars = pattern.chars().peekable();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        let current = alternatives.last_mut().unwrap();
+        match c {
+            '|' if !in_class => {
+                alternatives.push(String::new());
+                continue;
+            },
+            '\\' => {
+                current.push(c);
+                current.extend(chars.next());
+                continue;
+            },
+            '[' if !in_class => {
+                in_class = true;
+                current.push(c);
+                // A `]` first in the class is a literal, like in `Alternative::parse`
+                current.extend(chars.next_if_eq(&'^'));
+                current.extend(chars.next_if_eq(&']'));
+                continue;
+            },
+            ']' => in_class = false,
+            _ => (),
+        }
+        current.push(c);
+    }
+    alternatives
+}
+
+impl Alternative {
+    fn parse(pattern: &str) -> Result<Alternative, String> {
+        let mut chars = pattern.chars().peekable();
+        let start = chars.next_if_eq(&'^').is_some();
+        let mut end = false;
+        let mut items: Vec<(Atom, Repeat)> = Vec::new();
+        while let Some(c) = chars.next() {
+            let atom = match c {
+                '$' if chars.peek().is_none() => {
+                    end = true;
+                    continue;
+                },
+                '*' | '+' | '?' => {
+                    let Some((_, repeat @ Repeat::One)) = items.last_mut() else {
+                        return Err(format!("Nothing to repeat before `{}`", c));
+                    };
+                    *repeat = match c {
+                        '*' => Repeat::Many,
+                        '+' => Repeat::AtLeastOne,
+                        _ => Repeat::Optional,
+                    };
+                    continue;
+                },
+                '.' => Atom::Any,
+                '\\' => escape(chars.next().ok_or("Trailing `\\`")?),
+                '[' => {
+                    let negated = chars.next_if_eq(&'^').is_some();
+                    let mut ranges = Vec::new();
+                    loop {
+                        let c = match chars.next() {
+                            Some(']') if !ranges.is_empty() => break,
+                            Some('\\') => chars.next().ok_or("Trailing `\\`")?,
+                            Some(c) => c,
+                            None => return Err("Missing `]`".to_string()),
+                        };
+                        let end = match chars.next_if_eq(&'-') {
+                            Some(_) if chars.peek().is_some_and(|c| *c != ']') => chars.next().unwrap(),
+                            Some(dash) => {
+                                ranges.push((dash, dash));
+                                c
+                            },
+                            None => c,
+                        };
+                        ranges.push((c, end));
+                    }
+                    Atom::Class { ranges, negated }
+                },
+                c => Atom::Char(c),
+            };
+            items.push((atom, Repeat::One));
+        }
+        Ok(Alternative { start, end, items })
+    }
+}
+
+/// Atom of `\<c>`, the shorthand classes or the literal character
+fn escape(c: char) -> Atom {
+    let class = |ranges: &[(char, char)]| Atom::Class { ranges: ranges.to_vec(), negated: false };
+    match c {
+        'd' => class(&[('0', '9')]),
+        'w' => class(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' => class(&[(' ', ' '), ('\t', '\t'), ('\n', '\n')]),
+        c => Atom::Char(c),
+    }
+}
+
+impl Atom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Atom::Char(expected) => c == *expected,
+            Atom::Any => true,
+            Atom::Class { ranges, negated } => ranges.iter().any(|(from, to)| (*from..=*to).contains(&c)) != *negated,
+        }
+    }
+}
+
+/// Whether the items match at the start of `text`, greedily with backtracking
+fn match_here(items: &[(Atom, Repeat)], text: &[char], end: bool) -> bool {
+    let Some(((atom, repeat), rest)) = items.split_first() else { return !end || text.is_empty() };
+    let (min, max) = match repeat {
+        Repeat::One => (1, 1),
+        Repeat::Optional => (0, 1),
+        Repeat::Many => (0, usize::MAX),
+        Repeat::AtLeastOne => (1, usize::MAX),
+    };
+    let available = text.iter().take(max).take_while(|c| atom.matches(**c)).count();
+    available >= min && (min..=available).rev().any(|n| match_here(rest, &text[n..], end))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        Pattern::parse(pattern).unwrap().matches(text)
+    }
+
+    fn entry(functions: &[&str]) -> Entry {
+        Entry {
+            frames: functions.iter().map(|f| Frame { function: f.to_string(), module: String::new() }).collect(),
+            comm: "main".to_string(),
+            pid: 1,
+            tid: 1,
+            time: Some(0.002),
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("^[a-]$", "-"));
+        assert!(matches("^[a-]$", "a"));
+        assert!(!matches("^[a-]$", "b"));
+        assert!(matches("^[]a]+$", "]a]"));
+        assert!(!matches("^[^]a]$", "]"));
+        assert!(matches("^[^]a]$", "b"));
+        assert!(matches("^[]|]$", "|"));
+        assert!(matches(r"^\d+\w$", "12a"));
+    }
+
+    #[test]
+    fn anchors() {
+        assert!(matches("^alloc", "alloc::vec"));
+        assert!(!matches("^alloc", "core::alloc"));
+        assert!(matches("vec$", "alloc::vec"));
+        assert!(!matches("vec$", "alloc::vec::Vec"));
+        assert!(matches("^a.*c$", "abbc"));
+        assert!(!matches("^a.*c$", "abbcd"));
+        assert!(matches("a$b", "a$b"));
+    }
+
+    #[test]
+    fn alternatives() {
+        assert!(matches("^foo$|^bar$", "bar"));
+        assert!(!matches("^foo$|^bar$", "foobar"));
+        assert!(matches(r"^a\|b$", "a|b"));
+        assert!(!matches(r"^a\|b$", "b"));
+        assert_eq!(split_alternatives(r"a\|b|[|]"), vec![r"a\|b", "[|]"]);
+    }
+
+    #[test]
+    fn repetitions_backtrack() {
+        assert!(matches("^a*ab$", "aaab"));
+        assert!(matches("^x+y?z$", "xxz"));
+        assert!(!matches("^x+y?z$", "z"));
+        assert!(Pattern::parse("*a").is_err());
+        assert!(Pattern::parse("[a").is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        // `a b or c` is `(a and b) or c`, `not` only applies to the next selector
+        let expr = parse("function=^a$ function=^b$ or leaf=^c$").unwrap();
+        assert!(expr.matches(&entry(&["c"])));
+        assert!(expr.matches(&entry(&["a", "b"])));
+        assert!(!expr.matches(&entry(&["a"])));
+        let expr = parse("not leaf=^a$ and function=^b$").unwrap();
+        assert!(expr.matches(&entry(&["c", "b"])));
+        assert!(!expr.matches(&entry(&["a", "b"])));
+        let expr = parse("function=^a$ and (function=^b$ or function=^c$)").unwrap();
+        assert!(expr.matches(&entry(&["a", "c"])));
+        assert!(!expr.matches(&entry(&["c"])));
+        assert!(parse("(function=a").is_err());
+        assert!(parse("function=a or").is_err());
+    }
+
+    #[test]
+    fn time_ranges() {
+        assert!(matches!(parse("time=..5ms").unwrap(), Expr::Time(None, Some(to)) if (to - 0.005).abs() < 1e-12));
+        assert!(matches!(parse("time=1.5..").unwrap(), Expr::Time(Some(from), None) if from == 1.5));
+        assert!(parse("time=..5ms").unwrap().matches(&entry(&["a"])));
+        assert!(!parse("time=1ms..2ms").unwrap().matches(&entry(&["a"])));
+        assert!(parse("time=5").is_err());
+        assert!(parse("time=x..").is_err());
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(tokenize(r#"thread="tokio worker" (pid=1)"#).unwrap(), vec!["thread=tokio worker", "(", "pid=1", ")"]);
+        assert!(tokenize(r#"thread="open"#).is_err());
+    }
+}
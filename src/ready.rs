@@ -0,0 +1,223 @@
+//! Readiness probes delaying the start of sampling until a service is up
+//!
+//! perf starts with sampling disabled and is told to enable it through a control FIFO once the
+//! probe succeeds, so the startup of the service does not end up in the profile.
+
+use std::{fmt, fs::{self, OpenOptions}, io::{BufRead, BufReader, Read, Write}, net::{SocketAddr, TcpStream}, path::{Path, PathBuf}, process, sync::mpsc, thread, time::{Duration, Instant}};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::perf::{self, Recording};
+use crate::{log_command, print_step, resolve, resolve_status};
+
+/// Time between two attempts of a probe
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// The service accepts connections on this port of localhost
+    Port(u16),
+    /// The service printed a line containing the text
+    Log(String),
+    /// A GET request of the URL succeeds
+    Http(String),
+}
+
+
+/// Parse a probe of the form `port:8080`, `log:TEXT` or `http:URL`
+pub fn parse(text: &str) -> Result<Probe, String> {
+    let (kind, value) = text.split_once(':')
+        .ok_or_else(|| format!("expected port:PORT, log:TEXT or http:URL, got {:?}", text))?;
+    match kind {
+        "port" => value.parse().map(Probe::Port).map_err(|_| format!("invalid port {:?}", value)),
+        "log" if !value.is_empty() => Ok(Probe::Log(value.to_string())),
+        // The scheme is part of the URL, `http:https://...` works as well as `http://...`
+        "http" | "https" if value.starts_with("//") => Ok(Probe::Http(text.to_string())),
+        "http" => Ok(Probe::Http(value.to_string())),
+        _ => Err(format!("expected port:PORT, log:TEXT or http:URL, got {:?}", text)),
+    }
+}
+
+/// Record the application with sampling enabled once the probe succeeds, returns the path of the trace file
+pub fn record(recording: &Recording, probe: &Probe, timeout: Duration) -> PathBuf {
+    print_step("Running program with perf");
+    let mut child = start(recording, Some(probe), timeout);
+    let status = resolve(child.wait());
+    perf::check_data(recording);
+    app::check_exit(status, recording.ignore_exit);
+    perf::script(recording)
+}
+
+/// Start `perf record` in the background and wait for the probe, after which sampling is enabled
+pub fn start(recording: &Recording, probe: Option<&Probe>, timeout: Duration) -> process::Child {
+    perf::check_paranoid();
+    let _ = fs::remove_file(&recording.data);
+
+    let mut recording = recording.clone();
+    let control = recording.dir.join("perf-control.fifo");
+    if probe.is_some() {
+        start_disabled(&mut recording, &control);
+    }
+
+    let (mut child, lines) = spawn(&recording, probe);
+    let Some(probe) = probe else { return child };
+    if let Err(e) = wait(&mut child, probe, lines.as_ref(), timeout) {
+        stop(&mut child);
+        let _ = fs::remove_file(&control);
+        resolve::<(), _>(Err(e));
+    }
+    enable(&control);
+    child
+}
+
+/// Make perf start with sampling disabled until [`enable`] writes to the control FIFO
+pub fn start_disabled(recording: &mut Recording, control: &Path) {
+    let _ = fs::remove_file(control);
+    resolve_status(resolve(process::Command::new("mkfifo").arg(control).status()));
+    recording.record_args.extend(["--delay=-1".to_string(), format!("--control=fifo:{}", control.to_string_lossy())]);
+}
+
+/// Enable the sampling of a perf started with [`start_disabled`]
+pub fn enable(control: &Path) {
+    // perf keeps the FIFO open for reading, so opening it does not block
+    let enabled = OpenOptions::new().write(true).open(control)
+        .and_then(|mut fifo| fifo.write_all(b"enable\n"));
+    let _ = fs::remove_file(control);
+    resolve(enabled.map_err(|e| format!("Could not enable sampling ({})", e)));
+}
+
+/// Record the startup of the application until the probe succeeds, or for `limit` without one
+///
+/// Returns the path of the trace file and the time the application took to become ready.
+pub fn record_startup(recording: &Recording, probe: Option<&Probe>, limit: Duration) -> (PathBuf, Option<Duration>) {
+    print_step("Running program with perf");
+    perf::check_paranoid();
+    let _ = fs::remove_file(&recording.data);
+    let (mut child, lines) = spawn(recording, probe);
+
+    if let Some(probe) = probe {
+        print_step(&format!("Waiting for {}", probe));
+    }
+    let started = Instant::now();
+    let mut ready = None;
+    while resolve(child.try_wait()).is_none() {
+        if probe.is_some_and(|p| is_ready(p, lines.as_ref())) {
+            ready = Some(started.elapsed());
+            eprintln!("Ready after {:.1}s, stopping the recording", started.elapsed().as_secs_f64());
+            break;
+        }
+        if started.elapsed() > limit {
+            match probe {
+                Some(_) => eprintln!("{}", format!("Warning: The application did not become ready within {}s (see --wait-timeout)", limit.as_secs()).yellow()),
+                None => eprintln!("Recorded the first {}s (see --duration)", limit.as_secs()),
+            }
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    if probe.is_some() && ready.is_none() && resolve(child.try_wait()).is_some() {
+        eprintln!("{}", "Warning: The application exited before it was ready, the whole run was recorded".yellow());
+    }
+    stop(&mut child);
+    perf::check_data(recording);
+    (perf::script(recording), ready)
+}
+
+/// Start `perf record`, with the output of the application piped through for log probes
+pub fn spawn(recording: &Recording, probe: Option<&Probe>) -> (process::Child, Option<mpsc::Receiver<String>>) {
+    let mut command = perf::record_command(recording);
+    let is_log = matches!(probe, Some(Probe::Log(_)));
+    if is_log {
+        command.stdout(process::Stdio::piped()).stderr(process::Stdio::piped());
+    }
+    log_command(&command);
+    let mut child = resolve(command.spawn()
+        .map_err(|e| format!("Could not run perf ({})", e)));
+    let lines = is_log.then(|| forward_output(&mut child));
+    (child, lines)
+}
+
+/// Interrupt perf like Ctrl+C would, it writes the data and terminates the application
+pub fn stop(child: &mut process::Child) {
+    const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+    if resolve(child.try_wait()).is_some() {
+        return;
+    }
+    let mut command = process::Command::new("kill");
+    command.args(["-INT", &child.id().to_string()]);
+    log_command(&command);
+    let _ = command.status();
+
+    let started = Instant::now();
+    while resolve(child.try_wait()).is_none() {
+        if started.elapsed() > STOP_TIMEOUT {
+            eprintln!("{}", "Warning: perf did not stop in time, killing it".yellow());
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Pass the output of the child on and return the lines it prints
+fn forward_output(child: &mut process::Child) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+    let stderr = child.stderr.take().map(|s| Box::new(s) as Box<dyn Read + Send>);
+    for (output, is_stderr) in [(stdout, false), (stderr, true)] {
+        let Some(output) = output else { continue };
+        let sender = sender.clone();
+        let mut log = app::output_log(is_stderr);
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines().map_while(Result::ok) {
+                if is_stderr { eprintln!("{}", line) } else { println!("{}", line) }
+                if let Some(log) = &mut log {
+                    let _ = writeln!(log, "{}", line);
+                }
+                let _ = sender.send(line);
+            }
+        });
+    }
+    receiver
+}
+
+fn wait(child: &mut process::Child, probe: &Probe, lines: Option<&mpsc::Receiver<String>>, timeout: Duration) -> Result<(), String> {
+    print_step(&format!("Waiting for {}", probe));
+    let started = Instant::now();
+    loop {
+        if let Some(status) = resolve(child.try_wait()) {
+            return Err(format!("The application exited before it was ready ({})", status));
+        }
+        if is_ready(probe, lines) {
+            eprintln!("Ready after {:.1}s, sampling starts now", started.elapsed().as_secs_f64());
+            return Ok(());
+        }
+        if started.elapsed() > timeout {
+            return Err(format!("The application did not become ready within {}s (see --wait-timeout)", timeout.as_secs()));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn is_ready(probe: &Probe, lines: Option<&mpsc::Receiver<String>>) -> bool {
+    match probe {
+        Probe::Port(port) => TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], *port)), POLL_INTERVAL).is_ok(),
+        Probe::Log(text) => lines.is_some_and(|l| l.try_iter().any(|line| line.contains(text))),
+        Probe::Http(url) => process::Command::new("curl")
+            .args(["--silent", "--fail", "--output", "/dev/null", "--max-time", "1", url])
+            .status()
+            .is_ok_and(|s| s.success()),
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Probe::Port(port) => write!(f, "port {}", port),
+            Probe::Log(text) => write!(f, "a line containing {:?}", text),
+            Probe::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fs::{self, File}, path::Path, process};
+
+use crate::app;
+use crate::perf;
+use crate::report::{self, Format};
+use crate::{RemoteArgs, print_step, resolve, resolve_status, shell_quote};
+
+/// Directory on the remote machine the binary and the recording are placed in
+const REMOTE_DIR: &str = "/tmp/cargo-pprof";
+
+
+/// Build the binary, record it with perf on a remote machine and convert the data locally
+pub fn run(args: &RemoteArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Trace]);
+    let mut cargo_args = Vec::new();
+    if let Some(target) = &args.target {
+        cargo_args.extend(["--target", target.as_str()]);
+    }
+    let executable = crate::build(&cargo_args);
+    let dir = crate::output_dir(&executable);
+
+    let name = Path::new(&executable).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "app".to_string());
+    let remote_binary = format!("{}/{}", REMOTE_DIR, name);
+    let remote_data = format!("{}/perf.data", REMOTE_DIR);
+    let data_path = dir.join("remote.data");
+
+    print_step(&format!("Copying binary to {}", args.host));
+    let status = resolve(ssh(&args.host, &format!("mkdir -p {0} && cat > {1} && chmod +x {1}", REMOTE_DIR, shell_quote(&remote_binary)))
+        .stdin(resolve(File::open(&executable)))
+        .status());
+    resolve_status(status);
+
+    print_step("Running program with perf on the remote machine");
+    let mut command: Vec<String> = app::env().iter()
+        .map(|(key, value)| shell_quote(&format!("{}={}", key, value)))
+        .collect();
+    if !command.is_empty() {
+        command.insert(0, "env".to_string());
+    }
+    command.extend(["perf".to_string(), "record".to_string()]);
+    command.extend(perf::SAMPLING_ARGS.iter().map(|a| a.to_string()));
+    command.push(format!("--output={}", shell_quote(&remote_data)));
+    command.push(shell_quote(&remote_binary));
+    command.extend(args.run.app_args.iter().map(|a| shell_quote(a)));
+    let status = resolve(ssh(&args.host, &command.join(" ")).stdin(app::stdin()).status());
+    app::check_exit(status, args.run.ignore_exit);
+
+    print_step("Fetching recording");
+    let status = resolve(ssh(&args.host, &format!("cat {}", shell_quote(&remote_data)))
+        .stdout(resolve(File::create(&data_path)))
+        .status());
+    resolve_status(status);
+
+    // perf looks up binaries under their remote path inside the symfs directory
+    let symfs = dir.join("remote-symfs");
+    let symfs_binary = symfs.join(remote_binary.trim_start_matches('/'));
+    if let Some(parent) = symfs_binary.parent() {
+        resolve(fs::create_dir_all(parent));
+    }
+    resolve(fs::copy(&executable, &symfs_binary));
+
+    let mut recording = perf::Recording::new(dir, "remote", &executable, &[], args.run.ignore_exit);
+    recording.script_args = vec![format!("--symfs={}", symfs.to_string_lossy())];
+    let trace_path = perf::script(&recording);
+
+    perf::convert(&trace_path, &formats, dir, "remote");
+    if formats.contains(&Format::Trace) {
+        perf::print_trace_hint(&trace_path);
+    }
+}
+
+fn ssh(host: &str, command: &str) -> process::Command {
+    let mut ssh = process::Command::new("ssh");
+    ssh.arg(host).arg(command);
+    crate::log_command(&ssh);
+    ssh
+}
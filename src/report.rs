@@ -0,0 +1,367 @@
+use std::{collections::HashMap, env, fs::{File, OpenOptions}, io::{self, BufWriter, Write}, path::{Path, PathBuf}, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::messages::{self, Message};
+use crate::pprof;
+use crate::profile::Profile;
+
+/// Number of functions listed in the summary
+pub const SUMMARY_ROWS: usize = 20;
+
+/// Number of binaries listed in the summary
+const MODULE_ROWS: usize = 8;
+
+/// Files written during this run, so they can be opened afterwards
+static OUTPUTS: Mutex<Vec<(Format, PathBuf)>> = Mutex::new(Vec::new());
+
+/// The last profile passed to [`emit`], as it was written
+static EMITTED: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Whether `--flat` was given
+static FLAT: AtomicBool = AtomicBool::new(false);
+
+/// File that outputs are additionally appended to as `format path` lines, used by `watch`
+pub const OUTPUTS_ENV_VAR: &str = "CARGO_PPROF_OUTPUTS";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Raw `perf script` output, as accepted by the Firefox Profiler
+    Trace,
+    /// Collapsed stacks, as accepted by flamegraph.pl and inferno
+    Folded,
+    /// Table of the hottest functions printed to stdout
+    Summary,
+    /// Firefox Profiler JSON with marker and counter tracks
+    Gecko,
+    /// pprof protobuf, as accepted by `go tool pprof` and continuous profiling services
+    Pprof,
+    /// SVG timeline of when each thread was running or blocked (records context switches with perf)
+    Timechart,
+}
+
+
+pub fn set_flat(flat: bool) {
+    FLAT.store(flat, Ordering::Relaxed);
+}
+
+/// Whether reports only take the sampled functions into account and ignore their callers
+pub fn flat() -> bool {
+    FLAT.load(Ordering::Relaxed)
+}
+
+/// The requested formats, or the defaults if none were requested
+pub fn formats_or(requested: &[Format], defaults: &[Format]) -> Vec<Format> {
+    if requested.is_empty() {
+        defaults.to_vec()
+    } else {
+        requested.to_vec()
+    }
+}
+
+/// Print the location of a written output file and remember it for [`outputs`]
+pub fn print_output(format: Format, path: &Path) {
+    let label = match format {
+        Format::Trace => "Trace file",
+        Format::Folded => "Folded stacks",
+        Format::Summary => "Summary",
+        Format::Gecko => "Firefox Profiler file",
+        Format::Pprof => "pprof profile",
+        Format::Timechart => "Timechart",
+    };
+    println!("{}: {}", label, path.to_string_lossy().cyan());
+    OUTPUTS.lock().unwrap().push((format, path.to_path_buf()));
+    if let Some(name) = format.to_possible_value() {
+        messages::emit(Message::Artifact { format: name.get_name(), path });
+    }
+
+    if let Ok(list) = env::var(OUTPUTS_ENV_VAR)
+        && let Ok(mut file) = OpenOptions::new().create(true).append(true).open(list)
+        && let Some(name) = format.to_possible_value() {
+        let _ = writeln!(file, "{} {}", name.get_name(), path.to_string_lossy());
+    }
+}
+
+/// All files passed to [`print_output`] so far
+pub fn outputs() -> Vec<(Format, PathBuf)> {
+    OUTPUTS.lock().unwrap().clone()
+}
+
+/// Number of samples of the last emitted profile, if any
+pub fn sample_count() -> Option<u64> {
+    EMITTED.lock().unwrap().as_ref()
+        .map(|p| p.samples.iter().map(|s| s.values.first().copied().unwrap_or(0)).sum())
+}
+
+/// The last emitted profile with its value names, if any
+pub fn emitted() -> Option<Profile> {
+    EMITTED.lock().unwrap().clone()
+}
+
+/// The trace or else the folded stacks among the outputs, from which a profile can be loaded again
+pub fn profile_source(outputs: &[(Format, PathBuf)]) -> Option<PathBuf> {
+    outputs.iter().find(|(f, _)| *f == Format::Trace)
+        .or_else(|| outputs.iter().find(|(f, _)| *f == Format::Folded))
+        .map(|(_, p)| p.clone())
+}
+
+/// The output best suited for the Firefox Profiler, a Gecko file or else a trace
+pub fn profiler_output() -> Option<PathBuf> {
+    let outputs = outputs();
+    outputs.iter().find(|(f, _)| *f == Format::Gecko)
+        .or_else(|| outputs.iter().find(|(f, _)| *f == Format::Trace))
+        .map(|(_, p)| p.clone())
+}
+
+/// Generate all report formats except `trace`, `gecko` and `timechart`, which are produced by the backends themselves
+pub fn emit(profile: &Profile, formats: &[Format], dir: &Path, stem: &str) {
+    let flat_profile;
+    let profile = if flat() {
+        flat_profile = leaves(profile);
+        &flat_profile
+    } else {
+        profile
+    };
+    for format in formats {
+        match format {
+            Format::Trace | Format::Gecko | Format::Timechart => (),
+            Format::Folded => {
+                for path in crate::resolve(write_folded(profile, dir, stem)) {
+                    print_output(Format::Folded, &path);
+                }
+            },
+            Format::Summary => print_summary(profile),
+            Format::Pprof => {
+                let path = dir.join(format!("{}.pb", stem));
+                crate::resolve(pprof::write(profile, &path));
+                print_output(Format::Pprof, &path);
+            },
+        }
+    }
+    *EMITTED.lock().unwrap() = Some(profile.clone());
+}
+
+/// The profile with only the leaf frame of every sample, which does not depend on how well the stacks were unwound
+pub fn leaves(profile: &Profile) -> Profile {
+    let mut flat = profile.clone();
+    for sample in &mut flat.samples {
+        sample.frames.truncate(1);
+    }
+    flat
+}
+
+/// Write one folded file per value type, returns the paths of the written files
+pub fn write_folded(profile: &Profile, dir: &Path, stem: &str) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for (i, name) in profile.value_names.iter().enumerate() {
+        let path = if profile.value_names.len() == 1 {
+            dir.join(format!("{}.folded", stem))
+        } else {
+            dir.join(format!("{}.{}.folded", stem, file_name_part(name)))
+        };
+
+        let mut file = BufWriter::new(File::create(&path)?);
+        for (stack, value) in folded_stacks(profile, i) {
+            writeln!(file, "{} {}", stack, value)?;
+        }
+        file.flush()?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// Collapsed stacks (outermost frame first, separated by `;`) with their summed up value
+pub fn folded_stacks(profile: &Profile, value: usize) -> Vec<(String, u64)> {
+    let mut totals: HashMap<String, u64> = HashMap::new();
+    for sample in &profile.samples {
+        let stack: Vec<&str> = sample.frames.iter()
+            .rev()
+            .map(|f| f.function.as_str())
+            .collect();
+        *totals.entry(stack.join(";")).or_default() += sample.values[value];
+    }
+
+    let mut stacks: Vec<_> = totals.into_iter()
+        .filter(|(_, v)| *v > 0)
+        .collect();
+    stacks.sort();
+    stacks
+}
+
+/// Self and total values of a single function
+#[derive(Debug, Clone)]
+pub struct FunctionStats<'a> {
+    pub function: &'a str,
+    /// Values of the samples with the function as leaf, one per value name
+    pub self_values: Vec<u64>,
+    /// First value of all samples containing the function
+    pub total: u64,
+}
+
+/// Statistics of all leaf functions sorted by their first self value, and the sum of the first value
+pub fn function_stats(profile: &Profile) -> (Vec<FunctionStats<'_>>, u64) {
+    let nvalues = profile.value_names.len();
+    let mut self_values: HashMap<&str, Vec<u64>> = HashMap::new();
+    let mut total_values: HashMap<&str, u64> = HashMap::new();
+    let mut grand_total = 0;
+
+    for sample in &profile.samples {
+        let Some(leaf) = sample.frames.first() else { continue };
+        let entry = self_values.entry(leaf.function.as_str())
+            .or_insert_with(|| vec![0; nvalues]);
+        for (acc, value) in entry.iter_mut().zip(&sample.values) {
+            *acc += value;
+        }
+
+        let mut seen = Vec::new();
+        for frame in &sample.frames {
+            if !seen.contains(&frame.function.as_str()) {
+                seen.push(frame.function.as_str());
+                *total_values.entry(frame.function.as_str()).or_default() += sample.values[0];
+            }
+        }
+        grand_total += sample.values[0];
+    }
+
+    let mut rows: Vec<_> = self_values.into_iter()
+        .map(|(function, self_values)| FunctionStats { function, self_values, total: total_values[function] })
+        .collect();
+    rows.sort_by(|a, b| b.self_values[0].cmp(&a.self_values[0]).then(a.function.cmp(b.function)));
+    (rows, grand_total)
+}
+
+/// Share of `value` in `total` in percent
+pub fn percent(value: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { value as f64 * 100.0 / total as f64 }
+}
+
+/// First self value of every binary the leaf functions belong to, sorted by it, and the sum of the first value
+pub fn module_stats(profile: &Profile) -> (Vec<(&str, u64)>, u64) {
+    let mut totals: HashMap<&str, u64> = HashMap::new();
+    let mut grand_total = 0;
+    for sample in &profile.samples {
+        let Some(leaf) = sample.frames.first() else { continue };
+        *totals.entry(leaf.module.as_str()).or_default() += sample.values[0];
+        grand_total += sample.values[0];
+    }
+    let mut rows: Vec<(&str, u64)> = totals.into_iter().collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    (rows, grand_total)
+}
+
+/// Print the share of the own binary, system libraries and the kernel, if the profile knows the binaries
+fn print_modules(profile: &Profile) {
+    let (rows, grand_total) = module_stats(profile);
+    if rows.iter().all(|(module, _)| module.is_empty()) {
+        return;
+    }
+    println!("\n{}", format!("Time by binary ({})", profile.value_names[0]).bold());
+    println!("{:>8} {:>10}  Binary", "Self %", profile.value_names[0]);
+    for (module, value) in rows.iter().take(MODULE_ROWS) {
+        // Paths of system libraries are long and only the name matters
+        let name = Path::new(module).file_name().map(|n| n.to_string_lossy()).unwrap_or((*module).into());
+        println!("{:>7.2}% {:>10}  {}", percent(*value, grand_total), value, name);
+    }
+    if rows.len() > MODULE_ROWS {
+        let rest: u64 = rows[MODULE_ROWS..].iter().map(|(_, v)| v).sum();
+        println!("{:>7.2}% {:>10}  ({} more)", percent(rest, grand_total), rest, rows.len() - MODULE_ROWS);
+    }
+}
+
+/// Print the time by binary and the functions with the highest self value
+pub fn print_summary(profile: &Profile) {
+    print_modules(profile);
+    if flat() {
+        print_flat(profile);
+        return;
+    }
+    let (rows, grand_total) = function_stats(profile);
+    let widths: Vec<usize> = profile.value_names.iter()
+        .map(|n| n.len().max(10))
+        .collect();
+
+    println!("\n{}", format!("Top functions by {}", profile.value_names[0]).bold());
+    print!("{:>8} {:>8}", "Self %", "Total %");
+    for (name, width) in profile.value_names.iter().zip(&widths) {
+        print!(" {:>width$}", name, width = width);
+    }
+    println!("  Function");
+
+    for row in rows.into_iter().take(SUMMARY_ROWS) {
+        print!("{:>7.2}% {:>7.2}%", percent(row.self_values[0], grand_total), percent(row.total, grand_total));
+        for (value, width) in row.self_values.iter().zip(&widths) {
+            print!(" {:>width$}", value, width = width);
+        }
+        println!("  {}", row.function);
+    }
+}
+
+/// Print the functions with the highest self value and the running sum of their shares, without totals
+fn print_flat(profile: &Profile) {
+    let (rows, grand_total) = function_stats(profile);
+    let widths: Vec<usize> = profile.value_names.iter()
+        .map(|n| n.len().max(10))
+        .collect();
+
+    println!("\n{}", format!("Flat profile by {}", profile.value_names[0]).bold());
+    print!("{:>8} {:>8}", "Self %", "Cumul %");
+    for (name, width) in profile.value_names.iter().zip(&widths) {
+        print!(" {:>width$}", name, width = width);
+    }
+    println!("  Function");
+
+    let mut cumulative = 0;
+    for row in rows.into_iter().take(SUMMARY_ROWS) {
+        cumulative += row.self_values[0];
+        print!("{:>7.2}% {:>7.2}%", percent(row.self_values[0], grand_total), percent(cumulative, grand_total));
+        for (value, width) in row.self_values.iter().zip(&widths) {
+            print!(" {:>width$}", value, width = width);
+        }
+        println!("  {}", row.function);
+    }
+}
+
+/// Markdown table of the hottest functions, with the change against a baseline if given
+pub fn markdown_summary(profile: &Profile, baseline: Option<&Profile>) -> String {
+    let (rows, grand_total) = function_stats(profile);
+    let baseline = baseline.map(|b| {
+        let (rows, total) = function_stats(b);
+        rows.iter()
+            .map(|r| (r.function.to_string(), percent(r.self_values[0], total)))
+            .collect::<HashMap<String, f64>>()
+    });
+
+    let value_name = profile.value_names.first().map(String::as_str).unwrap_or("samples");
+    let mut markdown = format!("### Top functions by {}\n\n", value_name);
+    markdown.push_str(&format!("Total {}: {}\n\n", value_name, grand_total));
+    if baseline.is_some() {
+        markdown.push_str("| Self % | Baseline % | Change | Total % | Function |\n|---:|---:|---:|---:|---|\n");
+    } else {
+        markdown.push_str("| Self % | Total % | Function |\n|---:|---:|---|\n");
+    }
+    for row in rows.into_iter().take(SUMMARY_ROWS) {
+        let share = percent(row.self_values[0], grand_total);
+        let function = format!("`{}`", row.function.replace('|', "\\|").replace('`', "'"));
+        match &baseline {
+            Some(baseline) => {
+                let before = baseline.get(row.function).copied().unwrap_or(0.0);
+                markdown.push_str(&format!("| {:.2}% | {:.2}% | {:+.2} | {:.2}% | {} |\n",
+                    share, before, share - before, percent(row.total, grand_total), function));
+            },
+            None => markdown.push_str(&format!("| {:.2}% | {:.2}% | {} |\n",
+                share, percent(row.total, grand_total), function)),
+        }
+    }
+    markdown
+}
+
+/// Turn a value name into something that can be used inside a file name
+pub fn file_name_part(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect()
+}
@@ -0,0 +1,153 @@
+//! Symbolize a recording again with a binary that has (better) debug info
+//!
+//! The frames of the binary get their names from `addr2line` on the new binary instead of what
+//! perf found in the recorded one. Sampled addresses are translated to the binary with the
+//! memory maps snapshot of the recording, frames of recordings without one are looked up by the
+//! symbol perf found and the offset into it.
+
+use std::{collections::{BTreeSet, HashMap}, fs, path::{Path, PathBuf}};
+
+use colored::Colorize;
+
+use crate::demangle;
+use crate::inline;
+use crate::maps::{self, Snapshot};
+use crate::perf;
+use crate::profile;
+use crate::report::{self, Format};
+use crate::{ResymbolizeArgs, print_step, resolve};
+
+/// Stack line of a trace (`<ip> <symbol>[+0x<offset>] (<module>)`)
+struct StackLine<'a> {
+    ip: u64,
+    symbol: &'a str,
+    offset: Option<u64>,
+    module: &'a str,
+}
+
+
+pub fn run(args: &ResymbolizeArgs) {
+    if !args.input.is_file() {
+        resolve::<(), _>(Err(format!("{} does not exist", args.input.to_string_lossy())));
+    }
+    for binary in &args.binaries {
+        if !binary.is_file() {
+            resolve::<(), _>(Err(format!("{} does not exist", binary.to_string_lossy())));
+        }
+    }
+    let formats = report::formats_or(&args.formats, &[Format::Trace]);
+    let dir = args.input.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let stem = args.input.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+    let trace_path = if args.input.extension().is_some_and(|e| e == "trace") {
+        args.input.clone()
+    } else {
+        let mut recording = perf::Recording::new(dir, &stem, "", &[], false);
+        recording.data = args.input.clone();
+        recording.script_args.extend(["-F".to_string(), "+symoff".to_string()]);
+        perf::script(&recording)
+    };
+    let snapshot = maps::load(&maps::path_for(&args.input)).ok();
+    if snapshot.is_none() {
+        eprintln!("{}", "Warning: The recording has no memory maps snapshot, only frames perf found a symbol for are resymbolized".yellow());
+    }
+
+    print_step("Resymbolizing frames with addr2line");
+    let trace = resolve(fs::read_to_string(&trace_path));
+    let (resymbolized, count) = resymbolize(&trace, &args.binaries, snapshot.as_ref());
+    let output = dir.join(format!("{}.resymbolized.trace", stem));
+    resolve(fs::write(&output, resymbolized));
+    println!("Resymbolized {} frames", count);
+
+    let output_stem = format!("{}.resymbolized", stem);
+    perf::convert(&output, &formats, dir, &output_stem);
+    if formats.contains(&Format::Trace) {
+        perf::print_trace_hint(&output);
+    }
+}
+
+/// Replace the frames of the binaries with their inline chains, returns the trace and the number of frames replaced
+fn resymbolize(trace: &str, binaries: &[PathBuf], snapshot: Option<&Snapshot>) -> (String, usize) {
+    let lines: Vec<&str> = trace.lines().collect();
+    // Binary and address in it to look up for every stack line of one of the binaries
+    let mut lookups: Vec<Option<(usize, u64)>> = vec![None; lines.len()];
+    let mut starts: HashMap<usize, HashMap<String, Option<u64>>> = HashMap::new();
+    let mut file_addresses: HashMap<(usize, u64), Option<u64>> = HashMap::new();
+    let mut pid = 0;
+    let mut leaf = false;
+    for (i, line) in lines.iter().enumerate() {
+        if !line.starts_with(char::is_whitespace) {
+            pid = profile::parse_perf_header(line).pid;
+            leaf = true;
+            continue;
+        }
+        let is_leaf = leaf;
+        leaf = false;
+        let Some(frame) = parse_line(line) else { continue };
+        let module_name = Path::new(frame.module).file_name();
+        let Some(binary) = binaries.iter().position(|b| b.file_name() == module_name) else { continue };
+
+        let mapped = snapshot.and_then(|s| mapping_offset(s, pid, frame.module, frame.ip))
+            .and_then(|file_offset| *file_addresses.entry((binary, file_offset))
+                .or_insert_with(|| maps::file_address(&binaries[binary], file_offset)));
+        let by_symbol = || {
+            let starts = starts.entry(binary).or_insert_with(|| inline::symbol_starts(&binaries[binary].to_string_lossy()));
+            Some((*starts.get(&demangle::lookup_name(frame.symbol))?)? + frame.offset?)
+        };
+        if let Some(address) = mapped.or_else(by_symbol) {
+            lookups[i] = Some((binary, inline::lookup_offset(address, is_leaf)));
+        }
+    }
+
+    let mut chains: HashMap<(usize, u64), Vec<String>> = HashMap::new();
+    for (binary, path) in binaries.iter().enumerate() {
+        let addresses: BTreeSet<u64> = lookups.iter().flatten().filter(|(b, _)| *b == binary).map(|(_, a)| *a).collect();
+        if addresses.is_empty() {
+            continue;
+        }
+        match inline::inline_chains(&path.to_string_lossy(), addresses.into_iter()) {
+            Ok(found) => chains.extend(found.into_iter().map(|(address, chain)| ((binary, address), chain))),
+            Err(e) => eprintln!("{}", format!("Warning: {}", e).yellow()),
+        }
+    }
+
+    let mut output = String::with_capacity(trace.len());
+    let mut count = 0;
+    for (line, lookup) in lines.iter().zip(&lookups) {
+        let chain = lookup.and_then(|key| chains.get(&key)).filter(|c| !c.is_empty());
+        match (chain, parse_line(line)) {
+            (Some(chain), Some(frame)) => {
+                let prefix = &line[..line.len() - line.trim_start().len()];
+                for function in chain {
+                    output.push_str(&format!("{}{:x} {} ({})\n", prefix, frame.ip, function, frame.module));
+                }
+                count += 1;
+            },
+            _ => {
+                output.push_str(line);
+                output.push('\n');
+            },
+        }
+    }
+    (output, count)
+}
+
+/// Offset in the file that was mapped at `ip` in the process `pid`
+fn mapping_offset(snapshot: &Snapshot, pid: u32, module: &str, ip: u64) -> Option<u64> {
+    let module_name = Path::new(module).file_name();
+    snapshot.processes.iter()
+        .filter(|p| p.pid == pid)
+        .flat_map(|p| &p.mappings)
+        .find(|m| Path::new(&m.path).file_name() == module_name && (m.start..m.end).contains(&ip))
+        .map(|m| ip - m.start + m.offset)
+}
+
+fn parse_line(line: &str) -> Option<StackLine<'_>> {
+    let (ip, rest) = line.trim().split_once(char::is_whitespace)?;
+    let (symbol, module) = rest.trim().strip_suffix(')')?.rsplit_once(" (")?;
+    let (symbol, offset) = match symbol.rsplit_once("+0x") {
+        Some((symbol, offset)) => (symbol, u64::from_str_radix(offset, 16).ok()),
+        None => (symbol, None),
+    };
+    Some(StackLine { ip: u64::from_str_radix(ip, 16).ok()?, symbol, offset, module })
+}
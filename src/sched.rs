@@ -0,0 +1,268 @@
+//! Timeline of when each thread was running or blocked, for diagnosing parallelism problems
+//!
+//! The `sched:sched_switch` tracepoint is recorded whenever a thread of the application is
+//! switched out, with the state it leaves in and its call stack. It is woken up again by
+//! `sched:sched_wakeup`, which is only recorded if the waker belongs to the application as well,
+//! otherwise the thread is assumed to resume one sampling interval before its next event. Without
+//! access to the tracepoints, gaps between the samples of a thread are shown as idle.
+
+use std::{collections::{BTreeMap, HashMap}, fs, io, path::Path};
+
+use colored::Colorize;
+
+use crate::gecko::{Marker, Thread};
+use crate::perf;
+use crate::profile::PerfEvent;
+use crate::serve::html_escape;
+
+const SWITCH: &str = "sched:sched_switch";
+const WAKEUP: &str = "sched:sched_wakeup";
+
+const ROW_HEIGHT: f64 = 20.0;
+const LABEL_WIDTH: f64 = 240.0;
+const CHART_WIDTH: f64 = 1200.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Waiting for an event, like a lock or a socket
+    Sleeping,
+    /// Uninterruptible sleep, usually disk I/O
+    DiskIo,
+    /// Still runnable but another thread got the CPU
+    Preempted,
+    /// Not sampled, without sched events this can not be told apart from blocking
+    Idle,
+}
+
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub state: State,
+    /// Start and end time in seconds of the trace clock
+    pub start: f64,
+    pub end: f64,
+    /// Innermost function of the application when the thread was switched out
+    pub blocked_in: Option<String>,
+}
+
+/// All spans of a single thread
+#[derive(Debug, Clone)]
+pub struct Timeline {
+    pub name: String,
+    pub tid: u32,
+    pub spans: Vec<Span>,
+}
+
+
+impl State {
+    fn from_prev_state(state: &str) -> State {
+        match state.chars().next() {
+            Some('R') => State::Preempted,
+            Some('D') => State::DiskIo,
+            _ => State::Sleeping,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            State::Running => "Running",
+            State::Sleeping => "Sleeping",
+            State::DiskIo => "Disk I/O",
+            State::Preempted => "Preempted",
+            State::Idle => "Idle",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            State::Running => "#4caf50",
+            State::Sleeping => "#bdbdbd",
+            State::DiskIo => "#ff9800",
+            State::Preempted => "#f44336",
+            State::Idle => "#eeeeee",
+        }
+    }
+}
+
+/// Additional `perf record` arguments to capture context switches
+///
+/// perf only samples the CPU clock if no event is given, so the caller has to add it as well.
+pub fn record_args() -> Vec<String> {
+    let mut args = Vec::new();
+    if !tracepoints_available() {
+        eprintln!("{}", "Warning: The sched tracepoints are not accessible, the timechart only shows when threads were sampled \
+            (allow access with `sudo chmod -R a+rx /sys/kernel/tracing`)".yellow());
+        return args;
+    }
+    for tracepoint in [SWITCH, WAKEUP] {
+        args.push("-e".to_string());
+        // Record every switch instead of sampling them with the CPU frequency
+        args.push(format!("{}/period=1/", tracepoint));
+    }
+    args
+}
+
+fn tracepoints_available() -> bool {
+    ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"].iter()
+        .any(|dir| fs::read_to_string(Path::new(dir).join("events/sched/sched_switch/id")).is_ok())
+}
+
+pub fn is_sched_event(event: &PerfEvent) -> bool {
+    event.event.starts_with("sched:")
+}
+
+/// Spans of each thread, in the order the threads first appear
+pub fn timelines(samples: &[PerfEvent], sched_events: &[PerfEvent]) -> Vec<Timeline> {
+    let mut order: Vec<u32> = Vec::new();
+    let mut names: HashMap<u32, &str> = HashMap::new();
+    // Time and event of each thread, wakeups by the application only have the time
+    let mut events: HashMap<u32, Vec<(f64, Option<&PerfEvent>)>> = HashMap::new();
+    for event in samples.iter().chain(sched_events) {
+        if event.event == WAKEUP {
+            if let Some(tid) = field(&event.details, "pid").and_then(|p| p.parse().ok()) {
+                events.entry(tid).or_default().push((event.time, None));
+            }
+            continue;
+        }
+        if !names.contains_key(&event.tid) {
+            order.push(event.tid);
+        }
+        names.insert(event.tid, &event.comm);
+        events.entry(event.tid).or_default().push((event.time, Some(event)));
+    }
+
+    let with_switches = sched_events.iter().any(|e| e.event == SWITCH);
+    order.into_iter()
+        .map(|tid| {
+            let mut thread_events = events.remove(&tid).unwrap_or_default();
+            thread_events.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let spans = if with_switches { switch_spans(&thread_events) } else { sample_spans(&thread_events) };
+            Timeline { name: names[&tid].to_string(), tid, spans }
+        })
+        .collect()
+}
+
+/// Spans between the switches of a thread, with wakeups as events without a [`PerfEvent`]
+fn switch_spans(events: &[(f64, Option<&PerfEvent>)]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let Some(first) = events.iter().find(|(_, e)| e.is_some()) else { return spans };
+    let mut current = Span { state: State::Running, start: first.0, end: first.0, blocked_in: None };
+    for (time, event) in events.iter().skip_while(|(t, _)| *t < first.0) {
+        match event {
+            Some(event) if event.event == SWITCH && current.state == State::Running => {
+                current.end = *time;
+                let state = field(&event.details, "prev_state").map(State::from_prev_state).unwrap_or(State::Sleeping);
+                // The innermost frames are the scheduler in the kernel
+                let blocked_in = event.frames.iter().find(|f| !f.module.starts_with('['))
+                    .or(event.frames.first())
+                    .map(|f| f.function.clone());
+                spans.push(current);
+                current = Span { state, start: *time, end: *time, blocked_in };
+            },
+            Some(_) if current.state != State::Running => {
+                // The thread must have resumed at most one sample before it ran again
+                let resumed = (*time - perf::sampling_interval() / 1000.0).max(current.start);
+                current.end = resumed;
+                spans.push(current);
+                current = Span { state: State::Running, start: resumed, end: *time, blocked_in: None };
+            },
+            None if current.state != State::Running => {
+                current.end = *time;
+                spans.push(current);
+                current = Span { state: State::Running, start: *time, end: *time, blocked_in: None };
+            },
+            _ => current.end = *time,
+        }
+    }
+    spans.push(current);
+    spans.retain(|s| s.end > s.start);
+    spans
+}
+
+/// Runs of samples closer than two sampling intervals, with idle spans in between
+fn sample_spans(events: &[(f64, Option<&PerfEvent>)]) -> Vec<Span> {
+    let gap = 2.0 * perf::sampling_interval() / 1000.0;
+    let mut spans: Vec<Span> = Vec::new();
+    for (time, _) in events.iter().filter(|(_, e)| e.is_some()) {
+        match spans.last_mut() {
+            Some(last) if *time - last.end <= gap => last.end = *time,
+            Some(last) => {
+                let idle = Span { state: State::Idle, start: last.end, end: *time, blocked_in: None };
+                spans.push(idle);
+                spans.push(Span { state: State::Running, start: *time, end: *time, blocked_in: None });
+            },
+            None => spans.push(Span { state: State::Running, start: *time, end: *time, blocked_in: None }),
+        }
+    }
+    spans
+}
+
+fn field<'a>(details: &'a str, name: &str) -> Option<&'a str> {
+    details.split_whitespace()
+        .filter_map(|f| f.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Add a marker for every span to the Gecko thread of the same id, with times relative to `start`
+pub fn add_markers(threads: &mut [Thread], timelines: &[Timeline], start: f64) {
+    for timeline in timelines {
+        let Some(thread) = threads.iter_mut().find(|t| t.tid == timeline.tid) else { continue };
+        thread.markers.extend(timeline.spans.iter()
+            .filter(|s| s.state != State::Idle)
+            .map(|span| Marker {
+                name: span.state.label().to_string(),
+                start: (span.start - start) * 1000.0,
+                end: Some((span.end - start) * 1000.0),
+                text: span.blocked_in.clone().unwrap_or_default(),
+            }));
+    }
+}
+
+/// Draw one row per thread with the spans colored by state
+pub fn write_svg(timelines: &[Timeline], path: &Path) -> io::Result<()> {
+    let spans = || timelines.iter().flat_map(|t| &t.spans);
+    let start = spans().map(|s| s.start).fold(f64::INFINITY, f64::min);
+    let end = spans().map(|s| s.end).fold(f64::NEG_INFINITY, f64::max);
+    let duration = if end > start { end - start } else { 1.0 };
+    let x = |time: f64| LABEL_WIDTH + (time - start) / duration * CHART_WIDTH;
+
+    let mut totals: BTreeMap<&str, f64> = BTreeMap::new();
+    let mut body = String::new();
+    for (row, timeline) in timelines.iter().enumerate() {
+        let y = ROW_HEIGHT * (row as f64 + 2.0);
+        body.push_str(&format!("<text x=\"4\" y=\"{:.1}\">{} ({})</text>\n",
+            y + ROW_HEIGHT * 0.7, html_escape(&timeline.name), timeline.tid));
+        for span in &timeline.spans {
+            *totals.entry(span.state.label()).or_default() += span.end - span.start;
+            let tooltip = match &span.blocked_in {
+                Some(function) => format!("{} for {:.3} ms in {}", span.state.label(), (span.end - span.start) * 1000.0, function),
+                None => format!("{} for {:.3} ms", span.state.label(), (span.end - span.start) * 1000.0),
+            };
+            body.push_str(&format!("<rect x=\"{:.2}\" y=\"{:.1}\" width=\"{:.2}\" height=\"{:.1}\" fill=\"{}\"><title>{}</title></rect>\n",
+                x(span.start), y + 2.0, (x(span.end) - x(span.start)).max(0.5), ROW_HEIGHT - 4.0,
+                span.state.color(), html_escape(&tooltip)));
+        }
+    }
+
+    let height = ROW_HEIGHT * (timelines.len() as f64 + 3.0);
+    let mut legend = String::new();
+    let mut legend_x = LABEL_WIDTH;
+    for state in [State::Running, State::Sleeping, State::DiskIo, State::Preempted, State::Idle] {
+        let Some(total) = totals.get(state.label()) else { continue };
+        legend.push_str(&format!("<rect x=\"{:.1}\" y=\"4\" width=\"12\" height=\"12\" fill=\"{}\"/>\
+            <text x=\"{:.1}\" y=\"15\">{} ({:.1} ms)</text>\n",
+            legend_x, state.color(), legend_x + 16.0, state.label(), total * 1000.0));
+        legend_x += 180.0;
+    }
+    let axis = (0..=10).map(|i| {
+        let time = start + duration * i as f64 / 10.0;
+        format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{:.0} ms</text>\n",
+            x(time), height - 4.0, (time - start) * 1000.0)
+    }).collect::<String>();
+
+    fs::write(path, format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" font-family=\"sans-serif\" font-size=\"12\">\n\
+        <rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n{}{}{}</svg>\n",
+        LABEL_WIDTH + CHART_WIDTH + 40.0, height, legend, body, axis))
+}
@@ -0,0 +1,143 @@
+//! Long-running server collecting the recordings of a session
+//!
+//! The profiling output directories are polled for new or rewritten Firefox Profiler files and
+//! traces. Every version is kept in memory, so runs that overwrite the same file still show up
+//! as separate entries of the index page, each with a `from-url` link to the profiler.
+
+use std::{fs, net::TcpListener, path::{Path, PathBuf}, sync::{Arc, Mutex}, thread, time::{Duration, SystemTime}};
+
+use colored::Colorize;
+
+use crate::config;
+use crate::manifest;
+use crate::server::{self, percent_encode};
+use crate::viewer;
+use crate::{ServeArgs, print_step, resolve};
+
+/// Extensions of the files the profiler can load
+const EXTENSIONS: &[&str] = &["json", "trace"];
+
+/// Name of the directories cargo places profiling builds in
+const PROFILE_DIR: &str = "profiling";
+
+/// One version of a recorded file
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    content: Vec<u8>,
+}
+
+
+pub fn run(args: &ServeArgs) {
+    let metadata = resolve(manifest::load());
+    let listener = resolve(TcpListener::bind(("127.0.0.1", args.port)));
+    let base_url = format!("http://{}", resolve(listener.local_addr()));
+
+    print_step("Serving recordings");
+    println!("Recordings of this session: {}", base_url.cyan());
+    let entries = Arc::new(Mutex::new(Vec::new()));
+    collect(&metadata.target_directory, &entries);
+    let target_dir = metadata.target_directory.clone();
+    let collected = entries.clone();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        collect(&target_dir, &collected);
+    });
+
+    if args.open {
+        let url = base_url.clone();
+        let browser = args.browser.clone().or_else(|| config::load().browser.clone());
+        thread::spawn(move || viewer::open_url(&url, browser.as_deref()));
+    }
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        let Some((method, target)) = server::read_request(&stream) else { continue };
+        let entries = entries.lock().unwrap();
+        if method == "OPTIONS" {
+            server::write_response(&mut stream, "204 No Content", None, &[]);
+        } else if method != "GET" {
+            server::write_response(&mut stream, "405 Method Not Allowed", None, b"Method not allowed");
+        } else if target.is_empty() {
+            let page = index(&entries, &base_url, &metadata.target_directory);
+            server::write_response(&mut stream, "200 OK", Some("text/html; charset=utf-8"), page.as_bytes());
+        } else if let Some(entry) = entry_index(&target).and_then(|i| entries.get(i)) {
+            server::write_response(&mut stream, "200 OK", None, &entry.content);
+        } else {
+            server::write_response(&mut stream, "404 Not Found", None, b"Not found");
+        }
+    }
+}
+
+/// Add the files that are new or were modified since they were last collected
+fn collect(target_dir: &Path, entries: &Mutex<Vec<Entry>>) {
+    for path in recordings(target_dir) {
+        let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) else { continue };
+        let known = entries.lock().unwrap().iter().any(|e| e.path == path && e.modified == modified);
+        if known {
+            continue;
+        }
+        // Skip files that are still being written, they are picked up by the next poll
+        let Ok(content) = fs::read(&path) else { continue };
+        if fs::metadata(&path).and_then(|m| m.modified()).ok() != Some(modified) {
+            continue;
+        }
+        println!("New recording: {}", path.to_string_lossy().cyan());
+        entries.lock().unwrap().push(Entry { path, modified, content });
+    }
+}
+
+/// Profiler files in the profiling directories of all targets
+fn recordings(target_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![target_dir.join(PROFILE_DIR)];
+    if let Ok(entries) = fs::read_dir(target_dir) {
+        // Cross compiled builds are placed in target/<triple>/profiling
+        dirs.extend(entries.flatten().map(|e| e.path().join(PROFILE_DIR)));
+    }
+    dirs.iter()
+        .filter_map(|d| fs::read_dir(d).ok())
+        .flat_map(|entries| entries.flatten().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().is_some_and(|e| EXTENSIONS.iter().any(|x| e == *x)))
+        .collect()
+}
+
+/// Position of the entry served at `profiles/<index>/<name>`
+fn entry_index(target: &str) -> Option<usize> {
+    target.strip_prefix("profiles/")?.split('/').next()?.parse().ok()
+}
+
+/// HTML page listing all entries, the newest first
+fn index(entries: &[Entry], base_url: &str, target_dir: &Path) -> String {
+    let now = SystemTime::now();
+    let mut rows = String::new();
+    for (i, entry) in entries.iter().enumerate().rev() {
+        let name = entry.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let file_url = format!("{}/profiles/{}/{}", base_url, i, percent_encode(&name));
+        let path = entry.path.strip_prefix(target_dir).unwrap_or(&entry.path).to_string_lossy().to_string();
+        let age = now.duration_since(entry.modified).map(|d| d.as_secs()).unwrap_or(0);
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{:.1} KiB</td><td><a href=\"{}\">Open in the Firefox Profiler</a></td><td><a href=\"{}\">Download</a></td></tr>\n",
+            html_escape(&path), format_age(age), entry.content.len() as f64 / 1024.0,
+            html_escape(&server::from_url(&file_url)), html_escape(&file_url)));
+    }
+    if entries.is_empty() {
+        rows.push_str("<tr><td colspan=\"5\">No recordings yet, run <code>cargo pprof</code> to add one</td></tr>\n");
+    }
+
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>cargo pprof recordings</title>\n\
+        <style>body {{ font-family: sans-serif; }} td, th {{ padding: 0.2em 1em; text-align: left; }}</style>\n\
+        </head>\n<body>\n<h1>Recordings</h1>\n<table>\n<tr><th>File</th><th>Recorded</th><th>Size</th><th></th><th></th></tr>\n{}</table>\n</body>\n</html>\n",
+        rows)
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s ago", s),
+        s if s < 3600 => format!("{}min ago", s / 60),
+        s => format!("{}h {}min ago", s / 3600, s % 3600 / 60),
+    }
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
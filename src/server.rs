@@ -0,0 +1,108 @@
+//! Minimal HTTP server that hands recordings to the Firefox Profiler
+//!
+//! The profiler can load a profile from any URL that allows cross-origin requests, so the file
+//! is served on localhost and the profiler is opened with a `from-url` link to it.
+
+use std::{fs, io::{BufRead, BufReader, Write}, net::{TcpListener, TcpStream}, path::Path, sync::{Arc, Mutex}, thread};
+
+use crate::viewer;
+use crate::{print_step, resolve};
+
+/// Base URL of the Firefox Profiler
+pub const PROFILER_URL: &str = "https://profiler.firefox.com";
+
+
+/// Serve a single file on localhost until the profiler has fetched it
+pub fn serve_once(path: &Path, browser: Option<&str>) {
+    let content = resolve(fs::read(path));
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let listener = resolve(TcpListener::bind("127.0.0.1:0"));
+    let address = resolve(listener.local_addr());
+    let file_url = format!("http://{}/{}", address, percent_encode(&name));
+
+    print_step("Loading profile in the Firefox Profiler");
+    eprintln!("Serving {} at {}", path.to_string_lossy(), file_url);
+    // The browser command may only return once the browser is closed
+    let profiler_url = from_url(&file_url);
+    let browser = browser.map(str::to_string);
+    thread::spawn(move || viewer::open_url(&profiler_url, browser.as_deref()));
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        if respond(stream, &name, &content) {
+            break;
+        }
+    }
+}
+
+/// Serve the current content under `name` on localhost in the background, returns its URL
+///
+/// The content can be replaced while serving, so reloading the profiler shows the latest version.
+pub fn serve_latest(name: &str, content: Arc<Mutex<Vec<u8>>>) -> String {
+    let listener = resolve(TcpListener::bind("127.0.0.1:0"));
+    let address = resolve(listener.local_addr());
+    let file_url = format!("http://{}/{}", address, percent_encode(name));
+    let name = name.to_string();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let content = content.lock().unwrap().clone();
+            respond(stream, &name, &content);
+        }
+    });
+    file_url
+}
+
+/// Link that makes the profiler load the profile at `url`
+pub fn from_url(url: &str) -> String {
+    format!("{}/from-url/{}", PROFILER_URL, percent_encode(url))
+}
+
+/// Answer a single request, returns whether the file was delivered
+fn respond(mut stream: TcpStream, name: &str, content: &[u8]) -> bool {
+    let Some((method, target)) = read_request(&stream) else { return false };
+    let (status, body): (&str, &[u8]) = match method.as_str() {
+        "OPTIONS" => ("204 No Content", &[]),
+        "GET" if target == percent_encode(name) => ("200 OK", content),
+        _ => ("404 Not Found", b"Not found"),
+    };
+    write_response(&mut stream, status, None, body) && status.starts_with("200")
+}
+
+/// Method and target (without the leading slash) of a request, the headers are skipped
+pub fn read_request(stream: &TcpStream) -> Option<(String, String)> {
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream);
+    reader.read_line(&mut request).ok()?;
+    // Drain the headers, the request body is never needed
+    let mut line = String::new();
+    while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").trim_start_matches('/').to_string();
+    Some((method, target))
+}
+
+/// Write a response allowing cross-origin requests, returns whether it was delivered
+pub fn write_response(stream: &mut TcpStream, status: &str, content_type: Option<&str>, body: &[u8]) -> bool {
+    let content_type = content_type.map(|t| format!("Content-Type: {}\r\n", t)).unwrap_or_default();
+    let header = format!(
+        "HTTP/1.1 {}\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len());
+    stream.write_all(header.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .is_ok()
+}
+
+/// Escape everything except unreserved characters (RFC 3986)
+pub fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! Shutdown profiling: only the teardown of the application is sampled
+//!
+//! perf starts with sampling disabled, like for the readiness probes, and sampling is enabled once
+//! the trigger fires: either after a while, right before the application is sent a signal, or when
+//! the application writes a marker. Everything until the exit, the drops and flushes included,
+//! ends up in the profile.
+
+use std::{fs, path::PathBuf, process, thread, time::{Duration, Instant}};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::counters;
+use crate::markers;
+use crate::perf::{self, Recording};
+use crate::ready;
+use crate::{log_command, print_step, resolve};
+
+/// Time between two checks of the trigger
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Send the application this signal, by name without the `SIG` prefix or by number
+    Signal(String),
+    /// The application wrote a marker with this name to the marker FIFO
+    Marker(String),
+}
+
+
+/// Parse a trigger of the form `signal:TERM` or `marker:NAME`
+pub fn parse(text: &str) -> Result<Trigger, String> {
+    let (kind, value) = text.split_once(':')
+        .ok_or_else(|| format!("expected signal:SIG or marker:NAME, got {:?}", text))?;
+    match kind {
+        "signal" => {
+            let signal = value.strip_prefix("SIG").unwrap_or(value).to_ascii_uppercase();
+            if signal.is_empty() || !signal.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("invalid signal {:?}", value));
+            }
+            Ok(Trigger::Signal(signal))
+        },
+        "marker" if !value.is_empty() => Ok(Trigger::Marker(value.to_string())),
+        _ => Err(format!("expected signal:SIG or marker:NAME, got {:?}", text)),
+    }
+}
+
+/// Record the application with sampling enabled once the trigger fires, returns the path of the trace file
+///
+/// The signal is sent to the processes of the executable `after` the start, markers are read from `fifo`.
+pub fn record(recording: &Recording, executable: &str, trigger: &Trigger, after: Duration, fifo: Option<&markers::Fifo>) -> PathBuf {
+    print_step("Running program with perf");
+    perf::check_paranoid();
+    let _ = fs::remove_file(&recording.data);
+
+    let mut disabled = recording.clone();
+    let control = recording.dir.join("perf-control.fifo");
+    ready::start_disabled(&mut disabled, &control);
+    let (mut child, _) = ready::spawn(&disabled, None);
+
+    match trigger {
+        Trigger::Signal(signal) => print_step(&format!("Sending SIG{} in {}s", signal, after.as_secs())),
+        Trigger::Marker(name) => print_step(&format!("Waiting for the marker {:?}", name)),
+    }
+    let started = Instant::now();
+    loop {
+        if let Some(status) = resolve(child.try_wait()) {
+            let _ = fs::remove_file(&control);
+            resolve::<(), _>(Err(format!("The application exited before the shutdown was triggered ({})", status)));
+        }
+        let fired = match trigger {
+            Trigger::Signal(_) => started.elapsed() >= after,
+            Trigger::Marker(name) => fifo.is_some_and(|f| f.has_seen(name)),
+        };
+        if fired {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    ready::enable(&control);
+    eprintln!("Triggered after {:.1}s, sampling the shutdown", started.elapsed().as_secs_f64());
+    let triggered = Instant::now();
+    if let Trigger::Signal(signal) = trigger {
+        send(signal, executable);
+    }
+    let status = resolve(child.wait());
+    eprintln!("Shutdown took {:.1}ms", triggered.elapsed().as_secs_f64() * 1000.0);
+    perf::check_data(recording);
+    // The application is expected to die of the signal if it does not handle it
+    if matches!(trigger, Trigger::Marker(_)) {
+        app::check_exit(status, recording.ignore_exit);
+    }
+    perf::script(recording)
+}
+
+/// Send the signal to every process running the executable
+fn send(signal: &str, executable: &str) {
+    let executable = fs::canonicalize(executable).unwrap_or_else(|_| PathBuf::from(executable));
+    let pids = counters::processes(&executable);
+    if pids.is_empty() {
+        eprintln!("{}", "Warning: No process of the application found to send the signal to".yellow());
+        return;
+    }
+    let mut command = process::Command::new("kill");
+    command.arg(format!("-{}", signal)).args(pids.iter().map(u32::to_string));
+    log_command(&command);
+    if !command.status().is_ok_and(|s| s.success()) {
+        eprintln!("{}", format!("Warning: Could not send SIG{} to the application", signal).yellow());
+    }
+}
@@ -0,0 +1,116 @@
+//! Code size of a binary by crate and function, like cargo-bloat
+//!
+//! The sizes are those of the function symbols `nm` lists, and every function is attributed to
+//! the crate of its demangled path. The folded stacks have the crate as the outermost frame, so
+//! the flame graph of the sizes doubles as a treemap of the binary.
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, process};
+
+use colored::Colorize;
+
+use crate::demangle;
+use crate::profile::{Frame, Profile, Sample};
+use crate::report::{self, Format};
+use crate::viewer;
+use crate::{SizeArgs, log_command, print_step, resolve, resolve_status};
+
+/// `nm` symbol types of code
+const CODE_TYPES: &[&str] = &["t", "T", "w", "W"];
+
+/// Crate of the functions without one in their path
+const UNKNOWN_CRATE: &str = "[unknown]";
+
+/// Number of crates shown in the summary
+const CRATE_ROWS: usize = 20;
+
+
+pub fn run(args: &SizeArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Summary, Format::Folded]);
+    if formats.iter().any(|f| matches!(f, Format::Trace | Format::Gecko | Format::Timechart)) {
+        eprintln!("{}", "Warning: code sizes are only written as summary, folded stacks and pprof profiles".yellow());
+    }
+    let binary = match &args.binary {
+        Some(binary) => binary.clone(),
+        None => PathBuf::from(crate::build(&[])),
+    };
+
+    print_step("Reading symbol sizes");
+    let symbols = symbols(&binary);
+    if symbols.is_empty() {
+        resolve::<(), _>(Err(format!("{} has no function symbols with sizes (is it stripped?)", binary.to_string_lossy())));
+    }
+    let profile = Profile {
+        value_names: vec!["bytes".to_string()],
+        samples: symbols.into_iter()
+            .map(|(function, size)| Sample {
+                frames: vec![
+                    Frame { function: function.clone(), module: String::new() },
+                    Frame { function: crate_of(&function).to_string(), module: String::new() },
+                ],
+                values: vec![size],
+            })
+            .collect(),
+    };
+
+    if formats.contains(&Format::Summary) {
+        print_crates(&binary, &profile);
+    }
+    let dir = binary.parent().unwrap_or(Path::new("."));
+    let stem = format!("size.{}", report::file_name_part(&binary.file_name().unwrap_or_default().to_string_lossy()));
+    report::emit(&profile, &formats, dir, &stem);
+    if args.open {
+        viewer::open_outputs(None);
+    }
+}
+
+/// Demangled name and size of every function symbol
+fn symbols(binary: &Path) -> Vec<(String, u64)> {
+    let mut command = process::Command::new("nm");
+    command.args(["--print-size", "--size-sort", "--radix=d"]).arg(binary);
+    log_command(&command);
+    let output = match command.stderr(process::Stdio::inherit()).output() {
+        Ok(output) => output,
+        Err(e) => resolve(Err(format!("Could not run nm ({}), is binutils installed?", e))),
+    };
+    resolve_status(output.status);
+
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, ' ');
+            let (_address, size, kind, symbol) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+            CODE_TYPES.contains(&kind).then_some(())?;
+            Some((demangle::demangle(symbol), size.parse().ok()?))
+        })
+        .collect()
+}
+
+fn crate_of(function: &str) -> &str {
+    let name = demangle::crate_name(function);
+    // Names without a path, like the C functions or the methods of primitive types (`<char>::is_alphanumeric`)
+    if function.contains("::") && name.chars().all(|c| c.is_alphanumeric() || c == '_') { name } else { UNKNOWN_CRATE }
+}
+
+/// Print the file size and the share of the code each crate takes up
+fn print_crates(binary: &Path, profile: &Profile) {
+    let mut crates: HashMap<&str, (u64, usize)> = HashMap::new();
+    for sample in &profile.samples {
+        let entry = crates.entry(&sample.frames[1].function).or_default();
+        entry.0 += sample.values[0];
+        entry.1 += 1;
+    }
+    let mut rows: Vec<(&str, (u64, usize))> = crates.into_iter().collect();
+    rows.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(b.0)));
+    let total: u64 = rows.iter().map(|(_, (size, _))| size).sum();
+
+    let file_size = fs::metadata(binary).map(|m| m.len()).unwrap_or(0);
+    println!("\n{}: {} bytes, {} bytes of functions ({} symbols)", binary.to_string_lossy(), file_size, total, profile.samples.len());
+    println!("\n{}", "Code size by crate".bold());
+    println!("{:>8} {:>10} {:>8}  Crate", "Size %", "bytes", "symbols");
+    for (name, (size, count)) in rows.iter().take(CRATE_ROWS) {
+        println!("{:>7.2}% {:>10} {:>8}  {}", report::percent(*size, total), size, count, name);
+    }
+    if rows.len() > CRATE_ROWS {
+        let rest: u64 = rows[CRATE_ROWS..].iter().map(|(_, (size, _))| size).sum();
+        println!("{:>7.2}% {:>10} {:>8}  ({} more)", report::percent(rest, total), rest, "", rows.len() - CRATE_ROWS);
+    }
+}
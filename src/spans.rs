@@ -0,0 +1,160 @@
+//! Span events of the `tracing` crate, written by a JSON subscriber inside the profiled application
+
+use std::{collections::HashMap, fs, path::Path};
+
+use colored::Colorize;
+use serde_json::Value;
+
+use crate::gecko::Marker;
+
+/// Environment variable that tells the application where to write its span events
+pub const ENV_VAR: &str = "CARGO_PPROF_SPANS";
+
+/// Subscriber setup the application needs, printed if no spans were written
+pub const SNIPPET: &str = include_str!("tracing-snippet.rs");
+
+/// perf clock that matches the wall-clock timestamps of tracing-subscriber
+pub const PERF_CLOCK_ARGS: &[&str] = &["-k", "CLOCK_REALTIME"];
+
+/// Time a thread spent inside the outermost span of a task, in seconds since the epoch
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub task: String,
+    /// Rust id of the thread, like `ThreadId(3)`
+    pub thread: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+
+/// Read the span close events as markers with times in seconds since the epoch
+pub fn read(path: &Path) -> Vec<Marker> {
+    let Ok(content) = fs::read_to_string(path) else {
+        eprintln!("{}", "Warning: the application did not write any span events, initialize tracing with:".yellow());
+        eprintln!("\n{}", SNIPPET);
+        return Vec::new();
+    };
+    content.lines()
+        .filter_map(|l| serde_json::from_str::<Value>(l).ok())
+        .filter_map(|event| parse_close(&event))
+        .collect()
+}
+
+/// Times the outermost span of each thread was entered and exited, the polls of async tasks
+///
+/// The enter and exit events are only written with the `FmtSpan::ENTER | FmtSpan::EXIT` span
+/// events of the snippet, and carry the Rust id of the thread rather than the one of the OS.
+pub fn read_polls(path: &Path) -> Vec<Poll> {
+    let Ok(content) = fs::read_to_string(path) else { return Vec::new() };
+    let mut polls = Vec::new();
+    // Entered spans of each thread, the outermost first
+    let mut entered: HashMap<String, Vec<(String, f64)>> = HashMap::new();
+    for event in content.lines().filter_map(|l| serde_json::from_str::<Value>(l).ok()) {
+        let (Some(message), Some(thread), Some(time)) = (
+            event["fields"]["message"].as_str(),
+            event["threadId"].as_str(),
+            event["timestamp"].as_str().and_then(parse_timestamp),
+        ) else { continue };
+        let stack = entered.entry(thread.to_string()).or_default();
+        match message {
+            "enter" => stack.push((task_name(&event), time)),
+            "exit" => {
+                if let Some((task, start)) = stack.pop() && stack.is_empty() {
+                    polls.push(Poll { task, thread: thread.to_string(), start, end: time });
+                }
+            },
+            _ => (),
+        }
+    }
+    polls
+}
+
+/// Name of the task a span event belongs to, from the outermost span of its context
+///
+/// Tasks spawned by tokio (with `--cfg tokio_unstable`) have a `runtime.spawn` span carrying the
+/// name given to the task builder and where it was spawned.
+fn task_name(event: &Value) -> String {
+    let root = event["spans"].as_array().and_then(|s| s.first()).unwrap_or(&event["span"]);
+    let field = |name: &str| root.get(name).map(|v| v.as_str().map(str::to_string).unwrap_or_else(|| v.to_string()));
+    if let Some(name) = field("task.name") {
+        return name;
+    }
+    match (field("loc.file"), field("loc.line")) {
+        (Some(file), Some(line)) => format!("task spawned at {}:{}", file, line),
+        _ => field("name").unwrap_or_else(|| "[unnamed span]".to_string()),
+    }
+}
+
+/// Turn a `close` event into a marker, it carries the time the span was entered and idle
+fn parse_close(event: &Value) -> Option<Marker> {
+    let fields = &event["fields"];
+    if fields["message"].as_str()? != "close" {
+        return None;
+    }
+    let end = parse_timestamp(event["timestamp"].as_str()?)?;
+    let busy = parse_duration(fields["time.busy"].as_str()?)?;
+    let idle = fields["time.idle"].as_str().and_then(parse_duration).unwrap_or(0.0);
+
+    let span = event["span"].as_object()?;
+    let name = span.get("name")?.as_str()?.to_string();
+    let mut text: Vec<String> = span.iter()
+        .filter(|(k, _)| *k != "name")
+        .map(|(k, v)| match v.as_str() {
+            Some(s) => format!("{}={}", k, s),
+            None => format!("{}={}", k, v),
+        })
+        .collect();
+    if let Some(target) = event["target"].as_str() {
+        text.insert(0, target.to_string());
+    }
+
+    Some(Marker { name, start: end - busy - idle, end: Some(end), text: text.join(" ") })
+}
+
+/// Parse durations as formatted by tracing-subscriber, like `12.3µs` or `1.50s`, into seconds
+fn parse_duration(s: &str) -> Option<f64> {
+    let number_end = s.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (number, unit) = s.split_at(number_end);
+    let factor = match unit {
+        "ns" => 1e-9,
+        "µs" | "us" => 1e-6,
+        "ms" => 1e-3,
+        "s" => 1.0,
+        _ => return None,
+    };
+    Some(number.parse::<f64>().ok()? * factor)
+}
+
+/// Parse an RFC 3339 timestamp (`2024-05-01T12:34:56.123456Z`) into seconds since the epoch
+pub fn parse_timestamp(s: &str) -> Option<f64> {
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.split('-').map(|p| p.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, offset) = if let Some(time) = time.strip_suffix('Z') {
+        (time, 0)
+    } else {
+        let sign_at = time.rfind(['+', '-'])?;
+        let (time, offset) = time.split_at(sign_at);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (time, sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60))
+    };
+    let mut time = time.split(':');
+    let hours = time.next()?.parse::<i64>().ok()?;
+    let minutes = time.next()?.parse::<i64>().ok()?;
+    let seconds = time.next()?.parse::<f64>().ok()?;
+
+    let days = days_from_civil(year, month, day);
+    Some((days * 86400 + hours * 3600 + minutes * 60 - offset) as f64 + seconds)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
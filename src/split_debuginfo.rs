@@ -0,0 +1,105 @@
+//! Debug info that cargo keeps outside of the binary (`split-debuginfo` in the profile)
+//!
+//! With `packed` rustc writes a `<binary>.dwp` next to the binary, with `unpacked` it leaves the
+//! `.dwo` files of every codegen unit in `deps` and the binary only refers to them by path. On
+//! macOS the debug info ends up in a `<binary>.dSYM` bundle. GNU addr2line ignores all of them
+//! and loses the inlined functions and lines, so `llvm-addr2line` is used for such binaries.
+
+use std::{collections::HashMap, path::{Path, PathBuf}, process, sync::Mutex};
+
+use colored::Colorize;
+
+use crate::log_command;
+
+/// Where the debug info of a binary lives, if not in the binary itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitDebuginfo {
+    Packed(PathBuf),
+    /// The `.dwo` files that exist and those the binary refers to but which are gone
+    Unpacked { found: Vec<PathBuf>, missing: Vec<PathBuf> },
+    Dsym(PathBuf),
+}
+
+/// Detection results by binary, reading the debug info of a large binary takes a while
+static DETECTED: Mutex<Option<HashMap<PathBuf, Option<SplitDebuginfo>>>> = Mutex::new(None);
+
+
+/// How the debug info of `binary` is split off
+pub fn detect(binary: &Path) -> Option<SplitDebuginfo> {
+    let mut detected = DETECTED.lock().unwrap();
+    detected.get_or_insert_with(HashMap::new)
+        .entry(binary.to_path_buf())
+        .or_insert_with(|| detect_uncached(binary))
+        .clone()
+}
+
+fn detect_uncached(binary: &Path) -> Option<SplitDebuginfo> {
+    let with_extension = |extension: &str| {
+        let mut path = binary.as_os_str().to_owned();
+        path.push(extension);
+        Some(PathBuf::from(path)).filter(|p| p.exists())
+    };
+    if let Some(dwp) = with_extension(".dwp") {
+        return Some(SplitDebuginfo::Packed(dwp));
+    }
+    if let Some(dsym) = with_extension(".dSYM") {
+        return Some(SplitDebuginfo::Dsym(dsym));
+    }
+
+    // Only the compile units refer to the .dwo files
+    let mut command = process::Command::new("readelf");
+    command.args(["--debug-dump=info", "--dwarf-depth=1"]).arg(binary);
+    log_command(&command);
+    let output = command.stderr(process::Stdio::null()).output().ok()?;
+    let (found, missing): (Vec<PathBuf>, Vec<PathBuf>) = String::from_utf8_lossy(&output.stdout).lines()
+        .filter(|l| l.contains("DW_AT_dwo_name") || l.contains("DW_AT_GNU_dwo_name"))
+        .filter_map(|l| l.rsplit_once(": ").map(|(_, name)| PathBuf::from(name.trim())))
+        .partition(|dwo| dwo.exists());
+    if found.is_empty() && missing.is_empty() {
+        None
+    } else {
+        Some(SplitDebuginfo::Unpacked { found, missing })
+    }
+}
+
+/// Warn if the split debug info of the built binary is incomplete
+pub fn check(binary: &Path) {
+    match detect(binary) {
+        Some(SplitDebuginfo::Unpacked { found, missing }) if !missing.is_empty() => {
+            eprintln!("{}", format!("Warning: {} of {} .dwo files of {} are missing, their inlined functions and lines can not be resolved",
+                missing.len(), found.len() + missing.len(), binary.to_string_lossy()).yellow());
+            eprintln!("{}", "Hint: Rebuild with `cargo clean`, or set `split-debuginfo = \"packed\"` or `\"off\"` in [profile.profiling]".yellow());
+        },
+        Some(split) if !llvm_addr2line_available() => {
+            let files = match split {
+                SplitDebuginfo::Packed(_) => ".dwp",
+                SplitDebuginfo::Unpacked { .. } => ".dwo",
+                SplitDebuginfo::Dsym(_) => ".dSYM",
+            };
+            eprintln!("{}", format!("Warning: The debug info of {} is in {} files, which are only read with llvm-addr2line", binary.to_string_lossy(), files).yellow());
+            eprintln!("{}", "Hint: Install LLVM, or set `split-debuginfo = \"off\"` in [profile.profiling]".yellow());
+        },
+        _ => (),
+    }
+}
+
+fn llvm_addr2line_available() -> bool {
+    process::Command::new("llvm-addr2line").arg("--version")
+        .stdout(process::Stdio::null()).stderr(process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// addr2line invocation for `binary`, with `llvm-addr2line` and its split debug info if needed
+pub fn addr2line(binary: &Path) -> process::Command {
+    match detect(binary) {
+        Some(split) if llvm_addr2line_available() => {
+            let mut command = process::Command::new("llvm-addr2line");
+            if let SplitDebuginfo::Packed(dwp) = split {
+                command.arg(format!("--dwp={}", dwp.to_string_lossy()));
+            }
+            command
+        },
+        _ => process::Command::new("addr2line"),
+    }
+}
@@ -0,0 +1,90 @@
+//! Startup profiling: where the time from exec until the application is ready goes
+//!
+//! The samples are split into the phases of the initialization. Before `main` these are the
+//! exec itself, the dynamic linker and the setup of the Rust runtime, within `main` every function
+//! it calls directly is a phase of its own, in the order it first ran.
+
+use std::{path::Path, time::Duration};
+
+use colored::Colorize;
+
+use crate::category::{self, Category};
+use crate::profile::{self, Frame, PerfEvent};
+use crate::report;
+use crate::resolve;
+
+/// Sampling frequency unless `-F` is given, startups are short
+pub const FREQUENCY: u32 = 10000;
+
+/// Call stacks unless `--call-graph` is given, the binaries of the workspace may lack frame pointers
+pub const CALL_GRAPH: &str = "dwarf";
+
+/// Command perf runs as until it execs the application
+const PERF_EXEC: &str = "perf-exec";
+
+struct Phase {
+    name: String,
+    /// Seconds since the first sample
+    start: f64,
+    end: f64,
+    samples: u64,
+}
+
+
+/// Print the phases of the startup with the time they ran in and their share of the samples
+pub fn print_phases(trace_path: &Path, ready: Option<Duration>) {
+    let events = resolve(profile::parse_perf_events(trace_path));
+    let Some(first) = events.iter().map(|e| e.time).min_by(f64::total_cmp) else { return };
+    let main_pid = events.iter().find(|e| e.comm != PERF_EXEC).map(|e| e.pid);
+
+    let mut phases: Vec<Phase> = Vec::new();
+    for event in &events {
+        let name = phase(event, main_pid);
+        let time = event.time - first;
+        match phases.iter_mut().find(|p| p.name == name) {
+            Some(phase) => {
+                phase.start = phase.start.min(time);
+                phase.end = phase.end.max(time);
+                phase.samples += 1;
+            },
+            None => phases.push(Phase { name, start: time, end: time, samples: 1 }),
+        }
+    }
+    phases.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+    let title = match ready {
+        Some(ready) => format!("Startup phases (ready after {:.1}ms)", ready.as_secs_f64() * 1000.0),
+        None => "Startup phases".to_string(),
+    };
+    println!("\n{}", title.bold());
+    println!("{:>10} {:>10} {:>8} {:>8}  Phase", "start", "end", "Share %", "samples");
+    for phase in &phases {
+        println!("{:>8.1}ms {:>8.1}ms {:>7.2}% {:>8}  {}", phase.start * 1000.0, phase.end * 1000.0,
+            report::percent(phase.samples, events.len() as u64), phase.samples, phase.name);
+    }
+}
+
+/// The function `main` was in when the sample was taken, or what ran before it
+fn phase(event: &PerfEvent, main_pid: Option<u32>) -> String {
+    if event.comm == PERF_EXEC {
+        return "exec".to_string();
+    }
+    // Frames are innermost first, the outermost `main` of the workspace is the one of the binary
+    if let Some(i) = event.frames.iter().rposition(is_main) {
+        return event.frames[i.saturating_sub(1)].function.clone();
+    }
+    if Some(event.pid) != main_pid {
+        return format!("child process {}", event.comm);
+    }
+    if event.pid != event.tid {
+        return format!("thread {}", event.comm);
+    }
+    if event.frames.iter().any(|f| Path::new(&f.module).file_name().is_some_and(|n| n.to_string_lossy().starts_with("ld-"))) {
+        return "dynamic linking".to_string();
+    }
+    "runtime setup before main".to_string()
+}
+
+fn is_main(frame: &Frame) -> bool {
+    frame.function.ends_with("::main") && category::of(frame) == Category::User
+}
@@ -0,0 +1,270 @@
+//! History of recordings in `target/pprof/<timestamp>-<commit>/`
+//!
+//! After every run the written outputs are copied into a new directory together with a
+//! `run.json` manifest, so earlier recordings survive the next run overwriting the outputs.
+
+use std::{env, fs, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use clap::ValueEnum;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest;
+use crate::maps;
+use crate::profile;
+use crate::report::{self, Format};
+use crate::viewer;
+use crate::{CleanArgs, ShowArgs, git_commit, resolve, shell_word};
+
+/// Directory of the history inside the target directory
+const STORE_DIR: &str = "pprof";
+pub const MANIFEST: &str = "run.json";
+
+/// Name of the directories cargo places profiling builds in
+const PROFILE_DIR: &str = "profiling";
+
+/// Recordings the backends leave next to the binary, which are only needed until converted
+const DATA_FILES: &[&str] = &["perf.data", "perf.data.old"];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Run {
+    pub id: String,
+    /// Start of the run in seconds since the Unix epoch
+    pub started: u64,
+    /// Duration of the whole run including the build, in seconds
+    pub duration: f64,
+    pub commit: Option<String>,
+    /// Arguments `cargo pprof` was invoked with
+    pub command: Vec<String>,
+    pub samples: Option<u64>,
+    pub outputs: Vec<StoredOutput>,
+    /// Memory maps of the application with the build-ids of its files, see [`maps`]
+    #[serde(default)]
+    pub maps: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredOutput {
+    pub format: String,
+    /// File name inside the run directory
+    pub file: String,
+}
+
+
+/// Copy the outputs of this run into a new entry of the history, keeping at most `keep_last` runs
+pub fn save(started: SystemTime, keep_last: Option<usize>) {
+    let outputs = report::outputs();
+    if outputs.is_empty() {
+        return;
+    }
+    let Ok(metadata) = manifest::load() else { return };
+
+    let start = started.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let commit = git_commit();
+    let base_id = match &commit {
+        Some(commit) => format!("{}-{}", format_timestamp(start), commit),
+        None => format_timestamp(start),
+    };
+    // Runs started within the same second get a counter
+    let root = store_dir(&metadata.target_directory);
+    let id = (1..).map(|n| if n == 1 { base_id.clone() } else { format!("{}.{}", base_id, n) })
+        .find(|id| !root.join(id).exists())
+        .unwrap_or(base_id);
+    let dir = root.join(&id);
+    resolve(fs::create_dir_all(&dir));
+
+    let mut stored = Vec::new();
+    for (format, path) in &outputs {
+        let Some(file) = path.file_name() else { continue };
+        resolve(fs::copy(path, dir.join(file)));
+        stored.push(StoredOutput {
+            format: format.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default(),
+            file: file.to_string_lossy().to_string(),
+        });
+    }
+
+    let maps = maps::last().and_then(|path| {
+        let file = path.file_name()?.to_string_lossy().to_string();
+        fs::copy(&path, dir.join(&file)).ok()?;
+        Some(file)
+    });
+
+    let samples = report::sample_count().or_else(|| {
+        let (_, trace) = outputs.iter().find(|(f, _)| *f == Format::Trace)?;
+        profile::parse_perf_events(trace).ok().map(|events| events.len() as u64)
+    });
+    let run = Run {
+        id,
+        started: start,
+        duration: started.elapsed().map(|d| d.as_secs_f64()).unwrap_or(0.0),
+        commit,
+        command: env::args().skip_while(|a| a != "pprof").skip(1).collect(),
+        samples,
+        outputs: stored,
+        maps,
+    };
+    resolve(fs::write(dir.join(MANIFEST), resolve(serde_json::to_string_pretty(&run))));
+    println!("Stored as run {}", run.id.cyan());
+
+    if let Some(keep) = keep_last {
+        let (removed, freed) = prune(&metadata.target_directory, keep);
+        if removed > 0 {
+            println!("Removed {} old run(s), freeing {}", removed, format_size(freed));
+        }
+    }
+}
+
+/// Remove stored runs except the newest ones, and the perf data left in the profiling directories
+pub fn clean(args: &CleanArgs) {
+    let metadata = resolve(manifest::load());
+    let (removed, mut freed) = prune(&metadata.target_directory, args.keep_last);
+
+    let mut dirs = vec![metadata.target_directory.join(PROFILE_DIR)];
+    if let Ok(entries) = fs::read_dir(&metadata.target_directory) {
+        // Cross compiled builds are placed in target/<triple>/profiling
+        dirs.extend(entries.flatten().map(|e| e.path().join(PROFILE_DIR)));
+    }
+    let data_files: Vec<PathBuf> = dirs.iter()
+        .filter_map(|d| fs::read_dir(d).ok())
+        .flat_map(|entries| entries.flatten().map(|e| e.path()))
+        .filter(|p| p.file_name().is_some_and(|n| DATA_FILES.iter().any(|d| n == *d)))
+        .collect();
+    for path in &data_files {
+        freed += fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        resolve(fs::remove_file(path));
+    }
+
+    println!("Removed {} stored run(s) and {} perf data file(s), freeing {}", removed, data_files.len(), format_size(freed));
+}
+
+/// Remove all but the `keep` newest runs, returns the number of removed runs and their size
+fn prune(target_dir: &Path, keep: usize) -> (usize, u64) {
+    let runs = load_runs();
+    let root = store_dir(target_dir);
+    let excess = runs.len().saturating_sub(keep);
+    let mut freed = 0;
+    for run in &runs[..excess] {
+        let dir = root.join(&run.id);
+        freed += dir_size(&dir);
+        resolve(fs::remove_dir_all(&dir));
+    }
+    (excess, freed)
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir).into_iter()
+        .flat_map(|entries| entries.flatten())
+        .map(|e| match e.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&e.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b => format!("{:.1} KiB", b as f64 / 1024.0),
+    }
+}
+
+impl Run {
+    pub fn command_line(&self) -> String {
+        let mut words = vec!["cargo".to_string(), "pprof".to_string()];
+        words.extend(self.command.iter().map(|a| shell_word(a)));
+        words.join(" ")
+    }
+}
+
+/// Print a table of all stored runs, the oldest first
+pub fn list() {
+    let runs = load_runs();
+    if runs.is_empty() {
+        println!("No runs stored yet");
+        return;
+    }
+
+    println!("{:<24} {:>9} {:>9}  {:<10}  {}", "ID".bold(), "DURATION".bold(), "SAMPLES".bold(), "COMMIT".bold(), "COMMAND".bold());
+    for run in &runs {
+        println!("{:<24} {:>8.1}s {:>9}  {:<10}  {}",
+            run.id,
+            run.duration,
+            run.samples.map(|s| s.to_string()).unwrap_or_else(|| "-".to_string()),
+            run.commit.as_deref().unwrap_or("-"),
+            run.command_line());
+    }
+}
+
+/// Print the outputs and the summary of a stored run, and optionally open it again
+pub fn show(args: &ShowArgs) {
+    let (run, dir) = resolve(find_run(&args.id));
+    println!("Run {} ({}, {:.1}s)", run.id.cyan(), run.command_line(), run.duration);
+
+    let outputs: Vec<(Format, PathBuf)> = run.outputs.iter()
+        .filter_map(|o| Some((Format::from_str(&o.format, true).ok()?, dir.join(&o.file))))
+        .collect();
+    for (format, path) in &outputs {
+        report::print_output(*format, path);
+    }
+
+    if let Some(path) = report::profile_source(&outputs) {
+        report::print_summary(&resolve(profile::load(&path)));
+    }
+
+    if args.open {
+        viewer::open_outputs(args.browser.as_deref());
+    }
+}
+
+/// The run with the given id, a unique prefix of it or `latest`, and its directory
+pub fn find_run(id: &str) -> Result<(Run, PathBuf), String> {
+    let metadata = manifest::load()?;
+    let mut runs = load_runs();
+    let run = if id == "latest" {
+        runs.pop().ok_or_else(|| "No runs stored yet".to_string())?
+    } else {
+        let mut matching: Vec<Run> = runs.into_iter().filter(|r| r.id.starts_with(id)).collect();
+        match matching.len() {
+            0 => return Err(format!("No stored run {:?} (see `cargo pprof list`)", id)),
+            1 => matching.remove(0),
+            n => return Err(format!("{:?} matches {} runs, use a longer prefix", id, n)),
+        }
+    };
+    let dir = store_dir(&metadata.target_directory).join(&run.id);
+    Ok((run, dir))
+}
+
+/// All stored runs, the oldest first
+pub fn load_runs() -> Vec<Run> {
+    let Ok(metadata) = manifest::load() else { return Vec::new() };
+    let Ok(entries) = fs::read_dir(store_dir(&metadata.target_directory)) else { return Vec::new() };
+    let mut runs: Vec<Run> = entries.flatten()
+        .filter_map(|e| fs::read_to_string(e.path().join(MANIFEST)).ok())
+        .filter_map(|content| serde_json::from_str(&content).ok())
+        .collect();
+    runs.sort_by(|a, b| a.started.cmp(&b.started).then_with(|| a.id.cmp(&b.id)));
+    runs
+}
+
+pub fn store_dir(target_dir: &Path) -> PathBuf {
+    target_dir.join(STORE_DIR)
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC
+fn format_timestamp(seconds: u64) -> String {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = seconds % 86400;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
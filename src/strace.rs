@@ -0,0 +1,139 @@
+use std::{collections::HashMap, fs};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::report::{self, Format};
+use crate::{StraceArgs, print_step, resolve};
+
+#[derive(Debug, Default)]
+struct SyscallStats {
+    calls: u64,
+    errors: u64,
+    /// Total time spent in the syscall in microseconds
+    time: u64,
+}
+
+
+/// Build the binary, trace its syscalls and print aggregated statistics
+pub fn run(args: &StraceArgs) {
+    let formats = report::formats_or(&args.formats, &[Format::Folded]);
+    if formats.contains(&Format::Trace) {
+        eprintln!("{}", "Warning: syscall tracing does not produce traces".yellow());
+    }
+
+    let executable = crate::build(&[]);
+    let dir = crate::output_dir(&executable);
+    let log_path = dir.join("strace.log");
+
+    print_step("Running program with strace");
+    let mut command = app::command("strace");
+    command.args(["-f", "-T", "-qq"])
+        .arg("-o")
+        .arg(&log_path);
+    if !args.no_stacks {
+        command.arg("-k");
+    }
+    let status = app::run(command
+        .arg(&executable)
+        .args(&args.run.app_args));
+    app::check_exit(status, args.run.ignore_exit);
+    eprintln!("Syscall log: {}", log_path.to_string_lossy());
+
+    let content = resolve(fs::read_to_string(&log_path));
+    let (stats, profile) = parse(&content);
+    print_stats(&stats);
+    report::emit(&profile, &formats, dir, "strace");
+}
+
+/// Parse the output of `strace -f -T [-k]`
+///
+/// Returns statistics per syscall and a profile of the user stacks that issued them,
+/// with the syscall as innermost frame.
+fn parse(content: &str) -> (HashMap<String, SyscallStats>, Profile) {
+    let mut stats: HashMap<String, SyscallStats> = HashMap::new();
+    let mut samples = Vec::new();
+    let mut current: Option<Sample> = None;
+
+    for line in content.lines() {
+        if let Some(frame) = line.trim_start().strip_prefix("> ") {
+            if let Some(sample) = current.as_mut() {
+                sample.frames.push(parse_stack_frame(frame));
+            }
+            continue;
+        }
+        samples.extend(current.take());
+
+        // Strip the pid prefix added by -f
+        let call = match line.split_once(' ') {
+            Some((pid, rest)) if pid.chars().all(|c| c.is_ascii_digit()) => rest.trim_start(),
+            _ => line,
+        };
+        let name = if let Some(rest) = call.strip_prefix("<... ") {
+            rest.split_whitespace().next().unwrap_or("")
+        } else if call.ends_with("<unfinished ...>") {
+            continue;
+        } else {
+            call.split('(').next().unwrap_or("")
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            continue;
+        }
+
+        let Some((_, result)) = call.rsplit_once(" = ") else { continue };
+        let time = result.rsplit_once('<')
+            .and_then(|(_, t)| t.trim_end_matches('>').parse::<f64>().ok())
+            .map(|t| (t * 1_000_000.0).round() as u64)
+            .unwrap_or(0);
+
+        let entry = stats.entry(name.to_string()).or_default();
+        entry.calls += 1;
+        entry.time += time;
+        if result.starts_with('-') && result.contains(" E") {
+            entry.errors += 1;
+        }
+
+        current = Some(Sample {
+            frames: vec![Frame { function: format!("syscall:{}", name), module: "[kernel]".to_string() }],
+            values: vec![1, time],
+        });
+    }
+    samples.extend(current);
+
+    let profile = Profile {
+        value_names: vec!["syscalls".to_string(), "microseconds".to_string()],
+        samples,
+    };
+    (stats, profile)
+}
+
+/// Parse a stack line like `/usr/lib/libc.so.6(__write+0x14) [0x10e1d4]`
+fn parse_stack_frame(frame: &str) -> Frame {
+    let location = frame.rsplit_once(" [").map(|(l, _)| l).unwrap_or(frame);
+    match location.split_once('(') {
+        Some((module, function)) => {
+            let function = function.trim_end_matches(')');
+            let function = function.rsplit_once('+').map(|(f, _)| f).unwrap_or(function);
+            Frame {
+                function: if function.is_empty() { "[unknown]".to_string() } else { function.to_string() },
+                module: module.to_string(),
+            }
+        },
+        None => Frame { function: "[unknown]".to_string(), module: location.to_string() },
+    }
+}
+
+fn print_stats(stats: &HashMap<String, SyscallStats>) {
+    let mut rows: Vec<_> = stats.iter().collect();
+    rows.sort_by(|(a_name, a), (b_name, b)| b.time.cmp(&a.time).then(a_name.cmp(b_name)));
+    let total_time: u64 = stats.values().map(|s| s.time).sum();
+
+    println!("\n{}", "Syscalls by time".bold());
+    println!("{:>8} {:>12} {:>10} {:>8} {:>10}  Syscall", "Time %", "Time (us)", "Calls", "Errors", "Avg (us)");
+    for (name, s) in rows {
+        let percent = if total_time == 0 { 0.0 } else { s.time as f64 * 100.0 / total_time as f64 };
+        println!("{:>7.2}% {:>12} {:>10} {:>8} {:>10.1}  {}",
+            percent, s.time, s.calls, s.errors, s.time as f64 / s.calls as f64, name);
+    }
+}
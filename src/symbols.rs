@@ -0,0 +1,215 @@
+//! Symbol lookup settings for perf, and a report on how many frames could be symbolized
+//!
+//! perf fetches the debug info of system libraries from the servers in `DEBUGINFOD_URLS` itself if
+//! it was built with libdebuginfod. For other builds the files are fetched with `debuginfod-find`
+//! before the conversion and added to perf's build-id cache, like the files in `--debug-dir`.
+
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, process, sync::OnceLock};
+
+use colored::Colorize;
+
+use crate::config;
+use crate::kernel;
+use crate::maps;
+use crate::perf;
+use crate::profile::{Frame, PerfEvent};
+use crate::report;
+use crate::{log_command, print_step};
+
+/// Share of unresolved frames in percent from which the report is printed
+const POOR_QUALITY: f64 = 10.0;
+
+/// Number of binaries listed in the report
+const REPORT_ROWS: usize = 10;
+
+/// Servers given with `--debuginfod`
+static DEBUGINFOD: OnceLock<String> = OnceLock::new();
+
+/// Root directory given with `--symfs`
+static SYMFS: OnceLock<PathBuf> = OnceLock::new();
+
+/// Directories given with `--debug-dir`
+static DEBUG_DIRS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+
+/// Use the given debuginfod servers instead of the configured ones
+pub fn set_debuginfod(urls: String) {
+    let _ = DEBUGINFOD.set(urls);
+}
+
+/// Look up the binaries of a recording below `dir` and their debug info in `debug_dirs` as well
+pub fn set_search_paths(symfs: Option<PathBuf>, debug_dirs: Vec<PathBuf>) {
+    if let Some(symfs) = symfs {
+        let _ = SYMFS.set(symfs);
+    }
+    let _ = DEBUG_DIRS.set(debug_dirs);
+}
+
+/// Arguments for `perf script` and `perf report` with the `--symfs` directory and the `--vmlinux` image
+pub fn perf_args() -> Vec<String> {
+    let mut args: Vec<String> = SYMFS.get().map(|dir| format!("--symfs={}", dir.to_string_lossy())).into_iter().collect();
+    args.extend(kernel::perf_args());
+    args
+}
+
+/// Space separated debuginfod servers from `--debuginfod`, the configuration or `DEBUGINFOD_URLS`
+pub fn debuginfod_urls() -> Option<String> {
+    DEBUGINFOD.get().cloned()
+        .or_else(|| config::load().debuginfod.clone())
+        .or_else(|| env::var("DEBUGINFOD_URLS").ok())
+        .filter(|urls| !urls.trim().is_empty())
+}
+
+/// Check the recorded files against the memory maps snapshot and add the debug files of `--debug-dir`
+/// and those on the debuginfod servers to perf's build-id cache
+pub fn prepare(data: &Path) {
+    if let Ok(snapshot) = maps::load(&maps::path_for(data)) {
+        maps::verify(&snapshot, SYMFS.get().map(PathBuf::as_path));
+    }
+    let debug_files: Vec<PathBuf> = DEBUG_DIRS.get().into_iter().flatten().flat_map(|d| debug_files(d)).collect();
+    if !debug_files.is_empty() {
+        print_step("Adding debug files to the build-id cache");
+        for file in debug_files {
+            add_to_cache(&file);
+        }
+    }
+    fetch_debuginfo(data);
+}
+
+/// Files ending in `.debug` and everything below `.build-id` directories
+fn debug_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![(dir.to_path_buf(), false)];
+    while let Some((dir, in_build_id)) = pending.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push((path.clone(), in_build_id || path.ends_with(".build-id")));
+            } else if in_build_id || path.extension().is_some_and(|e| e == "debug") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn add_to_cache(file: &Path) {
+    let mut add = process::Command::new(perf::binary());
+    add.args(["buildid-cache", "--add"]).arg(file);
+    log_command(&add);
+    let _ = add.stdout(process::Stdio::null()).stderr(process::Stdio::null()).status();
+}
+
+/// Fetch the debug info of the sampled binaries outside of the package into perf's build-id cache
+fn fetch_debuginfo(data: &Path) {
+    let Some(urls) = debuginfod_urls() else { return };
+    let mut list = process::Command::new(perf::binary());
+    list.args(["buildid-list", "--with-hits"]).args(perf_args()).arg(format!("--input={}", data.to_string_lossy()));
+    log_command(&list);
+    let Ok(output) = list.stderr(process::Stdio::null()).output() else { return };
+
+    print_step("Fetching debug info with debuginfod");
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((build_id, path)) = line.trim().split_once(' ') else { continue };
+        if path.starts_with('[') || path.contains("/target/") {
+            continue;
+        }
+        let mut find = process::Command::new("debuginfod-find");
+        find.env("DEBUGINFOD_URLS", &urls).args(["debuginfo", build_id]);
+        log_command(&find);
+        let found = match find.stderr(process::Stdio::null()).output() {
+            Ok(found) => found,
+            Err(_) => {
+                // perf may still fetch the files itself
+                eprintln!("{}", "Warning: debuginfod-find is not installed, symbols are only fetched if perf supports debuginfod".yellow());
+                return;
+            },
+        };
+        let file = String::from_utf8_lossy(&found.stdout).trim().to_string();
+        if found.status.success() && !file.is_empty() {
+            add_to_cache(Path::new(&file));
+        }
+    }
+}
+
+/// Whether perf could not find a symbol for the frame
+pub fn is_unresolved(frame: &Frame) -> bool {
+    let function = frame.function.trim_start_matches("0x");
+    frame.function == "[unknown]" || (!function.is_empty() && function.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Print the share of samples with unresolved frames by binary if it is high, or always with -v
+pub fn print_quality(events: &[PerfEvent]) {
+    // Samples with frames of the binary, and those with an unresolved one among them
+    let mut by_module: HashMap<&str, (u64, u64)> = HashMap::new();
+    let (mut unresolved, mut unresolved_leaf, mut total) = (0, 0, 0);
+    for event in events.iter().filter(|e| !e.frames.is_empty()) {
+        let mut modules: Vec<(&str, bool)> = Vec::new();
+        for frame in &event.frames {
+            match modules.iter_mut().find(|(module, _)| *module == frame.module) {
+                Some((_, any)) => *any |= is_unresolved(frame),
+                None => modules.push((&frame.module, is_unresolved(frame))),
+            }
+        }
+        for (module, any) in modules {
+            let (unresolved, total) = by_module.entry(module).or_default();
+            *unresolved += any as u64;
+            *total += 1;
+        }
+        total += 1;
+        unresolved += event.frames.iter().any(is_unresolved) as u64;
+        unresolved_leaf += is_unresolved(&event.frames[0]) as u64;
+    }
+    let share = report::percent(unresolved, total);
+    if total == 0 || (share < POOR_QUALITY && crate::verbosity() < 2) {
+        return;
+    }
+
+    let line = format!("Symbols: {:.1}% of {} samples have unresolved frames ({:.1}% the innermost one)",
+        share, total, report::percent(unresolved_leaf, total));
+    if share < POOR_QUALITY {
+        println!("{}", line);
+        return;
+    }
+    println!("{}", line.yellow());
+    let mut modules: Vec<(&str, u64, u64)> = by_module.into_iter()
+        .filter(|(_, (unresolved, _))| *unresolved > 0)
+        .map(|(module, (unresolved, total))| (module, unresolved, total))
+        .collect();
+    modules.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    println!("{:>11} {:>8}  Binary", "Unresolved", "Samples");
+    for (module, unresolved, total) in modules.iter().take(REPORT_ROWS) {
+        println!("{:>10.1}% {:>8}  {}", report::percent(*unresolved, *total), total, module);
+    }
+
+    let mut fixes: Vec<&str> = Vec::new();
+    for (module, _, _) in modules.iter().take(REPORT_ROWS) {
+        for fix in fixes_for(module) {
+            if !fixes.contains(fix) {
+                fixes.push(fix);
+            }
+        }
+    }
+    for fix in fixes {
+        eprintln!("{}", format!("Hint: {}", fix).yellow());
+    }
+}
+
+fn fixes_for(module: &str) -> &'static [&'static str] {
+    if module == "[unknown]" {
+        &["Frames without a binary usually come from code without frame pointers, try --call-graph dwarf"]
+    } else if module.starts_with("[kernel") {
+        &["Kernel symbols are hidden, allow them with `sudo sysctl kernel.kptr_restrict=0`",
+          "Pass a kernel image with debug info with --vmlinux, or leave out the kernel with --kernel-symbols off"]
+    } else if module.contains("/target/") {
+        &["Set `debug = true` and do not set `strip` in [profile.profiling] in Cargo.toml",
+          "With `split-debuginfo = \"packed\"` or `\"unpacked\"` the .dwp/.dwo files have to stay where cargo put them \
+           and llvm-addr2line has to be installed, or set `split-debuginfo = \"off\"`"]
+    } else if module.contains("libstd-") {
+        &["The prebuilt standard library has little debug info, rebuild it with `cargo +nightly build -Z build-std`"]
+    } else {
+        &["Fetch the symbols of system libraries with `--debuginfod https://debuginfod.elfutils.org/` \
+           or install their debug symbol packages"]
+    }
+}
@@ -0,0 +1,306 @@
+//! Bytes and latency of I/O and socket operations per call site
+//!
+//! Syscalls are recorded for the application only. Block requests complete in interrupt or kworker
+//! context on another task, so the block tracepoints are recorded system-wide by a second perf and
+//! only the requests issued by the processes of the application are kept. Requests the kernel
+//! issues on its own behalf, like the writeback of dirty pages, are not attributed to the
+//! application; their cost shows up as the latency of `fsync` and friends instead.
+
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, path::Path, process};
+
+use colored::Colorize;
+
+use crate::RunArgs;
+use crate::perf;
+use crate::profile::{self, Frame, PerfEvent, Profile, Sample};
+use crate::ready;
+use crate::report::{self, Format};
+use crate::{log_command, print_step, resolve};
+
+/// Number of call sites listed in the table
+const TABLE_ROWS: usize = 30;
+
+/// Syscalls recorded in I/O mode
+const IO_SYSCALLS: &[&str] = &["read", "write", "pread64", "pwrite64", "readv", "writev", "fsync", "fdatasync"];
+
+/// Recorded syscalls whose return value is not a number of bytes, only their calls are counted
+const COUNT_ONLY_SYSCALLS: &[&str] = &["fsync", "fdatasync", "connect", "accept", "accept4"];
+
+/// Tracepoints recorded system-wide in I/O mode
+const BLOCK_TRACEPOINTS: &[&str] = &["block:block_rq_issue", "block:block_rq_complete"];
+
+/// Syscalls recorded in network mode
+const NET_SYSCALLS: &[&str] = &["sendto", "recvfrom", "sendmsg", "recvmsg", "sendmmsg", "recvmmsg", "connect", "accept", "accept4"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Block layer requests and file I/O syscalls
+    Io,
+    /// Socket syscalls
+    Net,
+}
+
+#[derive(Debug, Default)]
+struct SiteStats {
+    calls: u64,
+    bytes: u64,
+    /// Total latency in microseconds
+    latency: u64,
+}
+
+/// Operation that entered the kernel, waiting for its completion
+struct Pending {
+    operation: String,
+    time: f64,
+    bytes: u64,
+    frames: Vec<Frame>,
+}
+
+
+impl Mode {
+    fn stem(self) -> &'static str {
+        match self {
+            Mode::Io => "io",
+            Mode::Net => "net",
+        }
+    }
+
+    fn syscalls(self) -> &'static [&'static str] {
+        match self {
+            Mode::Io => IO_SYSCALLS,
+            Mode::Net => NET_SYSCALLS,
+        }
+    }
+
+    fn tracepoints(self) -> Vec<String> {
+        self.syscalls().iter()
+            .flat_map(|s| [format!("syscalls:sys_enter_{}", s), format!("syscalls:sys_exit_{}", s)])
+            .collect()
+    }
+}
+
+/// Record the mode's tracepoints with stacks and report bytes and latency per call site
+pub fn record(mode: Mode, executable: &str, run: &RunArgs, formats: &[Format], dir: &Path) {
+    let formats = report::formats_or(formats, &[Format::Folded]);
+
+    let mut recording = perf::Recording::new(dir, mode.stem(), executable, &run.app_args, run.ignore_exit);
+    recording.record_args = vec!["-g".to_string()];
+    for tracepoint in mode.tracepoints() {
+        recording.record_args.push("-e".to_string());
+        recording.record_args.push(tracepoint);
+    }
+    let mut block = (mode == Mode::Io).then(|| perf::Recording::new(dir, "io-block", "--all-cpus", &[], true));
+    let block_child = block.as_mut().and_then(start_block_recording);
+    let trace_path = perf::record(&recording);
+    let mut events = resolve(profile::parse_perf_events(&trace_path));
+    if let (Some(block), Some(child)) = (&block, block_child) {
+        events.extend(finish_block_recording(block, child, &events));
+        events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+    let (sites, profile) = aggregate(&events, executable);
+
+    print_sites(&sites);
+    report::emit(&profile, &formats, dir, mode.stem());
+    if formats.contains(&Format::Trace) {
+        perf::print_trace_hint(&trace_path);
+    }
+}
+
+/// Start recording the block tracepoints of all processes in the background
+fn start_block_recording(block: &mut perf::Recording) -> Option<process::Child> {
+    block.record_args = BLOCK_TRACEPOINTS.iter().flat_map(|t| ["-e".to_string(), t.to_string()]).collect();
+    let _ = fs::remove_file(&block.data);
+    let mut command = perf::record_command(block);
+    command.stdin(process::Stdio::null()).stdout(process::Stdio::null());
+    log_command(&command);
+    match command.spawn() {
+        Ok(child) => Some(child),
+        Err(e) => {
+            eprintln!("{}", format!("Warning: Could not record block requests ({}), only syscalls are reported", e).yellow());
+            None
+        },
+    }
+}
+
+/// Stop the block recording and return the requests issued by the processes of the application
+///
+/// Completions are kept regardless of the task they fire on, they are matched to the issues by sector.
+fn finish_block_recording(block: &perf::Recording, mut child: process::Child, app_events: &[PerfEvent]) -> Vec<PerfEvent> {
+    ready::stop(&mut child);
+    if fs::metadata(&block.data).map(|m| m.len()).unwrap_or(0) == 0 {
+        eprintln!("{}", "Warning: Could not record block requests system-wide (needs kernel.perf_event_paranoid <= 0 or root), only syscalls are reported".yellow());
+        return Vec::new();
+    }
+
+    print_step("Converting block requests to trace format");
+    let trace_path = block.dir.join(format!("{}.trace", block.stem));
+    let mut command = perf::script_command(block);
+    command.stdout(resolve(File::create(&trace_path))).stderr(process::Stdio::null());
+    log_command(&command);
+    if !command.status().is_ok_and(|s| s.success()) {
+        eprintln!("{}", "Warning: Could not convert the block requests, only syscalls are reported".yellow());
+        return Vec::new();
+    }
+    let pids: HashSet<u32> = app_events.iter().map(|e| e.pid).collect();
+    resolve(profile::parse_perf_events(&trace_path)).into_iter()
+        .filter(|e| e.event == "block:block_rq_complete" || pids.contains(&e.pid))
+        .collect()
+}
+
+/// Pair enter/exit (or issue/complete) events and sum them up per operation and call site
+fn aggregate(events: &[PerfEvent], executable: &str) -> (HashMap<(String, String), SiteStats>, Profile) {
+    let mut syscalls: HashMap<u32, Pending> = HashMap::new();
+    let mut requests: HashMap<String, Pending> = HashMap::new();
+    let mut sites: HashMap<(String, String), SiteStats> = HashMap::new();
+    let mut samples = Vec::new();
+
+    let mut finish = |pending: Pending, end: f64, bytes: u64| {
+        let latency = ((end - pending.time).max(0.0) * 1_000_000.0).round() as u64;
+        let site = call_site(&pending.frames, executable);
+        let stats = sites.entry((pending.operation.clone(), site)).or_default();
+        stats.calls += 1;
+        stats.bytes += bytes;
+        stats.latency += latency;
+
+        let mut frames = vec![Frame { function: pending.operation, module: "[kernel]".to_string() }];
+        frames.extend(pending.frames);
+        samples.push(Sample { frames, values: vec![bytes, latency, 1] });
+    };
+
+    for event in events {
+        if let Some(name) = event.event.strip_prefix("syscalls:sys_enter_") {
+            syscalls.insert(event.tid, Pending {
+                operation: format!("syscall:{}", name),
+                time: event.time,
+                bytes: 0,
+                frames: event.frames.clone(),
+            });
+        } else if let Some(name) = event.event.strip_prefix("syscalls:sys_exit_") {
+            if let Some(pending) = syscalls.remove(&event.tid) {
+                let bytes = if COUNT_ONLY_SYSCALLS.contains(&name) { 0 } else { syscall_bytes(&event.details) };
+                finish(pending, event.time, bytes);
+            }
+        } else if event.event == "block:block_rq_issue" {
+            let Some(key) = block_request_key(&event.details) else { continue };
+            let bytes = event.details.split_whitespace().nth(2)
+                .and_then(|b| b.parse().ok())
+                .unwrap_or(0);
+            requests.insert(key, Pending {
+                operation: "block:request".to_string(),
+                time: event.time,
+                bytes,
+                frames: event.frames.clone(),
+            });
+        } else if event.event == "block:block_rq_complete" {
+            let Some(key) = block_request_key(&event.details) else { continue };
+            if let Some(pending) = requests.remove(&key) {
+                let bytes = pending.bytes;
+                finish(pending, event.time, bytes);
+            }
+        }
+    }
+
+    let profile = Profile {
+        value_names: vec!["bytes".to_string(), "latency (us)".to_string(), "calls".to_string()],
+        samples,
+    };
+    (sites, profile)
+}
+
+/// Bytes transferred according to the return value of a syscall (`0x1000` or `4096`), errors count as zero
+fn syscall_bytes(details: &str) -> u64 {
+    let ret = details.trim();
+    let parsed = match ret.strip_prefix("0x") {
+        // Errors are printed as the two's complement, like `0xfffffffffffffff5`
+        Some(hex) => u64::from_str_radix(hex, 16).map(|n| n as i64),
+        None => ret.parse(),
+    };
+    match parsed {
+        Ok(n) if n > 0 => n as u64,
+        _ => 0,
+    }
+}
+
+/// Identify a block request by device and sector (`259,0 WS 4096 () 123456 + 8 [comm]`)
+fn block_request_key(details: &str) -> Option<String> {
+    let tokens: Vec<&str> = details.split_whitespace().collect();
+    let plus = tokens.iter().position(|t| *t == "+")?;
+    Some(format!("{} {}", tokens.first()?, tokens.get(plus.checked_sub(1)?)?))
+}
+
+/// The innermost frame inside the profiled binary, or the innermost known frame otherwise
+fn call_site(frames: &[Frame], executable: &str) -> String {
+    frames.iter()
+        .find(|f| f.module == executable)
+        .or_else(|| frames.iter().find(|f| f.function != "[unknown]"))
+        .map(|f| f.function.clone())
+        .unwrap_or_else(|| "[unknown]".to_string())
+}
+
+fn print_sites(sites: &HashMap<(String, String), SiteStats>) {
+    let mut rows: Vec<_> = sites.iter().collect();
+    rows.sort_by(|(a_key, a), (b_key, b)| b.latency.cmp(&a.latency).then(a_key.cmp(b_key)));
+
+    println!("\n{}", "Call sites by latency".bold());
+    println!("{:<20} {:>10} {:>14} {:>14} {:>12}  Call site", "Operation", "Calls", "Bytes", "Latency (us)", "Avg (us)");
+    for ((operation, site), s) in rows.into_iter().take(TABLE_ROWS) {
+        println!("{:<20} {:>10} {:>14} {:>14} {:>12.1}  {}",
+            operation, s.calls, s.bytes, s.latency, s.latency as f64 / s.calls as f64, site);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(tid: u32, time: f64, name: &str, details: &str) -> PerfEvent {
+        PerfEvent { pid: tid, tid, time, event: name.to_string(), details: details.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn bytes_of_return_values() {
+        assert_eq!(syscall_bytes("0x1000"), 4096);
+        assert_eq!(syscall_bytes(" 4096 "), 4096);
+        assert_eq!(syscall_bytes("0x0"), 0);
+        assert_eq!(syscall_bytes("0xfffffffffffffff5"), 0);
+        assert_eq!(syscall_bytes("-11"), 0);
+        assert_eq!(syscall_bytes(""), 0);
+    }
+
+    #[test]
+    fn block_requests_by_device_and_sector() {
+        assert_eq!(block_request_key("259,0 WS 4096 () 123456 + 8 [app]").as_deref(), Some("259,0 123456"));
+        assert_eq!(block_request_key("259,0 WS () 123456 + 8 [0]").as_deref(), Some("259,0 123456"));
+        assert_eq!(block_request_key("259,0 N 0 () 18446744073709551615"), None);
+    }
+
+    #[test]
+    fn connections_count_calls_only() {
+        let events = [
+            event(1, 1.0, "syscalls:sys_enter_connect", "fd: 0x3"),
+            event(1, 1.5, "syscalls:sys_exit_connect", "0x0"),
+            event(1, 2.0, "syscalls:sys_enter_accept4", "fd: 0x3"),
+            event(1, 2.5, "syscalls:sys_exit_accept4", "0x4"),
+            event(1, 3.0, "syscalls:sys_enter_sendto", "fd: 0x4"),
+            event(1, 3.5, "syscalls:sys_exit_sendto", "0x100"),
+        ];
+        let (sites, profile) = aggregate(&events, "app");
+        let bytes = |name: &str| sites.iter().find(|((o, _), _)| o == name).map(|(_, s)| (s.calls, s.bytes));
+        assert_eq!(bytes("syscall:connect"), Some((1, 0)));
+        assert_eq!(bytes("syscall:accept4"), Some((1, 0)));
+        assert_eq!(bytes("syscall:sendto"), Some((1, 256)));
+        assert_eq!(profile.samples.len(), 3);
+    }
+
+    #[test]
+    fn block_completions_on_other_tasks() {
+        let events = [
+            event(1, 1.0, "block:block_rq_issue", "259,0 W 4096 () 100 + 8 [app]"),
+            event(0, 1.25, "block:block_rq_complete", "259,0 W () 100 + 8 [0]"),
+        ];
+        let (sites, _) = aggregate(&events, "app");
+        let stats = &sites[&("block:request".to_string(), "[unknown]".to_string())];
+        assert_eq!((stats.calls, stats.bytes, stats.latency), (1, 4096, 250_000));
+    }
+}
@@ -0,0 +1,91 @@
+//! Attribution of the samples of async runtime worker threads to the tasks they were polling
+//!
+//! The span events only know the Rust id of a thread, so each Rust thread is matched to the OS
+//! thread with the most samples inside its polls. Every sample inside a poll then gets the task
+//! as its outermost frame, which groups the flame graph by task instead of by the runtime's
+//! `poll` functions.
+
+use std::{collections::{HashMap, HashSet}, fs, io, path::Path};
+
+use colored::Colorize;
+
+use crate::profile;
+use crate::spans::{self, Poll};
+use crate::print_step;
+
+/// Module shown for the task frames
+const TASK_MODULE: &str = "[async task]";
+
+
+/// Add the task of every sample taken during a poll to the trace, with the polls from the span events
+pub fn attribute(trace_path: &Path, spans_path: &Path) -> io::Result<()> {
+    let polls = spans::read_polls(spans_path);
+    if polls.is_empty() {
+        eprintln!("{}", "Warning: The span events have no enter and exit events with thread ids, samples can not be attributed to tasks".yellow());
+        eprintln!("{}", "Hint: Initialize tracing as printed by --tracing, with FmtSpan::FULL and .with_thread_ids(true)".yellow());
+        return Ok(());
+    }
+    print_step("Attributing samples to async tasks");
+    let trace = fs::read_to_string(trace_path)?;
+    let blocks: Vec<&str> = trace.split("\n\n").filter(|b| !b.trim().is_empty()).collect();
+    let samples: Vec<(u32, f64)> = blocks.iter()
+        .map(|b| {
+            let event = profile::parse_perf_header(b.trim_start_matches('\n').lines().next().unwrap_or_default());
+            (event.tid, event.time)
+        })
+        .collect();
+
+    let mut by_thread: HashMap<&str, Vec<&Poll>> = HashMap::new();
+    for poll in &polls {
+        by_thread.entry(&poll.thread).or_default().push(poll);
+    }
+    for thread_polls in by_thread.values_mut() {
+        thread_polls.sort_by(|a, b| a.start.total_cmp(&b.start));
+    }
+    let threads = match_threads(&by_thread, &samples);
+
+    let mut attributed = String::with_capacity(trace.len());
+    let mut count = 0;
+    let mut tasks = HashSet::new();
+    for (block, (tid, time)) in blocks.iter().zip(&samples) {
+        attributed.push_str(block.trim_matches('\n'));
+        attributed.push('\n');
+        if let Some(poll) = threads.get(tid).and_then(|thread| find_poll(&by_thread[thread], *time)) {
+            attributed.push_str(&format!("\t               0 {} ({})\n", poll.task, TASK_MODULE));
+            tasks.insert(&poll.task);
+            count += 1;
+        }
+        attributed.push('\n');
+    }
+    println!("Attributed {} of {} samples to {} tasks", count, samples.len(), tasks.len());
+    fs::write(trace_path, attributed)
+}
+
+/// OS thread id of each Rust thread, assigned greedily by the number of samples inside its polls
+fn match_threads<'a>(by_thread: &HashMap<&'a str, Vec<&Poll>>, samples: &[(u32, f64)]) -> HashMap<u32, &'a str> {
+    let mut counts: HashMap<(&str, u32), usize> = HashMap::new();
+    for (thread, polls) in by_thread {
+        for (tid, time) in samples {
+            if find_poll(polls, *time).is_some() {
+                *counts.entry((thread, *tid)).or_default() += 1;
+            }
+        }
+    }
+    let mut scores: Vec<(usize, &str, u32)> = counts.into_iter().map(|((thread, tid), score)| (score, thread, tid)).collect();
+    scores.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)).then(a.2.cmp(&b.2)));
+
+    let mut matched: HashMap<u32, &str> = HashMap::new();
+    let mut assigned: HashSet<&str> = HashSet::new();
+    for (_, thread, tid) in scores {
+        if !matched.contains_key(&tid) && assigned.insert(thread) {
+            matched.insert(tid, thread);
+        }
+    }
+    matched
+}
+
+/// The poll containing `time` among polls sorted by their start
+fn find_poll<'a>(polls: &[&'a Poll], time: f64) -> Option<&'a Poll> {
+    let after = polls.partition_point(|p| p.start <= time);
+    after.checked_sub(1).map(|i| polls[i]).filter(|p| time <= p.end)
+}
@@ -0,0 +1,157 @@
+//! Wall-clock benchmarking of the built binary without a profiler, like hyperfine
+//!
+//! Comparing the numbers with a recording shows how much the profiler slows the application
+//! down, and they make a cheap end-to-end check next to the profiles.
+
+use std::{fs, process, time::Instant};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::stats::{self, Summary};
+use crate::{PProfArgs, RunArgs, TimeArgs, print_step};
+
+/// Wall time overhead in percent from which ways to reduce it are suggested
+const HIGH_OVERHEAD: f64 = 20.0;
+
+/// Fallback for the clock ticks per second of `/proc` times
+const DEFAULT_CLOCK_TICKS: f64 = 100.0;
+
+/// Durations of one execution in seconds
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub wall: f64,
+    pub user: f64,
+    pub system: f64,
+}
+
+
+/// Build the binary and time repeated executions of it
+pub fn run(args: &TimeArgs) {
+    let executable = crate::build(&[]);
+
+    if args.warmup > 0 {
+        print_step(&format!("Warming up with {} run(s)", args.warmup));
+        for _ in 0..args.warmup {
+            time_run(&executable, args);
+        }
+    }
+    print_step(&format!("Timing {} run(s)", args.runs));
+    let measurements: Vec<Measurement> = (0..args.runs).map(|_| time_run(&executable, args)).collect();
+    print_measurements(&measurements);
+}
+
+fn time_run(executable: &str, args: &TimeArgs) -> Measurement {
+    let mut command = app::command(executable);
+    command.args(&args.run.app_args);
+    if !args.show_output {
+        command.stdout(process::Stdio::null()).stderr(process::Stdio::null());
+    }
+    let (status, measurement) = measure(|| app::run(&mut command));
+    app::check_exit(status, args.run.ignore_exit);
+    measurement
+}
+
+/// Run the application once without a profiler, to compare a recording against
+pub fn run_unprofiled(executable: &str, run: &RunArgs) -> Measurement {
+    print_step("Running program without profiler");
+    let (status, measurement) = measure(|| app::run(app::command(executable).args(&run.app_args)));
+    app::check_exit(status, run.ignore_exit);
+    measurement
+}
+
+/// Call `f` and measure the wall time and the CPU time of the child processes it waits for
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Measurement) {
+    let before = children_cpu_ticks();
+    let start = Instant::now();
+    let result = f();
+    let wall = start.elapsed().as_secs_f64();
+    let after = children_cpu_ticks();
+    // Only asked now, getconf is a child process itself
+    let ticks = clock_ticks();
+    let (user, system) = match (before, after) {
+        (Some(before), Some(after)) => ((after.0 - before.0) / ticks, (after.1 - before.1) / ticks),
+        _ => (0.0, 0.0),
+    };
+    (result, Measurement { wall, user, system })
+}
+
+pub fn print_measurements(measurements: &[Measurement]) {
+    let wall: Vec<f64> = measurements.iter().map(|m| m.wall).collect();
+    let user: Vec<f64> = measurements.iter().map(|m| m.user).collect();
+    let system: Vec<f64> = measurements.iter().map(|m| m.system).collect();
+    let summary = stats::summarize(&wall);
+    println!("{}   {} ± {}", "Time (mean ± σ):".bold(), format_seconds(summary.mean).green().bold(), format_seconds(summary.stddev));
+    println!("Median:            {}", format_seconds(summary.median));
+    println!("Range (min … max): {} … {}    {} runs", format_seconds(summary.min), format_seconds(summary.max), measurements.len());
+    println!("CPU (mean):        User: {}, System: {}",
+        format_seconds(stats::summarize(&user).mean), format_seconds(stats::summarize(&system).mean));
+    print_outliers(&wall, &summary);
+}
+
+/// Print how much longer and how much more CPU the profiled run took than the unprofiled one
+pub fn print_overhead(unprofiled: &Measurement, profiled: &Measurement, args: &PProfArgs) {
+    print_step("Profiling overhead");
+    let cpu = |m: &Measurement| m.user + m.system;
+    println!("{:<10} {:>12} {:>12}", "", "Wall time", "CPU time");
+    println!("{:<10} {:>12} {:>12}", "Unprofiled", format_seconds(unprofiled.wall), format_seconds(cpu(unprofiled)));
+    println!("{:<10} {:>12} {:>12}", "Profiled", format_seconds(profiled.wall), format_seconds(cpu(profiled)));
+    let wall_overhead = overhead(unprofiled.wall, profiled.wall);
+    println!("{:<10} {:>12} {:>12}", "Overhead", format_percent(wall_overhead), format_percent(overhead(cpu(unprofiled), cpu(profiled))));
+
+    if wall_overhead.is_some_and(|o| o > HIGH_OVERHEAD) {
+        let hint = if args.call_graph.as_deref().is_some_and(|m| m.starts_with("dwarf")) {
+            "Hint: dwarf call graphs copy the stack for every sample, try --call-graph fp or a lower --frequency"
+        } else {
+            "Hint: a lower --frequency reduces the overhead at the cost of fewer samples"
+        };
+        eprintln!("{}", hint.yellow());
+    }
+}
+
+/// Increase from `before` to `after` in percent
+fn overhead(before: f64, after: f64) -> Option<f64> {
+    (before > 0.0).then(|| (after - before) * 100.0 / before)
+}
+
+fn format_percent(percent: Option<f64>) -> String {
+    percent.map(|p| format!("{:+.1}%", p)).unwrap_or_else(|| "-".to_string())
+}
+
+/// Warn about runs far from the others, which usually means the machine was busy
+fn print_outliers(wall: &[f64], summary: &Summary) {
+    if summary.stddev == 0.0 {
+        return;
+    }
+    let outliers = wall.iter().filter(|w| (*w - summary.median).abs() > 3.0 * summary.stddev).count();
+    if outliers > 0 {
+        eprintln!("{}", format!("Warning: {} run(s) took much longer or shorter than the rest, consider --warmup or closing other programs", outliers).yellow());
+    }
+}
+
+pub fn format_seconds(seconds: f64) -> String {
+    if seconds < 1.0 {
+        format!("{:.1} ms", seconds * 1000.0)
+    } else {
+        format!("{:.3} s", seconds)
+    }
+}
+
+/// User and system time of all waited for children in clock ticks, from `/proc/self/stat`
+fn children_cpu_ticks() -> Option<(f64, f64)> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name in parentheses may contain spaces, the fields after it are numbers
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    // cutime and cstime are fields 16 and 17, counting from pid as field 1
+    let user: f64 = fields.get(13)?.parse().ok()?;
+    let system: f64 = fields.get(14)?.parse().ok()?;
+    Some((user, system))
+}
+
+/// Clock ticks per second, the unit of the CPU times in `/proc`
+pub fn clock_ticks() -> f64 {
+    let output = process::Command::new("getconf").arg("CLK_TCK").output();
+    output.ok()
+        .and_then(|o| String::from_utf8_lossy(&o.stdout).trim().parse().ok())
+        .unwrap_or(DEFAULT_CLOCK_TICKS)
+}
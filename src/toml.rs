@@ -0,0 +1,283 @@
+//! Parser for the subset of TOML used by configuration files
+//!
+//! Supports tables (`[a.b]`), `key = value` pairs with dotted keys, basic and literal strings,
+//! integers, floats, booleans, single-line arrays and inline tables, and `#` comments.
+
+use serde_json::{Map, Value};
+
+
+/// Parse a document into a JSON value, so it can be deserialized with serde
+pub fn parse(content: &str) -> Result<Value, String> {
+    let mut root = Map::new();
+    let mut table: Vec<String> = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let error = |e: String| format!("line {}: {}", i + 1, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let Some((header, rest)) = header.split_once(']') else {
+                return Err(error("unterminated table header".to_string()));
+            };
+            if !rest.trim().is_empty() && !rest.trim().starts_with('#') {
+                return Err(error("unexpected characters after table header".to_string()));
+            }
+            table = parse_key(header).map_err(error)?;
+            lookup(&mut root, &table).map_err(error)?;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(error("expected `key = value`".to_string()));
+        };
+        let mut path = table.clone();
+        path.extend(parse_key(key).map_err(error)?);
+        let mut parser = Parser { input: value.trim(), pos: 0 };
+        let value = parser.value().map_err(error)?;
+        parser.skip_whitespace();
+        if !parser.rest().is_empty() && !parser.rest().starts_with('#') {
+            return Err(error(format!("unexpected characters {:?}", parser.rest())));
+        }
+
+        let name = path.pop().unwrap_or_default();
+        lookup(&mut root, &path).map_err(error)?.insert(name, value);
+    }
+
+    Ok(Value::Object(root))
+}
+
+/// Split a dotted key (`a.b`, `"a.b".c`) into its parts
+fn parse_key(key: &str) -> Result<Vec<String>, String> {
+    let mut parser = Parser { input: key.trim(), pos: 0 };
+    let mut parts = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        let part = match parser.peek() {
+            Some('"') | Some('\'') => parser.string()?,
+            _ => {
+                let key: String = parser.rest().chars()
+                    .take_while(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                    .collect();
+                if key.is_empty() {
+                    return Err(format!("invalid key {:?}", parser.input));
+                }
+                parser.pos += key.len();
+                key
+            },
+        };
+        parts.push(part);
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => return Ok(parts),
+            Some('.') => parser.pos += 1,
+            Some(c) => return Err(format!("unexpected {:?} in key", c)),
+        }
+    }
+}
+
+/// The table at the given path, created if it does not exist yet
+fn lookup<'a>(root: &'a mut Map<String, Value>, path: &[String]) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+    for part in path {
+        let entry = table.entry(part.clone()).or_insert_with(|| Value::Object(Map::new()));
+        table = match entry {
+            Value::Object(map) => map,
+            _ => return Err(format!("{} is not a table", part)),
+        };
+    }
+    Ok(table)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(format!("expected {:?}", c))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') | Some('\'') => self.string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.peek().unwrap_or('"');
+        self.pos += 1;
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                c if c == quote => {
+                    self.pos += i + 1;
+                    return Ok(value);
+                },
+                '\\' if quote == '"' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some(c) => return Err(format!("unsupported escape \\{}", c)),
+                    None => break,
+                },
+                c => value.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        const UNTERMINATED: &str = "expected ',' or ']' in array (arrays have to be on a single line)";
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(values));
+                },
+                None => return Err(UNTERMINATED.to_string()),
+                Some(_) => (),
+            }
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => (),
+                _ => return Err(UNTERMINATED.to_string()),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut table = Map::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                return Ok(Value::Object(table));
+            }
+            let Some(end) = self.rest().find('=') else { return Err("expected `key = value`".to_string()) };
+            let mut path = parse_key(&self.rest()[..end])?;
+            self.pos += end + 1;
+            let value = self.value()?;
+            let name = path.pop().unwrap_or_default();
+            lookup(&mut table, &path)?.insert(name, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => (),
+                _ => return Err("expected ',' or '}' in inline table".to_string()),
+            }
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Value, String> {
+        let token: String = self.rest().chars()
+            .take_while(|c| !c.is_whitespace() && !matches!(c, ',' | ']' | '}' | '#'))
+            .collect();
+        self.pos += token.len();
+        let number = token.replace('_', "");
+        match token.as_str() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => if let Ok(n) = number.parse::<i64>() {
+                Ok(Value::from(n))
+            } else if let Ok(n) = number.parse::<f64>() {
+                Ok(Value::from(n))
+            } else {
+                Err(format!("unsupported value {:?}", token))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tables_and_values() {
+        let document = r#"
+# ~/.config/cargo-pprof/config.toml
+frequency = 4_999
+ratio = 0.5
+sudo = true
+browser = "flatpak run \"firefox\""
+perf-path = '/opt/perf\bin'
+formats = ["trace", "gecko"] # trailing comment
+
+[grafana]
+url = "https://grafana.example"
+
+[package.metadata.pprof.env]
+RUST_LOG = "info"
+inline = { a.b = 1, c = [] }
+"#;
+        assert_eq!(parse(document).unwrap(), json!({
+            "frequency": 4999,
+            "ratio": 0.5,
+            "sudo": true,
+            "browser": "flatpak run \"firefox\"",
+            "perf-path": "/opt/perf\\bin",
+            "formats": ["trace", "gecko"],
+            "grafana": { "url": "https://grafana.example" },
+            "package": { "metadata": { "pprof": { "env": {
+                "RUST_LOG": "info",
+                "inline": { "a": { "b": 1 }, "c": [] },
+            } } } },
+        }));
+    }
+
+    #[test]
+    fn dotted_and_quoted_keys() {
+        assert_eq!(parse("a.b = 1\n\"c.d\".e = 2").unwrap(), json!({ "a": { "b": 1 }, "c.d": { "e": 2 } }));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(parse("a = 1\nb").unwrap_err(), "line 2: expected `key = value`");
+        assert_eq!(parse("[a\n").unwrap_err(), "line 1: unterminated table header");
+        assert_eq!(parse("a = \"open").unwrap_err(), "line 1: unterminated string");
+        assert_eq!(parse("a = [1,\n2]").unwrap_err(), "line 1: expected ',' or ']' in array (arrays have to be on a single line)");
+        assert_eq!(parse("a = [\n1]").unwrap_err(), "line 1: expected ',' or ']' in array (arrays have to be on a single line)");
+        assert_eq!(parse("a = 1 2").unwrap_err(), "line 1: unexpected characters \"2\"");
+        assert_eq!(parse("a = nope").unwrap_err(), "line 1: unsupported value \"nope\"");
+        assert_eq!(parse("a = 1\n[a]").unwrap_err(), "line 2: a is not a table");
+    }
+}
@@ -0,0 +1,102 @@
+//! Live view of the hottest functions while the application runs, like `perf top`
+//!
+//! perf writes a new data file every interval (`--switch-output`), each of which is converted
+//! and added to the profile shown right away. The output of the application goes to a log file
+//! so it does not garble the display.
+
+use std::{fs::{self, File}, io::{self, IsTerminal}, path::{Path, PathBuf}, process, thread, time::{Duration, Instant}};
+
+use colored::Colorize;
+
+use crate::app;
+use crate::perf;
+use crate::profile::{self, Profile, Sample};
+use crate::report;
+use crate::{TopArgs, log_command, print_step, resolve};
+
+const STEM: &str = "top";
+
+
+/// Build the binary, then sample it and refresh the hottest functions until it exits
+pub fn run(args: &TopArgs) {
+    let executable = crate::build(&[]);
+    let dir = crate::output_dir(&executable);
+    let data = dir.join(format!("{}.data", STEM));
+    let log = dir.join("top-output.log");
+    for chunk in chunks(dir) {
+        let _ = fs::remove_file(chunk);
+    }
+
+    print_step("Sampling program with perf");
+    let mut record_args = perf::sampling_args(args.frequency, None, &[]);
+    record_args.push(format!("--switch-output={}ms", args.interval));
+    let mut recording = perf::Recording::new(dir, STEM, &executable, &args.run.app_args, args.run.ignore_exit);
+    recording.record_args = record_args;
+    recording.frequency = args.frequency.unwrap_or(perf::FREQUENCY);
+    let mut command = perf::record_command(&recording);
+    let log_file = resolve(File::create(&log));
+    command.stdout(resolve(log_file.try_clone())).stderr(log_file);
+    log_command(&command);
+    let mut child = resolve(command.spawn().map_err(|e| format!("Could not run perf ({})", e)));
+
+    let started = Instant::now();
+    let mut profile = Profile { value_names: vec!["samples".to_string()], samples: Vec::new() };
+    let status = loop {
+        thread::sleep(Duration::from_millis(args.interval));
+        let exited = resolve(child.try_wait());
+        add_chunks(&mut profile, &recording);
+        draw(&profile, &executable, started.elapsed(), &log);
+        if let Some(status) = exited {
+            break status;
+        }
+    };
+    // The last chunk is written when perf exits
+    if data.exists() {
+        let _ = fs::rename(&data, dir.join(format!("{}.data.last", STEM)));
+    }
+    add_chunks(&mut profile, &recording);
+    draw(&profile, &executable, started.elapsed(), &log);
+    app::check_exit(status, args.run.ignore_exit);
+}
+
+/// Data files perf finished writing, oldest first
+fn chunks(dir: &Path) -> Vec<PathBuf> {
+    let prefix = format!("{}.data.", STEM);
+    let mut chunks: Vec<PathBuf> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with(&prefix)))
+        .filter(|p| p.extension().is_none_or(|e| e != "trace"))
+        .collect();
+    chunks.sort();
+    chunks
+}
+
+/// Convert the finished data files and add their samples to the profile
+fn add_chunks(profile: &mut Profile, recording: &perf::Recording) {
+    for chunk in chunks(recording.dir) {
+        let trace = chunk.with_extension("trace");
+        let mut chunk_recording = recording.clone();
+        chunk_recording.data = chunk.clone();
+        let converted = File::create(&trace).and_then(|file| perf::script_command(&chunk_recording)
+            .stdout(file)
+            .stderr(process::Stdio::null())
+            .status());
+        if converted.is_ok_and(|s| s.success()) && let Ok(events) = profile::parse_perf_events(&trace) {
+            profile.samples.extend(events.into_iter().map(|e| Sample { frames: e.frames, values: vec![1] }));
+        }
+        let _ = fs::remove_file(&chunk);
+        let _ = fs::remove_file(&trace);
+    }
+}
+
+fn draw(profile: &Profile, executable: &str, elapsed: Duration, log: &Path) {
+    if io::stdout().is_terminal() {
+        print!("\x1b[H\x1b[2J");
+    }
+    println!("{} {} samples after {:.0}s",
+        format!("Sampling {}:", executable).bold(), profile.samples.len(), elapsed.as_secs_f64());
+    println!("Application output: {}", log.to_string_lossy().cyan());
+    if !profile.samples.is_empty() {
+        report::print_summary(profile);
+    }
+}
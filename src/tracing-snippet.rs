@@ -0,0 +1,10 @@
+// Write span events for `cargo pprof --tracing` (requires tracing-subscriber with the "json" feature)
+if let Ok(path) = std::env::var("CARGO_PPROF_SPANS") {
+    let file = std::fs::File::create(path).expect("could not create span file");
+    tracing_subscriber::fmt()
+        .json()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::FULL)
+        .with_thread_ids(true)
+        .with_writer(std::sync::Mutex::new(file))
+        .init();
+}
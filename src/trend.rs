@@ -0,0 +1,77 @@
+//! Share of a function across the stored runs, to spot regressions that creep in over many commits
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::manifest;
+use crate::profile::{self, Profile};
+use crate::report::{self, Format};
+use crate::store;
+use crate::{TrendArgs, resolve};
+
+/// Width of the bar of the largest share
+const BAR_WIDTH: usize = 40;
+
+/// Share of the matching functions in a single run
+struct Point {
+    run: store::Run,
+    /// Percent of the samples with a matching function as leaf
+    self_share: f64,
+    /// Percent of the samples with a matching function anywhere in the stack
+    total_share: f64,
+}
+
+
+pub fn run(args: &TrendArgs) {
+    let root = store::store_dir(&resolve(manifest::load()).target_directory);
+    let points: Vec<Point> = store::load_runs().into_iter()
+        .filter(|run| args.command.as_ref().is_none_or(|c| run.command_line().contains(c.as_str())))
+        .filter_map(|run| {
+            let outputs: Vec<(Format, PathBuf)> = run.outputs.iter()
+                .filter_map(|o| Some((Format::from_str(&o.format, true).ok()?, root.join(&run.id).join(&o.file))))
+                .collect();
+            let profile = profile::load(&report::profile_source(&outputs)?).ok()?;
+            let (self_share, total_share) = shares(&profile, &args.function);
+            Some(Point { run, self_share, total_share })
+        })
+        .collect();
+    if points.is_empty() {
+        resolve::<(), _>(Err("No stored run has a trace or folded stacks (see `cargo pprof list`)"));
+    }
+
+    if args.csv {
+        println!("id,started,commit,samples,self_percent,total_percent");
+        for point in &points {
+            println!("{},{},{},{},{:.4},{:.4}", point.run.id, point.run.started, point.run.commit.as_deref().unwrap_or(""),
+                point.run.samples.map(|s| s.to_string()).unwrap_or_default(), point.self_share, point.total_share);
+        }
+        return;
+    }
+
+    println!("{}", format!("Share of functions containing {:?} (total %, bar scaled to the largest)", args.function).bold());
+    println!("{:<24} {:<10} {:>8} {:>8}", "ID".bold(), "COMMIT".bold(), "SELF %".bold(), "TOTAL %".bold());
+    let max = points.iter().map(|p| p.total_share).fold(0.0, f64::max);
+    for point in &points {
+        let width = if max > 0.0 { (point.total_share / max * BAR_WIDTH as f64).round() as usize } else { 0 };
+        println!("{:<24} {:<10} {:>7.2}% {:>7.2}%  {}", point.run.id, point.run.commit.as_deref().unwrap_or("-"),
+            point.self_share, point.total_share, "#".repeat(width));
+    }
+}
+
+/// Self and total share in percent of the functions whose names contain `function`
+fn shares(profile: &Profile, function: &str) -> (f64, f64) {
+    let (mut own, mut inclusive, mut total) = (0, 0, 0);
+    for sample in &profile.samples {
+        let value = sample.values[0];
+        total += value;
+        if sample.frames.first().is_some_and(|f| f.function.contains(function)) {
+            own += value;
+        }
+        if sample.frames.iter().any(|f| f.function.contains(function)) {
+            inclusive += value;
+        }
+    }
+    (report::percent(own, total), report::percent(inclusive, total))
+}
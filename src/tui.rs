@@ -0,0 +1,483 @@
+//! Interactive terminal viewer of a recording, for investigating profiles over SSH
+//!
+//! The terminal is switched to raw mode with `stty` and drawn with plain ANSI escape sequences.
+//! Besides the call tree and the list of hot functions of the whole profile, every thread of a
+//! trace can be viewed on its own.
+
+use std::{collections::HashMap, fs::File, io::{Read, Write}, path::Path, process};
+
+use colored::Colorize;
+
+use crate::perf;
+use crate::profile::{self, Profile, Sample};
+use crate::report;
+use crate::{TuiArgs, resolve};
+
+const HELP: &str = "↑↓ move  →← expand/collapse  Tab switch view  t/T thread  / search  n next match  q quit";
+
+/// Lines taken by the header and the status line
+const CHROME_LINES: usize = 3;
+
+/// Call tree of one profile, node 0 is the root
+struct Tree {
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    function: String,
+    total: u64,
+    self_value: u64,
+    parent: usize,
+    children: Vec<usize>,
+}
+
+/// Profile of the whole recording or of a single thread
+struct Thread {
+    name: String,
+    tree: Tree,
+    expanded: Vec<bool>,
+    /// Function, self and total value, hottest first
+    functions: Vec<(String, u64, u64)>,
+    total: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    Tree,
+    Functions,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Escape,
+    Backspace,
+    Tab,
+    Quit,
+    Char(char),
+}
+
+struct App {
+    title: String,
+    threads: Vec<Thread>,
+    thread: usize,
+    view: View,
+    cursor: usize,
+    scroll: usize,
+    /// Search text while it is typed
+    input: Option<String>,
+    query: String,
+    message: String,
+}
+
+/// Restores the terminal when dropped, also if the viewer panics
+struct Terminal {
+    tty: File,
+    saved: String,
+}
+
+
+pub fn run(args: &TuiArgs) {
+    if !args.path.is_file() {
+        resolve::<(), _>(Err(format!("{} does not exist", args.path.to_string_lossy())));
+    }
+    let threads = resolve(load(&args.path));
+    let mut app = App {
+        title: args.path.to_string_lossy().to_string(),
+        threads,
+        thread: 0,
+        view: View::Tree,
+        cursor: 0,
+        scroll: 0,
+        input: None,
+        query: String::new(),
+        message: String::new(),
+    };
+
+    let mut terminal = resolve(Terminal::open());
+    loop {
+        let (rows, cols) = terminal.size();
+        terminal.draw(&app.render(rows, cols));
+        let mut buffer = [0; 32];
+        let n = match terminal.tty.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let page = rows.saturating_sub(CHROME_LINES).max(1);
+        if parse_keys(&buffer[..n]).into_iter().any(|key| !app.handle(key, page)) {
+            break;
+        }
+    }
+}
+
+/// Load a trace, folded stacks or perf.data, with one profile per thread after the whole one
+fn load(path: &Path) -> Result<Vec<Thread>, String> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let trace = if name.ends_with(".data") || name.contains("perf.data") {
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        let mut recording = perf::Recording::new(dir, &stem, "", &[], false);
+        recording.data = path.to_path_buf();
+        perf::script(&recording)
+    } else {
+        path.to_path_buf()
+    };
+
+    if trace.extension().is_some_and(|e| e == "folded") {
+        return Ok(vec![Thread::new("All threads".to_string(), &profile::load(&trace)?)]);
+    }
+    let events = profile::parse_perf_events(&trace)
+        .map_err(|e| format!("Could not read {} ({})", trace.to_string_lossy(), e))?;
+    let mut threads = vec![Thread::new("All threads".to_string(), &profile::from_perf_events(&events))];
+    let mut by_thread: HashMap<u32, (String, Vec<Sample>)> = HashMap::new();
+    for event in &events {
+        let entry = by_thread.entry(event.tid).or_insert_with(|| (format!("{} ({})", event.comm, event.tid), Vec::new()));
+        entry.1.push(Sample { frames: event.frames.clone(), values: vec![1] });
+    }
+    // With a single thread the whole profile already is the one of the thread
+    if by_thread.len() > 1 {
+        let mut by_thread: Vec<_> = by_thread.into_values().collect();
+        by_thread.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+        for (name, samples) in by_thread {
+            threads.push(Thread::new(name, &Profile { value_names: vec!["samples".to_string()], samples }));
+        }
+    }
+    Ok(threads)
+}
+
+impl Tree {
+    fn build(profile: &Profile) -> Tree {
+        let mut nodes = vec![Node { function: "all".to_string(), total: 0, self_value: 0, parent: 0, children: Vec::new() }];
+        let mut index: HashMap<(usize, &str), usize> = HashMap::new();
+        for sample in &profile.samples {
+            let value = sample.values.first().copied().unwrap_or(0);
+            let mut current = 0;
+            nodes[0].total += value;
+            for frame in sample.frames.iter().rev() {
+                current = *index.entry((current, frame.function.as_str())).or_insert_with(|| {
+                    nodes.push(Node { function: frame.function.clone(), total: 0, self_value: 0, parent: current, children: Vec::new() });
+                    let child = nodes.len() - 1;
+                    nodes[current].children.push(child);
+                    child
+                });
+                nodes[current].total += value;
+            }
+            nodes[current].self_value += value;
+        }
+
+        let totals: Vec<(u64, String)> = nodes.iter().map(|n| (n.total, n.function.clone())).collect();
+        for node in &mut nodes {
+            node.children.sort_by(|a, b| totals[*b].0.cmp(&totals[*a].0).then(totals[*a].1.cmp(&totals[*b].1)));
+        }
+        Tree { nodes }
+    }
+
+    /// All nodes below the root in the order they are displayed
+    fn preorder(&self) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut stack: Vec<usize> = self.nodes[0].children.iter().rev().copied().collect();
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.nodes[node].children.iter().rev());
+        }
+        order
+    }
+}
+
+impl Thread {
+    fn new(name: String, profile: &Profile) -> Thread {
+        let tree = Tree::build(profile);
+        let (rows, total) = report::function_stats(profile);
+        let functions = rows.iter().map(|r| (r.function.to_string(), r.self_values[0], r.total)).collect();
+        let mut expanded = vec![false; tree.nodes.len()];
+        expanded[0] = true;
+        Thread { name, expanded, tree, functions, total }
+    }
+
+    /// Visible nodes of the call tree with their depth
+    fn rows(&self) -> Vec<(usize, usize)> {
+        let mut rows = Vec::new();
+        let mut stack: Vec<(usize, usize)> = self.tree.nodes[0].children.iter().rev().map(|c| (*c, 0)).collect();
+        while let Some((node, depth)) = stack.pop() {
+            rows.push((node, depth));
+            if self.expanded[node] {
+                stack.extend(self.tree.nodes[node].children.iter().rev().map(|c| (*c, depth + 1)));
+            }
+        }
+        rows
+    }
+}
+
+impl App {
+    fn current(&self) -> &Thread {
+        &self.threads[self.thread]
+    }
+
+    fn row_count(&self) -> usize {
+        match self.view {
+            View::Tree => self.current().rows().len(),
+            View::Functions => self.current().functions.len(),
+        }
+    }
+
+    /// Apply a key press, returns false to quit
+    fn handle(&mut self, key: Key, page: usize) -> bool {
+        if let Some(input) = &mut self.input {
+            match key {
+                Key::Enter => {
+                    self.query = self.input.take().unwrap_or_default();
+                    self.next_match();
+                },
+                Key::Escape | Key::Quit => self.input = None,
+                Key::Backspace => { input.pop(); },
+                Key::Char(c) => input.push(c),
+                _ => (),
+            }
+            return true;
+        }
+
+        self.message.clear();
+        let count = self.row_count();
+        match key {
+            Key::Quit | Key::Char('q') => return false,
+            Key::Up | Key::Char('k') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down | Key::Char('j') => self.cursor += 1,
+            Key::PageUp => self.cursor = self.cursor.saturating_sub(page),
+            Key::PageDown => self.cursor += page,
+            Key::Home | Key::Char('g') => self.cursor = 0,
+            Key::End | Key::Char('G') => self.cursor = count.saturating_sub(1),
+            Key::Right | Key::Char('l') => self.expand(),
+            Key::Left | Key::Char('h') => self.collapse(),
+            Key::Enter | Key::Char(' ') => self.toggle(),
+            Key::Tab | Key::Char('f') => {
+                self.view = if self.view == View::Tree { View::Functions } else { View::Tree };
+                self.cursor = 0;
+            },
+            Key::Char('t') => self.switch_thread(1),
+            Key::Char('T') => self.switch_thread(self.threads.len() - 1),
+            Key::Char('/') => self.input = Some(String::new()),
+            Key::Char('n') => self.next_match(),
+            _ => (),
+        }
+        self.cursor = self.cursor.min(self.row_count().saturating_sub(1));
+        true
+    }
+
+    fn selected_node(&self) -> Option<usize> {
+        (self.view == View::Tree).then(|| self.current().rows().get(self.cursor).map(|r| r.0)).flatten()
+    }
+
+    fn expand(&mut self) {
+        let Some(node) = self.selected_node() else { return };
+        let thread = &mut self.threads[self.thread];
+        if thread.tree.nodes[node].children.is_empty() {
+            return;
+        }
+        if thread.expanded[node] {
+            self.cursor += 1;
+        } else {
+            thread.expanded[node] = true;
+        }
+    }
+
+    fn collapse(&mut self) {
+        let Some(node) = self.selected_node() else { return };
+        let thread = &mut self.threads[self.thread];
+        if thread.expanded[node] {
+            thread.expanded[node] = false;
+        } else if let Some(row) = thread.rows().iter().position(|r| r.0 == thread.tree.nodes[node].parent) {
+            self.cursor = row;
+        }
+    }
+
+    fn toggle(&mut self) {
+        let Some(node) = self.selected_node() else { return };
+        let expanded = &mut self.threads[self.thread].expanded;
+        expanded[node] = !expanded[node];
+    }
+
+    fn switch_thread(&mut self, step: usize) {
+        self.thread = (self.thread + step) % self.threads.len();
+        self.cursor = 0;
+        self.scroll = 0;
+    }
+
+    /// Move to the next function containing the query, expanding the call tree up to it
+    fn next_match(&mut self) {
+        if self.query.is_empty() {
+            return;
+        }
+        let query = self.query.to_lowercase();
+        let matches = |function: &str| function.to_lowercase().contains(&query);
+        match self.view {
+            View::Functions => {
+                let functions = &self.current().functions;
+                let found = (1..=functions.len())
+                    .map(|i| (self.cursor + i) % functions.len())
+                    .find(|i| matches(&functions[*i].0));
+                match found {
+                    Some(row) => self.cursor = row,
+                    None => self.message = format!("No function matches {:?}", self.query),
+                }
+            },
+            View::Tree => {
+                let thread = &self.threads[self.thread];
+                let order = thread.tree.preorder();
+                let start = self.selected_node().and_then(|n| order.iter().position(|o| *o == n)).unwrap_or(order.len());
+                let found = (1..=order.len())
+                    .map(|i| order[(start + i) % order.len()])
+                    .find(|n| matches(&thread.tree.nodes[*n].function));
+                let Some(node) = found else {
+                    self.message = format!("No function matches {:?}", self.query);
+                    return;
+                };
+                let thread = &mut self.threads[self.thread];
+                let mut ancestor = thread.tree.nodes[node].parent;
+                while ancestor != 0 {
+                    thread.expanded[ancestor] = true;
+                    ancestor = thread.tree.nodes[ancestor].parent;
+                }
+                self.cursor = thread.rows().iter().position(|r| r.0 == node).unwrap_or(0);
+            },
+        }
+    }
+
+    fn render(&mut self, rows: usize, cols: usize) -> Vec<String> {
+        let height = rows.saturating_sub(CHROME_LINES).max(1);
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + height {
+            self.scroll = self.cursor + 1 - height;
+        }
+
+        let thread = self.current();
+        let view = match self.view {
+            View::Tree => "Call tree",
+            View::Functions => "Hot functions",
+        };
+        let mut lines = vec![
+            truncate(&format!("{} | {} ({}/{}) | {}", self.title, thread.name, self.thread + 1, self.threads.len(), view), cols).bold().to_string(),
+            truncate(&format!("{:>8} {:>8}  Function", if self.view == View::Tree { "Total %" } else { "Self %" },
+                if self.view == View::Tree { "Self %" } else { "Total %" }), cols).dimmed().to_string(),
+        ];
+
+        let entries: Vec<String> = match self.view {
+            View::Tree => thread.rows().into_iter().skip(self.scroll).take(height).map(|(node, depth)| {
+                let node_data = &thread.tree.nodes[node];
+                let marker = if node_data.children.is_empty() { " " } else if thread.expanded[node] { "▾" } else { "▸" };
+                format!("{:>7.2}% {:>7.2}%  {}{} {}", report::percent(node_data.total, thread.total),
+                    report::percent(node_data.self_value, thread.total), "  ".repeat(depth), marker, node_data.function)
+            }).collect(),
+            View::Functions => thread.functions.iter().skip(self.scroll).take(height).map(|(function, self_value, total)| {
+                format!("{:>7.2}% {:>7.2}%  {}", report::percent(*self_value, thread.total),
+                    report::percent(*total, thread.total), function)
+            }).collect(),
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            let line = truncate(entry, cols);
+            lines.push(if self.scroll + i == self.cursor { format!("{:<width$}", line, width = cols).reversed().to_string() } else { line });
+        }
+        lines.resize(rows.saturating_sub(1), String::new());
+
+        let status = match &self.input {
+            Some(input) => format!("/{}", input),
+            None if !self.message.is_empty() => self.message.clone(),
+            None => HELP.to_string(),
+        };
+        lines.push(truncate(&status, cols).dimmed().to_string());
+        lines
+    }
+}
+
+fn truncate(line: &str, cols: usize) -> String {
+    line.chars().take(cols).collect()
+}
+
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let sequence = match bytes {
+        b"\x1b[A" | b"\x1bOA" => Some(Key::Up),
+        b"\x1b[B" | b"\x1bOB" => Some(Key::Down),
+        b"\x1b[C" | b"\x1bOC" => Some(Key::Right),
+        b"\x1b[D" | b"\x1bOD" => Some(Key::Left),
+        b"\x1b[5~" => Some(Key::PageUp),
+        b"\x1b[6~" => Some(Key::PageDown),
+        b"\x1b[H" | b"\x1b[1~" | b"\x1bOH" => Some(Key::Home),
+        b"\x1b[F" | b"\x1b[4~" | b"\x1bOF" => Some(Key::End),
+        b"\x1b" => Some(Key::Escape),
+        _ => None,
+    };
+    if let Some(key) = sequence {
+        return vec![key];
+    }
+    String::from_utf8_lossy(bytes).chars().filter_map(|c| match c {
+        '\r' | '\n' => Some(Key::Enter),
+        '\t' => Some(Key::Tab),
+        '\x7f' | '\x08' => Some(Key::Backspace),
+        // Ctrl+C and Ctrl+D, which raw mode delivers as characters
+        '\x03' | '\x04' => Some(Key::Quit),
+        // Unknown escape sequences
+        '\x1b' => None,
+        c if c.is_control() => None,
+        c => Some(Key::Char(c)),
+    }).collect()
+}
+
+impl Terminal {
+    fn open() -> Result<Terminal, String> {
+        let tty = File::options().read(true).write(true).open("/dev/tty")
+            .map_err(|e| format!("The viewer needs a terminal ({})", e))?;
+        let saved = stty(&tty, &["-g"])?;
+        stty(&tty, &["raw", "-echo"])?;
+        let mut terminal = Terminal { tty, saved };
+        // Alternate screen and hidden cursor
+        let _ = terminal.tty.write_all(b"\x1b[?1049h\x1b[?25l");
+        Ok(terminal)
+    }
+
+    /// Rows and columns of the terminal, queried before every redraw to follow resizes
+    fn size(&self) -> (usize, usize) {
+        let size = stty(&self.tty, &["size"]).unwrap_or_default();
+        let mut parts = size.split_whitespace().filter_map(|p| p.parse().ok());
+        match (parts.next(), parts.next()) {
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => (rows, cols),
+            _ => (24, 80),
+        }
+    }
+
+    fn draw(&mut self, lines: &[String]) {
+        // Overwriting the lines instead of clearing the screen avoids flicker
+        let mut screen = String::from("\x1b[H");
+        screen.push_str(&lines.join("\x1b[K\r\n"));
+        screen.push_str("\x1b[K\x1b[J");
+        let _ = self.tty.write_all(screen.as_bytes());
+        let _ = self.tty.flush();
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.tty.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stty(&self.tty, &[self.saved.as_str()]);
+    }
+}
+
+fn stty(tty: &File, args: &[&str]) -> Result<String, String> {
+    let stdin = tty.try_clone().map_err(|e| e.to_string())?;
+    let output = process::Command::new("stty")
+        .args(args)
+        .stdin(stdin)
+        .output()
+        .map_err(|e| format!("Could not run stty ({})", e))?;
+    if !output.status.success() {
+        return Err(format!("stty failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -0,0 +1,180 @@
+//! Publishing recordings to the Firefox Profiler's storage, a user-provided object store or a
+//! S3/GCS bucket
+
+use std::{env, fs::{self, File}, path::{Path, PathBuf}, process, time::{SystemTime, UNIX_EPOCH}};
+
+use colored::Colorize;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config;
+use crate::report;
+use crate::server::{self, PROFILER_URL};
+use crate::{UploadArgs, git_commit, print_step, resolve, resolve_status};
+
+/// Endpoint the profiler's "Upload" button posts gzipped profiles to
+const STORE_URL: &str = "https://api.profiler.firefox.com/compressed-store";
+
+/// Media type the store expects in the `Accept` header
+const STORE_ACCEPT: &str = "application/vnd.firefox-profiler+json;version=1.0";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TokenClaims {
+    profile_token: String,
+}
+
+
+pub fn run(args: &UploadArgs) {
+    match args.upload_to.as_deref().or(config::load().upload_to.as_deref()) {
+        Some(bucket) if is_bucket(bucket) => archive(std::slice::from_ref(&args.path), bucket),
+        store => upload(&args.path, store),
+    }
+}
+
+/// Upload the Firefox Profiler file or trace written during this run, or archive all outputs in a bucket
+pub fn upload_outputs(store: Option<&str>) {
+    if let Some(bucket) = store.filter(|s| is_bucket(s)) {
+        let mut paths: Vec<PathBuf> = report::outputs().into_iter().map(|(_, p)| p).collect();
+        paths.dedup();
+        if paths.is_empty() {
+            eprintln!("{}", "Warning: no output files to archive".yellow());
+        } else {
+            archive(&paths, bucket);
+        }
+        return;
+    }
+
+    match report::profiler_output() {
+        Some(path) => upload(&path, store),
+        None => eprintln!("{}", "Warning: no output can be uploaded, use --format gecko or --format trace".yellow()),
+    }
+}
+
+/// Upload a profile and print a link that opens it in the Firefox Profiler
+///
+/// Without a store the profile is published on the profiler's public storage. Otherwise it is
+/// PUT to `<store>/<file name>`, which has to be readable and allow cross-origin requests.
+pub fn upload(path: &Path, store: Option<&str>) {
+    let link = match store {
+        Some(store) => {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let url = format!("{}/{}", store.trim_end_matches('/'), server::percent_encode(&name));
+            print_step(&format!("Uploading profile to {}", url));
+            let status = resolve(process::Command::new("curl")
+                .args(["--silent", "--show-error", "--fail", "--upload-file"])
+                .arg(path)
+                .arg(&url)
+                .stdout(process::Stdio::null())
+                .status());
+            resolve_status(status);
+            server::from_url(&url)
+        },
+        None => {
+            print_step("Uploading profile to the Firefox Profiler");
+            let token = resolve(upload_to_profiler(path));
+            format!("{}/public/{}/", PROFILER_URL, token)
+        },
+    };
+    println!("Shareable link: {}", link.bright_blue());
+}
+
+/// S3 and GCS buckets are handled by their CLIs instead of plain HTTP uploads
+fn is_bucket(store: &str) -> bool {
+    store.starts_with("s3://") || store.starts_with("gs://")
+}
+
+/// Copy the files and a metadata manifest to `<bucket>/<timestamp>-<commit>/` using `aws` or `gcloud`
+fn archive(paths: &[PathBuf], bucket: &str) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let commit = git_commit();
+    let run_dir = match &commit {
+        Some(commit) => format!("{}-{}", timestamp, commit),
+        None => timestamp.to_string(),
+    };
+    let destination = format!("{}/{}", bucket.trim_end_matches('/'), run_dir);
+
+    let files: Vec<String> = paths.iter()
+        .map(|p| p.file_name().unwrap_or_default().to_string_lossy().to_string())
+        .collect();
+    let metadata = json!({
+        "timestamp": timestamp,
+        "commit": commit,
+        "command": env::args().collect::<Vec<_>>(),
+        "files": files,
+    });
+    let metadata_path = paths[0].with_file_name("metadata.json");
+    resolve(fs::write(&metadata_path, metadata.to_string()));
+
+    print_step(&format!("Archiving profile in {}", destination));
+    for path in paths.iter().chain([&metadata_path]) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut command = if bucket.starts_with("s3://") {
+            let mut command = process::Command::new("aws");
+            command.args(["s3", "cp", "--only-show-errors"]);
+            command
+        } else {
+            let mut command = process::Command::new("gcloud");
+            command.args(["storage", "cp"]);
+            command
+        };
+        let status = resolve(command
+            .arg(path)
+            .arg(format!("{}/{}", destination, name))
+            .status());
+        resolve_status(status);
+    }
+    println!("Archived run: {}", destination.bright_blue());
+}
+
+/// Post the gzipped profile to the profiler's store, returns the token of the published profile
+fn upload_to_profiler(path: &Path) -> Result<String, String> {
+    let gzip = process::Command::new("gzip")
+        .args(["--stdout", "--best"])
+        .stdin(File::open(path).map_err(|e| format!("Could not open {} ({})", path.to_string_lossy(), e))?)
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run gzip ({})", e))?;
+    let output = process::Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--data-binary", "@-"])
+        .arg("--header").arg(format!("Accept: {}", STORE_ACCEPT))
+        .arg(STORE_URL)
+        .stdin(gzip.stdout.ok_or("Could not read gzip output")?)
+        .stderr(process::Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not run curl ({})", e))?;
+    if !output.status.success() {
+        return Err("Upload failed".to_string());
+    }
+
+    // The store answers with a JWT, its payload carries the token of the profile
+    let jwt = String::from_utf8_lossy(&output.stdout);
+    let payload = jwt.trim().split('.').nth(1).ok_or("Unexpected response from the profile store")?;
+    let claims: TokenClaims = serde_json::from_slice(&base64url_decode(payload)?)
+        .map_err(|e| format!("Unexpected response from the profile store ({})", e))?;
+    Ok(claims.profile_token)
+}
+
+fn base64url_decode(input: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' | b'+' => 62,
+            b'_' | b'/' => 63,
+            _ => return Err(format!("Invalid base64 character {:?}", c as char)),
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(bytes)
+}
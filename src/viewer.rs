@@ -0,0 +1,167 @@
+//! Opening recordings in a browser or format-specific viewer
+
+use std::{collections::BTreeSet, env, fs::{self, File}, io, path::{Path, PathBuf}, process, sync::OnceLock};
+
+use colored::Colorize;
+
+use crate::category::{self, Category};
+use crate::config;
+use crate::perf;
+use crate::profile::Frame;
+use crate::report::{self, Format};
+use crate::server;
+use crate::wsl;
+use crate::{find_in_path, print_step, resolve, resolve_status};
+
+
+/// Firefox binary given with `--firefox-path`
+static FIREFOX: OnceLock<PathBuf> = OnceLock::new();
+
+
+/// Use the given Firefox binary as the fallback browser
+pub fn set_firefox_path(path: PathBuf) {
+    let _ = FIREFOX.set(path);
+}
+
+/// Firefox binary from `--firefox-path` or the configuration, or `firefox` from the PATH
+fn firefox() -> PathBuf {
+    FIREFOX.get_or_init(|| config::load().firefox_path.clone().unwrap_or_else(|| PathBuf::from("firefox"))).clone()
+}
+
+/// Command that opens a URL, in order of preference `--browser`, `$BROWSER`, the Windows
+/// browser under WSL, `xdg-open` and finally Firefox (see [`set_firefox_path`])
+pub fn browser_command(browser: Option<&str>) -> process::Command {
+    let configured = browser.map(str::to_string)
+        .or_else(|| env::var("BROWSER").ok().filter(|b| !b.trim().is_empty()));
+    if let Some(browser) = configured {
+        // Allow commands with arguments like `flatpak run org.chromium.Chromium`
+        let mut parts = browser.split_whitespace();
+        let mut command = match parts.next() {
+            Some(program) => process::Command::new(program),
+            None => process::Command::new(firefox()),
+        };
+        command.args(parts);
+        return command;
+    }
+
+    if wsl::detect().is_some() {
+        wsl::browser_command()
+    } else if find_in_path("xdg-open").is_some() {
+        process::Command::new("xdg-open")
+    } else if cfg!(target_os = "macos") {
+        process::Command::new("open")
+    } else {
+        process::Command::new(firefox())
+    }
+}
+
+pub fn open_url(url: &str, browser: Option<&str>) {
+    let status = resolve(browser_command(browser)
+        .arg(url)
+        .status());
+    resolve_status(status);
+}
+
+/// Open the most capable output written during this run in its viewer
+///
+/// Firefox Profiler files and traces are loaded into the profiler, pprof profiles into pprof's
+/// web UI and folded stacks are rendered to an SVG flame graph.
+pub fn open_outputs(browser: Option<&str>) {
+    let outputs = report::outputs();
+    let find = |format: Format| outputs.iter().find(|(f, _)| *f == format).map(|(_, p)| p.as_path());
+
+    if let Some(path) = report::profiler_output() {
+        server::serve_once(&path, browser);
+    } else if let Some(path) = find(Format::Pprof) {
+        open_pprof(path);
+    } else if let Some(path) = find(Format::Folded) {
+        open_flamegraph(path, browser);
+    } else {
+        eprintln!("{}", "Warning: there is no output that can be opened, choose a format other than summary".yellow());
+    }
+}
+
+/// Open the perf data recorded during this run in KDAB's Hotspot
+pub fn open_hotspot() {
+    let Some(data) = perf::last_data() else {
+        eprintln!("{}", "Warning: only perf recordings can be opened in Hotspot".yellow());
+        return;
+    };
+    if find_in_path("hotspot").is_none() {
+        resolve::<(), _>(Err("Hotspot is not installed (https://github.com/KDAB/hotspot)"));
+    }
+
+    print_step("Opening recording in Hotspot");
+    let status = resolve(process::Command::new("hotspot")
+        .arg(&data)
+        .status());
+    resolve_status(status);
+}
+
+fn open_pprof(path: &Path) {
+    print_step("Opening pprof web UI");
+    let mut command = match find_in_path("pprof") {
+        Some(pprof) => process::Command::new(pprof),
+        None => {
+            let mut command = process::Command::new("go");
+            command.args(["tool", "pprof"]);
+            command
+        },
+    };
+    let status = resolve(command
+        .arg("-http=localhost:0")
+        .arg(path)
+        .status());
+    resolve_status(status);
+}
+
+/// Render folded stacks with inferno (or flamegraph.pl) and open the SVG
+///
+/// The functions of the workspace are drawn in warm colors and everything else in muted ones,
+/// through the `palette.map` both renderers read with `--cp`.
+fn open_flamegraph(path: &Path, browser: Option<&str>) {
+    let svg_path = path.with_extension("svg");
+    let renderer = ["inferno-flamegraph", "flamegraph.pl"].into_iter()
+        .find(|r| find_in_path(r).is_some());
+    let Some(renderer) = renderer else {
+        eprintln!("{}", "Warning: install inferno (cargo install inferno) to render flame graphs".yellow());
+        return;
+    };
+
+    print_step("Rendering flame graph");
+    let path = resolve(fs::canonicalize(path));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    resolve(write_palette(&path, &dir.join("palette.map")));
+    let status = resolve(process::Command::new(renderer)
+        .arg("--cp")
+        .arg(&path)
+        .current_dir(dir)
+        .stdout(resolve(File::create(&svg_path)))
+        .status());
+    resolve_status(status);
+    println!("Flame graph: {}", svg_path.to_string_lossy().cyan());
+    open_url(&svg_path.to_string_lossy(), browser);
+}
+
+/// Assign each function of the folded stacks a color by its origin (`<function>->rgb(r,g,b)` lines)
+fn write_palette(folded: &Path, palette: &Path) -> io::Result<()> {
+    let content = fs::read_to_string(folded)?;
+    let functions: BTreeSet<&str> = content.lines()
+        .filter_map(|l| l.rsplit_once(' '))
+        .flat_map(|(stack, _)| stack.split(';'))
+        .collect();
+    let mut map = String::new();
+    for function in functions {
+        let frame = Frame { function: function.to_string(), module: String::new() };
+        // Vary the shade a little, so that neighbouring frames can be told apart
+        let shade = function.bytes().fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(u32::from(b))) % 40;
+        let (r, g, b) = match category::of(&frame) {
+            Category::User => (215 + shade / 2, 90 + shade * 2, 30),
+            Category::Dependency => (150 + shade, 150 + shade, 200 + shade / 2),
+            Category::Std => (170 + shade, 190 + shade, 210),
+            Category::Other | Category::System | Category::Kernel => (190 + shade, 190 + shade, 190 + shade),
+        };
+        map.push_str(&format!("{}->rgb({},{},{})\n", function, r, g, b));
+    }
+    fs::write(palette, map)
+}
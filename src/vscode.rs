@@ -0,0 +1,77 @@
+//! VS Code tasks running cargo-pprof, written by `--emit-tasks`
+//!
+//! Build errors are passed through by cargo in rustc's format, so the tasks use the `$rustc`
+//! problem matcher of the Rust extensions, with colors disabled for the patterns to match. The
+//! watch task is a background task that is ready whenever a recording finished.
+
+use std::{fs, path::Path};
+
+use serde_json::{Value, json};
+
+use crate::{print_step, resolve};
+
+const TASKS_FILE: &str = ".vscode/tasks.json";
+
+
+/// Add the cargo-pprof tasks to `.vscode/tasks.json`, replacing earlier versions of them
+pub fn emit_tasks() {
+    print_step(&format!("Writing tasks to {}", TASKS_FILE));
+    let path = Path::new(TASKS_FILE);
+    let mut config = match fs::read_to_string(path) {
+        Ok(content) => resolve(serde_json::from_str::<Value>(&content).map_err(|e| format!(
+            "Could not parse {} ({}), comments are not supported, add the tasks by hand:\n{}",
+            TASKS_FILE, e, resolve(serde_json::to_string_pretty(&tasks()))))),
+        Err(_) => json!({ "version": "2.0.0", "tasks": [] }),
+    };
+
+    let Some(existing) = config.get_mut("tasks").and_then(Value::as_array_mut) else {
+        resolve(Err(format!("{} has no tasks array", TASKS_FILE)))
+    };
+    for task in tasks() {
+        let label = task["label"].clone();
+        match existing.iter_mut().find(|t| t["label"] == label) {
+            Some(old) => *old = task,
+            None => existing.push(task),
+        }
+    }
+
+    if let Some(dir) = path.parent() {
+        resolve(fs::create_dir_all(dir));
+    }
+    resolve(fs::write(path, resolve(serde_json::to_string_pretty(&config)) + "\n"));
+    eprintln!("Done, run them with \"Tasks: Run Task\" or bind \"workbench.action.tasks.runTask\" to a key");
+}
+
+fn tasks() -> Vec<Value> {
+    vec![
+        json!({
+            "label": "cargo pprof",
+            "type": "shell",
+            "command": "cargo",
+            "args": ["pprof", "--color", "never", "--open"],
+            "group": "build",
+            "problemMatcher": ["$rustc"],
+        }),
+        json!({
+            "label": "cargo pprof watch",
+            "type": "shell",
+            "command": "cargo",
+            "args": ["pprof", "watch", "--color", "never", "--open"],
+            "isBackground": true,
+            "problemMatcher": {
+                "base": "$rustc",
+                "background": {
+                    "beginsPattern": "^=> Building binary",
+                    "endsPattern": "^(Trace file|Error):",
+                },
+            },
+        }),
+        json!({
+            "label": "cargo pprof time",
+            "type": "shell",
+            "command": "cargo",
+            "args": ["pprof", "time", "--color", "never"],
+            "problemMatcher": ["$rustc"],
+        }),
+    ]
+}
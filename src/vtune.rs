@@ -0,0 +1,67 @@
+use std::{fs, path::Path, process};
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::{print_step, resolve, resolve_status};
+
+
+/// Collect hotspots with Intel VTune and return the CPU time per function
+///
+/// VTune's reports do not include full call stacks, so every function is a single-frame sample.
+pub fn record(executable: &str, app_args: &[String], dir: &Path, ignore_exit: bool) -> Profile {
+    let result_dir = dir.join("vtune");
+    let report_path = dir.join("vtune-hotspots.tsv");
+    // VTune refuses to overwrite an existing result directory
+    let _ = fs::remove_dir_all(&result_dir);
+
+    print_step("Running program with vtune");
+    let status = app::run(app::command("vtune")
+        .args(["-collect", "hotspots", "-result-dir"])
+        .arg(&result_dir)
+        .arg("--")
+        .arg(executable)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+    eprintln!("VTune result: {} (open with vtune-gui)", result_dir.to_string_lossy());
+
+    print_step("Exporting hotspots report");
+    let status = resolve(process::Command::new("vtune")
+        .args(["-report", "hotspots", "-group-by", "function", "-format", "csv", "-csv-delimiter", "tab", "-result-dir"])
+        .arg(&result_dir)
+        .arg("-report-output")
+        .arg(&report_path)
+        .status());
+    resolve_status(status);
+
+    let content = resolve(fs::read_to_string(&report_path));
+    resolve(parse(&content))
+}
+
+/// Parse a tab separated hotspots report with `Function`, `CPU Time` and `Module` columns
+fn parse(content: &str) -> Result<Profile, String> {
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines.next().ok_or("Empty VTune report")?.split('\t').collect();
+    let column = |name: &str| header.iter().position(|h| h.trim() == name || h.trim().starts_with(&format!("{}:", name)));
+    let function_column = column("Function").ok_or("VTune report has no Function column")?;
+    let time_column = column("CPU Time").ok_or("VTune report has no CPU Time column")?;
+    let module_column = column("Module");
+
+    let samples = lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let seconds: f64 = fields.get(time_column)?.trim().parse().ok()?;
+            Some(Sample {
+                frames: vec![Frame {
+                    function: fields.get(function_column)?.to_string(),
+                    module: module_column.and_then(|c| fields.get(c)).unwrap_or(&"").to_string(),
+                }],
+                values: vec![(seconds * 1_000_000.0).round() as u64],
+            })
+        })
+        .collect();
+
+    Ok(Profile {
+        value_names: vec!["CPU time (us)".to_string()],
+        samples,
+    })
+}
@@ -0,0 +1,100 @@
+//! Profiling of WASI modules with wasmtime's guest profiler
+//!
+//! The guest profiler writes the processed Firefox Profiler format, which stores every table
+//! column-wise and is read here to produce the other outputs.
+
+use std::{fs, path::Path};
+
+use colored::Colorize;
+use serde_json::Value;
+
+use crate::app;
+use crate::profile::{Frame, Profile, Sample};
+use crate::report::{self, Format};
+use crate::{print_step, resolve};
+
+/// Sampling interval of the guest profiler
+const INTERVAL: &str = "1ms";
+
+
+pub fn is_wasm_target(target: &str) -> bool {
+    target.starts_with("wasm32-wasi")
+}
+
+/// Run the module under wasmtime with the guest profiler and emit the requested formats
+pub fn record(module: &str, app_args: &[String], formats: &[Format], dir: &Path, ignore_exit: bool) {
+    let profile_path = dir.join("wasmtime.json");
+    if formats.contains(&Format::Trace) {
+        eprintln!("{}", "Warning: wasmtime does not produce perf traces, use --format gecko".yellow());
+    }
+
+    print_step("Running module with wasmtime");
+    let status = app::run(app::command("wasmtime")
+        .arg("run")
+        .arg(format!("--profile=guest,{},{}", profile_path.to_string_lossy(), INTERVAL))
+        .arg(module)
+        .args(app_args));
+    app::check_exit(status, ignore_exit);
+
+    let content = resolve(fs::read_to_string(&profile_path));
+    let json: Value = resolve(serde_json::from_str(&content));
+    let profile = resolve(parse_processed(&json, module));
+    report::emit(&profile, formats, dir, "wasmtime");
+    if formats.contains(&Format::Gecko) {
+        report::print_output(Format::Gecko, &profile_path);
+    }
+}
+
+/// Collect the samples of all threads of a processed Firefox Profiler profile
+fn parse_processed(json: &Value, module: &str) -> Result<Profile, String> {
+    let threads = json["threads"].as_array()
+        .ok_or("Profile does not contain any threads")?;
+    let shared_strings = &json["shared"]["stringArray"];
+    let mut samples = Vec::new();
+
+    for thread in threads {
+        let strings = if thread["stringArray"].is_array() { &thread["stringArray"] } else { shared_strings };
+        let column = |table: &str, name: &str| -> Vec<Option<usize>> {
+            thread[table][name].as_array()
+                .map(|c| c.iter().map(|v| v.as_u64().map(|i| i as usize)).collect())
+                .unwrap_or_default()
+        };
+        let stack_frames = column("stackTable", "frame");
+        let stack_prefixes = column("stackTable", "prefix");
+        let frame_funcs = column("frameTable", "func");
+        let func_names = column("funcTable", "name");
+        let func_resources = column("funcTable", "resource");
+        let resource_names = column("resourceTable", "name");
+        let string = |index: Option<usize>| -> Option<String> {
+            strings.get(index?)?.as_str().map(str::to_string)
+        };
+
+        let weights = thread["samples"]["weight"].as_array();
+        for (i, stack) in column("samples", "stack").into_iter().enumerate() {
+            let mut frames = Vec::new();
+            let mut current = stack;
+            while let Some(index) = current {
+                let func = stack_frames.get(index).copied().flatten()
+                    .and_then(|f| frame_funcs.get(f).copied().flatten());
+                let function = func.and_then(|f| string(func_names.get(f).copied().flatten()));
+                let resource = func.and_then(|f| func_resources.get(f).copied().flatten())
+                    .and_then(|r| string(resource_names.get(r).copied().flatten()));
+                frames.push(Frame {
+                    function: function.unwrap_or_else(|| "[unknown]".to_string()),
+                    module: resource.unwrap_or_else(|| module.to_string()),
+                });
+                current = stack_prefixes.get(index).copied().flatten();
+            }
+            if frames.is_empty() {
+                continue;
+            }
+            let weight = weights.and_then(|w| w.get(i)).and_then(Value::as_u64).unwrap_or(1);
+            samples.push(Sample { frames, values: vec![weight] });
+        }
+    }
+
+    Ok(Profile {
+        value_names: vec!["samples".to_string()],
+        samples,
+    })
+}
@@ -0,0 +1,140 @@
+//! Re-recording the application whenever its sources change
+//!
+//! Every recording runs in a child `cargo pprof` with the forwarded options, so a failing build
+//! or recording only ends that iteration. The latest profiler output is served on a fixed URL,
+//! so reloading the profiler tab shows the newest recording.
+
+use std::{collections::HashMap, env, fs, path::{Path, PathBuf}, process, sync::{Arc, Mutex}, thread, time::{Duration, SystemTime}};
+
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::manifest;
+use crate::report::{self, Format};
+use crate::server;
+use crate::viewer;
+use crate::{WatchArgs, print_step, resolve};
+
+/// Options only handled by the watching process and not forwarded to the recordings
+const WATCH_FLAGS: &[&str] = &["--open"];
+const WATCH_OPTIONS: &[&str] = &["--poll-interval"];
+
+/// Modification times of the watched files
+type Snapshot = HashMap<PathBuf, SystemTime>;
+
+
+pub fn run(args: &WatchArgs) {
+    let metadata = resolve(manifest::load());
+    let Some(root) = metadata.current_package().and_then(|p| p.manifest_path.parent()).map(Path::to_path_buf) else {
+        resolve(Err("Could not find the package to watch"))
+    };
+    let forwarded = forwarded_args();
+    let interval = Duration::from_millis(args.poll_interval);
+    let outputs_list = metadata.target_directory.join("pprof-watch-outputs");
+
+    let latest = Arc::new(Mutex::new(Vec::new()));
+    let mut url = None;
+    let mut snapshot = scan(&root, &metadata.target_directory);
+    loop {
+        let _ = fs::remove_file(&outputs_list);
+        let status = resolve(process::Command::new(resolve(env::current_exe()))
+            .arg("pprof")
+            .args(&forwarded)
+            .env(report::OUTPUTS_ENV_VAR, &outputs_list)
+            .status());
+        if status.success() {
+            if let Some(path) = profiler_output(&outputs_list) {
+                *latest.lock().unwrap() = resolve(fs::read(&path));
+                match &url {
+                    None if args.run.open => {
+                        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                        let file_url = server::serve_latest(&name, latest.clone());
+                        // The browser command may only return once the browser is closed
+                        let profiler_url = server::from_url(&file_url);
+                        let browser = args.run.browser.clone();
+                        thread::spawn(move || viewer::open_url(&profiler_url, browser.as_deref()));
+                        url = Some(file_url);
+                    },
+                    Some(file_url) => println!("Serving the new recording at {}, reload the profiler to see it", file_url.cyan()),
+                    None => (),
+                }
+            }
+        } else {
+            eprintln!("{}", "Warning: the recording failed".yellow());
+        }
+
+        print_step("Waiting for changes");
+        snapshot = wait_for_change(&root, &metadata.target_directory, snapshot, interval);
+    }
+}
+
+/// Arguments of this invocation without the `watch` subcommand and its own options
+fn forwarded_args() -> Vec<String> {
+    let mut args = env::args().skip_while(|a| a != "pprof").skip(1);
+    let mut forwarded = Vec::new();
+    let mut removed_subcommand = false;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            forwarded.push(arg);
+            forwarded.extend(args.by_ref());
+        } else if arg == "watch" && !removed_subcommand {
+            removed_subcommand = true;
+        } else if WATCH_FLAGS.contains(&arg.as_str()) {
+            continue;
+        } else if WATCH_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !WATCH_OPTIONS.iter().any(|o| arg.starts_with(&format!("{}=", o))) {
+            forwarded.push(arg);
+        }
+    }
+    forwarded
+}
+
+/// The Firefox Profiler file or trace a recording wrote to the outputs list
+fn profiler_output(list: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(list).ok()?;
+    let outputs: Vec<(Format, PathBuf)> = content.lines()
+        .filter_map(|l| l.split_once(' '))
+        .filter_map(|(format, path)| Some((Format::from_str(format, true).ok()?, PathBuf::from(path))))
+        .collect();
+    outputs.iter().find(|(f, _)| *f == Format::Gecko)
+        .or_else(|| outputs.iter().find(|(f, _)| *f == Format::Trace))
+        .map(|(_, p)| p.clone())
+}
+
+/// Block until a file was added, removed or modified and no further changes follow within one interval
+fn wait_for_change(root: &Path, target_dir: &Path, mut snapshot: Snapshot, interval: Duration) -> Snapshot {
+    let mut changed = false;
+    loop {
+        thread::sleep(interval);
+        let current = scan(root, target_dir);
+        if current != snapshot {
+            changed = true;
+            snapshot = current;
+        } else if changed {
+            return snapshot;
+        }
+    }
+}
+
+/// Modification times of all files of the package, except the target directory and hidden files
+fn scan(root: &Path, target_dir: &Path) -> Snapshot {
+    let mut snapshot = HashMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else { continue };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') || path == target_dir {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else { continue };
+            if metadata.is_dir() {
+                dirs.push(path);
+            } else if let Ok(modified) = metadata.modified() {
+                snapshot.insert(path, modified);
+            }
+        }
+    }
+    snapshot
+}
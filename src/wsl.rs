@@ -0,0 +1,71 @@
+use std::{fs, path::PathBuf, process};
+
+use crate::find_in_path;
+
+/// Directory Debian and Ubuntu install kernel-specific perf builds into
+const LINUX_TOOLS_DIR: &str = "/usr/lib/linux-tools";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WslVersion {
+    /// Syscall translation layer without perf events
+    Wsl1,
+    /// Lightweight VM running Microsoft's kernel
+    Wsl2,
+}
+
+
+/// Detect whether we are running inside the Windows Subsystem for Linux
+pub fn detect() -> Option<WslVersion> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease").ok()?;
+    if release.contains("WSL2") || release.contains("microsoft-standard") {
+        Some(WslVersion::Wsl2)
+    } else if release.to_lowercase().contains("microsoft") {
+        Some(WslVersion::Wsl1)
+    } else {
+        None
+    }
+}
+
+/// Find a perf binary that works on the running kernel
+///
+/// The `perf` wrapper of most distributions looks for a build matching the kernel version,
+/// which does not exist for Microsoft's kernel, so fall back to any installed build.
+pub fn perf_binary() -> Option<PathBuf> {
+    let works = |perf: &PathBuf| process::Command::new(perf)
+        .arg("--version")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+
+    if let Some(perf) = find_in_path("perf").filter(works) {
+        return Some(perf);
+    }
+    let mut candidates: Vec<PathBuf> = fs::read_dir(LINUX_TOOLS_DIR).ok()?
+        .flatten()
+        .map(|e| e.path().join("perf"))
+        .filter(works)
+        .collect();
+    candidates.sort();
+    candidates.pop()
+}
+
+/// Command that opens a URL in the default Windows browser
+///
+/// `cmd.exe /c start` would split URLs at `&`, while rundll32 passes the URL on untouched.
+pub fn browser_command() -> process::Command {
+    match find_in_path("wslview") {
+        Some(wslview) => process::Command::new(wslview),
+        None => {
+            let mut command = process::Command::new("rundll32.exe");
+            command.arg("url.dll,FileProtocolHandler");
+            command
+        },
+    }
+}
+
+/// How to keep a sysctl setting like `kernel.perf_event_paranoid=2`, which WSL2 resets whenever its VM restarts
+pub fn sysctl_hint(setting: &str) -> String {
+    format!("to keep it across restarts of WSL, add {} to /etc/sysctl.d/99-perf.conf (with systemd enabled) \
+        or `command = sysctl -w {}` to the [boot] section of /etc/wsl.conf", setting, setting)
+}